@@ -1,6 +1,10 @@
 extern crate clap;
 extern crate paritydb;
 
+mod shell;
+
+use std::io;
+
 use clap::{Arg, ArgMatches, App, SubCommand};
 use paritydb::{Database, Error, Options};
 
@@ -51,6 +55,26 @@ fn do_delete(db: &str, key: &str) -> Result<(), Error> {
 	Ok(())
 }
 
+fn do_shell(db: &str) -> Result<(), Error> {
+	let mut db = Database::open(db, Options::default())?;
+	let stdin = io::stdin();
+	shell::run(&mut db, stdin.lock(), io::stdout(), true)?;
+	Ok(())
+}
+
+fn do_dump(file: &str, matches: &ArgMatches) -> Result<(), Error> {
+	// the layout of the file depends on the options the database was created with
+	let mut options = Options::from_env("PARITYDB")?;
+	for &(arg, name) in &[("KEY_LEN", "key_len"), ("VALUE_LEN", "value_len")] {
+		if let Some(value) = matches.value_of(arg) {
+			options.set(name, value)?;
+		}
+	}
+
+	let stdout = io::stdout();
+	paritydb::debug::dump_file(file, &options, stdout.lock())
+}
+
 fn main() {
 	let matches =
 		App::new("paritydb-cli")
@@ -91,6 +115,22 @@ fn main() {
 					.short("d")
 					.long("db")
 					.takes_value(true)))
+			.subcommand(SubCommand::with_name("dump")
+				.about("Print records of a data or collision file, other options are read from PARITYDB_* variables")
+				.arg(Arg::with_name("FILE")
+					.required(true)
+					.index(1))
+				.arg(Arg::with_name("KEY_LEN")
+					.long("key-len")
+					.takes_value(true))
+				.arg(Arg::with_name("VALUE_LEN")
+					.long("value-len")
+					.takes_value(true)))
+			.subcommand(SubCommand::with_name("shell")
+				.about("Run interactive shell on database")
+				.arg(Arg::with_name("DB")
+					.required(true)
+					.index(1)))
 			.get_matches();
 
 	match matches.subcommand() {
@@ -115,6 +155,20 @@ fn main() {
 				println!("errors for delete");
 			}
 		},
+		("dump", Some(sub_m)) => {
+			if let Some(file) = sub_m.value_of("FILE") {
+				do_dump(file, &sub_m).expect("execute dump error.");
+			} else {
+				println!("errors for dump.");
+			}
+		},
+		("shell", Some(sub_m)) => {
+			if let Some(db) = sub_m.value_of("DB") {
+				do_shell(db).expect("execute shell error.");
+			} else {
+				println!("errors for shell.");
+			}
+		},
 		_ => {}
 	}
 }
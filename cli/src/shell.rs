@@ -0,0 +1,230 @@
+//! Interactive shell for debugging sessions.
+//!
+//! Reads one command per line. Keys and values are given either as utf8 text
+//! or as hex prefixed with `0x`, and are printed as text if they are printable
+//! ascii and as hex otherwise.
+//!
+//! ```text
+//! get <key>
+//! put <key> <value>
+//! delete <key>
+//! scan [prefix] [limit]
+//! stats
+//! verify
+//! help
+//! exit
+//! ```
+
+use std::io::{self, BufRead, Write};
+
+use paritydb::{Database, Error};
+
+const DEFAULT_SCAN_LIMIT: usize = 20;
+const PROMPT: &'static str = "paritydb> ";
+
+const HELP: &'static str = "\
+get <key>              print the value of the key
+put <key> <value>      insert the value
+delete <key>           delete the key
+scan [prefix] [limit]  print at most limit records with keys starting with prefix
+stats                  print statistics and health of the database
+verify                 read every record and report errors
+help                   print this message
+exit                   leave the shell
+
+keys and values are utf8 text or hex prefixed with 0x";
+
+/// Formats `data` as text if it is printable ascii, or as `0x` prefixed hex otherwise.
+pub fn format_bytes(data: &[u8]) -> String {
+	let printable = !data.is_empty() && data.iter().all(|byte| *byte >= 0x20 && *byte < 0x7f);
+	if printable && !data.starts_with(b"0x") {
+		return String::from_utf8_lossy(data).into_owned();
+	}
+
+	format!("0x{}", to_hex(data))
+}
+
+/// Parses `0x` prefixed hex, or returns the utf8 bytes of `input` otherwise.
+pub fn parse_bytes(input: &str) -> Option<Vec<u8>> {
+	if input.starts_with("0x") {
+		from_hex(&input[2..])
+	} else {
+		Some(input.as_bytes().to_vec())
+	}
+}
+
+fn to_hex(data: &[u8]) -> String {
+	data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+	if hex.len() % 2 != 0 {
+		return None;
+	}
+
+	hex.as_bytes().chunks(2)
+		.map(|byte| ::std::str::from_utf8(byte).ok().and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+		.collect()
+}
+
+/// Runs commands read from `input` against `db` until `exit` or the end of the input.
+///
+/// The prompt is only written if `interactive` is set.
+pub fn run<R: BufRead, W: Write>(db: &mut Database, input: R, mut output: W, interactive: bool) -> io::Result<()> {
+	if interactive {
+		write!(output, "{}", PROMPT)?;
+		output.flush()?;
+	}
+
+	for line in input.lines() {
+		let line = line?;
+		let args: Vec<_> = line.split_whitespace().collect();
+
+		match args.first() {
+			Some(&"exit") | Some(&"quit") => break,
+			Some(_) => {
+				// a failed command must not end the session
+				if let Err(err) = execute(db, &args, &mut output) {
+					writeln!(output, "error: {}", err)?;
+				}
+			},
+			None => {},
+		}
+
+		if interactive {
+			write!(output, "{}", PROMPT)?;
+			output.flush()?;
+		}
+	}
+
+	Ok(())
+}
+
+fn execute<W: Write>(db: &mut Database, args: &[&str], output: &mut W) -> Result<(), Error> {
+	let parsed: Option<Vec<Vec<u8>>> = args[1..].iter().map(|arg| parse_bytes(arg)).collect();
+	let params = match parsed {
+		Some(params) => params,
+		None => {
+			writeln!(output, "invalid hex argument")?;
+			return Ok(());
+		},
+	};
+
+	match (args[0], params.len()) {
+		("get", 1) => match db.get(&params[0])? {
+			Some(value) => writeln!(output, "{}", format_bytes(&value.to_vec()))?,
+			None => writeln!(output, "not found")?,
+		},
+		("put", 2) => {
+			let mut tx = db.create_transaction();
+			tx.insert(&params[0], &params[1])?;
+			db.commit(&tx)?;
+			db.flush_journal(1)?;
+		},
+		("delete", 1) => {
+			let mut tx = db.create_transaction();
+			tx.delete(&params[0])?;
+			db.commit(&tx)?;
+			db.flush_journal(1)?;
+		},
+		("scan", 0) | ("scan", 1) | ("scan", 2) => {
+			let limit = match args.get(2) {
+				Some(limit) => match limit.parse() {
+					Ok(limit) => limit,
+					Err(_) => {
+						writeln!(output, "invalid limit: {}", limit)?;
+						return Ok(());
+					},
+				},
+				None => DEFAULT_SCAN_LIMIT,
+			};
+
+			let prefix = params.first().cloned().unwrap_or_default();
+			for item in db.iter_prefix(&prefix)?.take(limit) {
+				let (key, value) = item?;
+				writeln!(output, "{} {}", format_bytes(key), format_bytes(&value.to_vec()))?;
+			}
+		},
+		("stats", 0) => {
+			let stats = db.statistics();
+			writeln!(output, "data file bytes: {}", stats.data_file_bytes)?;
+			writeln!(output, "occupied bytes: {}", stats.occupied_bytes)?;
+			writeln!(output, "space amplification: {:.2}", stats.space_amplification())?;
+			writeln!(output, "journal eras: {}", stats.journal_eras)?;
+			writeln!(output, "collided prefixes: {}", stats.collided_prefixes)?;
+			writeln!(output, "journal bytes: {}", stats.journal_bytes)?;
+			writeln!(output, "collision log bytes: {}", stats.collision_log_bytes)?;
+			writeln!(output, "garbage bytes: {}", stats.garbage_bytes)?;
+			writeln!(output, "reads: {}, writes: {}, deletes: {}", stats.reads, stats.writes, stats.deletes)?;
+			writeln!(output, "compactions: {}", stats.compactions)?;
+			writeln!(output, "cache hit rate: {:.2}", stats.cache_hit_rate())?;
+			writeln!(output, "health: {}", db.health().to_json())?;
+		},
+		("verify", 0) => {
+			let mut records = 0;
+			let mut errors = 0;
+			for item in db.iter()? {
+				match item {
+					Ok(_) => records += 1,
+					Err(err) => {
+						errors += 1;
+						writeln!(output, "error: {}", err)?;
+					},
+				}
+			}
+			writeln!(output, "{} records, {} errors", records, errors)?;
+		},
+		("help", _) => writeln!(output, "{}", HELP)?,
+		(command, _) => writeln!(output, "invalid command: {}, see help", command)?,
+	}
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	extern crate tempdir;
+
+	use paritydb::{Database, Options, ValuesLen};
+	use super::{format_bytes, parse_bytes, run};
+
+	#[test]
+	fn test_format_bytes() {
+		assert_eq!(format_bytes(b"abc"), "abc");
+		assert_eq!(format_bytes(b"\x00\xab"), "0x00ab");
+		assert_eq!(format_bytes(b"0x1"), "0x307831");
+		assert_eq!(format_bytes(b""), "0x");
+		assert_eq!(parse_bytes("abc"), Some(b"abc".to_vec()));
+		assert_eq!(parse_bytes("0x00ab"), Some(b"\x00\xab".to_vec()));
+		assert_eq!(parse_bytes("0x0"), None);
+		assert_eq!(parse_bytes(&format_bytes(b"0x1")), Some(b"0x1".to_vec()));
+	}
+
+	#[test]
+	fn test_shell() {
+		let temp = tempdir::TempDir::new("test_shell").unwrap();
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		}).unwrap();
+
+		let input = "put aaa 001\nput 0x000102 0xff0000\n\nget aaa\nget bbb\nscan\nscan a 1\ndelete aaa\nget aaa\nput a 001\nverify\nfoo\nexit\nget 0x000102\n";
+		let mut output = Vec::new();
+		run(&mut db, input.as_bytes(), &mut output, false).unwrap();
+
+		let output = String::from_utf8(output).unwrap();
+		let mut lines = output.lines();
+		assert_eq!(lines.next(), Some("001"));
+		assert_eq!(lines.next(), Some("not found"));
+		assert_eq!(lines.next(), Some("0x000102 0xff0000"));
+		assert_eq!(lines.next(), Some("aaa 001"));
+		assert_eq!(lines.next(), Some("aaa 001"));
+		assert_eq!(lines.next(), Some("not found"));
+		assert!(lines.next().unwrap().starts_with("error: "));
+		assert_eq!(lines.next(), Some("1 records, 0 errors"));
+		assert_eq!(lines.next(), Some("invalid command: foo, see help"));
+		assert_eq!(lines.next(), None);
+	}
+}
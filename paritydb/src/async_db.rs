@@ -0,0 +1,147 @@
+//! Futures based access to the database for asynchronous applications.
+//!
+//! Available with the `async` feature. Reads and commits run on a pool of threads,
+//! so the event loop of the application, e.g. a tokio reactor, never waits for the disk.
+//! Reads share the database, while commits and flushes wait for the running reads and
+//! run one at a time.
+
+use std::sync::Arc;
+
+use futures::{stream, Future, Stream};
+use futures_cpupool::{CpuFuture, CpuPool};
+use parking_lot::RwLock;
+
+use database::Database;
+use error::{Error, Result};
+use transaction::Transaction;
+
+/// Database with operations returning futures which are resolved on a thread pool.
+#[derive(Clone)]
+pub struct AsyncDatabase {
+	db: Arc<RwLock<Database>>,
+	pool: CpuPool,
+}
+
+impl AsyncDatabase {
+	/// Creates a wrapper running the operations on `db` on given `pool`.
+	pub fn new(db: Database, pool: CpuPool) -> Self {
+		AsyncDatabase {
+			db: Arc::new(RwLock::new(db)),
+			pool,
+		}
+	}
+
+	/// Returns the wrapped database, e.g. for operations without an asynchronous version.
+	pub fn inner(&self) -> &Arc<RwLock<Database>> {
+		&self.db
+	}
+
+	/// Returns transaction with the key length of the database.
+	pub fn create_transaction(&self) -> Transaction {
+		self.db.read().create_transaction()
+	}
+
+	/// Returns the value of the key.
+	pub fn get<K: AsRef<[u8]>>(&self, key: K) -> CpuFuture<Option<Vec<u8>>, Error> {
+		let db = self.db.clone();
+		let key = key.as_ref().to_vec();
+		self.pool.spawn_fn(move || -> Result<_> {
+			Ok(db.read().get(&key)?.map(|value| value.to_vec()))
+		})
+	}
+
+	/// Commits the transaction, see `Database::commit`.
+	pub fn commit(&self, tx: Transaction) -> CpuFuture<(), Error> {
+		let db = self.db.clone();
+		self.pool.spawn_fn(move || db.write().commit(&tx))
+	}
+
+	/// Flushes at most `max` journal eras, see `Database::flush_journal`.
+	pub fn flush_journal(&self, max: Option<usize>) -> CpuFuture<(), Error> {
+		let db = self.db.clone();
+		self.pool.spawn_fn(move || db.write().flush_journal(max))
+	}
+
+	/// Returns a stream of all key-value pairs ordered by key.
+	///
+	/// Pairs are read in batches of `batch` pairs. Every batch is read separately, so commits
+	/// made while the stream is polled may be visible in the following batches.
+	pub fn iter(&self, batch: usize) -> Box<Stream<Item = (Vec<u8>, Vec<u8>), Error = Error> + Send> {
+		let db = self.db.clone();
+		let pool = self.pool.clone();
+		let batch = ::std::cmp::max(batch, 1);
+
+		// state is the last key of the previous batch, empty before the first one
+		let batches = stream::unfold(Some(Vec::new()), move |after: Option<Vec<u8>>| {
+			after.map(|after| {
+				let db = db.clone();
+				pool.spawn_fn(move || -> Result<_> {
+					let items = read_batch(&db.read(), &after, batch)?;
+					let next = match items.last() {
+						Some(&(ref key, _)) if items.len() == batch => Some(key.clone()),
+						_ => None,
+					};
+
+					Ok((items, next))
+				})
+			})
+		});
+
+		Box::new(batches.map(stream::iter_ok::<_, Error>).flatten())
+	}
+}
+
+/// Reads at most `limit` key-value pairs with keys greater than `after`.
+fn read_batch(db: &Database, after: &[u8], limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+	let iter = if after.is_empty() { db.iter()? } else { db.iter_from(after)? };
+	iter.filter(|item| match *item {
+			Ok((key, _)) => key != after,
+			Err(_) => true,
+		})
+		.take(limit)
+		.map(|item| item.map(|(key, value)| (key.to_vec(), value.to_vec())))
+		.collect()
+}
+
+#[cfg(test)]
+mod tests {
+	extern crate tempdir;
+
+	use futures::{Future, Stream};
+	use futures_cpupool::CpuPool;
+
+	use database::Database;
+	use options::{Options, ValuesLen};
+	use super::AsyncDatabase;
+
+	#[test]
+	fn test_async_database() {
+		let temp = tempdir::TempDir::new("test_async_database").unwrap();
+		let db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		}).unwrap();
+		let db = AsyncDatabase::new(db, CpuPool::new(2));
+
+		let mut tx = db.create_transaction();
+		tx.insert("aaa", "001").unwrap();
+		tx.insert("bbb", "002").unwrap();
+		tx.insert("ccc", "003").unwrap();
+		db.commit(tx).wait().unwrap();
+		db.flush_journal(None).wait().unwrap();
+
+		assert_eq!(db.get("bbb").wait().unwrap(), Some(b"002".to_vec()));
+		assert_eq!(db.get("ddd").wait().unwrap(), None);
+		assert!(db.get("dd").wait().is_err());
+
+		let items = db.iter(2).collect().wait().unwrap();
+		assert_eq!(items, vec![
+			(b"aaa".to_vec(), b"001".to_vec()),
+			(b"bbb".to_vec(), b"002".to_vec()),
+			(b"ccc".to_vec(), b"003".to_vec()),
+		]);
+		assert_eq!(db.iter(3).collect().wait().unwrap().len(), 3);
+	}
+}
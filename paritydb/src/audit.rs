@@ -0,0 +1,44 @@
+//! Audit hook called for every commit.
+
+use std::fmt;
+
+use error::Result;
+use transaction::Transaction;
+
+/// Commit passed to the audit hook before it is written to the journal.
+#[derive(Debug)]
+pub struct AuditRecord<'a> {
+	/// Sequence number the commit will have.
+	pub sequence: u64,
+	/// Seconds since the unix epoch when the commit was requested.
+	pub timestamp: u64,
+	/// Committed operations, with values as they were passed to `commit`.
+	pub transaction: &'a Transaction,
+}
+
+/// Hook with the audit callback registered by the user.
+#[derive(Default)]
+pub struct Audit {
+	hook: Option<Box<Fn(&AuditRecord) -> Result<()> + Send + Sync>>,
+}
+
+impl fmt::Debug for Audit {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "Audit {{ hook: {} }}", if self.hook.is_some() { "Some(..)" } else { "None" })
+	}
+}
+
+impl Audit {
+	/// Replaces the current hook.
+	pub fn set_hook(&mut self, hook: Box<Fn(&AuditRecord) -> Result<()> + Send + Sync>) {
+		self.hook = Some(hook);
+	}
+
+	/// Passes the record to the hook. An error of the hook aborts the commit.
+	pub fn record(&self, record: &AuditRecord) -> Result<()> {
+		match self.hook {
+			Some(ref hook) => hook(record),
+			None => Ok(()),
+		}
+	}
+}
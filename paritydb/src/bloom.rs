@@ -0,0 +1,79 @@
+//! Bloom filter of the keys of a collision file.
+//!
+//! Lookups of keys which are not in the filter are answered without reading the
+//! index or the log. The filter never forgets a key, so deleted keys stay in it
+//! until it is built again with the index.
+
+use std::cmp;
+
+/// Number of bits of the filter per key it is built for, about 1% of absent keys are reported
+/// as present.
+const BITS_PER_KEY: usize = 10;
+/// Number of bits set for every key.
+const HASHES: usize = 7;
+/// Size of the smallest filter in bits.
+const MIN_BITS: usize = 64;
+
+#[derive(Debug)]
+pub struct BloomFilter {
+	bits: Vec<u64>,
+}
+
+impl BloomFilter {
+	/// Creates an empty filter sized for `keys` keys.
+	pub fn with_capacity(keys: usize) -> Self {
+		let bits = cmp::max(keys.saturating_mul(BITS_PER_KEY), MIN_BITS);
+		BloomFilter {
+			bits: vec![0; (bits + 63) / 64],
+		}
+	}
+
+	/// Adds `key` to the filter.
+	pub fn insert(&mut self, key: &[u8]) {
+		for bit in &self.bits_of(key) {
+			self.bits[bit / 64] |= 1u64 << (bit % 64);
+		}
+	}
+
+	/// Returns false if `key` was never added to the filter.
+	pub fn may_contain(&self, key: &[u8]) -> bool {
+		self.bits_of(key).iter().all(|bit| self.bits[bit / 64] & (1u64 << (bit % 64)) != 0)
+	}
+
+	/// Returns positions of the bits of `key`, derived from two halves of its FNV-1a hash.
+	fn bits_of(&self, key: &[u8]) -> [usize; HASHES] {
+		let mut hash = 0xcbf2_9ce4_8422_2325u64;
+		for byte in key {
+			hash ^= *byte as u64;
+			hash = hash.wrapping_mul(0x0100_0000_01b3);
+		}
+
+		let len = self.bits.len() as u64 * 64;
+		let (first, second) = (hash & 0xffff_ffff, (hash >> 32) | 1);
+		let mut bits = [0; HASHES];
+		for (i, bit) in bits.iter_mut().enumerate() {
+			*bit = (first.wrapping_add((i as u64).wrapping_mul(second)) % len) as usize;
+		}
+		bits
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::BloomFilter;
+
+	#[test]
+	fn test_bloom_filter() {
+		let mut filter = BloomFilter::with_capacity(1000);
+		for i in 0..1000u32 {
+			filter.insert(format!("key{}", i).as_bytes());
+		}
+
+		assert!((0..1000u32).all(|i| filter.may_contain(format!("key{}", i).as_bytes())));
+		let false_positives = (0..1000u32).filter(|i| filter.may_contain(format!("absent{}", i).as_bytes())).count();
+		assert!(false_positives < 50, "{} false positives", false_positives);
+
+		let empty = BloomFilter::with_capacity(0);
+		assert!(!empty.may_contain(b"key"));
+	}
+}
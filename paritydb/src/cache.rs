@@ -0,0 +1,261 @@
+//! Cache of values read from the data file and collision files.
+//!
+//! Values are cached after reversing value transforms, by their key, which also determines
+//! their prefix. The cache holds up to `Options::cache_size` bytes of keys and values and
+//! drops the least recently used values first. Values are removed from the cache when the
+//! journal eras writing them are flushed.
+//!
+//! Pinned values are kept besides the size-bounded values until they are unpinned, also
+//! when the cache is disabled. The database reads them again after flushing their keys.
+
+use std::collections::{BTreeMap, HashMap};
+
+/// Approximate memory used by a cached value besides its key and the value itself.
+const ENTRY_OVERHEAD: usize = 64;
+
+/// Counters of the value cache, see `Database::cache_stats`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct CacheStats {
+	/// Number of reads answered from the cache.
+	pub hits: u64,
+	/// Number of reads of the data file or a collision file.
+	pub misses: u64,
+	/// Number of cached values.
+	pub entries: usize,
+	/// Number of bytes taken by the cached keys and values.
+	pub bytes: usize,
+	/// Number of pinned keys.
+	pub pinned: usize,
+}
+
+/// Size-bounded cache of decoded values, least recently used values are dropped first.
+#[derive(Debug)]
+pub struct ValueCache {
+	capacity: usize,
+	/// Values and the tick of their last use by key.
+	values: HashMap<Vec<u8>, (Vec<u8>, u64)>,
+	/// Keys by the tick of their last use.
+	used: BTreeMap<u64, Vec<u8>>,
+	/// Pinned values by key, `None` if the key is not stored.
+	pinned: HashMap<Vec<u8>, Option<Vec<u8>>>,
+	tick: u64,
+	stats: CacheStats,
+}
+
+impl ValueCache {
+	/// Creates a cache of at most `capacity` bytes, 0 disables the cache.
+	pub fn new(capacity: usize) -> Self {
+		ValueCache {
+			capacity,
+			values: HashMap::new(),
+			used: BTreeMap::new(),
+			pinned: HashMap::new(),
+			tick: 0,
+			stats: CacheStats::default(),
+		}
+	}
+
+	/// Returns true if values are cached.
+	pub fn is_enabled(&self) -> bool {
+		self.capacity != 0
+	}
+
+	fn entry_size(key: &[u8], value: &[u8]) -> usize {
+		key.len() + value.len() + ENTRY_OVERHEAD
+	}
+
+	/// Returns the cached value of `key` and marks it as the most recently used one.
+	pub fn get(&mut self, key: &[u8]) -> Option<Vec<u8>> {
+		self.tick += 1;
+		let tick = self.tick;
+		let value = match self.values.get_mut(key) {
+			Some(&mut (ref value, ref mut used)) => {
+				let key = self.used.remove(&*used).expect("every cached value has its tick; qed");
+				self.used.insert(tick, key);
+				*used = tick;
+				value.clone()
+			},
+			None => {
+				self.stats.misses += 1;
+				return None;
+			},
+		};
+
+		self.stats.hits += 1;
+		Some(value)
+	}
+
+	/// Returns true if a value of `key` is cached, without marking it as used.
+	pub fn contains(&self, key: &[u8]) -> bool {
+		self.values.contains_key(key)
+	}
+
+	/// Returns the pinned value of `key`, `Some(None)` if the pinned key is not stored.
+	pub fn get_pinned(&mut self, key: &[u8]) -> Option<Option<Vec<u8>>> {
+		let value = self.pinned.get(key).cloned();
+		if value.is_some() {
+			self.stats.hits += 1;
+		}
+		value
+	}
+
+	/// Keeps `value` of `key` until the key is unpinned, `None` if the key is not stored.
+	pub fn pin(&mut self, key: &[u8], value: Option<Vec<u8>>) {
+		self.remove(key);
+		self.pinned.insert(key.to_vec(), value);
+		self.stats.pinned = self.pinned.len();
+	}
+
+	/// Drops the pinned value of `key`. Returns false if the key was not pinned.
+	pub fn unpin(&mut self, key: &[u8]) -> bool {
+		let unpinned = self.pinned.remove(key).is_some();
+		self.stats.pinned = self.pinned.len();
+		unpinned
+	}
+
+	/// Returns true if `key` is pinned.
+	pub fn is_pinned(&self, key: &[u8]) -> bool {
+		self.pinned.contains_key(key)
+	}
+
+	/// Returns the pinned keys.
+	pub fn pinned_keys(&self) -> Vec<Vec<u8>> {
+		self.pinned.keys().cloned().collect()
+	}
+
+	/// Caches `value` of `key`, dropping the least recently used values if the cache is full.
+	pub fn insert(&mut self, key: &[u8], value: Vec<u8>) {
+		let size = Self::entry_size(key, &value);
+		if size > self.capacity || self.is_pinned(key) {
+			return;
+		}
+
+		self.remove(key);
+		let capacity = self.capacity;
+		self.shrink_to(capacity - size);
+
+		self.tick += 1;
+		self.used.insert(self.tick, key.to_vec());
+		self.values.insert(key.to_vec(), (value, self.tick));
+		self.stats.entries += 1;
+		self.stats.bytes += size;
+	}
+
+	/// Changes the capacity of the cache, dropping the least recently used values which
+	/// don't fit anymore. 0 disables the cache.
+	pub fn set_capacity(&mut self, capacity: usize) {
+		self.capacity = capacity;
+		self.shrink_to(capacity);
+	}
+
+	/// Drops the least recently used values until the cache takes at most `bytes` bytes.
+	fn shrink_to(&mut self, bytes: usize) {
+		while self.stats.bytes > bytes {
+			let oldest = *self.used.keys().next().expect("cache is not empty if it takes bytes; qed");
+			let key = self.used.remove(&oldest).expect("tick was just taken from the map; qed");
+			self.remove(&key);
+		}
+	}
+
+	/// Drops the cached value of `key`, if any.
+	pub fn remove(&mut self, key: &[u8]) {
+		if let Some((value, used)) = self.values.remove(key) {
+			self.used.remove(&used);
+			self.stats.entries -= 1;
+			self.stats.bytes -= Self::entry_size(key, &value);
+		}
+	}
+
+	/// Drops all cached values, the pinned ones are kept.
+	pub fn clear(&mut self) {
+		self.values.clear();
+		self.used.clear();
+		self.stats.entries = 0;
+		self.stats.bytes = 0;
+	}
+
+	/// Returns the counters of the cache.
+	pub fn stats(&self) -> CacheStats {
+		self.stats.clone()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::{ValueCache, ENTRY_OVERHEAD};
+
+	#[test]
+	fn test_value_cache() {
+		let mut cache = ValueCache::new(2 * (ENTRY_OVERHEAD + 4));
+		assert!(cache.is_enabled());
+		assert!(!ValueCache::new(0).is_enabled());
+
+		cache.insert(b"aa", b"01".to_vec());
+		cache.insert(b"bb", b"02".to_vec());
+		assert_eq!(cache.get(b"aa"), Some(b"01".to_vec()));
+
+		// `bb` is the least recently used value
+		cache.insert(b"cc", b"03".to_vec());
+		assert_eq!(cache.get(b"bb"), None);
+		assert_eq!(cache.get(b"aa"), Some(b"01".to_vec()));
+		assert_eq!(cache.get(b"cc"), Some(b"03".to_vec()));
+
+		cache.insert(b"cc", b"04".to_vec());
+		assert_eq!(cache.get(b"cc"), Some(b"04".to_vec()));
+		cache.remove(b"aa");
+		assert_eq!(cache.get(b"aa"), None);
+
+		// values which don't fit in the cache are not cached
+		cache.insert(b"dd", vec![0; 2 * ENTRY_OVERHEAD]);
+		assert_eq!(cache.get(b"dd"), None);
+
+		let stats = cache.stats();
+		assert_eq!((stats.hits, stats.misses, stats.entries), (4, 3, 1));
+		assert_eq!(stats.bytes, ENTRY_OVERHEAD + 4);
+
+		cache.clear();
+		assert_eq!(cache.stats().entries, 0);
+		assert_eq!(cache.get(b"cc"), None);
+	}
+
+	#[test]
+	fn test_set_capacity() {
+		let mut cache = ValueCache::new(3 * (ENTRY_OVERHEAD + 4));
+		cache.insert(b"aa", b"01".to_vec());
+		cache.insert(b"bb", b"02".to_vec());
+		cache.insert(b"cc", b"03".to_vec());
+		assert_eq!(cache.get(b"aa"), Some(b"01".to_vec()));
+
+		// `bb` and `cc` are the least recently used values
+		cache.set_capacity(ENTRY_OVERHEAD + 4);
+		assert_eq!(cache.stats().entries, 1);
+		assert_eq!(cache.get(b"aa"), Some(b"01".to_vec()));
+
+		cache.set_capacity(0);
+		assert!(!cache.is_enabled());
+		assert_eq!(cache.stats().bytes, 0);
+		cache.insert(b"aa", b"01".to_vec());
+		assert_eq!(cache.get(b"aa"), None);
+	}
+
+	#[test]
+	fn test_pinned_values() {
+		// pinned values are kept even by a disabled cache
+		let mut cache = ValueCache::new(0);
+		cache.pin(b"aa", Some(b"01".to_vec()));
+		cache.pin(b"bb", None);
+		assert_eq!(cache.get_pinned(b"aa"), Some(Some(b"01".to_vec())));
+		assert_eq!(cache.get_pinned(b"bb"), Some(None));
+		assert_eq!(cache.get_pinned(b"cc"), None);
+
+		cache.clear();
+		assert!(cache.is_pinned(b"aa"));
+		assert_eq!(cache.stats().pinned, 2);
+
+		assert!(cache.unpin(b"aa"));
+		assert!(!cache.unpin(b"aa"));
+		assert_eq!(cache.get_pinned(b"aa"), None);
+		assert_eq!(cache.pinned_keys(), vec![b"bb".to_vec()]);
+		assert_eq!((cache.stats().hits, cache.stats().pinned), (2, 1));
+	}
+}
@@ -0,0 +1,182 @@
+//! Checkpoints of a live database, see `Database::begin_checkpoint`.
+//!
+//! Journal era files are never modified after they are committed, so a checkpoint hard
+//! links them into its directory when it is taken. The data, metadata and collision files
+//! are modified in place by journal flushes and compactions, which are held back until
+//! the checkpoint has copied them. Commits meanwhile add era files the checkpoint does
+//! not see, so writers are not stopped.
+//!
+//! The data file is copied last, so a directory of an unfinished checkpoint can't be
+//! opened as a database.
+//!
+//! On file systems supporting reflinks, e.g. btrfs or XFS on Linux, the copies share the
+//! blocks of the original files until either of them is modified, so a checkpoint of a
+//! large database takes as long as one of an empty one. Hard links can't be used, the
+//! checkpoint would then be modified along with the database.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use error::Result;
+
+/// Number of unfinished checkpoints of a database.
+pub(crate) type CheckpointPins = Arc<AtomicUsize>;
+
+/// Consistent copy of the database being written into a directory.
+///
+/// Journal flushes of the database flush nothing and compactions fail with
+/// `CheckpointInProgress` until the checkpoint is finished or dropped.
+#[derive(Debug)]
+pub struct Checkpoint {
+	dir: PathBuf,
+	/// Files modified in place, copied by `finish` in this order.
+	files: Vec<PathBuf>,
+	pins: CheckpointPins,
+}
+
+impl Checkpoint {
+	pub(crate) fn new(dir: &Path, files: Vec<PathBuf>, pins: &CheckpointPins) -> Self {
+		pins.fetch_add(1, Ordering::SeqCst);
+
+		Checkpoint {
+			dir: dir.to_path_buf(),
+			files,
+			pins: pins.clone(),
+		}
+	}
+
+	/// Returns the directory of the checkpoint.
+	pub fn path(&self) -> &Path {
+		&self.dir
+	}
+
+	/// Copies the files modified in place and syncs the checkpoint to the disk.
+	///
+	/// Doesn't borrow the database, so it can run on another thread while the database
+	/// is committing.
+	pub fn finish(self) -> Result<()> {
+		for file in &self.files {
+			let name = file.file_name().expect("checkpointed paths are created from file names; qed");
+			let target = self.dir.join(name);
+			let partial = target.with_extension("partial");
+			clone_or_copy(file, &partial)?;
+			fs::File::open(&partial)?.sync_all()?;
+			fs::rename(&partial, &target)?;
+		}
+
+		fs::File::open(&self.dir)?.sync_all()?;
+		Ok(())
+	}
+}
+
+/// Copies `from` to `to`, sharing the blocks of the files if the file system supports it.
+fn clone_or_copy(from: &Path, to: &Path) -> Result<()> {
+	if reflink(from, to).is_err() {
+		fs::copy(from, to)?;
+	}
+
+	Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn reflink(from: &Path, to: &Path) -> io::Result<()> {
+	use std::os::raw::{c_int, c_ulong};
+	use std::os::unix::io::AsRawFd;
+
+	extern "C" {
+		fn ioctl(fd: c_int, request: c_ulong, ...) -> c_int;
+	}
+
+	/// `FICLONE` of `linux/fs.h`.
+	const FICLONE: c_ulong = 0x4004_9409;
+
+	let source = fs::File::open(from)?;
+	let target = fs::File::create(to)?;
+	if unsafe { ioctl(target.as_raw_fd(), FICLONE, source.as_raw_fd()) } != 0 {
+		let err = io::Error::last_os_error();
+		drop(target);
+		fs::remove_file(to)?;
+		return Err(err);
+	}
+
+	Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn reflink(_from: &Path, _to: &Path) -> io::Result<()> {
+	Err(io::Error::new(io::ErrorKind::Other, "reflinks are only supported on Linux"))
+}
+
+/// Returns true if files of the database copied by checkpoints may not be changed.
+pub(crate) fn in_progress(pins: &CheckpointPins) -> bool {
+	pins.load(Ordering::SeqCst) != 0
+}
+
+impl Drop for Checkpoint {
+	fn drop(&mut self) {
+		self.pins.fetch_sub(1, Ordering::SeqCst);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	extern crate tempdir;
+
+	use std::fs;
+	use std::io::{Read, Write};
+	use std::path::Path;
+	use std::sync::Arc;
+	use std::sync::atomic::{AtomicUsize, Ordering};
+
+	use super::Checkpoint;
+
+	fn read(path: &Path) -> Vec<u8> {
+		let mut data = Vec::new();
+		fs::File::open(path).unwrap().read_to_end(&mut data).unwrap();
+		data
+	}
+
+	#[test]
+	fn test_checkpoint_files() {
+		let temp = tempdir::TempDir::new("test_checkpoint_files").unwrap();
+		let source = temp.path().join("source");
+		let target = temp.path().join("target");
+		fs::create_dir_all(&source).unwrap();
+		fs::create_dir_all(&target).unwrap();
+		fs::File::create(source.join("meta.db")).unwrap().write_all(b"meta").unwrap();
+		fs::File::create(source.join("data.db")).unwrap().write_all(b"data").unwrap();
+
+		let pins = Arc::new(AtomicUsize::new(0));
+		let files = vec![source.join("meta.db"), source.join("data.db")];
+		let checkpoint = Checkpoint::new(&target, files, &pins);
+		assert!(super::in_progress(&pins));
+		assert_eq!(checkpoint.path(), target.as_path());
+
+		checkpoint.finish().unwrap();
+		assert_eq!(pins.load(Ordering::SeqCst), 0);
+		assert_eq!(read(&target.join("data.db")), b"data".to_vec());
+		assert_eq!(read(&target.join("meta.db")), b"meta".to_vec());
+		assert!(!target.join("data.partial").exists());
+
+		// dropping an unfinished checkpoint releases the database as well
+		drop(Checkpoint::new(&target, Vec::new(), &pins));
+		assert_eq!(pins.load(Ordering::SeqCst), 0);
+	}
+
+	#[test]
+	fn test_clone_or_copy() {
+		let temp = tempdir::TempDir::new("test_clone_or_copy").unwrap();
+		let source = temp.path().join("data.db");
+		let target = temp.path().join("data.partial");
+		fs::File::create(&source).unwrap().write_all(b"data").unwrap();
+
+		// reflinked or copied, the target has its own contents
+		super::clone_or_copy(&source, &target).unwrap();
+		fs::OpenOptions::new().write(true).open(&target).unwrap().write_all(b"DATA").unwrap();
+		assert_eq!(read(&target), b"DATA".to_vec());
+		assert_eq!(read(&source), b"data".to_vec());
+	}
+}
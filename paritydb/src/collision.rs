@@ -1,15 +1,19 @@
-use std::cmp::Ordering;
-use std::collections::btree_map;
+use std::cmp::{self, Ordering};
 use std::collections::BTreeMap;
 use std::fs::{self, File};
-use std::io::{self, Seek, SeekFrom, Write};
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 use std::slice;
+use std::time::Instant;
+use std::vec;
 
 use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
 use memmap::{Mmap, Protection};
+use parking_lot::Mutex;
 
-use error::Result;
+use bloom::BloomFilter;
+use error::{ErrorKind, Result};
+use options::ValuesLen;
 use transaction::Operation;
 
 /// A data file representing all the data for a given prefix. All the data for this prefix exists in
@@ -20,151 +24,511 @@ use transaction::Operation;
 ///
 /// When the collision file is opened it is traversed to build the in-memory index.
 ///
-/// Idea: grow the log file in chunks (instead of appending to a file) and mmap it. When compacting
-/// the log we rewrite it with the keys sorted (since we have the in-memory index), so iteration
-/// should be fast on compacted log files.
+/// The log file is grown in chunks of `CHUNK_SIZE` bytes and memory mapped, so entries are written
+/// and read directly in the mapping. The unused end of the file is zeroed and an entry with an
+/// empty key marks the end of the log. The file is mapped again only when it grows.
+///
+/// Deletes and overwrites only append to the log, so `compact` rewrites it with the live entries
+/// sorted by key once the garbage outweighs them, which also makes iteration sequential.
+///
+/// Every entry ends with a CRC32 of its key and value, which is verified whenever the entry is
+/// read. Logs written before checksums were introduced have no `LOG_MAGIC` header and are read
+/// without verification until `compact` rewrites them.
+/// An incomplete entry left at the end of the log by an interrupted append is zeroed when the file
+/// is opened.
+///
+/// The index may be dropped with `evict` to save memory. It is rebuilt by the next lookup or
+/// mutable operation, iteration builds a temporary index instead.
+///
+/// Keys of the live entries are also added to a bloom filter, which is kept when the index is
+/// evicted. Lookups of most keys which are not in the file read neither the index nor the log.
+///
+/// If the values are of constant size all entries of the log are of the same size, so the index
+/// only keeps positions of the entries in a vector sorted by their keys, see `Index`.
 ///
 /// Alternative: use exactly the same strategy as used for the data file but ignoring the first `n`
 /// bits of the prefix and adding extra bits as needed
 ///
 #[derive(Debug)]
 pub struct Collision {
-	index: BTreeMap<LogSlice, IndexEntry>,
+	index: Index,
+	/// Keys of the log, built with the index.
+	bloom: BloomFilter,
+	resident: bool,
+	/// Index rebuilt by a lookup after the index was evicted, moved back by `ensure_resident`.
+	rebuilt: Mutex<Option<Index>>,
+	last_used: Mutex<Instant>,
 	prefix: u32,
 	path: PathBuf,
 	mmap: Mmap,
-	file: File,
+	/// Length of the log, the rest of the file is unused.
+	len: u64,
+	/// Number of bytes of the log taken by live entries, also known while the index is evicted.
+	live: u64,
 }
 
 #[derive(Debug)]
 pub struct IndexEntry {
     position: u64,
-	// TODO: we can optimize this data structure for constant value sizes
     size: usize,
 }
 
+/// In-memory index of the live entries of the log.
+#[derive(Debug)]
+enum Index {
+	/// Positions and sizes of entries by their keys.
+	Variable(BTreeMap<LogSlice, IndexEntry>),
+	/// Positions of entries with values of `value_len` bytes sorted by their keys, which are read
+	/// from the log.
+	Constant { value_len: usize, positions: Vec<u64> },
+}
+
+impl Index {
+	fn new(value_len: &ValuesLen) -> Index {
+		match *value_len {
+			ValuesLen::Constant(value_len) => Index::Constant { value_len, positions: Vec::new() },
+			ValuesLen::Variable { .. } => Index::Variable(BTreeMap::new()),
+		}
+	}
+
+	/// Returns an empty index of the same kind.
+	fn cleared(&self) -> Index {
+		match *self {
+			Index::Variable(_) => Index::Variable(BTreeMap::new()),
+			Index::Constant { value_len, .. } => Index::Constant { value_len, positions: Vec::new() },
+		}
+	}
+
+	/// Returns the length of all values, if it is constant.
+	fn value_len(&self) -> Option<usize> {
+		match *self {
+			Index::Variable(_) => None,
+			Index::Constant { value_len, .. } => Some(value_len),
+		}
+	}
+
+	/// Returns position of the entry of `key` in the log in `data`.
+	fn get(&self, data: &[u8], key: &[u8]) -> Option<u64> {
+		match *self {
+			Index::Variable(ref index) => index.get(&LogSlice::new(key)).map(|entry| entry.position),
+			Index::Constant { ref positions, .. } => positions
+				.binary_search_by(|position| LogEntry::key_at(data, *position as usize).cmp(key))
+				.ok()
+				.map(|i| positions[i]),
+		}
+	}
+
+	/// Indexes the entry of `size` bytes at `position` of the log in `data`. `key` has to point
+	/// into `data`. Returns the size of the replaced entry.
+	fn insert(&mut self, data: &[u8], key: &[u8], position: u64, size: usize) -> Option<usize> {
+		match *self {
+			Index::Variable(ref mut index) =>
+				index.insert(LogSlice::new(key), IndexEntry { position, size }).map(|replaced| replaced.size),
+			Index::Constant { ref mut positions, .. } => {
+				match positions.binary_search_by(|position| LogEntry::key_at(data, *position as usize).cmp(key)) {
+					Ok(i) => {
+						positions[i] = position;
+						Some(size)
+					},
+					Err(i) => {
+						positions.insert(i, position);
+						None
+					},
+				}
+			},
+		}
+	}
+
+	/// Removes `key` from the index. Returns the size of the removed entry.
+	fn remove(&mut self, data: &[u8], key: &[u8]) -> Option<usize> {
+		match *self {
+			Index::Variable(ref mut index) => index.remove(&LogSlice::new(key)).map(|removed| removed.size),
+			Index::Constant { ref mut positions, .. } => {
+				match positions.binary_search_by(|position| LogEntry::key_at(data, *position as usize).cmp(key)) {
+					Ok(i) => Some(LogEntry::size_at(data, positions.remove(i) as usize)),
+					Err(_) => None,
+				}
+			},
+		}
+	}
+
+	/// Returns positions of the entries ordered by key.
+	fn positions(&self) -> Vec<u64> {
+		match *self {
+			Index::Variable(ref index) => index.values().map(|entry| entry.position).collect(),
+			Index::Constant { ref positions, .. } => positions.clone(),
+		}
+	}
+
+	/// Returns the number of bytes taken by the indexed entries of the log in `data`.
+	fn live(&self, data: &[u8]) -> u64 {
+		match *self {
+			Index::Variable(ref index) => index.values().map(|entry| entry.size as u64).sum(),
+			Index::Constant { ref positions, .. } =>
+				positions.iter().map(|position| LogEntry::size_at(data, *position as usize) as u64).sum(),
+		}
+	}
+}
+
+/// Header of collision logs with checksummed entries. The last byte is the version of the log
+/// layout, so logs can change independently of `Metadata::DB_VERSION`.
+const LOG_MAGIC: &'static [u8] = b"pdbclog\x01";
+/// Collision files grow by multiples of this size.
+const CHUNK_SIZE: u64 = 64 * 1024;
+/// Logs smaller than this are never compacted automatically.
+const COMPACTION_MIN_SIZE: u64 = 64 * 1024;
+/// Percentage of the log taken by stale entries which triggers compaction.
+const COMPACTION_GARBAGE_PERCENT: u64 = 50;
+
 impl Collision {
 	fn collision_file_path<P: AsRef<Path>>(path: P, prefix: u32) -> PathBuf {
 		let collision_file_name = format!("collision-{}.log", prefix);
 		path.as_ref().join(collision_file_name)
 	}
 
-	fn build_index(data: &[u8]) -> Result<BTreeMap<LogSlice, IndexEntry>> {
-		let log = LogIterator::new(data);
-
-		let mut index = BTreeMap::new();
+	/// Returns the prefix of a collision file called `name`, or `None` if it isn't one.
+	pub fn file_name_prefix(name: &str) -> Option<u32> {
+		if name.starts_with("collision-") && name.ends_with(".log") {
+			name["collision-".len()..name.len() - ".log".len()].parse().ok()
+		} else {
+			None
+		}
+	}
 
-		for (position, entry) in log {
+	/// Builds the index of the log in `data`. Returns it with the length of the log and the number
+	/// of bytes taken by live entries.
+	///
+	/// An invalid entry with nothing written after it was left by an interrupted append and
+	/// ends the log, any other invalid entry is an error.
+	fn build_index(path: &Path, data: &[u8], mut index: Index) -> Result<(Index, u64, u64)> {
+		let mut log = LogIterator::new(path, data);
+		let checksums = log.checksums;
+
+		let mut len = None;
+
+		for item in &mut log {
+			let (position, entry) = match item {
+				Ok(item) => item,
+				Err(err) => {
+					let torn = match *err.kind() {
+						ErrorKind::Corruption(_, offset) if LogEntry::is_last(data, offset as usize) => Some(offset),
+						_ => None,
+					};
+
+					match torn {
+						Some(offset) => {
+							len = Some(offset);
+							break;
+						},
+						None => return Err(err),
+					}
+				},
+			};
 			if let Some(value) = entry.value {
-				let position = position as u64;
-				let size = LogEntry::len(&entry.key, &value);
-
-				index.insert(
-					LogSlice::new(entry.key),
-					IndexEntry { position, size });
-
+				if let Some(value_len) = index.value_len() {
+					if value.len() != value_len {
+						bail!(ErrorKind::InvalidValueLen(value_len, value.len()));
+					}
+				}
+
+				let size = LogEntry::len(&entry.key, &value, checksums);
+				index.insert(data, entry.key, position as u64, size);
 			} else {
-				index.remove(&LogSlice::new(entry.key));
+				index.remove(data, entry.key);
 			}
 		}
 
-		Ok(index)
+		let len = len.unwrap_or(log.position as u64);
+		let live = index.live(data);
+		Ok((index, len, live))
+	}
+
+	/// Builds the bloom filter of the keys of the live entries of the log in `data`.
+	fn build_bloom(data: &[u8], index: &Index) -> BloomFilter {
+		let positions = index.positions();
+		let mut bloom = BloomFilter::with_capacity(positions.len());
+		for position in positions {
+			bloom.insert(LogEntry::key_at(data, position as usize));
+		}
+		bloom
 	}
 
-	/// Create a new collision file for the given prefix.
-	pub fn create<P: AsRef<Path>>(path: P, prefix: u32) -> Result<Collision> {
+	/// Create a new collision file for the given prefix and values of `value_len`.
+	pub fn create<P: AsRef<Path>>(path: P, prefix: u32, value_len: &ValuesLen) -> Result<Collision> {
 		// Create directories if necessary.
 		fs::create_dir_all(&path)?;
 
 		let path = Self::collision_file_path(path, prefix);
-		let mut file = fs::OpenOptions::new()
-			.write(true)
-			.create_new(true)
-			.open(&path)?;
+		{
+			let file = fs::OpenOptions::new()
+				.write(true)
+				.create_new(true)
+				.open(&path)?;
+			file.set_len(CHUNK_SIZE)?;
+		}
 
-		// TODO: grow file in chunks to avoid rebuilding index on every mutable operation
-		file.set_len(1)?;
-		file.flush()?;
-		let mmap = Mmap::open_path(&path, Protection::Read)?;
+		let mut mmap = Mmap::open_path(&path, Protection::ReadWrite)?;
+		unsafe { mmap.as_mut_slice()[..LOG_MAGIC.len()].copy_from_slice(LOG_MAGIC) };
 
-		let index = BTreeMap::new();
+		let index = Index::new(value_len);
+		let bloom = BloomFilter::with_capacity(0);
+		let len = LOG_MAGIC.len() as u64;
 
-		Ok(Collision { index, prefix, path, mmap, file })
+		Ok(Collision { index, bloom, resident: true, rebuilt: Mutex::new(None), last_used: Mutex::new(Instant::now()), prefix, path, mmap, len, live: 0 })
 	}
 
-	/// Open collision file if it exists, returns `None` otherwise.
-	pub fn open<P: AsRef<Path>>(path: P, prefix: u32) -> Result<Option<Collision>> {
-		let path = Self::collision_file_path(path, prefix);
-		let open_options = fs::OpenOptions::new()
-			.append(true)
-			.open(&path);
+	/// Open collision file with values of `value_len` if it exists, returns `None` otherwise.
+	pub fn open<P: AsRef<Path>>(path: P, prefix: u32, value_len: &ValuesLen) -> Result<Option<Collision>> {
+		Self::open_mapped(path, prefix, value_len, false)
+	}
 
-		let file = match open_options {
-			Ok(file) => file,
+	/// Like `open`, but maps the file read-only and leaves an incomplete entry after the end of
+	/// the log as it is. Entries of the opened file must not be appended or repaired.
+	pub fn open_read_only<P: AsRef<Path>>(path: P, prefix: u32, value_len: &ValuesLen) -> Result<Option<Collision>> {
+		Self::open_mapped(path, prefix, value_len, true)
+	}
+
+	fn open_mapped<P: AsRef<Path>>(path: P, prefix: u32, value_len: &ValuesLen, read_only: bool) -> Result<Option<Collision>> {
+		let path = Self::collision_file_path(path, prefix);
+		let protection = if read_only { Protection::Read } else { Protection::ReadWrite };
+		let mut mmap = match Mmap::open_path(&path, protection) {
+			Ok(mmap) => mmap,
 			Err(ref err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
 			Err(err) => return Err(err.into()),
 		};
 
-		let mmap = Mmap::open_path(&path, Protection::Read)?;
-		let index = {
+		let (index, bloom, len, live) = {
 			let data = unsafe { &mmap.as_slice() };
-			Collision::build_index(data)?
+			let (index, len, live) = Collision::build_index(&path, data, Index::new(value_len))?;
+			let bloom = Collision::build_bloom(data, &index);
+			(index, bloom, len, live)
 		};
 
-		Ok(Some(Collision { index, prefix, path, mmap, file }))
+		// zero the incomplete entry left after the end of the log, if any
+		let garbage = if read_only {
+			None
+		} else {
+			unsafe { mmap.as_slice()[len as usize..].iter().rposition(|byte| *byte != 0) }
+		};
+		if let Some(last) = garbage {
+			let start = len as usize;
+			unsafe {
+				for byte in &mut mmap.as_mut_slice()[start..start + last + 1] {
+					*byte = 0;
+				}
+			}
+			mmap.flush()?;
+		}
+
+		Ok(Some(Collision { index, bloom, resident: true, rebuilt: Mutex::new(None), last_used: Mutex::new(Instant::now()), prefix, path, mmap, len, live }))
 	}
 
 	fn rebuild_index(&mut self) -> Result<()> {
-		let mmap = Mmap::open_path(&self.path, Protection::Read)?;
-		let index = {
-			let data = unsafe { &mmap.as_slice() };
-			Collision::build_index(data)?
+		let (index, bloom, len, live) = {
+			let data = unsafe { &self.mmap.as_slice() };
+			let (index, len, live) = Collision::build_index(&self.path, data, self.index.cleared())?;
+			let bloom = Collision::build_bloom(data, &index);
+			(index, bloom, len, live)
 		};
 
-		self.mmap = mmap;
 		self.index = index;
+		self.bloom = bloom;
+		self.len = len;
+		self.live = live;
+		self.resident = true;
 
 		Ok(())
 	}
 
+	/// Makes sure `additional` bytes can be appended to the log, growing the file in whole chunks.
+	fn reserve(&mut self, additional: u64) -> Result<()> {
+		let required = self.len + additional;
+		if required <= self.mmap.len() as u64 {
+			return Ok(());
+		}
+
+		let size = (required + CHUNK_SIZE - 1) / CHUNK_SIZE * CHUNK_SIZE;
+		self.mmap.flush()?;
+		{
+			let file = fs::OpenOptions::new().write(true).open(&self.path)?;
+			file.set_len(size)?;
+		}
+
+		// the index points into the old mapping
+		self.index = self.index.cleared();
+		self.mmap = Mmap::open_path(&self.path, Protection::ReadWrite)?;
+		self.rebuild_index()
+	}
+
+	/// Returns true if the entries of the log are checksummed.
+	fn checksums(&self) -> bool {
+		unsafe { self.mmap.as_slice().starts_with(LOG_MAGIC) }
+	}
+
+	/// Appends an entry to the log and returns its position. `None` value appends a tombstone.
+	fn append(&mut self, key: &[u8], value: Option<&[u8]>) -> Result<u64> {
+		let checksums = self.checksums();
+		let mut entry = io::Cursor::new(Vec::new());
+		match value {
+			Some(value) => LogEntry::write(&mut entry, key, value, checksums)?,
+			None => LogEntry::write_deleted(&mut entry, key, checksums)?,
+		};
+		let entry = entry.into_inner();
+
+		self.reserve(entry.len() as u64)?;
+		let position = self.len;
+		{
+			let start = position as usize;
+			let data = unsafe { &mut self.mmap.as_mut_slice()[start..start + entry.len()] };
+			data.copy_from_slice(&entry);
+		}
+		self.len += entry.len() as u64;
+
+		Ok(position)
+	}
+
+	/// Rebuilds the index if it was evicted.
+	pub fn ensure_resident(&mut self) -> Result<()> {
+		*self.last_used.lock() = Instant::now();
+		if self.resident {
+			return Ok(());
+		}
+
+		// the log has not changed since the index was rebuilt by a lookup
+		if let Some(index) = self.rebuilt.get_mut().take() {
+			self.index = index;
+			self.resident = true;
+			return Ok(());
+		}
+
+		self.rebuild_index()
+	}
+
+	/// Drops the in-memory index. It is rebuilt by the next lookup or mutable operation.
+	pub fn evict(&mut self) {
+		self.index = self.index.cleared();
+		self.resident = false;
+		*self.rebuilt.get_mut() = None;
+	}
+
+	/// Returns true if the index is in memory.
+	pub fn is_resident(&self) -> bool {
+		self.resident || self.rebuilt.lock().is_some()
+	}
+
+	/// Returns when the collision file was last accessed.
+	pub fn last_used(&self) -> Instant {
+		*self.last_used.lock()
+	}
+
+	/// Returns position of the entry of `key` in the log. An evicted index is rebuilt first.
+	fn position(&self, key: &[u8]) -> Result<Option<u64>> {
+		let data = unsafe { &self.mmap.as_slice() };
+		if self.resident {
+			return Ok(self.index.get(data, key));
+		}
+
+		let mut rebuilt = self.rebuilt.lock();
+		if rebuilt.is_none() {
+			*rebuilt = Some(Collision::build_index(&self.path, data, self.index.cleared())?.0);
+		}
+
+		Ok(rebuilt.as_ref().and_then(|index| index.get(data, key)))
+	}
+
 	/// Inserts the given key-value pair into the collision file.
 	pub fn insert(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+		assert!(!key.is_empty(), "empty key marks the end of the log");
+		self.ensure_resident()?;
 		if let Some(current_value) = self.get(key)? {
 			if current_value == value { return Ok(()); }
 		}
 
-		// FIXME: have write return the `LogSlice` to avoid re-reading the entry
-		let position = LogEntry::write(&mut self.file, key, value)?;
-		let size = LogEntry::len(&key, &value);
-
-		self.rebuild_index()?;
+		let position = self.append(key, Some(value))?;
+		let size = LogEntry::len(&key, &value, self.checksums());
 
-		let data = unsafe { &self.mmap.as_slice()[position as usize..] };
-		let (_, entry) = LogEntry::read(data);
+		let data = unsafe { &self.mmap.as_slice() };
+		let (_, entry) = LogEntry::read_at(&self.path, data, position as usize)?;
 
 		assert!(key == entry.key,
 				"found incorrect key after insertion into log");
 
-		self.index.insert(LogSlice::new(entry.key), IndexEntry { position, size });
+		if let Some(replaced) = self.index.insert(data, entry.key, position, size) {
+			self.live -= replaced as u64;
+		}
+		self.bloom.insert(key);
+		self.live += size as u64;
 
 		Ok(())
 	}
 
 	/// Removes the given `key` from the collision file.
 	pub fn delete(&mut self, key: &[u8]) -> Result<()> {
-		if let Some(_) = self.index.remove(&LogSlice::new(key)) {
-			LogEntry::write_deleted(&mut self.file, key)?;
-			self.rebuild_index()?;
+		self.ensure_resident()?;
+		if !self.contains(key)? {
+			return Ok(());
+		}
+
+		// growing the log rebuilds the index, so the entry is removed only after the append
+		self.append(key, None)?;
+		let data = unsafe { &self.mmap.as_slice() };
+		if let Some(removed) = self.index.remove(data, key) {
+			self.live -= removed as u64;
 		}
 
 		Ok(())
 	}
 
+	/// Rewrites the corrupted entry of `key` at `offset` of the log with `value` in place.
+	///
+	/// Only entries whose key and value length are intact are rewritten. Returns false for any
+	/// other entry and for entries which are not corrupted.
+	pub fn repair(&mut self, offset: u64, key: &[u8], value: &[u8]) -> Result<bool> {
+		let checksums = self.checksums();
+		let position = offset as usize;
+		if position + LogEntry::len(key, value, checksums) > self.len as usize {
+			return Ok(false);
+		}
+
+		{
+			let data = unsafe { &self.mmap.as_slice()[position..] };
+			if LogEntry::read(data, checksums).is_some() {
+				return Ok(false);
+			}
+
+			let intact = LittleEndian::read_u32(data) as usize == key.len() &&
+				&data[4..4 + key.len()] == key &&
+				LittleEndian::read_u32(&data[4 + key.len()..]) as usize == value.len();
+			if !intact {
+				return Ok(false);
+			}
+		}
+
+		let mut entry = io::Cursor::new(Vec::new());
+		LogEntry::write(&mut entry, key, value, checksums)?;
+		let entry = entry.into_inner();
+		unsafe { self.mmap.as_mut_slice()[position..position + entry.len()].copy_from_slice(&entry) };
+		self.mmap.flush()?;
+
+		Ok(true)
+	}
+
+	/// Writes appended entries to the disk.
+	pub fn flush(&self) -> Result<()> {
+		self.mmap.flush()?;
+		Ok(())
+	}
+
 	/// Lookup a value associated with the given `key` in the collision file.
 	pub fn get(&self, key: &[u8]) -> Result<Option<&[u8]>> {
-		if let Some(entry) = self.index.get(&LogSlice::new(key)) {
-			let data = unsafe { &self.mmap.as_slice()[entry.position as usize..] };
-			let (_, entry) = LogEntry::read(data);
+		*self.last_used.lock() = Instant::now();
+		if !self.bloom.may_contain(key) {
+			return Ok(None);
+		}
+
+		let data = unsafe { &self.mmap.as_slice() };
+		if let Some(position) = self.position(key)? {
+			let (_, entry) = LogEntry::read_at(&self.path, data, position as usize)?;
 			assert!(key == entry.key,
 					"index pointed to log entry with different key");
 
@@ -174,6 +538,53 @@ impl Collision {
 		}
 	}
 
+	/// Lookup values associated with all `keys` in the collision file.
+	///
+	/// Entries are read in the order of their positions, so the log is read in a single forward
+	/// pass. Results are returned in the same order as `keys`.
+	pub fn get_many(&self, keys: &[&[u8]]) -> Vec<Result<Option<&[u8]>>> {
+		*self.last_used.lock() = Instant::now();
+		let mut values: Vec<Result<Option<&[u8]>>> = keys.iter().map(|_| Ok(None)).collect();
+		let mut positions = Vec::new();
+		for (i, key) in keys.iter().enumerate() {
+			if !self.bloom.may_contain(key) {
+				continue;
+			}
+
+			match self.position(key) {
+				Ok(Some(position)) => positions.push((position, i)),
+				Ok(None) => {},
+				Err(err) => values[i] = Err(err),
+			}
+		}
+
+		positions.sort();
+		let data = unsafe { &self.mmap.as_slice() };
+		for (position, i) in positions {
+			values[i] = LogEntry::read_at(&self.path, data, position as usize).map(|(_, entry)| {
+				assert!(keys[i] == entry.key,
+						"index pointed to log entry with different key");
+
+				Some(entry.value.expect("index only points to live entries; qed"))
+			});
+		}
+
+		values
+	}
+
+	/// Returns true if the collision file contains the given `key`.
+	///
+	/// Only the bloom filter and the index are consulted, the log file is not read unless the
+	/// index has to be rebuilt.
+	pub fn contains(&self, key: &[u8]) -> Result<bool> {
+		*self.last_used.lock() = Instant::now();
+		if !self.bloom.may_contain(key) {
+			return Ok(false);
+		}
+
+		Ok(self.position(key)?.is_some())
+	}
+
 	/// Applies the given `Operation` by dispatching to the `insert` or `delete` methods.
 	pub fn apply(&mut self, op: Operation) -> Result<()> {
 		match op {
@@ -182,31 +593,196 @@ impl Collision {
 		}
 	}
 
+	/// Deletes the collision file.
+	pub fn remove(self) -> Result<()> {
+		let path = self.path.clone();
+		// the file is unmapped before it is deleted
+		drop(self);
+		fs::remove_file(path)?;
+		Ok(())
+	}
+
 	/// Return the `prefix` that this collision file refers to, i.e. all keys stored in this file
 	/// have this prefix.
 	pub fn prefix(&self) -> u32 {
 		self.prefix
 	}
 
+	/// Returns path of the collision file.
+	pub fn path(&self) -> &Path {
+		&self.path
+	}
+
+	/// Returns the length of the log.
+	pub fn log_bytes(&self) -> u64 {
+		self.len
+	}
+
+	/// Returns the number of bytes of the log taken by live entries.
+	pub fn live_bytes(&self) -> u64 {
+		self.live
+	}
+
+	/// Returns the number of bytes of the log taken by overwritten and deleted entries.
+	pub fn garbage_bytes(&self) -> u64 {
+		let header = if self.checksums() { LOG_MAGIC.len() as u64 } else { 0 };
+		self.len - self.live - header
+	}
+
+	/// Returns true if the log is checksummed and holds no overwritten or deleted entries.
+	pub fn is_compact(&self) -> bool {
+		self.checksums() && self.len - self.live == LOG_MAGIC.len() as u64
+	}
+
+	/// Returns true if stale entries take so much of the log that it should be compacted.
+	///
+	/// Depends only on the contents of the log, so whether the index is resident does not
+	/// change when the log is compacted.
+	pub fn needs_compaction(&self) -> bool {
+		let log_bytes = self.log_bytes();
+		log_bytes >= COMPACTION_MIN_SIZE &&
+			(log_bytes - self.live_bytes()) * 100 >= log_bytes * COMPACTION_GARBAGE_PERCENT
+	}
+
+	/// Rewrites the log file with only the live entries ordered by key.
+	///
+	/// The new log is written next to the old one and atomically replaces it.
+	pub fn compact(&mut self) -> Result<()> {
+		self.ensure_resident()?;
+		let tmp_path = self.path.with_extension("tmp");
+		{
+			let mut writer = BufWriter::new(File::create(&tmp_path)?);
+			writer.write_all(LOG_MAGIC)?;
+			for item in self.iter()? {
+				let (key, value) = item?;
+				LogEntry::write(&mut writer, key, value, true)?;
+			}
+
+			let len = writer.seek(SeekFrom::Current(0))?;
+			writer.flush()?;
+			// an empty file can't be mapped
+			let chunks = cmp::max((len + CHUNK_SIZE - 1) / CHUNK_SIZE, 1);
+			writer.get_ref().set_len(chunks * CHUNK_SIZE)?;
+			writer.get_ref().sync_all()?;
+		}
+
+		// the index points into the old mapping
+		self.index = self.index.cleared();
+		fs::rename(&tmp_path, &self.path)?;
+		self.mmap = Mmap::open_path(&self.path, Protection::ReadWrite)?;
+
+		self.rebuild_index()
+	}
+
 	/// Returns an iterator over all key-value pairs in the collision file ordered by key.
+	///
+	/// If the index was evicted, a temporary index is built from the log file.
 	pub fn iter<'a>(&'a self) -> Result<CollisionLogIterator> {
+		let positions = self.positions()?;
 		let data = unsafe { &self.mmap.as_slice() };
+		CollisionLogIterator::new(&self.path, data, positions.into_iter())
+	}
+
+	/// Returns an iterator over all key-value pairs in the collision file in descending key order.
+	pub fn iter_rev<'a>(&'a self) -> Result<CollisionLogIterator> {
+		let mut positions = self.positions()?;
+		positions.reverse();
+		let data = unsafe { &self.mmap.as_slice() };
+		CollisionLogIterator::new(&self.path, data, positions.into_iter())
+	}
+
+	/// Returns positions of the live entries ordered by key.
+	///
+	/// If the index was evicted, a temporary index is built from the log file.
+	fn positions(&self) -> Result<Vec<u64>> {
+		*self.last_used.lock() = Instant::now();
+		if self.resident {
+			return Ok(self.index.positions());
+		}
+
+		if let Some(ref index) = *self.rebuilt.lock() {
+			return Ok(index.positions());
+		}
+
+		let data = unsafe { &self.mmap.as_slice() };
+		Ok(Collision::build_index(&self.path, data, self.index.cleared())?.0.positions())
+	}
+}
 
-		CollisionLogIterator::new(data, self.index.values())
+/// Serializes `entries` into the contents of a collision file. `None` values are tombstones.
+pub fn encode_log(entries: &[(Vec<u8>, Option<Vec<u8>>)]) -> Vec<u8> {
+	let mut log = io::Cursor::new(LOG_MAGIC.to_vec());
+	log.set_position(LOG_MAGIC.len() as u64);
+	for &(ref key, ref value) in entries {
+		let written = match *value {
+			Some(ref value) => LogEntry::write(&mut log, key, value, true),
+			None => LogEntry::write_deleted(&mut log, key, true),
+		};
+		written.expect("writing to a vec cannot fail; qed");
 	}
+
+	let mut log = log.into_inner();
+	let chunks = cmp::max((log.len() as u64 + CHUNK_SIZE - 1) / CHUNK_SIZE, 1);
+	log.resize((chunks * CHUNK_SIZE) as usize, 0);
+	log
+}
+
+/// Entry of a collision log as stored in the file, see `decode_log`.
+#[derive(Debug)]
+pub struct RawLogEntry<'a> {
+	pub position: usize,
+	pub key: &'a [u8],
+	/// `None` for tombstones.
+	pub value: Option<&'a [u8]>,
+	/// Stored and computed checksum of the entry, `None` in logs without checksums.
+	pub checksum: Option<(u32, u32)>,
+}
+
+/// Decodes the log in `data` without verifying checksums, e.g. to print a damaged log.
+///
+/// Returns true if the log has checksums, its entries and the position where decoding
+/// stopped, either at the end of the log or at an entry which does not fit in `data`.
+pub fn decode_log(data: &[u8]) -> (bool, Vec<RawLogEntry>, usize) {
+	let checksums = data.starts_with(LOG_MAGIC);
+	let mut position = if checksums { LOG_MAGIC.len() } else { 0 };
+	let mut entries = Vec::new();
+
+	while position + 4 <= data.len() && LittleEndian::read_u32(&data[position..]) != 0 {
+		let rest = &data[position..];
+		let (read, entry) = match LogEntry::read(rest, false) {
+			Some(entry) => entry,
+			None => break,
+		};
+
+		let checksum = if checksums {
+			if rest.len() - read < LogEntry::ENTRY_CHECKSUM_SIZE {
+				break;
+			}
+			Some((LittleEndian::read_u32(&rest[read..]), LogEntry::checksum(entry.key, entry.value)))
+		} else {
+			None
+		};
+
+		entries.push(RawLogEntry { position, key: entry.key, value: entry.value, checksum });
+		position += read + if checksums { LogEntry::ENTRY_CHECKSUM_SIZE } else { 0 };
+	}
+
+	(checksums, entries, position)
 }
 
 pub struct CollisionLogIterator<'a> {
+	path: &'a Path,
 	data: &'a [u8],
-	index_iter: btree_map::Values<'a, LogSlice, IndexEntry>,
+	positions: vec::IntoIter<u64>,
 }
 
 impl<'a> CollisionLogIterator<'a> {
 	fn new(
+		path: &'a Path,
 		data: &'a [u8],
-		index_iter: btree_map::Values<'a, LogSlice, IndexEntry>,
+		positions: vec::IntoIter<u64>,
 	) -> Result<CollisionLogIterator<'a>> {
-		Ok(CollisionLogIterator { data, index_iter })
+		Ok(CollisionLogIterator { path, data, positions })
 	}
 }
 
@@ -214,10 +790,9 @@ impl<'a> Iterator for CollisionLogIterator<'a> {
 	type Item = Result<(&'a [u8], &'a [u8])>;
 
 	fn next(&mut self) -> Option<Self::Item> {
-		self.index_iter.next().and_then(|entry| {
-			let read_next = || {
-				let data = &self.data[entry.position as usize..];
-				let (_, entry) = LogEntry::read(data);
+		self.positions.next().and_then(|position| {
+			let read_next = || -> Result<(&'a [u8], &'a [u8])> {
+				let (_, entry) = LogEntry::read_at(self.path, self.data, position as usize)?;
 
 				Ok((entry.key,
 					entry.value.expect("index only points to live entries; qed")))
@@ -268,6 +843,11 @@ impl PartialEq for LogSlice {
 
 impl Eq for LogSlice {}
 
+// The slice points into the memory map of the collision file holding the index, which is
+// only written through `&mut Collision`.
+unsafe impl Send for LogSlice {}
+unsafe impl Sync for LogSlice {}
+
 impl LogSlice {
 	fn new(data: &[u8]) -> LogSlice {
 		LogSlice {
@@ -283,81 +863,180 @@ impl LogSlice {
 
 impl<'a> LogEntry<'a> {
 	const ENTRY_STATIC_SIZE: usize = 8; // key_size(4) + value_size(4)
+	const ENTRY_CHECKSUM_SIZE: usize = 4;
 	const ENTRY_TOMBSTONE: u32 = !0; // used as value_size to represent a deleted entry
-	// FIXME: validate max value size
 
-	fn write_deleted<W: Write + Seek>(writer: &mut W, key: &[u8]) -> Result<u64> {
+	fn write_deleted<W: Write + Seek>(writer: &mut W, key: &[u8], checksums: bool) -> Result<u64> {
 		let position = writer.seek(SeekFrom::Current(0))?;
 		writer.write_u32::<LittleEndian>(key.len() as u32)?;
 		writer.write_all(key)?;
 		writer.write_u32::<LittleEndian>(LogEntry::ENTRY_TOMBSTONE)?;
+		if checksums {
+			writer.write_u32::<LittleEndian>(LogEntry::checksum(key, None))?;
+		}
 		Ok(position)
 	}
 
-	fn write<W: Write + Seek>(writer: &mut W, key: &[u8], value: &[u8]) -> Result<u64> {
+	fn write<W: Write + Seek>(writer: &mut W, key: &[u8], value: &[u8], checksums: bool) -> Result<u64> {
 		let position = writer.seek(SeekFrom::Current(0))?;
 		writer.write_u32::<LittleEndian>(key.len() as u32)?;
 		writer.write_all(key)?;
 		writer.write_u32::<LittleEndian>(value.len() as u32)?;
 		writer.write_all(value)?;
+		if checksums {
+			writer.write_u32::<LittleEndian>(LogEntry::checksum(key, Some(value)))?;
+		}
 		Ok(position)
 	}
 
-	// FIXME: should return Result
-	fn read(data: &[u8]) -> (usize, LogEntry) {
-		// FIXME: add sanity limits for key_size and value_size
-		//        this is to avoid issues if the file gets corruped
+	/// Reads the entry at the beginning of `data`. Returns `None` if the entry does not fit
+	/// in `data` or its checksum does not match.
+	fn read(data: &[u8], checksums: bool) -> Option<(usize, LogEntry)> {
+		if data.len() < 4 {
+			return None;
+		}
+
 		let mut offset = 4;
-		let key_size = LittleEndian::read_u32(&data[..offset]) as usize;
+		let key_size = LittleEndian::read_u32(data) as usize;
+		if data.len() - offset < key_size + 4 {
+			return None;
+		}
 
 		let key = &data[offset..offset + key_size];
 		offset += key_size;
 
-		let value_size = LittleEndian::read_u32(&data[offset..]) as usize;
+		let value_size = LittleEndian::read_u32(&data[offset..]);
 		offset += 4;
 
 		let value =
-			if value_size == LogEntry::ENTRY_TOMBSTONE as usize {
+			if value_size == LogEntry::ENTRY_TOMBSTONE {
 				None
 			} else {
+				let value_size = value_size as usize;
+				if data.len() - offset < value_size {
+					return None;
+				}
+
 				let v = Some(&data[offset..offset + value_size]);
 				offset += value_size;
 				v
 			};
 
+		if checksums {
+			if data.len() - offset < LogEntry::ENTRY_CHECKSUM_SIZE {
+				return None;
+			}
+
+			if LittleEndian::read_u32(&data[offset..]) != LogEntry::checksum(key, value) {
+				return None;
+			}
+			offset += LogEntry::ENTRY_CHECKSUM_SIZE;
+		}
 
-		(offset, LogEntry { key, value })
+		Some((offset, LogEntry { key, value }))
 	}
 
-	fn len(key: &[u8], value: &[u8]) -> usize {
-		LogEntry::ENTRY_STATIC_SIZE + key.len() + value.len()
+	/// Reads the entry at `position` of the log in `data` read from `path`.
+	fn read_at(path: &Path, data: &'a [u8], position: usize) -> Result<(usize, LogEntry<'a>)> {
+		match LogEntry::read(&data[position..], data.starts_with(LOG_MAGIC)) {
+			Some(entry) => Ok(entry),
+			None => bail!(ErrorKind::Corruption(path.to_owned(), position as u64)),
+		}
+	}
+
+	/// Returns the key of the valid entry at `position` of the log in `data`.
+	fn key_at(data: &'a [u8], position: usize) -> &'a [u8] {
+		let key_size = LittleEndian::read_u32(&data[position..]) as usize;
+		&data[position + 4..position + 4 + key_size]
+	}
+
+	/// Returns the size of the valid entry at `position` of the log in `data`.
+	fn size_at(data: &[u8], position: usize) -> usize {
+		let key_size = LittleEndian::read_u32(&data[position..]) as usize;
+		let value_size = LittleEndian::read_u32(&data[position + 4 + key_size..]);
+		let value_size = if value_size == LogEntry::ENTRY_TOMBSTONE { 0 } else { value_size as usize };
+		let checksum_size = if data.starts_with(LOG_MAGIC) { LogEntry::ENTRY_CHECKSUM_SIZE } else { 0 };
+		LogEntry::ENTRY_STATIC_SIZE + key_size + value_size + checksum_size
+	}
+
+	/// Returns true if nothing was written after the possibly incomplete entry at `position`.
+	fn is_last(data: &[u8], position: usize) -> bool {
+		let data = &data[position..];
+		let end = if data.len() < 8 {
+			data.len()
+		} else {
+			let key_size = LittleEndian::read_u32(data) as usize;
+			if data.len() - 8 < key_size {
+				data.len()
+			} else {
+				let value_size = LittleEndian::read_u32(&data[4 + key_size..]);
+				let value_size = if value_size == LogEntry::ENTRY_TOMBSTONE { 0 } else { value_size as usize };
+				cmp::min(data.len(), LogEntry::ENTRY_STATIC_SIZE + key_size + value_size + LogEntry::ENTRY_CHECKSUM_SIZE)
+			}
+		};
+
+		data[end..].iter().all(|byte| *byte == 0)
+	}
+
+	fn checksum(key: &[u8], value: Option<&[u8]>) -> u32 {
+		let crc = crc32(0, key);
+		value.map_or(crc, |value| crc32(crc, value))
+	}
+
+	fn len(key: &[u8], value: &[u8], checksums: bool) -> usize {
+		let checksum_size = if checksums { LogEntry::ENTRY_CHECKSUM_SIZE } else { 0 };
+		LogEntry::ENTRY_STATIC_SIZE + key.len() + value.len() + checksum_size
+	}
+}
+
+/// Updates CRC32 (IEEE 802.3) checksum `crc` with `data`.
+fn crc32(crc: u32, data: &[u8]) -> u32 {
+	let mut crc = !crc;
+	for byte in data {
+		crc ^= *byte as u32;
+		for _ in 0..8 {
+			crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+		}
 	}
+	!crc
 }
 
 struct LogIterator<'a> {
+	path: &'a Path,
 	data: &'a [u8],
 	position: usize,
+	checksums: bool,
 }
 
 impl<'a> LogIterator<'a> {
-	fn new(data: &[u8]) -> LogIterator {
-		let position = 0;
-		LogIterator { data, position }
+	fn new(path: &'a Path, data: &'a [u8]) -> LogIterator<'a> {
+		let checksums = data.starts_with(LOG_MAGIC);
+		let position = if checksums { LOG_MAGIC.len() } else { 0 };
+		LogIterator { path, data, position, checksums }
 	}
 }
 
 impl<'a> Iterator for LogIterator<'a> {
-	type Item = (usize, LogEntry<'a>);
+	type Item = Result<(usize, LogEntry<'a>)>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		let end = self.position + 4 > self.data.len() ||
+			LittleEndian::read_u32(&self.data[self.position..]) == 0;
 
-	fn next(&mut self) -> Option<(usize, LogEntry<'a>)> {
-		if self.position >= self.data.len() { None }
+		if end { None }
 		else {
-			let (read, entry) = LogEntry::read(&self.data[self.position..]);
 			let position = self.position;
-
-			self.position += read;
-
-			Some((position, entry))
+			match LogEntry::read_at(self.path, self.data, position) {
+				Ok((read, entry)) => {
+					self.position += read;
+					Some(Ok((position, entry)))
+				},
+				Err(err) => {
+					// nothing after a corrupted entry can be trusted
+					self.position = self.data.len();
+					Some(Err(err))
+				},
+			}
 		}
 	}
 }
@@ -366,20 +1045,243 @@ impl<'a> Iterator for LogIterator<'a> {
 mod tests {
 	extern crate tempdir;
 
-	use super::Collision;
+	use std::fs;
+	use std::io::{Seek, SeekFrom, Write};
+
+	use error::ErrorKind;
+	use options::ValuesLen;
+	use super::{crc32, Collision, LogEntry, LOG_MAGIC};
+
+	const VARIABLE: ValuesLen = ValuesLen::Variable { expected: 8 };
 
 	#[test]
 	fn test_roundtrip() {
 		let temp = tempdir::TempDir::new("test_roundtrip").unwrap();
 
 		{
-			let mut collision = Collision::create(temp.path(), 0).unwrap();
+			let mut collision = Collision::create(temp.path(), 0, &VARIABLE).unwrap();
+			collision.insert(b"hello", b"world").unwrap();
+			assert_eq!(collision.get(b"hello").unwrap().unwrap(), b"world");
+		}
+
+		let collision = Collision::open(temp.path(), 0, &VARIABLE).unwrap().unwrap();
+		assert_eq!(collision.get(b"hello").unwrap().unwrap(), b"world");
+		assert!(collision.contains(b"hello").unwrap());
+		assert!(!collision.contains(b"world").unwrap());
+	}
+
+	#[test]
+	fn test_crc32() {
+		assert_eq!(crc32(0, b""), 0);
+		assert_eq!(crc32(0, b"123456789"), 0xcbf4_3926);
+		assert_eq!(crc32(crc32(0, b"1234"), b"56789"), 0xcbf4_3926);
+	}
+
+	#[test]
+	fn test_corruption() {
+		let temp = tempdir::TempDir::new("test_corruption").unwrap();
+
+		let path = {
+			let mut collision = Collision::create(temp.path(), 0, &VARIABLE).unwrap();
+			collision.insert(b"hello", b"world").unwrap();
+			collision.insert(b"hallo", b"welt").unwrap();
+			collision.flush().unwrap();
+			collision.path().to_owned()
+		};
+
+		// flip a byte of the value of the first entry
+		let offset = LOG_MAGIC.len() as u64;
+		{
+			let mut file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+			file.seek(SeekFrom::Start(offset + 4 + 5 + 4)).unwrap();
+			file.write_all(b"W").unwrap();
+		}
+
+		let err = Collision::open(temp.path(), 0, &VARIABLE).unwrap_err();
+		assert_eq!(*err.kind(), ErrorKind::Corruption(path, offset));
+	}
+
+	#[test]
+	fn test_torn_tail() {
+		let temp = tempdir::TempDir::new("test_torn_tail").unwrap();
+
+		let path = {
+			let mut collision = Collision::create(temp.path(), 0, &VARIABLE).unwrap();
 			collision.insert(b"hello", b"world").unwrap();
+			collision.insert(b"hallo", b"welt").unwrap();
+			collision.flush().unwrap();
+			collision.path().to_owned()
+		};
+
+		// lose the value and the checksum of the last entry
+		let len = (LOG_MAGIC.len() + LogEntry::len(b"hello", b"world", true)) as u64;
+		{
+			let mut file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+			file.seek(SeekFrom::Start(len + 4 + 5 + 4)).unwrap();
+			file.write_all(&[0; 8]).unwrap();
+		}
+
+		{
+			let mut collision = Collision::open(temp.path(), 0, &VARIABLE).unwrap().unwrap();
+			assert_eq!(collision.log_bytes(), len);
 			assert_eq!(collision.get(b"hello").unwrap().unwrap(), b"world");
+			assert_eq!(collision.get(b"hallo").unwrap(), None);
+			collision.insert(b"hey", b"you").unwrap();
+			collision.flush().unwrap();
 		}
 
-		let collision = Collision::open(temp.path(), 0).unwrap().unwrap();
+		let collision = Collision::open(temp.path(), 0, &VARIABLE).unwrap().unwrap();
+		let keys: Vec<_> = collision.iter().unwrap().map(|entry| entry.unwrap().0.to_vec()).collect();
+		assert_eq!(keys, vec![b"hello".to_vec(), b"hey".to_vec()]);
+	}
+
+	#[test]
+	fn test_legacy_log() {
+		let temp = tempdir::TempDir::new("test_legacy_log").unwrap();
+
+		{
+			let mut file = fs::File::create(temp.path().join("collision-0.log")).unwrap();
+			LogEntry::write(&mut file, b"hello", b"world", false).unwrap();
+			LogEntry::write(&mut file, b"hallo", b"welt", false).unwrap();
+			LogEntry::write_deleted(&mut file, b"hallo", false).unwrap();
+			file.set_len(4096).unwrap();
+		}
+
+		let mut collision = Collision::open(temp.path(), 0, &VARIABLE).unwrap().unwrap();
 		assert_eq!(collision.get(b"hello").unwrap().unwrap(), b"world");
+		collision.insert(b"hallo", b"welt").unwrap();
+		collision.compact().unwrap();
+
+		let collision = Collision::open(temp.path(), 0, &VARIABLE).unwrap().unwrap();
+		assert_eq!(collision.get(b"hallo").unwrap().unwrap(), b"welt");
+		let live = LogEntry::len(b"hello", b"world", true) + LogEntry::len(b"hallo", b"welt", true);
+		assert_eq!(collision.log_bytes(), (LOG_MAGIC.len() + live) as u64);
+	}
+
+	#[test]
+	fn test_compact() {
+		let temp = tempdir::TempDir::new("test_compact").unwrap();
+
+		let mut collision = Collision::create(temp.path(), 0, &VARIABLE).unwrap();
+		for i in 0..100u8 {
+			collision.insert(&[i], &[0; 1024]).unwrap();
+			collision.insert(&[i], &[i; 1024]).unwrap();
+			if i % 2 == 1 {
+				collision.delete(&[i]).unwrap();
+			}
+		}
+
+		assert!(collision.needs_compaction());
+		assert!(!collision.is_compact());
+		let log_bytes = collision.log_bytes();
+		collision.compact().unwrap();
+		assert!(!collision.needs_compaction());
+		assert!(collision.is_compact());
+		assert_eq!(collision.log_bytes(), LOG_MAGIC.len() as u64 + collision.live_bytes());
+		assert!(collision.log_bytes() < log_bytes / 3);
+
+		collision.insert(b"x", b"y").unwrap();
+		let collision = Collision::open(temp.path(), 0, &VARIABLE).unwrap().unwrap();
+		let keys: Vec<_> = collision.iter().unwrap().map(|entry| entry.unwrap().0.to_vec()).collect();
+		let mut expected: Vec<_> = (0..100u8).filter(|i| i % 2 == 0).map(|i| vec![i]).collect();
+		expected.push(b"x".to_vec());
+		assert_eq!(keys, expected);
+		assert_eq!(collision.get(&[10]).unwrap().unwrap(), &[10; 1024][..]);
+	}
+
+	#[test]
+	fn test_grow() {
+		let temp = tempdir::TempDir::new("test_grow").unwrap();
+
+		{
+			let mut collision = Collision::create(temp.path(), 0, &VARIABLE).unwrap();
+			for i in 0..1000u16 {
+				let key = [(i >> 8) as u8, i as u8];
+				collision.insert(&key, &[i as u8; 100]).unwrap();
+			}
+			assert_eq!(collision.log_bytes(), LOG_MAGIC.len() as u64 + 1000 * 114);
+			collision.flush().unwrap();
+		}
+
+		let collision = Collision::open(temp.path(), 0, &VARIABLE).unwrap().unwrap();
+		assert_eq!(collision.log_bytes(), LOG_MAGIC.len() as u64 + 1000 * 114);
+		assert_eq!(collision.iter().unwrap().count(), 1000);
+		assert_eq!(collision.get(&[3, 0xe7]).unwrap().unwrap(), &[0xe7; 100][..]);
+	}
+
+	#[test]
+	fn test_compact_empty() {
+		let temp = tempdir::TempDir::new("test_compact_empty").unwrap();
+
+		let mut collision = Collision::create(temp.path(), 0, &VARIABLE).unwrap();
+		collision.insert(b"hello", b"world").unwrap();
+		collision.delete(b"hello").unwrap();
+		collision.compact().unwrap();
+
+		let collision = Collision::open(temp.path(), 0, &VARIABLE).unwrap().unwrap();
+		assert_eq!(collision.iter().unwrap().count(), 0);
+	}
+
+	#[test]
+	fn test_iter_rev() {
+		let temp = tempdir::TempDir::new("test_iter_rev").unwrap();
+
+		let mut collision = Collision::create(temp.path(), 0, &VARIABLE).unwrap();
+		collision.insert(b"0", b"0").unwrap();
+		collision.insert(b"2", b"2").unwrap();
+		collision.insert(b"1", b"1").unwrap();
+		collision.delete(b"2").unwrap();
+
+		let expected: Vec<(&[u8], &[u8])> = vec![(b"1", b"1"), (b"0", b"0")];
+		assert_eq!(collision.iter_rev().unwrap().map(|entry| entry.unwrap()).collect::<Vec<_>>(), expected);
+
+		collision.evict();
+		assert_eq!(collision.iter_rev().unwrap().map(|entry| entry.unwrap()).collect::<Vec<_>>(), expected);
+	}
+
+	#[test]
+	fn test_get_rebuilds_evicted_index() {
+		let temp = tempdir::TempDir::new("test_get_rebuilds_evicted_index").unwrap();
+
+		let mut collision = Collision::create(temp.path(), 0, &VARIABLE).unwrap();
+		collision.insert(b"0", b"0").unwrap();
+		collision.insert(b"1", b"1").unwrap();
+		collision.delete(b"1").unwrap();
+
+		collision.evict();
+		assert!(!collision.is_resident());
+		assert_eq!(collision.get(b"0").unwrap(), Some(&b"0"[..]));
+		assert!(collision.is_resident());
+		assert!(!collision.contains(b"1").unwrap());
+
+		// the rebuilt index is used by mutable operations
+		collision.insert(b"2", b"2").unwrap();
+		assert_eq!(collision.iter().unwrap().count(), 2);
+		assert_eq!(collision.live_bytes(), collision.iter().unwrap().map(|entry| {
+			let (key, value) = entry.unwrap();
+			LogEntry::len(key, value, true) as u64
+		}).sum());
+
+		collision.evict();
+		assert!(!collision.is_resident());
+		assert_eq!(collision.get(b"2").unwrap(), Some(&b"2"[..]));
+	}
+
+	#[test]
+	fn test_get_many() {
+		let temp = tempdir::TempDir::new("test_get_many").unwrap();
+
+		let mut collision = Collision::create(temp.path(), 0, &VARIABLE).unwrap();
+		collision.insert(b"2", b"2").unwrap();
+		collision.insert(b"0", b"0").unwrap();
+		collision.insert(b"1", b"1").unwrap();
+		collision.delete(b"1").unwrap();
+		collision.evict();
+
+		let keys: Vec<&[u8]> = vec![b"0", b"1", b"2", b"3", b"2"];
+		let values: Vec<_> = collision.get_many(&keys).into_iter().map(|value| value.unwrap()).collect();
+		assert_eq!(values, vec![Some(&b"0"[..]), None, Some(&b"2"[..]), None, Some(&b"2"[..])]);
+		assert!(collision.get_many(&[]).is_empty());
 	}
 
 	#[test]
@@ -387,7 +1289,7 @@ mod tests {
 		let temp = tempdir::TempDir::new("test_roundtrip").unwrap();
 
 		{
-			let mut collision = Collision::create(temp.path(), 0).unwrap();
+			let mut collision = Collision::create(temp.path(), 0, &VARIABLE).unwrap();
 			collision.insert(b"0", b"0").unwrap();
 			collision.insert(b"2", b"2").unwrap();
 			collision.insert(b"1", b"1").unwrap();
@@ -396,7 +1298,7 @@ mod tests {
 			collision.delete(b"4").unwrap();
 		}
 
-		let collision = Collision::open(temp.path(), 0).unwrap().unwrap();
+		let collision = Collision::open(temp.path(), 0, &VARIABLE).unwrap().unwrap();
 		let collision: Vec<_> = collision.iter().unwrap().flat_map(|entry| entry.ok()).collect();
 
 		let expected: Vec<(&[u8], &[u8])> =
@@ -404,4 +1306,68 @@ mod tests {
 
 		assert_eq!(collision, expected);
 	}
+
+	#[test]
+	fn test_bloom_filter() {
+		let temp = tempdir::TempDir::new("test_bloom_filter").unwrap();
+
+		let path = {
+			let mut collision = Collision::create(temp.path(), 0, &VARIABLE).unwrap();
+			collision.insert(b"hello", b"world").unwrap();
+			collision.insert(b"hallo", b"welt").unwrap();
+			collision.flush().unwrap();
+			collision.path().to_owned()
+		};
+
+		let mut collision = Collision::open(temp.path(), 0, &VARIABLE).unwrap().unwrap();
+		collision.evict();
+
+		// flip a byte of the value of the first entry, so rebuilding the index fails
+		{
+			let mut file = fs::OpenOptions::new().write(true).open(&path).unwrap();
+			file.seek(SeekFrom::Start(LOG_MAGIC.len() as u64 + 4 + 5 + 4)).unwrap();
+			file.write_all(b"W").unwrap();
+		}
+
+		// keys which are not in the file are not looked up in the log
+		assert_eq!(collision.get(b"hullo").unwrap(), None);
+		assert!(!collision.contains(b"hullo").unwrap());
+		assert!(collision.get(b"hello").is_err());
+	}
+
+	#[test]
+	fn test_constant_values() {
+		let temp = tempdir::TempDir::new("test_constant_values").unwrap();
+		let constant = ValuesLen::Constant(100);
+
+		{
+			let mut collision = Collision::create(temp.path(), 0, &constant).unwrap();
+			for i in (0..1000u16).rev() {
+				let key = [(i >> 8) as u8, i as u8];
+				collision.insert(&key, &[0; 100]).unwrap();
+				collision.insert(&key, &[i as u8; 100]).unwrap();
+				if i % 2 == 1 {
+					collision.delete(&key).unwrap();
+				}
+			}
+			assert_eq!(collision.live_bytes(), 500 * 114);
+			assert!(!collision.contains(&[3, 0xe7]).unwrap());
+			assert_eq!(collision.get(&[3, 0xe8]).unwrap().unwrap(), &[0xe8; 100][..]);
+			collision.flush().unwrap();
+		}
+
+		let mut collision = Collision::open(temp.path(), 0, &constant).unwrap().unwrap();
+		assert_eq!(collision.live_bytes(), 500 * 114);
+		let keys: Vec<_> = collision.iter().unwrap().map(|entry| entry.unwrap().0.to_vec()).collect();
+		let expected: Vec<_> = (0..1000u16).filter(|i| i % 2 == 0).map(|i| vec![(i >> 8) as u8, i as u8]).collect();
+		assert_eq!(keys, expected);
+
+		collision.compact().unwrap();
+		assert!(collision.is_compact());
+		assert_eq!(collision.get(&[3, 0xe8]).unwrap().unwrap(), &[0xe8; 100][..]);
+
+		// a log with values of another length can't be opened with constant values
+		let err = Collision::open(temp.path(), 0, &ValuesLen::Constant(99)).unwrap_err();
+		assert_eq!(*err.kind(), ErrorKind::InvalidValueLen(99, 100));
+	}
 }
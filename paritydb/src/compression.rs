@@ -0,0 +1,291 @@
+//! Built-in compression of values, see `Options::compression`.
+//!
+//! Compression is a value transform, so compressed values are marked by
+//! the flags byte of their envelope. A compressed value starts with a byte
+//! identifying the algorithm, which lets values compressed by any of the
+//! algorithms be read whichever algorithm is configured.
+//!
+//! ```text
+//!  algorithm  compressed value
+//!   /          /
+//! |.|..............|
+//! ```
+//!
+//! Compression is the first transform, so inserting or removing it would change the meaning
+//! of the flags of stored values. Whether values have it in their envelope is recorded once
+//! the database stores values, and from then on the compressor is kept even if nothing is
+//! compressed anymore. A database storing values without it can't be compressed later.
+//!
+//! Columns, i.e. keys with a given prefix, may be compressed with another algorithm,
+//! see `Database::set_column_compression`. The envelope of the database and algorithms of
+//! the columns are stored in their own file, starting with 1 if values have the compressor
+//! in their envelope and 0 if they don't, followed by entries of the algorithm, the prefix
+//! length and the prefix. A missing file means the database stores no values yet and all
+//! columns use `Options::compression`.
+//!
+//! ```text
+//!  compressor  algorithm  length  prefix
+//!   /           /          /       /
+//! |.|         .|.|.......|
+//! ```
+
+use std::fs;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use lz4_compress;
+use snap;
+
+use error::{ErrorKind, Result};
+use options::Compression;
+use transform::{ValueTransform, ValueTransforms};
+
+pub const COMPRESSION_FILE: &'static str = "COMPRESSION";
+
+const NONE: u8 = 0;
+const LZ4: u8 = 1;
+const SNAPPY: u8 = 2;
+
+/// Value transform compressing values with the configured algorithm.
+pub struct Compressor {
+	compression: Compression,
+}
+
+impl Compressor {
+	/// Returns `transforms` preceded by the transform of `compression` if values have the
+	/// compressor in their envelope, with the compression of `columns` replacing it for their keys.
+	///
+	/// The compressor is kept even if it compresses nothing, so the flags of stored values don't
+	/// change when the compression is changed, see `has_compressor`.
+	pub fn transforms(
+		compression: Compression,
+		columns: &[(Vec<u8>, Compression)],
+		compressor: bool,
+		transforms: Vec<Box<ValueTransform>>,
+	) -> Result<ValueTransforms> {
+		if !compressor {
+			return ValueTransforms::new(transforms);
+		}
+
+		let mut chain: Vec<Box<ValueTransform>> = vec![Box::new(Compressor { compression })];
+		chain.extend(transforms);
+		let mut chain = ValueTransforms::new(chain)?;
+		chain.set_overrides(Self::overrides(columns))?;
+		Ok(chain)
+	}
+
+	/// Returns compressors of `columns` by their prefix.
+	pub fn overrides(columns: &[(Vec<u8>, Compression)]) -> Vec<(Vec<u8>, Box<ValueTransform>)> {
+		columns.iter()
+			.map(|&(ref prefix, compression)| (prefix.clone(), Box::new(Compressor { compression }) as Box<ValueTransform>))
+			.collect()
+	}
+}
+
+/// Returns true if values have the compressor in their envelope, given the `envelope` recorded
+/// in the database directory and the compression the database is opened with.
+///
+/// Until the envelope is recorded, values get the compressor if anything is compressed.
+pub fn has_compressor(envelope: Option<bool>, compression: Compression, columns: &[(Vec<u8>, Compression)]) -> bool {
+	envelope.unwrap_or(compression != Compression::None || !columns.is_empty())
+}
+
+/// Sets `compression` of the column with keys starting with `prefix` in `columns`.
+pub fn set_column(columns: &mut Vec<(Vec<u8>, Compression)>, prefix: &[u8], compression: Compression) {
+	match columns.iter().position(|&(ref column, _)| column.as_slice() == prefix) {
+		Some(index) => columns[index].1 = compression,
+		None => columns.push((prefix.to_vec(), compression)),
+	}
+}
+
+/// Serializes the envelope of the database and compression of the columns.
+pub fn encode(compressor: bool, columns: &[(Vec<u8>, Compression)]) -> Vec<u8> {
+	let mut data = vec![compressor as u8];
+	for &(ref prefix, compression) in columns {
+		data.push(match compression {
+			Compression::None => NONE,
+			Compression::Lz4 => LZ4,
+			Compression::Snappy => SNAPPY,
+		});
+		data.push(prefix.len() as u8);
+		data.extend_from_slice(prefix);
+	}
+	data
+}
+
+/// Reads the envelope of the database and compression of the columns from database directory `dir`.
+///
+/// The envelope is `None` if it was not recorded yet, see `has_compressor`.
+pub fn read_columns<P: AsRef<Path>>(dir: P) -> Result<(Option<bool>, Vec<(Vec<u8>, Compression)>)> {
+	let path = dir.as_ref().join(COMPRESSION_FILE);
+	if !path.exists() {
+		return Ok((None, Vec::new()));
+	}
+
+	let mut data = Vec::new();
+	fs::File::open(&path)?.read_to_end(&mut data)?;
+
+	let compressor = match data.first() {
+		Some(&0) => false,
+		Some(&1) => true,
+		Some(&envelope) => bail!(ErrorKind::CorruptedCompression(path, format!("Unknown envelope {}", envelope))),
+		None => bail!(ErrorKind::CorruptedCompression(path, "Missing envelope".into())),
+	};
+
+	let mut columns = Vec::new();
+	let mut rest = &data[1..];
+	while !rest.is_empty() {
+		if rest.len() < 2 || rest.len() < 2 + rest[1] as usize {
+			bail!(ErrorKind::CorruptedCompression(path, format!("Truncated column at offset {}", data.len() - rest.len())));
+		}
+
+		let compression = match rest[0] {
+			NONE => Compression::None,
+			LZ4 => Compression::Lz4,
+			SNAPPY => Compression::Snappy,
+			algorithm => bail!(ErrorKind::CorruptedCompression(path, format!("Unknown compression algorithm {}", algorithm))),
+		};
+		let end = 2 + rest[1] as usize;
+		columns.push((rest[2..end].to_vec(), compression));
+		rest = &rest[end..];
+	}
+
+	if !compressor && !columns.is_empty() {
+		bail!(ErrorKind::CorruptedCompression(path, "Columns are compressed without the compressor".into()));
+	}
+
+	Ok((Some(compressor), columns))
+}
+
+/// Atomically replaces the envelope and compression of the columns in database directory `dir`.
+pub fn write_columns<P: AsRef<Path>>(dir: P, compressor: bool, columns: &[(Vec<u8>, Compression)]) -> Result<()> {
+	let dir = dir.as_ref();
+	let tmp_path = dir.join(COMPRESSION_FILE).with_extension("tmp");
+
+	{
+		let mut file = fs::File::create(&tmp_path)?;
+		file.write_all(&encode(compressor, columns))?;
+		file.sync_all()?;
+	}
+
+	fs::rename(&tmp_path, dir.join(COMPRESSION_FILE))?;
+	Ok(())
+}
+
+impl ValueTransform for Compressor {
+	fn encode(&self, value: &[u8]) -> Option<Vec<u8>> {
+		let (algorithm, compressed) = match self.compression {
+			Compression::None => return None,
+			Compression::Lz4 => (LZ4, lz4_compress::compress(value)),
+			Compression::Snappy => match snap::Encoder::new().compress_vec(value) {
+				Ok(compressed) => (SNAPPY, compressed),
+				Err(_) => return None,
+			},
+		};
+
+		if compressed.len() + 1 >= value.len() {
+			return None;
+		}
+
+		let mut encoded = Vec::with_capacity(1 + compressed.len());
+		encoded.push(algorithm);
+		encoded.extend_from_slice(&compressed);
+		Some(encoded)
+	}
+
+	fn decode(&self, value: &[u8]) -> Result<Vec<u8>> {
+		if value.is_empty() {
+			bail!(ErrorKind::InvalidValueEnvelope("missing compression algorithm".into()));
+		}
+
+		match value[0] {
+			LZ4 => lz4_compress::decompress(&value[1..])
+				.map_err(|err| ErrorKind::InvalidValueEnvelope(format!("invalid lz4 value: {:?}", err)).into()),
+			SNAPPY => snap::Decoder::new().decompress_vec(&value[1..])
+				.map_err(|err| ErrorKind::InvalidValueEnvelope(format!("invalid snappy value: {}", err)).into()),
+			algorithm => bail!(ErrorKind::InvalidValueEnvelope(format!("unknown compression algorithm {}", algorithm))),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	extern crate tempdir;
+
+	use std::fs::File;
+	use std::io::Write;
+
+	use error::ErrorKind;
+	use options::Compression;
+	use transform::ValueTransform;
+	use super::{has_compressor, read_columns, set_column, write_columns, Compressor, COMPRESSION_FILE};
+
+	#[test]
+	fn test_compressor() {
+		let value = b"0123456789".iter().cycle().take(1000).cloned().collect::<Vec<u8>>();
+		let lz4 = Compressor { compression: Compression::Lz4 };
+		let snappy = Compressor { compression: Compression::Snappy };
+
+		let compressed = lz4.encode(&value).unwrap();
+		assert!(compressed.len() < value.len());
+		assert_eq!(lz4.decode(&compressed).unwrap(), value);
+		// values compressed by other algorithms remain readable
+		assert_eq!(snappy.decode(&compressed).unwrap(), value);
+		assert_eq!(lz4.decode(&snappy.encode(&value).unwrap()).unwrap(), value);
+
+		// values which don't get smaller are stored as they are
+		assert_eq!(lz4.encode(b"abc"), None);
+		assert_eq!(Compressor { compression: Compression::None }.encode(&value), None);
+
+		assert!(matches!(*lz4.decode(b"\x07abc").unwrap_err().kind(), ErrorKind::InvalidValueEnvelope(_)));
+		assert!(Compressor::transforms(Compression::Lz4, &[], false, Vec::new()).unwrap().is_empty());
+		assert!(!Compressor::transforms(Compression::None, &[], true, Vec::new()).unwrap().is_empty());
+
+		// the compressor is kept once values have it in their envelope
+		assert!(!has_compressor(None, Compression::None, &[]));
+		assert!(has_compressor(None, Compression::Lz4, &[]));
+		assert!(has_compressor(Some(true), Compression::None, &[]));
+		assert!(!has_compressor(Some(false), Compression::Lz4, &[]));
+	}
+
+	#[test]
+	fn test_column_compression() {
+		let value = b"0123456789".iter().cycle().take(1000).cloned().collect::<Vec<u8>>();
+		let columns = vec![(b"\x01".to_vec(), Compression::Snappy), (b"\x02".to_vec(), Compression::None)];
+
+		// the compressor is kept for the columns although nothing else is compressed
+		let transforms = Compressor::transforms(Compression::None, &columns, true, Vec::new()).unwrap();
+		assert_eq!(transforms.encode(b"\x00key", &value)[0], 0);
+		assert_eq!(transforms.encode(b"\x02key", &value)[0], 0);
+
+		let snappy = transforms.encode(b"\x01key", &value);
+		let lz4 = Compressor::transforms(Compression::Lz4, &columns, true, Vec::new()).unwrap().encode(b"\x00key", &value);
+		assert_eq!(snappy[..2].to_vec(), vec![1, 2]);
+		assert_eq!(lz4[..2].to_vec(), vec![1, 1]);
+		assert_eq!(transforms.decode(&snappy).unwrap(), value);
+		assert_eq!(transforms.decode(&lz4).unwrap(), value);
+	}
+
+	#[test]
+	fn test_columns_file() {
+		let temp = tempdir::TempDir::new("test_columns_file").unwrap();
+		assert_eq!(read_columns(temp.path()).unwrap(), (None, vec![]));
+
+		let mut columns = Vec::new();
+		set_column(&mut columns, b"\x01", Compression::Lz4);
+		set_column(&mut columns, b"", Compression::Snappy);
+		set_column(&mut columns, b"\x01", Compression::None);
+		assert_eq!(columns, vec![(b"\x01".to_vec(), Compression::None), (Vec::new(), Compression::Snappy)]);
+
+		write_columns(temp.path(), true, &columns).unwrap();
+		assert_eq!(read_columns(temp.path()).unwrap(), (Some(true), columns));
+		write_columns(temp.path(), false, &[]).unwrap();
+		assert_eq!(read_columns(temp.path()).unwrap(), (Some(false), vec![]));
+
+		let path = temp.path().join(COMPRESSION_FILE);
+		for data in &[&[1, 1, 3, 0][..], &[1, 9, 0], &[2], &[], &[0, 1, 0]] {
+			File::create(&path).unwrap().write_all(data).unwrap();
+			assert!(matches!(*read_columns(temp.path()).unwrap_err().kind(), ErrorKind::CorruptedCompression(..)));
+		}
+	}
+}
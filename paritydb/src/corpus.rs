@@ -0,0 +1,284 @@
+//! Generator of database files for fuzzing and format tests.
+//!
+//! Inputs are generated from a seed, so a failing input can be reproduced from
+//! the seed alone. Valid files are serialized by the same code which writes them
+//! in a database. Near-valid files are valid ones with a single `FileCorruption`
+//! applied on top, which is what a crash or a bad disk leaves behind.
+
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+
+use collision;
+use database::Database;
+use error::Result;
+use journal;
+use options::{Options, ValuesLen};
+use transaction::Transaction;
+
+/// Mixed into seeds, so that small seeds start in well mixed states.
+const SEED_MIX: u64 = 0x9e37_79b9_7f4a_7c15;
+/// Generated variable length values are at most this many bytes longer than expected.
+const MAX_VALUE_LEN_DEVIATION: usize = 32;
+
+/// Damage applied to a generated file.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FileCorruption {
+	/// Flips a single bit.
+	FlipBit,
+	/// Cuts off the end of the file.
+	Truncate,
+	/// Overwrites a range of bytes with zeros.
+	ZeroRange,
+	/// Appends random bytes to the file.
+	AppendGarbage,
+}
+
+/// Seeded generator of data files, collision logs and journal segments.
+#[derive(Debug)]
+pub struct CorpusGenerator {
+	state: u64,
+}
+
+impl CorpusGenerator {
+	/// Creates a generator. The same seed always generates the same inputs.
+	pub fn new(seed: u64) -> Self {
+		let state = seed ^ SEED_MIX;
+		// xorshift never leaves the zero state
+		CorpusGenerator {
+			state: if state == 0 { SEED_MIX } else { state },
+		}
+	}
+
+	fn next_u64(&mut self) -> u64 {
+		self.state ^= self.state >> 12;
+		self.state ^= self.state << 25;
+		self.state ^= self.state >> 27;
+		self.state.wrapping_mul(0x2545_f491_4f6c_dd1d)
+	}
+
+	/// Returns a random number lower than `n`, or 0 if `n` is 0.
+	fn below(&mut self, n: usize) -> usize {
+		if n == 0 {
+			return 0;
+		}
+
+		(self.next_u64() % n as u64) as usize
+	}
+
+	fn bytes(&mut self, len: usize) -> Vec<u8> {
+		(0..len).map(|_| self.next_u64() as u8).collect()
+	}
+
+	fn value(&mut self, value_len: &ValuesLen) -> Vec<u8> {
+		let len = match *value_len {
+			ValuesLen::Constant(len) => len,
+			ValuesLen::Variable { expected } => {
+				let len = expected + self.below(2 * MAX_VALUE_LEN_DEVIATION + 1);
+				len.saturating_sub(MAX_VALUE_LEN_DEVIATION)
+			},
+		};
+
+		self.bytes(len)
+	}
+
+	/// Generates a transaction with `operations` inserts and deletes of random keys.
+	///
+	/// Every fourth operation on average deletes a key inserted earlier in the transaction.
+	pub fn transaction(&mut self, key_len: usize, value_len: &ValuesLen, operations: usize) -> Transaction {
+		let mut tx = Transaction::new(key_len);
+		let mut keys: Vec<Vec<u8>> = Vec::with_capacity(operations);
+
+		for _ in 0..operations {
+			if !keys.is_empty() && self.below(4) == 0 {
+				let index = self.below(keys.len());
+				tx.delete(&keys[index]).expect("key has the length of the transaction keys; qed");
+			} else {
+				let key = self.bytes(key_len);
+				let value = self.value(value_len);
+				tx.insert(&key, &value).expect("key has the length of the transaction keys; qed");
+				keys.push(key);
+			}
+		}
+
+		tx
+	}
+
+	/// Generates the contents of a journal era file with `operations` operations.
+	///
+	/// The result can be checked with `Database::apply_journal_segment`.
+	pub fn journal_segment(&mut self, key_len: usize, value_len: &ValuesLen, operations: usize) -> Vec<u8> {
+		let tx = self.transaction(key_len, value_len, operations);
+		journal::encode_segment(&tx)
+	}
+
+	/// Generates the contents of a collision file with `entries` log entries.
+	///
+	/// Keys share their first byte like keys of a collided prefix. Every fourth entry
+	/// on average is an overwrite or a tombstone of an earlier key.
+	pub fn collision_log(&mut self, key_len: usize, value_len: &ValuesLen, entries: usize) -> Vec<u8> {
+		assert!(key_len > 0, "empty key marks the end of the log");
+
+		let first = self.next_u64() as u8;
+		let mut keys: Vec<Vec<u8>> = Vec::with_capacity(entries);
+		let mut log = Vec::with_capacity(entries);
+
+		for _ in 0..entries {
+			let entry = if !keys.is_empty() && self.below(4) == 0 {
+				let key = keys[self.below(keys.len())].clone();
+				if self.below(2) == 0 {
+					(key, None)
+				} else {
+					let value = self.value(value_len);
+					(key, Some(value))
+				}
+			} else {
+				let mut key = self.bytes(key_len);
+				key[0] = first;
+				keys.push(key.clone());
+				let value = self.value(value_len);
+				(key, Some(value))
+			};
+
+			log.push(entry);
+		}
+
+		collision::encode_log(&log)
+	}
+
+	/// Generates a data file with `records` records by committing them to a new database
+	/// created with `options` in `dir`.
+	///
+	/// The whole journal is flushed, so the data file holds every record.
+	pub fn data_file<P: AsRef<Path>>(&mut self, dir: P, options: Options, records: usize) -> Result<Vec<u8>> {
+		let dir = dir.as_ref();
+		let tx = {
+			let value_len = options.value_len.clone();
+			let mut tx = Transaction::new(options.key_len);
+			for _ in 0..records {
+				let key = self.bytes(options.key_len);
+				let value = self.value(&value_len);
+				tx.insert(&key, &value)?;
+			}
+			tx
+		};
+
+		{
+			let mut db = Database::create(dir, Options { journal_eras: 0, ..options })?;
+			db.commit(&tx)?;
+			db.flush_journal(None)?;
+		}
+
+		let mut data = Vec::new();
+		fs::File::open(dir.join(Database::DB_FILE))?.read_to_end(&mut data)?;
+		Ok(data)
+	}
+
+	/// Applies `corruption` to `data` at a random position.
+	pub fn corrupt(&mut self, data: &mut Vec<u8>, corruption: FileCorruption) {
+		let len = data.len();
+
+		match corruption {
+			FileCorruption::FlipBit => {
+				if len > 0 {
+					let offset = self.below(len);
+					data[offset] ^= 1 << self.below(8);
+				}
+			},
+			FileCorruption::Truncate => {
+				let new_len = self.below(len);
+				data.truncate(new_len);
+			},
+			FileCorruption::ZeroRange => {
+				if len > 0 {
+					let start = self.below(len);
+					let end = start + self.below(len - start) + 1;
+					for byte in &mut data[start..end] {
+						*byte = 0;
+					}
+				}
+			},
+			FileCorruption::AppendGarbage => {
+				let garbage_len = self.below(64) + 1;
+				let garbage = self.bytes(garbage_len);
+				data.extend_from_slice(&garbage);
+			},
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	extern crate tempdir;
+
+	use std::fs;
+	use std::io::Write;
+
+	use collision::Collision;
+	use database::Database;
+	use journal;
+	use options::{Options, ValuesLen};
+	use super::{CorpusGenerator, FileCorruption};
+
+	#[test]
+	fn test_generator_is_deterministic() {
+		let value_len = ValuesLen::Variable { expected: 16 };
+		let a = CorpusGenerator::new(7).journal_segment(4, &value_len, 50);
+		let b = CorpusGenerator::new(7).journal_segment(4, &value_len, 50);
+		let c = CorpusGenerator::new(8).journal_segment(4, &value_len, 50);
+		assert_eq!(a, b);
+		assert!(a != c);
+	}
+
+	#[test]
+	fn test_journal_segment() {
+		let temp = tempdir::TempDir::new("test_corpus_journal_segment").unwrap();
+		let options = Options {
+			journal_eras: 0,
+			key_len: 4,
+			value_len: ValuesLen::Constant(8),
+			..Default::default()
+		};
+
+		let mut generator = CorpusGenerator::new(1);
+		let segment = generator.journal_segment(4, &options.value_len, 100);
+		assert!(journal::segment_operations(&segment).is_ok());
+
+		let mut db = Database::create(temp.path(), options).unwrap();
+		db.apply_journal_segment(&segment).unwrap();
+
+		let mut corrupted = segment.clone();
+		generator.corrupt(&mut corrupted, FileCorruption::FlipBit);
+		assert!(journal::segment_operations(&corrupted).is_err());
+
+		let mut truncated = segment.clone();
+		generator.corrupt(&mut truncated, FileCorruption::Truncate);
+		assert!(truncated.len() < segment.len());
+		assert!(db.apply_journal_segment(&truncated).is_err());
+	}
+
+	#[test]
+	fn test_collision_log() {
+		let temp = tempdir::TempDir::new("test_corpus_collision_log").unwrap();
+
+		let mut generator = CorpusGenerator::new(2);
+		let log = generator.collision_log(4, &ValuesLen::Variable { expected: 8 }, 200);
+		fs::File::create(temp.path().join("collision-0.log")).unwrap().write_all(&log).unwrap();
+
+		let collision = Collision::open(temp.path(), 0, &ValuesLen::Variable { expected: 8 }).unwrap().unwrap();
+		assert!(collision.iter().unwrap().count() > 0);
+	}
+
+	#[test]
+	fn test_data_file() {
+		let temp = tempdir::TempDir::new("test_corpus_data_file").unwrap();
+		let options = Options {
+			key_len: 4,
+			value_len: ValuesLen::Constant(8),
+			..Default::default()
+		};
+
+		let data = CorpusGenerator::new(3).data_file(temp.path().join("db"), options, 100).unwrap();
+		assert!(data.iter().any(|byte| *byte != 0));
+	}
+}
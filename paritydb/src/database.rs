@@ -1,27 +1,52 @@
 use std::cmp::Ordering;
-use std::collections::{btree_set, BTreeMap};
+use std::collections::{btree_set, BTreeMap, BTreeSet, HashSet};
 use std::collections::btree_map::Entry;
-use std::io::Write;
+use std::io::{Read, Write};
+use std::ops::Range;
 use std::path::{PathBuf, Path};
+use std::rc::Rc;
+use std::time::Instant;
 use std::{cmp, fs};
 use std::fs::File;
 
-use fs2::FileExt;
+use bit_vec::BitVec;
+use fs2::{self, FileExt};
 use memmap::{Mmap, Protection};
+use parking_lot::Mutex;
 use itertools::Itertools;
 use itertools::EitherOrBoth;
 
+use audit::{Audit, AuditRecord};
+use cache::{CacheStats, ValueCache};
+use checkpoint::{self, Checkpoint, CheckpointPins};
 use collision::Collision;
+use compression::{self, Compressor};
+use diff::{self, Change};
 use error::{ErrorKind, Result};
+use events::{Event, Events};
+use export::{DumpReader, DumpWriter};
+use fence;
+use field::{self, field_size};
 use find;
 use find::RecordIterator;
 use flush::Flush;
-use journal::Journal;
+use health::{ErrorLog, Health};
+use idempotency::{self, Idempotency};
+use journal::{self, Journal, JournalOperation};
 use key::Key;
+use latency::{Latencies, LatencyReport};
 use metadata::{self, Metadata};
-use options::{Options, InternalOptions};
+use options::{Compression, FsyncPolicy, Options, InternalOptions, ValuesLen};
+use read::{ReadOptions, ReadStats};
 use record::Record;
+use repair::Repair;
+use seal;
+use snapshot::{self, ReadTransaction, Snapshot, SnapshotPins, SnapshotWriter};
+use spawn::{Spawner, ThreadSpawner};
+use stats::{Counters, KeyStatistics, Statistics, StatsHistory};
 use transaction::{Operation, Transaction};
+use transform::{ValueTransform, ValueTransforms};
+use trigger::Triggers;
 
 /// A database record value.
 #[derive(Debug, PartialEq)]
@@ -30,6 +55,8 @@ pub enum Value<'a> {
 	Raw(&'a [u8]),
 	/// DB record
 	Record(Record<'a>),
+	/// Decoded data
+	Owned(Vec<u8>),
 }
 
 impl<'a> Value<'a> {
@@ -37,6 +64,7 @@ impl<'a> Value<'a> {
 	pub fn to_vec(&self) -> Vec<u8> {
 		match *self {
 			Value::Raw(ref slice) => slice.to_vec(),
+			Value::Owned(ref vec) => vec.clone(),
 			Value::Record(ref record) => {
 				let mut v = Vec::with_capacity(record.value_len());
 				v.resize(record.value_len(), 0);
@@ -46,10 +74,24 @@ impl<'a> Value<'a> {
 		}
 	}
 
+	/// Copies the value to `buf`, replacing its contents but reusing its allocation.
+	pub fn copy_to(&self, buf: &mut Vec<u8>) {
+		buf.clear();
+		match *self {
+			Value::Raw(slice) => buf.extend_from_slice(slice),
+			Value::Owned(ref vec) => buf.extend_from_slice(vec),
+			Value::Record(ref record) => {
+				buf.resize(record.value_len(), 0);
+				record.read_value(buf);
+			},
+		}
+	}
+
 	/// Returns value if it is a continuous slice of memory, otherwise returns None.
 	pub fn as_slice(&self) -> Option<&[u8]> {
 		match *self {
 			Value::Raw(ref slice) => Some(slice),
+			Value::Owned(ref vec) => Some(vec),
 			Value::Record(ref record) => record.value_raw_slice(),
 		}
 	}
@@ -59,6 +101,7 @@ impl<'a, T: AsRef<[u8]>> PartialEq<T> for Value<'a> {
 	fn eq(&self, other: &T) -> bool {
 		match *self {
 			Value::Raw(slice) => slice == other.as_ref(),
+			Value::Owned(ref vec) => vec.as_slice() == other.as_ref(),
 			Value::Record(ref record) => record.value_is_equal(other.as_ref()),
 		}
 	}
@@ -73,7 +116,166 @@ impl<'a> From<Record<'a>> for Value<'a> {
 	}
 }
 
+/// Outcome of `Database::shutdown`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct ShutdownReport {
+	/// Number of journal eras flushed during the shutdown.
+	pub flushed_eras: usize,
+	/// Number of journal eras left in the journal. They are replayed when the database is opened.
+	pub pending_eras: usize,
+	/// True if the deadline passed before all excessive eras were flushed.
+	pub timed_out: bool,
+}
+
+/// Stage of opening the database.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum OpenPhase {
+	/// Reading and verifying journal eras.
+	Journal,
+	/// Finishing a journal flush interrupted by a crash.
+	Flush,
+	/// Building indices of collision files.
+	Collisions,
+}
+
+/// Progress of `Database::open_with_progress`.
+#[derive(Debug, PartialEq, Clone)]
+pub struct OpenProgress {
+	/// Current stage.
+	pub phase: OpenPhase,
+	/// Number of files of the stage processed so far.
+	pub files_done: usize,
+	/// Number of files processed in the stage.
+	pub files_total: usize,
+	/// Number of bytes of journal eras or collision files read in the stage so far.
+	pub bytes: u64,
+}
+
+/// Decision of a compaction filter about a single record.
+#[derive(Debug, PartialEq, Clone)]
+pub enum FilterDecision {
+	/// Leave the record unchanged.
+	Keep,
+	/// Delete the record.
+	Remove,
+	/// Replace the value of the record.
+	Change(Vec<u8>),
+}
+
+/// Order in which `Database::iter_ordered` yields key-value pairs.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum IterationOrder {
+	/// Ordered by key, the data file, collision files and journal are merged like in `iter`.
+	Lexicographic,
+	/// Records of the data file first, by index prefix, then the entries of each collision file
+	/// and finally the keys which are only journaled. Keys are not compared, which makes it the
+	/// faster order for bulk exports.
+	Physical,
+}
+
+/// State of the database read from the disk on open.
+struct DiskState {
+	journal: Journal,
+	metadata: Metadata,
+	metadata_mmap: Mmap,
+	mmap: Mmap,
+	collisions: BTreeMap<u32, Collision>,
+}
+
+impl DiskState {
+	/// Reads the database files, finishing an interrupted flush if there is one.
+	///
+	/// With `read_only` nothing is written: the data and metadata files are mapped copy-on-write,
+	/// so an interrupted flush is only finished in memory, and stale collision files are kept.
+	fn recover(path: &Path, options: &InternalOptions, read_only: bool, progress: &mut FnMut(&OpenProgress)) -> Result<Self> {
+		let mut journal = Journal::open_with_progress(path, |files_done, files_total, bytes| progress(&OpenProgress {
+			phase: OpenPhase::Journal,
+			files_done,
+			files_total,
+			bytes,
+		}))?;
+		if options.external.archive_journal && !read_only {
+			journal.set_archive(path.join(Database::ARCHIVE_DIR))?;
+		}
+
+		let protection = if read_only { Protection::ReadCopy } else { Protection::ReadWrite };
+		let db_file_path = path.join(Database::DB_FILE);
+		let mut mmap = Mmap::open_path(db_file_path, protection)?;
+
+		let meta_file_path = path.join(Database::META_FILE);
+		let mut metadata_mmap = Mmap::open_path(meta_file_path, protection)?;
+
+		let mut metadata = metadata::bytes::read(unsafe { metadata_mmap.as_slice() }, options.external.key_index_bits)?;
+
+		if let Some(flush) = Flush::open(path, options.external.key_index_bits)? {
+			progress(&OpenProgress { phase: OpenPhase::Flush, files_done: 0, files_total: 1, bytes: 0 });
+			flush.flush(unsafe { mmap.as_mut_slice() }, unsafe { metadata_mmap.as_mut_slice() }, &mut metadata);
+			if !read_only {
+				flush_blocks(&mut mmap, &flush, options.external.block_size)?;
+				metadata_mmap.flush()?;
+				flush.delete()?;
+			}
+			progress(&OpenProgress { phase: OpenPhase::Flush, files_done: 1, files_total: 1, bytes: 0 });
+		}
+
+		let mut collisions = BTreeMap::new();
+
+		let max_resident = options.external.max_resident_collisions;
+		let collisions_total = metadata.collided_prefixes.prefixes_iter().count();
+		let mut collision_bytes = 0;
+		for prefix in metadata.collided_prefixes.prefixes_iter() {
+			let collision_file = if read_only {
+				Collision::open_read_only(path, prefix, &options.external.value_len)?
+			} else {
+				Collision::open(path, prefix, &options.external.value_len)?
+			};
+			let mut collision_file = collision_file.expect(
+				"prefix is declared as collided in metadata; \
+				 collision file should exist; qed");
+
+			if max_resident != 0 && collisions.len() >= max_resident {
+				collision_file.evict();
+			}
+
+			collision_bytes += collision_file.log_bytes();
+			collisions.insert(prefix, collision_file);
+			progress(&OpenProgress {
+				phase: OpenPhase::Collisions,
+				files_done: collisions.len(),
+				files_total: collisions_total,
+				bytes: collision_bytes,
+			});
+		}
+
+		// collision files of prefixes which are not collided are left by a compaction or a merge
+		// of small collision files interrupted by a crash, they would stop the prefix from colliding again
+		if !read_only {
+			for entry in fs::read_dir(path)? {
+				let entry = entry?;
+				let prefix = entry.file_name().to_str().and_then(Collision::file_name_prefix);
+				if let Some(prefix) = prefix {
+					if !collisions.contains_key(&prefix) {
+						fs::remove_file(entry.path())?;
+					}
+				}
+			}
+		}
+
+		Ok(DiskState {
+			journal,
+			metadata,
+			metadata_mmap,
+			mmap,
+			collisions,
+		})
+	}
+}
+
 /// A top-level database API.
+///
+/// The database is `Send` and `Sync`. Reads take `&self`, so many threads may read at once,
+/// e.g. from an `Arc<Database>` or through the read guards of an `RwLock`, while commits and
+/// other writes take `&mut self` and stay serialized.
 #[derive(Debug)]
 pub struct Database {
 	path: PathBuf,
@@ -83,13 +285,47 @@ pub struct Database {
 	metadata_mmap: Mmap,
 	collisions: BTreeMap<u32, Collision>,
 	mmap: Mmap,
-	lock_file: File,
+	latencies: Latencies,
+	events: Events,
+	audit: Audit,
+	triggers: Triggers,
+	transforms: ValueTransforms,
+	spawner: Spawner,
+	repair: Repair,
+	stats: Option<StatsHistory>,
+	key_stats: KeyStatistics,
+	counters: Counters,
+	errors: ErrorLog,
+	snapshots: SnapshotPins,
+	checkpoints: CheckpointPins,
+	last_wal_sync: Instant,
+	sealed: Vec<Range<u32>>,
+	/// Compression of the columns by key prefix, see `set_column_compression`.
+	columns: Vec<(Vec<u8>, Compression)>,
+	/// True if stored values have the compressor in their envelope, `None` until it's recorded.
+	envelope: Option<bool>,
+	/// Idempotency keys of the most recent commits.
+	idempotency: Idempotency,
+	cache: Mutex<ValueCache>,
+	/// `None` if the database is opened read-only.
+	lock_file: Option<File>,
 }
 
 impl Database {
-	const DB_FILE: &'static str = "data.db";
+	pub(crate) const DB_FILE: &'static str = "data.db";
 	const META_FILE: &'static str = "meta.db";
 	const LOCK_FILE: &'static str = "LOCK";
+	const ARCHIVE_DIR: &'static str = "archive";
+	/// Size of the transactions committing decisions of `compact_with_filter`.
+	const FILTER_BATCH_BYTES: usize = 4 << 20;
+	/// Options which may be changed with `set_option` while the database is open.
+	pub const TUNABLE_OPTIONS: &'static [&'static str] = &[
+		"cache_size",
+		"journal_eras",
+		"extend_threshold_percent",
+		"max_prefix_collisions",
+		"fsync",
+	];
 
 	fn acquire_lock_file<P: AsRef<Path>>(path: P) -> Result<File> {
 		let lock_file_path = path.as_ref().join(Self::LOCK_FILE);
@@ -109,6 +345,14 @@ impl Database {
 		// Create directories if necessary.
 		fs::create_dir_all(&path)?;
 
+		let granularity = fs2::allocation_granularity(&path)? as usize;
+		if options.external.block_size % granularity != 0 {
+			bail!(ErrorKind::InvalidOptions(
+				"block_size",
+				format!("{} is not a multiple of the filesystem block size {}", options.external.block_size, granularity)
+			));
+		}
+
 		// Create/Acquire Lock file.
 		let lock_file = Self::acquire_lock_file(&path)?;
 
@@ -134,506 +378,4846 @@ impl Database {
 			file.flush()?;
 		}
 
-		Self::open_internal(path, lock_file, options.external)
+		Self::open_internal(path, Some(lock_file), options.external, &mut |_: &OpenProgress| {})
+	}
+
+	/// Returns version of the on-disk format written by this build.
+	///
+	/// Databases are portable between architectures. A database can be opened
+	/// by any build which returns the same format version.
+	pub fn format_version() -> u16 {
+		Metadata::DB_VERSION
 	}
 
 	/// Opens an existing DB at given location.
 	pub fn open<P: AsRef<Path>>(path: P, options: Options) -> Result<Self> {
-		let lock_file = Self::acquire_lock_file(&path)?;
-		Self::open_internal(path, lock_file, options)
+		Self::open_with_progress(path, options, |_| {})
 	}
 
-	fn open_internal<P: AsRef<Path>>(path: P, lock_file: File, options: Options) -> Result<Self> {
-		let options = InternalOptions::from_external(options)?;
-		let journal = Journal::open(&path)?;
+	/// Opens an existing DB at given location, reporting progress of reading its files to `progress`.
+	///
+	/// `progress` is called after every processed journal era and collision file and before and
+	/// after finishing an interrupted flush, so applications can show the progress of a long open.
+	pub fn open_with_progress<P, F>(path: P, options: Options, mut progress: F) -> Result<Self> where
+		P: AsRef<Path>,
+		F: FnMut(&OpenProgress),
+	{
+		let lock_file = Self::acquire_lock_file(&path)?;
+		Self::open_internal(path, Some(lock_file), options, &mut progress)
+	}
 
-		let db_file_path = path.as_ref().join(Self::DB_FILE);
-		let mut mmap = Mmap::open_path(db_file_path, Protection::ReadWrite)?;
+	/// Opens an existing DB without writing to its directory, e.g. a snapshot mounted from
+	/// a read-only filesystem.
+	///
+	/// No lock file is created, journal eras are replayed into memory instead of being flushed and
+	/// an interrupted flush is only finished in memory. The files must not be changed while the
+	/// database is open. Reads work as usual, everything writing to the disk fails with
+	/// `ErrorKind::ReadOnly` and the statistics history is not kept.
+	pub fn open_read_only<P: AsRef<Path>>(path: P, options: Options) -> Result<Self> {
+		Self::open_internal(path, None, options, &mut |_: &OpenProgress| {})
+	}
 
-		let meta_file_path = path.as_ref().join(Self::META_FILE);
-		let mut metadata_mmap = Mmap::open_path(meta_file_path, Protection::ReadWrite)?;
+	fn open_internal<P: AsRef<Path>>(path: P, lock_file: Option<File>, options: Options, progress: &mut FnMut(&OpenProgress)) -> Result<Self> {
+		let options = InternalOptions::from_external(options)?;
+		let latencies = Latencies::new(options.external.track_latencies);
+		let read_only = lock_file.is_none();
+		let state = DiskState::recover(path.as_ref(), &options, read_only, progress)?;
+
+		let stats = match options.external.stats_retention {
+			0 => None,
+			_ if read_only => None,
+			retention => Some(StatsHistory::open(&path, retention)?),
+		};
+		let sealed = seal::read_sealed(&path)?;
+		let cache = Mutex::new(ValueCache::new(options.external.cache_size));
+		let (envelope, columns) = compression::read_columns(&path)?;
+		if envelope == Some(false) && options.external.compression != Compression::None {
+			bail!(ErrorKind::InvalidOptions(
+				"compression",
+				"values of the database are stored without compression, export them into a compressed database instead".into()
+			));
+		}
+		let compressor = compression::has_compressor(envelope, options.external.compression, &columns);
+		let transforms = Compressor::transforms(options.external.compression, &columns, compressor, Vec::new())?;
+		let idempotency = Idempotency::open(&path, options.external.idempotency_window, &state.journal, read_only)?;
 
-		let mut metadata = metadata::bytes::read(unsafe { metadata_mmap.as_slice() }, options.external.key_index_bits);
+		let mut db = Database {
+			path: path.as_ref().to_owned(),
+			options,
+			journal: state.journal,
+			metadata: state.metadata,
+			metadata_mmap: state.metadata_mmap,
+			mmap: state.mmap,
+			collisions: state.collisions,
+			latencies,
+			events: Events::default(),
+			audit: Audit::default(),
+			triggers: Triggers::default(),
+			transforms,
+			spawner: Spawner::default(),
+			repair: Repair::default(),
+			stats,
+			key_stats: KeyStatistics::default(),
+			counters: Counters::default(),
+			errors: ErrorLog::default(),
+			snapshots: SnapshotPins::new(Mutex::new(BTreeMap::new())),
+			checkpoints: CheckpointPins::default(),
+			last_wal_sync: Instant::now(),
+			sealed,
+			columns,
+			envelope,
+			idempotency,
+			cache,
+			lock_file,
+		};
 
-		if let Some(flush) = Flush::open(path.as_ref(), options.external.key_index_bits)? {
-			flush.flush(unsafe { mmap.as_mut_slice() }, unsafe { metadata_mmap.as_mut_slice() }, &mut metadata);
-			mmap.flush()?;
-			metadata_mmap.flush()?;
-			flush.delete()?;
+		db.load_key_stats()?;
+		if envelope.is_none() && !read_only {
+			// databases with values but without the file were created before it was recorded
+			if compressor || db.iter()?.next().is_some() {
+				db.record_envelope(compressor)?;
+			}
 		}
+		Ok(db)
+	}
 
-		let mut collisions = BTreeMap::new();
+	/// Records whether values have the compressor in their envelope, see `compression::has_compressor`.
+	fn record_envelope(&mut self, compressor: bool) -> Result<()> {
+		compression::write_columns(&self.path, compressor, &self.columns)?;
+		self.envelope = Some(compressor);
+		Ok(())
+	}
 
-		for prefix in metadata.collided_prefixes.prefixes_iter() {
-			let collision_file = Collision::open(&path, prefix)?.expect(
-				"prefix is declared as collided in metadata; \
-				 collision file should exist; qed");
+	/// Returns true if the database was opened with `open_read_only`.
+	pub fn is_read_only(&self) -> bool {
+		self.lock_file.is_none()
+	}
 
-			collisions.insert(prefix, collision_file);
+	fn check_writable(&self) -> Result<()> {
+		if self.is_read_only() {
+			bail!(ErrorKind::ReadOnly);
 		}
 
-		Ok(Database {
-			path: path.as_ref().to_owned(),
-			options,
-			journal,
-			metadata,
-			metadata_mmap,
-			mmap,
-			collisions,
-			lock_file,
-		})
+		Ok(())
 	}
 
-	/// Create a new transaction.
-	pub fn create_transaction(&self) -> Transaction {
-		Transaction::new(self.options.external.key_len)
+	fn check_not_checkpointing(&self) -> Result<()> {
+		if checkpoint::in_progress(&self.checkpoints) {
+			bail!(ErrorKind::CheckpointInProgress);
+		}
+
+		Ok(())
 	}
 
-	/// Commits changes in the transaction.
-	pub fn commit(&mut self, tx: &Transaction) -> Result<()> {
-		self.journal.push(tx)?;
+	/// Re-runs the recovery performed on open without releasing the database lock.
+	///
+	/// The journal, metadata, unfinished flush and collision files are read from the disk again,
+	/// discarding the in-memory state. Use it after a failed flush or compaction marked the
+	/// database as degraded. Options, listeners and transforms are kept.
+	pub fn try_recover(&mut self) -> Result<()> {
+		let state = DiskState::recover(&self.path, &self.options, self.is_read_only(), &mut |_: &OpenProgress| {})?;
+
+		self.journal = state.journal;
+		self.metadata = state.metadata;
+		self.metadata_mmap = state.metadata_mmap;
+		self.mmap = state.mmap;
+		self.collisions = state.collisions;
+		self.cache.lock().clear();
+		self.refresh_pins()?;
+		self.load_key_stats()?;
+		self.errors.degraded = false;
+
 		Ok(())
 	}
 
-	/// Flushes up to `max` excessive journal eras to the disk.
-	pub fn flush_journal<T: Into<Option<usize>>>(&mut self, max: T) -> Result<()> {
-		let len = self.journal.len();
-		let max = max.into().unwrap_or(len);
+	/// Reads the saved key statistics, or counts the stored keys if the saved ones are stale.
+	fn load_key_stats(&mut self) -> Result<()> {
+		let era = self.first_journaled_era();
+		if let Some(key_stats) = KeyStatistics::read(&self.path, era)? {
+			self.key_stats = key_stats;
+			return Ok(());
+		}
 
-		if len < self.options.external.journal_eras {
-			return Ok(())
+		let mut key_stats = KeyStatistics::default();
+		for item in self.record_collisions_iter()? {
+			let (_, value) = item?;
+			key_stats.insert(value_len(&value));
 		}
 
-		let to_flush = cmp::min(len - self.options.external.journal_eras, max);
+		if !self.is_read_only() {
+			key_stats.write(&self.path, era)?;
+		}
+		self.key_stats = key_stats;
+		Ok(())
+	}
 
-		let prefix_bits = self.options.external.key_index_bits;
-		let collisions = &mut self.collisions;
+	/// Returns index of the oldest era in the journal, or of the next one if the journal is empty.
+	fn first_journaled_era(&self) -> u64 {
+		self.journal.next_era_index() - self.journal.len() as u64
+	}
 
-		for era in self.journal.drain_front(to_flush) {
-			let flush = {
-				let collided_prefixes = &self.metadata.collided_prefixes;
+	/// Returns the key statistics after flushing each of the oldest `eras` journaled eras.
+	fn key_stats_after(&self, eras: usize) -> Result<Vec<KeyStatistics>> {
+		let mut key_stats = self.key_stats.clone();
+		let mut result = Vec::with_capacity(eras);
+		for position in 0..eras {
+			let era = self.journal.era(position).expect("position is lower than the journal length; qed");
+			for op in era.iter() {
+				// the stored value as left by the flushes of the previous eras
+				if let Some(value) = self.lookup_raw_in(op.key(), &mut ReadStats::default(), position)? {
+					key_stats.remove(value_len(&value));
+				}
 
-				// partition operations by whether they affect collided prefixes
-				let (collided_operations, operations): (Vec<_>, Vec<_>) =
-					era.iter().partition(|op| {
-						let key = Key::new(op.key(), prefix_bits);
-						collided_prefixes.has(key.prefix).unwrap_or(false)
-					});
+				if let Operation::Insert(_, value) = op {
+					key_stats.insert(value.len());
+				}
+			}
 
-				// flush operations for collided prefixes to their own collision file
-				for op in collided_operations {
-					let key = Key::new(op.key(), prefix_bits);
-					let collision = collisions.get_mut(&key.prefix).expect(
-						"prefix is declared as collided; \
-						 collision file should exist in collisions index; qed");
+			result.push(key_stats.clone());
+		}
 
-					collision.apply(op)?;
-				}
+		Ok(result)
+	}
 
-				// create flush to data file for everything else
-				Flush::new(
-					&self.path,
-					&self.options,
-					unsafe { self.mmap.as_slice() },
-					&self.metadata,
-					operations.into_iter(),
-				)?
+	/// Registers a listener notified about database events.
+	pub fn set_event_listener<F>(&mut self, listener: F) where F: Fn(&Event) + Send + Sync + 'static {
+		self.events.set_listener(Box::new(listener));
+	}
+
+	/// Registers a source of values of keys whose entries in collision files are corrupted,
+	/// e.g. a replica serving reads.
+	///
+	/// The source returns the value as it is stored, i.e. encoded by value transforms, or `None`
+	/// if it doesn't have it. A read which finds a corrupted entry returns the value of the
+	/// source instead of failing, and the entry is rewritten by the next `apply_repairs`.
+	pub fn set_repair_source<F>(&mut self, source: F) where F: Fn(&[u8]) -> Option<Vec<u8>> + Send + Sync + 'static {
+		self.repair.set_source(Box::new(source));
+	}
+
+	/// Rewrites corrupted entries of collision files found by reads since the last call with the
+	/// values fetched from the repair source. Called by every `flush_journal`.
+	///
+	/// Emits `Event::Repaired` for every rewritten entry and returns their number.
+	pub fn apply_repairs(&mut self) -> Result<usize> {
+		self.check_writable()?;
+		self.check_not_checkpointing()?;
+
+		let mut repaired = 0;
+		for repair in self.repair.take_pending() {
+			// the prefix could have been moved back to the data file since the read
+			let collision = match self.collisions.get_mut(&repair.prefix) {
+				Some(collision) => collision,
+				None => continue,
 			};
 
-			era.delete()?;
+			if collision.repair(repair.offset, &repair.key, &repair.value)? {
+				self.events.emit(Event::Repaired {
+					path: collision.path().to_owned(),
+					offset: repair.offset,
+					key: repair.key,
+				});
+				repaired += 1;
+			}
+		}
 
-			// TODO: metadata should be a single structure
-			// updating self.metadata should happen after all calls
-			// which may fail ("?")
-			flush.flush(unsafe { self.mmap.as_mut_slice() }, unsafe { self.metadata_mmap.as_mut_slice() }, &mut self.metadata);
-			self.mmap.flush()?;
-			self.metadata_mmap.flush()?;
-			flush.delete()?;
+		Ok(repaired)
+	}
+
+	/// Registers a hook called with every commit before it is written to the journal.
+	///
+	/// If the hook returns an error, the commit is aborted with that error.
+	pub fn set_audit_hook<F>(&mut self, hook: F) where F: Fn(&AuditRecord) -> Result<()> + Send + Sync + 'static {
+		self.audit.set_hook(Box::new(hook));
+	}
+
+	/// Adds a trigger called on commit for every operation on a key starting with `prefix`,
+	/// e.g. the prefix of a `NamespacedDatabase`.
+	///
+	/// The trigger receives the operation and the value the key had before the commit. If it
+	/// returns an error, the commit is aborted with `ErrorKind::CommitRejected`.
+	pub fn add_trigger<P, F>(&mut self, prefix: P, trigger: F) where
+		P: AsRef<[u8]>,
+		F: Fn(&Operation, Option<&[u8]>) -> ::std::result::Result<(), String> + Send + Sync + 'static,
+	{
+		self.triggers.add(prefix.as_ref().to_vec(), Box::new(trigger));
+	}
+
+	/// Removes all triggers added with `add_trigger`.
+	pub fn clear_triggers(&mut self) {
+		self.triggers.clear();
+	}
+
+	/// Changes one of `TUNABLE_OPTIONS` without reopening the database.
+	///
+	/// `value` uses the format of `Options::set`. The change applies to all subsequent
+	/// operations and is reported with `Event::OptionChanged`.
+	pub fn set_option(&mut self, name: &str, value: &str) -> Result<()> {
+		let name = match Self::TUNABLE_OPTIONS.iter().find(|tunable| **tunable == name) {
+			Some(name) => *name,
+			None => bail!(ErrorKind::OptionNotTunable(name.into())),
+		};
+
+		let mut options = self.options.external.clone();
+		let old = options.get(name)?;
+		options.set(name, value)?;
+		let new = options.get(name)?;
+		self.options = InternalOptions::from_external(options)?;
+		if name == "cache_size" {
+			self.cache.lock().set_capacity(self.options.external.cache_size);
 		}
 
+		self.events.emit(Event::OptionChanged { name, old, new });
 		Ok(())
 	}
 
-	/// Lookup a value associated with given `key`.
-	pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Value>> {
-		let key = key.as_ref();
-		if key.len() != self.options.external.key_len {
-			return Err(ErrorKind::InvalidKeyLen(self.options.external.key_len, key.len()).into());
-		}
+	/// Create a new transaction.
+	pub fn create_transaction(&self) -> Transaction {
+		Transaction::new(self.options.external.key_len)
+	}
 
-		// check if the key-value pair is currently journaled
-		if let Some(res) = self.journal.get(key) {
-			return Ok(Some(Value::Raw(res)));
+	/// Sets transforms applied to values on commit and reversed on read.
+	///
+	/// Transforms are not persisted. The same transforms have to be set every time the database
+	/// is opened, before any values are read or committed. Only variable length values can be
+	/// transformed. Values are compressed before they are transformed, see `Options::compression`.
+	pub fn set_value_transforms(&mut self, transforms: Vec<Box<ValueTransform>>) -> Result<()> {
+		if self.options.external.value_len.is_const() {
+			bail!(ErrorKind::InvalidOptions(
+				"value_transforms",
+				"values of constant length cannot be transformed".into()
+			));
 		}
 
-		let field_body_size = self.options.field_body_size;
-		let value_size = self.options.value_size;
+		let compressor = compression::has_compressor(self.envelope, self.options.external.compression, &self.columns);
+		self.transforms = Compressor::transforms(self.options.external.compression, &self.columns, compressor, transforms)?;
+		self.cache.lock().clear();
+		self.refresh_pins()
+	}
 
-		let key = Key::new(key, self.options.external.key_index_bits);
+	/// Compresses values of the column with keys starting with `prefix` with `compression`
+	/// instead of `Options::compression`, e.g. with a slower but denser algorithm for cold data.
+	///
+	/// Keys of several columns are compressed like the column with the longest prefix, which
+	/// can be at most as long as the keys and 255 bytes long.
+	/// Compression of the columns is persisted in the database directory. Values written
+	/// before are rewritten with the compression of their column when compaction moves them
+	/// between the data file and collision files, or when they are changed by
+	/// `compact_with_filter`. Unless `Options::compression` is set, values are stored without
+	/// the envelope of the compressor, so without it the first column has to be set before
+	/// any values are committed and before value transforms.
+	pub fn set_column_compression(&mut self, prefix: &[u8], compression: Compression) -> Result<()> {
+		self.check_writable()?;
+
+		let key_len = self.options.external.key_len;
+		if prefix.len() > key_len {
+			bail!(ErrorKind::InvalidOptions(
+				"compression",
+				format!("column prefix of {} bytes is longer than keys of {} bytes", prefix.len(), key_len)
+			));
+		}
 
-		// fetch from the collision file if this is a collided prefix
-		if self.metadata.collided_prefixes.has(key.prefix).unwrap_or(false) {
-			let collision = self.collisions.get(&key.prefix).expect(
-				"prefix is declared as collided; \
-				 collision file should exist in collisions index; qed");
+		// the COMPRESSION file stores the length of a prefix in a single byte
+		if prefix.len() > ::std::u8::MAX as usize {
+			bail!(ErrorKind::InvalidOptions(
+				"compression",
+				format!("column prefix of {} bytes is longer than {} bytes", prefix.len(), ::std::u8::MAX)
+			));
+		}
 
-			return Ok(collision.get(key.key)?.map(Value::Raw))
+		if compression != Compression::None && self.options.external.value_len.is_const() {
+			bail!(ErrorKind::InvalidOptions(
+				"compression",
+				"values of constant length cannot be compressed".into()
+			));
 		}
 
-		// check if there's any data stored on the data file for the given prefix
-		if !self.metadata.prefixes.has(key.prefix).unwrap_or(false) {
-			return Ok(None);
+		let has_compressor = self.envelope == Some(true);
+		if !has_compressor && (self.envelope.is_some() || !self.transforms.is_empty()) {
+			bail!(ErrorKind::InvalidOptions(
+				"compression",
+				"without `compression` the first column can only be set in an empty database without value transforms".into()
+			));
 		}
 
-		let offset = key.prefix as usize * self.options.record_offset;
-		let data = unsafe { &self.mmap.as_slice()[offset..] };
+		let mut columns = self.columns.clone();
+		compression::set_column(&mut columns, prefix, compression);
+		compression::write_columns(&self.path, true, &columns)?;
 
-		match find::find_record(data, field_body_size, value_size, key.key)? {
-			find::RecordResult::Found(record) => Ok(Some(Value::from(record))),
-			find::RecordResult::NotFound => Ok(None),
-			find::RecordResult::OutOfRange => unimplemented!(),
+		if has_compressor {
+			self.transforms.set_overrides(Compressor::overrides(&columns))?;
+		} else {
+			self.transforms = Compressor::transforms(self.options.external.compression, &columns, true, Vec::new())?;
 		}
+		self.columns = columns;
+		self.envelope = Some(true);
+		Ok(())
 	}
 
-	/// Returns an iterator over all the database key-value pairs ordered by key.
-	pub fn iter(&self) -> Result<DatabaseIterator> {
-		let record_collisions_iter = self.record_collisions_iter()?;
-		let journal_iter = self.journal.iter();
-		let pending = IteratorValue::None;
+	/// Returns compression of the columns set with `set_column_compression`.
+	pub fn column_compression(&self) -> &[(Vec<u8>, Compression)] {
+		&self.columns
+	}
 
-		Ok(DatabaseIterator { record_collisions_iter, journal_iter, pending })
+	/// Sets the spawner of the threads the database uses besides the calling thread, e.g. to
+	/// pin them to cores. Threads are spawned with `std::thread` by default.
+	///
+	/// The only such threads are the `encode_threads` workers of large commits, named
+	/// `paritydb-encode-<index>`. Flushes and compactions run on the calling thread, see
+	/// `flush_journal`.
+	pub fn set_thread_spawner(&mut self, spawner: Box<ThreadSpawner>) {
+		self.spawner = Spawner::new(spawner);
 	}
 
-	/// Returns an iterator over only the database key-value pairs stored in the data file ordered
-	/// by key (i.e. it doesn't include data from the journal or collision files).
-	fn record_iter(&self) -> Result<RecordIterator> {
-		let data = unsafe { &self.mmap.as_slice() };
-		let occupied_prefixes_iter = self.metadata.prefixes.prefixes_iter();
-		let field_body_size = self.options.field_body_size;
-		let key_size = self.options.external.key_len;
-		let value_size = self.options.value_size;
+	/// Create a new transaction with space reserved for `operations` inserts.
+	pub fn create_transaction_with_capacity(&self, operations: usize) -> Transaction {
+		let mut tx = self.create_transaction();
+		let value_len = match self.options.external.value_len {
+			ValuesLen::Constant(len) => len,
+			ValuesLen::Variable { expected } => expected,
+		};
+		tx.reserve(operations, value_len);
+		tx
+	}
 
-		let record_iter = find::iter(
-			data,
-			occupied_prefixes_iter,
-			field_body_size,
-			key_size,
-			value_size,
-		)?;
+	/// Commits changes in the transaction.
+	pub fn commit(&mut self, tx: &Transaction) -> Result<()> {
+		self.check_writable()?;
+
+		let result = if self.latencies.enabled() {
+			let start = Instant::now();
+			let result = self.commit_internal(tx);
+			self.latencies.record_commit(start.elapsed());
+			result
+		} else {
+			self.commit_internal(tx)
+		};
 
-		Ok(record_iter)
+		if let Err(ref err) = result {
+			self.errors.record(err);
+		}
+		result
 	}
 
-	// TODO: refactor to avoid boxed iterator
-	/// Returns an iterator over the database key-value pairs stored in the data file and collision
-	/// files.
-	fn record_collisions_iter<'a>(&'a self) -> Result<Box<Iterator<Item=Result<(&'a [u8], Value<'a>)>> + 'a>> {
-		let collided_records = self.collisions.values()
-			.flat_map(|it| it.iter().ok()) // FIXME: swallowing errors here
-			.flat_map(|it| it);
+	/// Returns true if a transaction with idempotency `key` was committed by one of the last
+	/// `Options::idempotency_window` commits with keys, so committing it again does nothing.
+	pub fn contains_idempotency_key<K: AsRef<[u8]>>(&self, key: K) -> bool {
+		self.idempotency.is_applied(key.as_ref())
+	}
 
-		let records = self.record_iter()?;
+	/// Commits changes in the transaction if `epoch` is not lower than the fencing epoch of the database.
+	///
+	/// The fencing epoch is read from the disk on every call, so once a new writer raised it
+	/// with `advance_fencing_epoch`, commits of the previous writer are rejected.
+	pub fn commit_fenced(&mut self, tx: &Transaction, epoch: u64) -> Result<()> {
+		let current = fence::read_epoch(&self.path)?;
+		if epoch < current {
+			bail!(ErrorKind::FencedOff(epoch, current));
+		}
 
-		Ok(Box::new(records.merge_join_by(collided_records, |r, c| {
-			match (r, c) {
-				(&Err(_), _) => Ordering::Less,
-				(_, &Err(_)) => Ordering::Greater,
-				(&Ok(ref r), &Ok(ref c)) => r.key().cmp(&c.0),
-			}
-		}).map(|either| {
-			match either {
-				EitherOrBoth::Left(Err(err)) => Err(err.into()),
-				EitherOrBoth::Right(Err(err)) => Err(err),
-				EitherOrBoth::Left(Ok(r)) => Ok((r.key(), Value::Record(r))),
-				EitherOrBoth::Right(Ok(c)) => Ok((c.0, Value::Raw(c.1))),
-				EitherOrBoth::Both(_, _) =>
-					unreachable!("value exists in collision file; \
-								  so cannot exist in data file; qed"),
-			}
-		})))
+		self.commit(tx)
 	}
 
-	fn collisions(&self) -> Result<BTreeMap<u32, Vec<&[u8]>>> {
-		let mut collisions: BTreeMap<u32, Vec<&[u8]>> = BTreeMap::new();
+	/// Returns the fencing epoch of the database, 0 if it was never advanced.
+	pub fn fencing_epoch(&self) -> Result<u64> {
+		fence::read_epoch(&self.path)
+	}
 
-		for record in self.record_iter()? {
-			let key = record?.key();
+	/// Raises the fencing epoch, e.g. when this process takes over as the primary writer.
+	///
+	/// Fails if `epoch` is not greater than the current epoch.
+	pub fn advance_fencing_epoch(&mut self, epoch: u64) -> Result<()> {
+		self.check_writable()?;
 
-			let prefix = Key::new(key, self.options.external.key_index_bits).prefix;
-			match collisions.entry(prefix) {
-				Entry::Vacant(entry) => {
-					entry.insert(vec![key]);
-				},
-				Entry::Occupied(mut entry) => {
-					entry.get_mut().push(key);
-				},
-			}
+		let current = fence::read_epoch(&self.path)?;
+		if epoch <= current {
+			bail!(ErrorKind::FencedOff(epoch, current));
 		}
 
-		// drop prefixes that have a number of collisions lower than the allowed threshold
-		let collisions: BTreeMap<u32, Vec<&[u8]>> =
-			collisions.into_iter().filter(|p| {
-				p.1.len() >= self.options.external.max_prefix_collisions
+		fence::write_epoch(&self.path, epoch)
+	}
+
+	fn commit_internal(&mut self, tx: &Transaction) -> Result<()> {
+		let idempotency_key = tx.idempotency_key();
+		if let Some(key) = idempotency_key {
+			if self.options.external.idempotency_window == 0 {
+				bail!(ErrorKind::InvalidOptions(
+					"idempotency_window",
+					"must be greater than 0 to commit transactions with idempotency keys".into()
+				));
+			}
+
+			if self.idempotency.is_applied(key) {
+				return Ok(());
+			}
+		}
+
+		self.validate(tx)?;
+
+		// the compressor is recorded when the database is opened or the first column is set
+		if self.envelope.is_none() {
+			self.record_envelope(false)?;
+		}
+
+		let sequence = self.journal.next_era_index();
+		self.audit.record(&AuditRecord {
+			sequence,
+			timestamp: Statistics::now(),
+			transaction: tx,
+		})?;
+
+		let encoded;
+		let journaled = if self.transforms.is_empty() {
+			tx
+		} else {
+			let values: Vec<_> = tx.operations().filter_map(|operation| match operation {
+				Operation::Insert(key, value) => Some((key, value)),
+				_ => None,
 			}).collect();
+			let mut values = self.transforms.encode_all(&values, self.options.external.encode_threads, &self.spawner)?.into_iter();
+
+			let mut transaction = self.create_transaction();
+			for operation in tx.operations() {
+				match operation {
+					Operation::Insert(key, _) => {
+						let value = values.next().expect("one encoded value for every insert; qed");
+						transaction.insert(key, value)?
+					},
+					Operation::Delete(key) => transaction.delete(key)?,
+				}
+			}
 
-		Ok(collisions)
+			encoded = transaction;
+			&encoded
+		};
+
+		// the key is logged first, so it's never missing for a journaled commit
+		if let Some(key) = idempotency_key {
+			self.idempotency.prepare(key, sequence)?;
+		}
+
+		if let Err(err) = self.journal.push(journaled) {
+			if idempotency_key.is_some() {
+				self.idempotency.abort()?;
+			}
+			return Err(err);
+		}
+
+		if let Some(key) = idempotency_key {
+			self.idempotency.commit(key, sequence);
+		}
+
+		for operation in tx.operations() {
+			match operation {
+				Operation::Delete(_) => self.counters.deletes += 1,
+				_ => self.counters.writes += 1,
+			}
+		}
+
+		let sync = match self.options.external.fsync {
+			FsyncPolicy::Never => false,
+			FsyncPolicy::EveryCommit => true,
+			FsyncPolicy::Interval(interval) => self.last_wal_sync.elapsed() >= interval,
+		};
+
+		if sync {
+			self.flush_wal()?;
+		}
+
+		Ok(())
 	}
 
-	/// Finds prefixes that have a number of collisions higher than the configured threshold and
-	/// moves all their data to a separate file (one file for each collided prefix). Returns a
-	/// vector of collided prefixes (empty if no collisions have been found).
-	pub fn compact(&mut self) -> Result<Vec<u32>> {
-		let mut collision_files = Vec::new();
-		let mut collided_prefixes = Vec::new();
+	/// Syncs journal eras committed since the last sync to the disk.
+	///
+	/// Once it returns, all commits survive a crash of the machine. See `FsyncPolicy`.
+	pub fn flush_wal(&mut self) -> Result<()> {
+		self.check_writable()?;
 
-		let (metadata, flush) = {
-			let collisions = self.collisions()?;
+		self.idempotency.sync()?;
+		self.journal.sync()?;
+		self.last_wal_sync = Instant::now();
+		Ok(())
+	}
 
-			// create collision files and insert data from collided prefixes
-			for (prefix, keys) in collisions.iter() {
-				let mut collision_file = Collision::create(&self.path, *prefix)?;
+	/// Deletes all keys greater or equal to `key` with a single commit.
+	///
+	/// Intended for rolling back the tip of the chain on reorganizations.
+	/// Returns number of deleted keys.
+	pub fn truncate_from<K: AsRef<[u8]>>(&mut self, key: K) -> Result<usize> {
+		let mut tx = self.create_transaction();
+		let mut deleted = 0;
+		// only the keys from `key` on are read
+		for item in self.iter_from(key)? {
+			let (k, _) = item?;
+			tx.delete(k)?;
+			deleted += 1;
+		}
 
-				for key in keys {
-					// FIXME: store a reference to the value in the return Map from collisions
-					let value = self.get(&key)?.expect("The key has been returned by the iterator; qed");
-					collision_file.insert(&key, value.as_slice().unwrap_or(&value.to_vec()))?;
+		if deleted > 0 {
+			self.commit(&tx)?;
+		}
+
+		Ok(deleted)
+	}
+
+	/// Checks that the transaction would be accepted by `commit` without committing it.
+	///
+	/// Keys and values have to be of the lengths the database was created with and must not
+	/// be in sealed prefixes. With `write_once` option existing keys must not be deleted or
+	/// overwritten.
+	/// Finally the operations have to be accepted by the triggers of their keys, see `add_trigger`.
+	///
+	/// Sealed prefixes are the read-only parts of the key space, see `seal_prefixes`. There are
+	/// no quotas, and no conflicts to check for either: commits are applied one at a time by the
+	/// only writer, so a transaction validated against the current state can only be rejected
+	/// by `commit` because of commits made after the validation.
+	pub fn validate(&self, tx: &Transaction) -> Result<()> {
+		let key_len = self.options.external.key_len;
+		for operation in tx.operations() {
+			if operation.key().len() != key_len {
+				bail!(ErrorKind::InvalidKeyLen(key_len, operation.key().len()));
+			}
+
+			if let (Operation::Insert(_, value), &ValuesLen::Constant(value_len)) = (operation, &self.options.external.value_len) {
+				if value.len() != value_len {
+					bail!(ErrorKind::InvalidValueLen(value_len, value.len()));
 				}
+			}
 
-				collision_files.push(collision_file);
-				collided_prefixes.push(*prefix);
+			if !self.sealed.is_empty() {
+				let prefix = Key::new(operation.key(), self.options.external.key_index_bits).prefix;
+				if seal::is_sealed(&self.sealed, prefix) {
+					bail!(ErrorKind::PrefixSealed(operation.key().to_vec(), prefix));
+				}
 			}
+		}
 
-			// clone metadata and update it with collided prefixes but don't persist it
-			let mut metadata = self.metadata.clone();
-			for prefix in collided_prefixes.iter() {
-				metadata.add_prefix_collision(*prefix);
+		if self.options.external.write_once {
+			self.check_write_once(tx)?;
+		}
+
+		if !self.triggers.is_empty() {
+			for operation in tx.operations() {
+				self.triggers.check(&operation, || Ok(self.get(operation.key())?.map(|value| value.to_vec())))?;
 			}
+		}
 
-			// prepare flush to delete colliding keys but don't apply it
-			let deletions = collisions.values().flat_map(|ks| ks).map(|k| Operation::Delete(k));
+		Ok(())
+	}
 
-			let flush = Flush::new(
-				&self.path,
-				&self.options,
-				unsafe { self.mmap.as_slice() },
-				&metadata,
-				deletions)?;
+	/// Fails if the transaction deletes a key or inserts a key which already exists.
+	fn check_write_once(&self, tx: &Transaction) -> Result<()> {
+		let mut inserted = HashSet::new();
+		for operation in tx.operations() {
+			match operation {
+				Operation::Delete(key) => bail!(ErrorKind::WriteOnceViolation(key.to_vec())),
+				Operation::Insert(key, _) => {
+					if !inserted.insert(key) || self.contains(key)? {
+						bail!(ErrorKind::WriteOnceViolation(key.to_vec()));
+					}
+				},
+			}
+		}
 
-			(metadata, flush)
+		Ok(())
+	}
+
+	/// Returns percentiles of `get`, `commit` and iteration latencies.
+	///
+	/// Latencies are only recorded if the database was opened with `track_latencies` option.
+	pub fn latency_report(&self) -> LatencyReport {
+		self.latencies.report()
+	}
+
+	/// Flushes up to `max` excessive journal eras to the disk.
+	///
+	/// Eras committed after the oldest live `Snapshot` was taken are not flushed, and nothing
+	/// is flushed while a `Checkpoint` is unfinished.
+	///
+	/// A commit touching both the data file and collision files stays atomic across crashes
+	/// during the flush. Its era is removed only after the changes of both are on the disk,
+	/// and until then recovery replays the whole era.
+	///
+	/// Files written by flushes depend only on the flushed commits and not on how many
+	/// of them are flushed at once, so replicas applying the same commits and calling
+	/// `compact` after the same commits have byte-identical files.
+	///
+	/// Collision logs which the flushed eras left mostly taken by overwritten and deleted
+	/// entries are compacted by the flush, on the calling thread. A compaction in the
+	/// background would make the files depend on when it finished, and the compacted log
+	/// has to replace the mapping and index which `get` reads without locking, which only
+	/// a call borrowing the database mutably can do.
+	pub fn flush_journal<T: Into<Option<usize>>>(&mut self, max: T) -> Result<()> {
+		self.check_writable()?;
+
+		let result = self.flush_journal_internal(max.into());
+		if let Err(ref err) = result {
+			self.errors.record(err);
+			self.errors.degraded = true;
+		}
+		result
+	}
+
+	fn flush_journal_internal(&mut self, max: Option<usize>) -> Result<()> {
+		if checkpoint::in_progress(&self.checkpoints) {
+			return Ok(());
+		}
+
+		// flushed operations read the current entries of collision files
+		self.apply_repairs()?;
+
+		let len = self.journal.len();
+		let max = max.unwrap_or(len);
+
+		if len < self.options.external.journal_eras {
+			return Ok(())
+		}
+
+		let mut to_flush = cmp::min(len - self.options.external.journal_eras, max);
+		if let Some(&sequence) = self.snapshots.lock().keys().next() {
+			to_flush = cmp::min(to_flush, self.journal.len_before(sequence));
+		}
+
+		let prefix_bits = self.options.external.key_index_bits;
+		let archive = self.journal.archive().map(Path::to_path_buf);
+
+		// keys of flushed eras are not checked against the journal anymore
+		if to_flush > 0 {
+			self.idempotency.confirm()?;
+		}
+
+		let key_stats = self.key_stats_after(to_flush)?;
+		let mut repin = Vec::new();
+		for (era, key_stats) in self.journal.drain_front(to_flush).zip(key_stats) {
+			{
+				let mut cache = self.cache.lock();
+				for op in era.iter() {
+					cache.remove(op.key());
+					if cache.is_pinned(op.key()) {
+						repin.push(op.key().to_vec());
+					}
+				}
+			}
+
+			let collisions = &mut self.collisions;
+			let flush = {
+				let collided_prefixes = &self.metadata.collided_prefixes;
+
+				// partition operations by whether they affect collided prefixes
+				let (collided_operations, operations): (Vec<_>, Vec<_>) =
+					era.iter().partition(|op| {
+						let key = Key::new(op.key(), prefix_bits);
+						collided_prefixes.has(key.prefix).unwrap_or(false)
+					});
+
+				// the flush of the data file is prepared first, so that an invalid era
+				// leaves the collision files untouched
+				let flush = Flush::new(
+					&self.path,
+					&self.options,
+					unsafe { self.mmap.as_slice() },
+					&self.metadata,
+					operations.into_iter(),
+				)?;
+
+				// flush operations for collided prefixes to their own collision file
+				let mut touched = BTreeSet::new();
+				for op in collided_operations {
+					let key = Key::new(op.key(), prefix_bits);
+					let collision = collisions.get_mut(&key.prefix).expect(
+						"prefix is declared as collided; \
+						 collision file should exist in collisions index; qed");
+
+					collision.apply(op)?;
+					touched.insert(key.prefix);
+				}
+
+				// logs are compacted after the era which made them garbage heavy, so the files
+				// don't depend on how many eras are flushed at once
+				for prefix in touched {
+					let collision = collisions.get_mut(&prefix).expect("prefix was taken from the collisions index; qed");
+					collision.flush()?;
+					if collision.needs_compaction() {
+						collision.compact()?;
+					}
+				}
+
+				flush
+			};
+
+			// the era is the only record of the commit until the new flush file and
+			// collision files are durable, replaying either of them is idempotent
+			fs::File::open(&self.path)?.sync_all()?;
+
+			match archive {
+				Some(ref archive) => era.archive(archive)?,
+				None => era.delete()?,
+			}
+
+			// TODO: metadata should be a single structure
+			// updating self.metadata should happen after all calls
+			// which may fail ("?")
+			flush.flush(unsafe { self.mmap.as_mut_slice() }, unsafe { self.metadata_mmap.as_mut_slice() }, &mut self.metadata);
+			flush_blocks(&mut self.mmap, &flush, self.options.external.block_size)?;
+			self.metadata_mmap.flush()?;
+			flush.delete()?;
+			self.key_stats = key_stats;
+		}
+
+		if to_flush > 0 {
+			let era = self.first_journaled_era();
+			self.key_stats.write(&self.path, era)?;
+		}
+
+		for key in repin {
+			self.pin_stored(&key)?;
+		}
+
+		self.evict_collision_indices();
+
+		let retention = self.options.external.archive_retention;
+		if to_flush > 0 && retention != 0 {
+			self.journal.prune_archive(self.journal.next_era_index().saturating_sub(retention))?;
+		}
+
+		if to_flush > 0 {
+			let statistics = self.statistics();
+			if let Some(ref mut stats) = self.stats {
+				stats.record(&statistics)?;
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Exports a consistent snapshot of the database into `dir`.
+	///
+	/// The snapshot contains copies of the data, metadata, collision and journal files sealed
+	/// by a manifest with their checksums. The directory can be opened as a regular database,
+	/// e.g. by another process serving read queries.
+	pub fn export_snapshot<P: AsRef<Path>>(&self, dir: P) -> Result<()> {
+		let mut snapshot = SnapshotWriter::create(dir)?;
+		self.write_snapshot(&mut snapshot)?;
+		snapshot.seal()
+	}
+
+	/// Writes the files of the database into a snapshot which is not sealed yet.
+	pub(crate) fn write_snapshot(&self, snapshot: &mut SnapshotWriter) -> Result<()> {
+		snapshot.write(Self::DB_FILE, unsafe { self.mmap.as_slice() })?;
+		snapshot.write(Self::META_FILE, unsafe { self.metadata_mmap.as_slice() })?;
+		for collision in self.collisions.values() {
+			snapshot.copy(collision.path())?;
+		}
+		for era in self.journal.era_paths() {
+			snapshot.copy(era)?;
+		}
+		for (name, data) in self.state_files()? {
+			snapshot.write(name, &data)?;
+		}
+
+		Ok(())
+	}
+
+	/// Returns names and contents of the files holding the state of the database next to the
+	/// data, metadata, collision and journal files. Snapshots and checkpoints write all of them.
+	fn state_files(&self) -> Result<Vec<(&'static str, Vec<u8>)>> {
+		let mut files = Vec::new();
+		let epoch = fence::read_epoch(&self.path)?;
+		if epoch != 0 {
+			files.push((fence::EPOCH_FILE, fence::encode(epoch)));
+		}
+		if !self.sealed.is_empty() {
+			files.push((seal::SEALED_FILE, seal::encode(&self.sealed)));
+		}
+		if let Some(compressor) = self.envelope {
+			files.push((compression::COMPRESSION_FILE, compression::encode(compressor, &self.columns)));
+		}
+		// the log is appended by commits, which can't run while the database is borrowed
+		if self.idempotency.path().exists() {
+			let mut data = Vec::new();
+			File::open(self.idempotency.path())?.read_to_end(&mut data)?;
+			files.push((idempotency::IDEMPOTENCY_FILE, data));
+		}
+
+		Ok(files)
+	}
+
+	/// Writes a consistent copy of the database into `path`, e.g. for a backup of a live node.
+	///
+	/// Equivalent to `begin_checkpoint` followed by `Checkpoint::finish`.
+	pub fn checkpoint<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+		self.begin_checkpoint(path)?.finish()
+	}
+
+	/// Starts a checkpoint of the database in `path`, which can be opened as a database once
+	/// the returned `Checkpoint` is finished.
+	///
+	/// Journal and archived era files are hard linked, or copied if they can't be linked.
+	/// The data, metadata and collision files are copied by `Checkpoint::finish`, which can
+	/// run on another thread while commits continue. The checkpoint holds the commits made
+	/// before this call.
+	pub fn begin_checkpoint<P: AsRef<Path>>(&self, path: P) -> Result<Checkpoint> {
+		let path = path.as_ref();
+		if path.join(Self::DB_FILE).exists() {
+			bail!(ErrorKind::CheckpointExists(path.into()));
+		}
+		fs::create_dir_all(path)?;
+
+		for era in self.journal.era_paths() {
+			let name = era.file_name().expect("era file path is created from a file name; qed");
+			link_or_copy(era, &path.join(name))?;
+		}
+
+		if let Some(archive) = self.journal.archive() {
+			let checkpoint_archive = path.join(Self::ARCHIVE_DIR);
+			fs::create_dir_all(&checkpoint_archive)?;
+			for entry in fs::read_dir(archive)? {
+				let era = entry?.path();
+				let name = era.file_name().expect("read_dir returns paths with file names; qed").to_owned();
+				link_or_copy(&era, &checkpoint_archive.join(name))?;
+			}
+		}
+
+		for (name, data) in self.state_files()? {
+			let mut file = File::create(path.join(name))?;
+			file.write_all(&data)?;
+			file.sync_all()?;
+		}
+
+		// the data file goes last, see `Checkpoint::finish`
+		let mut files: Vec<_> = self.collisions.values().map(|collision| collision.path().to_path_buf()).collect();
+		files.push(self.path.join(Self::META_FILE));
+		files.push(self.path.join(Self::DB_FILE));
+		Ok(Checkpoint::new(path, files, &self.checkpoints))
+	}
+
+	/// Clones the database into an empty directory at `path` and opens the clone, e.g. to fork
+	/// a fixture database for every test.
+	///
+	/// Journal era files are never modified, so they are hard linked and verified against their
+	/// checksums when the clone is opened. The data, metadata and collision files are modified
+	/// in place, so they are reflinked where the file system supports it and copied otherwise,
+	/// see `checkpoint`.
+	pub fn test_clone<P: AsRef<Path>>(&self, path: P) -> Result<Database> {
+		self.checkpoint(&path)?;
+		Database::open(path, self.options.external.clone())
+	}
+
+	/// Writes all key-value pairs into `writer` in a portable dump format, see `import`.
+	///
+	/// Values are dumped without value transforms and compression, so the dump can be imported
+	/// into a database with other options or on-disk format version, e.g. on another machine.
+	/// Returns the number of dumped records.
+	pub fn export<W: Write>(&self, writer: W) -> Result<u64> {
+		let mut dump = DumpWriter::new(writer, self.options.external.key_len)?;
+		for item in self.iter()? {
+			let (key, value) = item?;
+			dump.write(key, value.as_slice().unwrap_or(&value.to_vec()))?;
+		}
+
+		dump.finish()
+	}
+
+	/// Commits the key-value pairs of a dump written by `export`. Returns the number of records.
+	///
+	/// Every chunk of the dump is verified against its checksum and committed as a single
+	/// transaction, so a damaged or truncated dump fails after the chunks before the damage
+	/// were committed. Import into an empty database to be able to discard it on failure.
+	pub fn import<R: Read>(&mut self, reader: R) -> Result<u64> {
+		let mut dump = DumpReader::new(reader, self.options.external.key_len)?;
+		while let Some(records) = dump.next_chunk()? {
+			let mut tx = self.create_transaction();
+			for (key, value) in records {
+				tx.insert(key, value)?;
+			}
+			self.commit(&tx)?;
+			// every chunk is an era, so a large dump would otherwise pile up in the journal
+			self.flush_journal(None)?;
+		}
+
+		Ok(dump.records())
+	}
+
+	/// Checks that the snapshot exported to `dir` is complete and was not modified.
+	pub fn verify_snapshot<P: AsRef<Path>>(dir: P) -> Result<()> {
+		snapshot::verify_snapshot(dir)
+	}
+
+	/// Returns a summary of the database health.
+	pub fn health(&self) -> Health {
+		let journal_eras = self.journal.len();
+		let data_len = self.mmap.len() as u64;
+		let occupied_percent = match data_len {
+			0 => 0,
+			len => cmp::min(100, self.metadata.occupied_bytes * 100 / len) as u8,
 		};
 
-		// persist metadata updated with collided prefixes
-		// if we crash after this the flush will be applied on restart and the metadata will
-		// already be properly updated
-		metadata.as_bytes().copy_to_slice(unsafe { self.metadata_mmap.as_mut_slice() });
-		self.metadata_mmap.flush()?;
+		Health {
+			lock_held: self.path.join(Self::LOCK_FILE).exists(),
+			journal_eras,
+			journal_lag: journal_eras.saturating_sub(self.options.external.journal_eras),
+			occupied_percent,
+			compaction_pending: occupied_percent > self.options.external.extend_threshold_percent,
+			error_count: self.errors.count,
+			last_error: self.errors.last.clone(),
+			degraded: self.errors.degraded,
+		}
+	}
 
-		// perform the flush and update metadata
-		flush.flush(unsafe { self.mmap.as_mut_slice() }, unsafe { self.metadata_mmap.as_mut_slice() }, &mut self.metadata);
-		self.mmap.flush()?;
-		self.metadata_mmap.flush()?;
-		flush.delete()?;
+	/// Keeps the value of `key` in the value cache until it is unpinned, so reads of the key
+	/// never touch the data or collision files, even if the cache is disabled.
+	///
+	/// The value is read again whenever the key is flushed. If the key is stored in a collision
+	/// file, the index of the file is also kept in memory, see `Options::max_resident_collisions`.
+	pub fn pin<K: AsRef<[u8]>>(&mut self, key: K) -> Result<()> {
+		let key = key.as_ref();
+		let key_len = self.options.external.key_len;
+		if key.len() != key_len {
+			bail!(ErrorKind::InvalidKeyLen(key_len, key.len()));
+		}
 
-		// update collisions index
-		for collision_file in collision_files {
-			let prev = self.collisions.insert(collision_file.prefix(), collision_file);
-			assert!(prev.is_none());
+		let prefix = Key::new(key, self.options.external.key_index_bits).prefix;
+		if let Some(collision) = self.collisions.get_mut(&prefix) {
+			collision.ensure_resident()?;
 		}
 
-		Ok(collided_prefixes)
+		self.pin_stored(key)
 	}
-}
 
-impl Drop for Database {
-	fn drop(&mut self) {
-		let _ = self.lock_file.unlock();
+	/// Lets the value of `key` be evicted from the value cache again.
+	///
+	/// Returns false if the key was not pinned.
+	pub fn unpin<K: AsRef<[u8]>>(&mut self, key: K) -> bool {
+		self.cache.lock().unpin(key.as_ref())
+	}
+
+	/// Pins the stored value of `key`, ignoring the journal.
+	fn pin_stored(&self, key: &[u8]) -> Result<()> {
+		let value = match self.lookup_raw_in(key, &mut ReadStats::default(), 0)? {
+			Some(value) => Some(self.transforms.decode_value(value)?.to_vec()),
+			None => None,
+		};
+
+		self.cache.lock().pin(key, value);
+		Ok(())
+	}
+
+	/// Reads the pinned values again after the stored values or their encoding changed.
+	fn refresh_pins(&self) -> Result<()> {
+		let pinned = self.cache.lock().pinned_keys();
+		for key in pinned {
+			self.pin_stored(&key)?;
+		}
+
+		Ok(())
+	}
+
+	/// Returns the counters of the value cache, see `Options::cache_size`.
+	pub fn cache_stats(&self) -> CacheStats {
+		self.cache.lock().stats()
+	}
+
+	/// Returns current statistics of the database.
+	///
+	/// Reads, writes, deletes, compactions and cache counters are counted since the database
+	/// was opened, the other fields describe its current files.
+	pub fn statistics(&self) -> Statistics {
+		let cache = self.cache_stats();
+		Statistics {
+			timestamp: Statistics::now(),
+			data_file_bytes: self.mmap.len() as u64,
+			occupied_bytes: self.metadata.occupied_bytes,
+			journal_eras: self.journal.len() as u64,
+			collided_prefixes: self.collisions.len() as u64,
+			journal_bytes: self.journal.bytes(),
+			collision_log_bytes: self.collisions.values().map(Collision::log_bytes).sum(),
+			garbage_bytes: self.collisions.values().map(Collision::garbage_bytes).sum(),
+			reads: self.counters.reads(),
+			writes: self.counters.writes,
+			deletes: self.counters.deletes,
+			compactions: self.counters.compactions,
+			cache_hits: cache.hits,
+			cache_misses: cache.misses,
+		}
+	}
+
+	/// Returns counts of the keys and the value lengths in the data file and collision files.
+	///
+	/// The counts are updated by journal flushes, so keys which are only journaled are not
+	/// included. They are saved with every flush and only counted from the stored records
+	/// when the database is opened after a crash interrupted saving them.
+	pub fn key_statistics(&self) -> KeyStatistics {
+		self.key_stats.clone()
+	}
+
+	/// Returns which fields of the data file at the positions of `prefixes` are occupied.
+	///
+	/// Bit `i` of the result is set if the field at the position of prefix `prefixes.start + i`
+	/// holds the beginning or the continuation of a record. Records of a prefix may be stored
+	/// in the fields of the following prefixes, so a set bit does not mean that the prefix is used.
+	/// The result ends with the last field of the data file.
+	pub fn occupancy(&self, prefixes: Range<u32>) -> BitVec {
+		let data = unsafe { self.mmap.as_slice() };
+		let mut occupancy = BitVec::new();
+		for prefix in prefixes {
+			let offset = prefix as usize * self.options.record_offset;
+			if offset >= data.len() {
+				break;
+			}
+
+			occupancy.push(data[offset] != field::Header::Uninitialized as u8);
+		}
+
+		occupancy
+	}
+
+	/// Returns persisted statistics snapshots taken within `range` of unix timestamps.
+	///
+	/// Snapshots are only persisted if the database was opened with non-zero `stats_retention` option.
+	pub fn stats_history(&self, range: Range<u64>) -> Vec<Statistics> {
+		match self.stats {
+			Some(ref stats) => stats.range(range),
+			None => Vec::new(),
+		}
+	}
+
+	/// Flushes excessive journal eras one by one until `deadline`, syncs all files and closes the database.
+	///
+	/// Eras left in the journal are durable and replayed when the database is opened again,
+	/// so stopping at the deadline never loses committed data.
+	pub fn shutdown(mut self, deadline: Instant) -> Result<ShutdownReport> {
+		let mut flushed_eras = 0;
+		let mut timed_out = false;
+
+		while self.journal.len() > self.options.external.journal_eras {
+			if Instant::now() >= deadline {
+				timed_out = true;
+				break;
+			}
+
+			self.flush_journal(1)?;
+			flushed_eras += 1;
+		}
+
+		self.mmap.flush()?;
+		self.metadata_mmap.flush()?;
+
+		Ok(ShutdownReport {
+			flushed_eras,
+			pending_eras: self.journal.len(),
+			timed_out,
+		})
+	}
+
+	/// Returns sequence number of the next commit.
+	///
+	/// Commits are numbered consecutively starting from 0.
+	pub fn next_sequence(&self) -> u64 {
+		self.journal.next_era_index()
+	}
+
+	/// Returns sequence number of the oldest commit whose journal segment is still available.
+	///
+	/// A follower which applied all commits before it can catch up with `journal_segment`.
+	/// Equal to `next_sequence` if no segments are available.
+	pub fn oldest_replayable_seq(&self) -> Result<u64> {
+		self.journal.oldest_era_index()
+	}
+
+	/// Returns true if the commit with given sequence number is still journaled or archived.
+	pub(crate) fn has_sequence(&self, sequence: u64) -> bool {
+		self.journal.has_era(sequence)
+	}
+
+	/// Returns operations on the `key` of all commits still journaled or archived, in commit order.
+	///
+	/// Each entry holds the sequence number of the commit and the inserted value, `None` for a delete.
+	/// Flushed commits are only available if the database was opened with `archive_journal` option.
+	pub fn history<K: AsRef<[u8]>>(&self, key: K) -> Result<Vec<(u64, Option<Vec<u8>>)>> {
+		let key = key.as_ref();
+		if key.len() != self.options.external.key_len {
+			return Err(ErrorKind::InvalidKeyLen(self.options.external.key_len, key.len()).into());
+		}
+
+		let mut history = Vec::new();
+		for sequence in 0..self.next_sequence() {
+			if !self.journal.has_era(sequence) {
+				continue;
+			}
+
+			let era = self.journal.open_era(sequence)?;
+			match era.get(key) {
+				Some(JournalOperation::Insert(value)) => {
+					let value = self.transforms.decode_value(Value::Raw(value))?.to_vec();
+					history.push((sequence, Some(value)));
+				},
+				Some(JournalOperation::Delete) => history.push((sequence, None)),
+				None => {},
+			}
+		}
+
+		Ok(history)
+	}
+
+	/// Folds commits with sequence numbers in `range` into a single transaction.
+	///
+	/// Only the last operation for each key is kept. Flushed commits are read from
+	/// the journal archive, so the database has to be opened with `archive_journal`.
+	pub fn changeset(&self, range: Range<u64>) -> Result<Transaction> {
+		let mut changes = BTreeMap::new();
+		for index in range {
+			let era = self.journal.open_era(index)?;
+			for operation in era.iter() {
+				match operation {
+					Operation::Insert(key, value) => {
+						let value = self.transforms.decode_value(Value::Raw(value))?.to_vec();
+						changes.insert(key.to_vec(), Some(value))
+					},
+					Operation::Delete(key) => changes.insert(key.to_vec(), None),
+				};
+			}
+		}
+
+		let mut tx = self.create_transaction();
+		for (key, value) in changes {
+			match value {
+				Some(value) => tx.insert(key, value)?,
+				None => tx.delete(key)?,
+			}
+		}
+
+		Ok(tx)
+	}
+
+	/// Returns the serialized journal era with sequence number `sequence`.
+	///
+	/// The segment can be applied to another database created with the same options
+	/// with `apply_journal_segment`.
+	pub fn journal_segment(&self, sequence: u64) -> Result<Vec<u8>> {
+		Ok(self.journal.open_era(sequence)?.raw().to_vec())
+	}
+
+	/// Validates a journal segment of another database and commits its operations.
+	///
+	/// Both databases have to be created with the same options. The segment is committed
+	/// as a single transaction with the next sequence number of this database.
+	pub fn apply_journal_segment(&mut self, segment: &[u8]) -> Result<()> {
+		self.check_writable()?;
+
+		let mut tx = self.create_transaction();
+		for operation in journal::segment_operations(segment)? {
+			match operation {
+				Operation::Insert(key, value) => {
+					let value = self.transforms.decode_value(Value::Raw(value))?.to_vec();
+					tx.insert(key, value)?;
+				},
+				Operation::Delete(key) => tx.delete(key)?,
+			}
+		}
+
+		self.commit(&tx)
+	}
+
+	/// Lookup a value associated with given `key`.
+	pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Value>> {
+		self.get_stats(key.as_ref(), &mut ReadStats::default())
+	}
+
+	/// Lookup a value associated with given `key` and copy it to `buf`.
+	///
+	/// Reusing the same buffer for many reads avoids allocating a new one for every value.
+	/// Returns false and leaves `buf` empty if there is no value.
+	pub fn get_into<K: AsRef<[u8]>>(&self, key: K, buf: &mut Vec<u8>) -> Result<bool> {
+		match self.get(key)? {
+			Some(value) => {
+				value.copy_to(buf);
+				Ok(true)
+			},
+			None => {
+				buf.clear();
+				Ok(false)
+			},
+		}
+	}
+
+	/// Lookup a value associated with given `key` with per-read `options`.
+	///
+	/// Returns `ReadStats` of the lookup if `options.collect_stats` is set.
+	pub fn get_with<K: AsRef<[u8]>>(&self, key: K, options: &ReadOptions) -> Result<(Option<Value>, Option<ReadStats>)> {
+		let mut stats = ReadStats::default();
+		let value = self.get_stats(key.as_ref(), &mut stats)?;
+
+		if options.collect_stats {
+			Ok((value, Some(stats)))
+		} else {
+			Ok((value, None))
+		}
+	}
+
+	fn get_stats(&self, key: &[u8], stats: &mut ReadStats) -> Result<Option<Value>> {
+		self.record_get(|| self.lookup(key, stats))
+	}
+
+	/// Counts the read made by `lookup` and records its latency.
+	fn record_get<'a, F>(&'a self, lookup: F) -> Result<Option<Value<'a>>> where
+		F: FnOnce() -> Result<Option<Value<'a>>>,
+	{
+		self.counters.record_read();
+		if !self.latencies.enabled() {
+			return lookup();
+		}
+
+		let start = Instant::now();
+		let result = lookup();
+		self.latencies.record_get(start.elapsed());
+		result
+	}
+
+	/// Lookup values associated with all `keys`, see `get_many`.
+	pub fn multi_get<K: AsRef<[u8]>>(&self, keys: &[K]) -> Result<Vec<Option<Value>>> {
+		self.get_many(keys.iter())
+	}
+
+	/// Lookup values associated with all `keys`, e.g. the nodes of a trie level.
+	///
+	/// Keys are looked up in key order and grouped by their prefix. The values of a group
+	/// which are neither journaled nor cached are read from the collision file of a collided
+	/// prefix in a single forward pass, and the data file is read front to back instead of
+	/// seeking back and forth. Results are returned in the same order as `keys`. All keys are
+	/// validated before any lookup is made, so an invalid key never results in a partial answer.
+	pub fn get_many<I, K>(&self, keys: I) -> Result<Vec<Option<Value>>> where I: IntoIterator<Item = K>, K: AsRef<[u8]> {
+		let keys: Vec<K> = keys.into_iter().collect();
+		let key_len = self.options.external.key_len;
+		if let Some(key) = keys.iter().map(|key| key.as_ref()).find(|key| key.len() != key_len) {
+			bail!(ErrorKind::InvalidKeyLen(key_len, key.len()));
+		}
+
+		let key_index_bits = self.options.external.key_index_bits;
+		let journal_eras = self.journal.len();
+		let mut order: Vec<usize> = (0..keys.len()).collect();
+		order.sort_by(|a, b| keys[*a].as_ref().cmp(keys[*b].as_ref()));
+
+		let mut values: Vec<Option<Option<Value>>> = keys.iter().map(|_| None).collect();
+		let mut start = 0;
+		while start < order.len() {
+			let prefix = Key::new(keys[order[start]].as_ref(), key_index_bits).prefix;
+			let len = order[start..].iter()
+				.take_while(|index| Key::new(keys[**index].as_ref(), key_index_bits).prefix == prefix)
+				.count();
+			let group = &order[start..start + len];
+			start += len;
+
+			let mut prefetched: Vec<Option<Result<Option<&[u8]>>>> = group.iter().map(|_| None).collect();
+			if self.metadata.collided_prefixes.has(prefix).unwrap_or(false) {
+				let collision = self.collisions.get(&prefix).expect(
+					"prefix is declared as collided; \
+					 collision file should exist in collisions index; qed");
+
+				let pending: Vec<usize> = {
+					let cache = self.cache.lock();
+					(0..group.len()).filter(|i| {
+						let key = keys[group[*i]].as_ref();
+						self.journal.get(key).is_none() && !cache.is_pinned(key) && !cache.contains(key)
+					}).collect()
+				};
+				let pending_keys: Vec<&[u8]> = pending.iter().map(|i| keys[group[*i]].as_ref()).collect();
+				for (i, value) in pending.into_iter().zip(collision.get_many(&pending_keys)) {
+					prefetched[i] = Some(value);
+				}
+			}
+
+			for (i, index) in group.iter().enumerate() {
+				let key = keys[*index].as_ref();
+				let value = match prefetched[i].take() {
+					Some(value) => self.record_get(|| self.lookup_cached(key, &mut ReadStats::default(), journal_eras, |stats| {
+						self.collided_value(&Key::new(key, key_index_bits), value, stats)
+					})),
+					None => self.get_stats(key, &mut ReadStats::default()),
+				};
+				values[*index] = Some(value?);
+			}
+		}
+
+		Ok(values.into_iter().map(|value| value.expect("every key was looked up; qed")).collect())
+	}
+
+	fn lookup(&self, key: &[u8], stats: &mut ReadStats) -> Result<Option<Value>> {
+		self.lookup_in(key, stats, self.journal.len())
+	}
+
+	/// Lookup a value ignoring all but the oldest `journal_eras` journaled eras.
+	///
+	/// Values read from the data file or collision files are cached, see `Options::cache_size`,
+	/// and values of pinned keys are read from the cache, see `pin`.
+	fn lookup_in(&self, key: &[u8], stats: &mut ReadStats, journal_eras: usize) -> Result<Option<Value>> {
+		self.lookup_cached(key, stats, journal_eras, |stats| self.lookup_raw_in(key, stats, journal_eras))
+	}
+
+	/// Lookup a value like `lookup_in`, reading it with `lookup_raw` if it is not cached.
+	fn lookup_cached<'a, F>(&'a self, key: &[u8], stats: &mut ReadStats, journal_eras: usize, lookup_raw: F) -> Result<Option<Value<'a>>> where
+		F: FnOnce(&mut ReadStats) -> Result<Option<Value<'a>>>,
+	{
+		let journaled = self.journal.get_in(key, journal_eras).is_some();
+		if !journaled {
+			if let Some(value) = self.cache.lock().get_pinned(key) {
+				stats.cache_hit = true;
+				return Ok(value.map(Value::Owned));
+			}
+		}
+
+		let cached = self.cache.lock().is_enabled() &&
+			key.len() == self.options.external.key_len &&
+			!journaled;
+
+		if !cached {
+			return match lookup_raw(stats)? {
+				Some(value) => self.transforms.decode_value(value).map(Some),
+				None => Ok(None),
+			};
+		}
+
+		if let Some(value) = self.cache.lock().get(key) {
+			stats.cache_hit = true;
+			return Ok(Some(Value::Owned(value)));
+		}
+
+		let value = match lookup_raw(stats)? {
+			Some(value) => self.transforms.decode_value(value)?.to_vec(),
+			None => return Ok(None),
+		};
+
+		self.cache.lock().insert(key, value.clone());
+		Ok(Some(Value::Owned(value)))
+	}
+
+	/// Lookup a value as it is stored in the database, i.e. without reversing value transforms.
+	fn lookup_raw(&self, key: &[u8], stats: &mut ReadStats) -> Result<Option<Value>> {
+		self.lookup_raw_in(key, stats, self.journal.len())
+	}
+
+	fn lookup_raw_in(&self, key: &[u8], stats: &mut ReadStats, journal_eras: usize) -> Result<Option<Value>> {
+		if key.len() != self.options.external.key_len {
+			return Err(ErrorKind::InvalidKeyLen(self.options.external.key_len, key.len()).into());
+		}
+
+		// check if the key-value pair is currently journaled
+		match self.journal.get_in(key, journal_eras) {
+			Some(JournalOperation::Insert(value)) => {
+				stats.journal_hit = true;
+				return Ok(Some(Value::Raw(value)));
+			},
+			Some(JournalOperation::Delete) => {
+				stats.journal_hit = true;
+				return Ok(None);
+			},
+			None => {},
+		}
+
+		let field_body_size = self.options.field_body_size;
+		let value_size = self.options.value_size;
+
+		let key = Key::new(key, self.options.external.key_index_bits);
+
+		// fetch from the collision file if this is a collided prefix
+		if self.metadata.collided_prefixes.has(key.prefix).unwrap_or(false) {
+			let collision = self.collisions.get(&key.prefix).expect(
+				"prefix is declared as collided; \
+				 collision file should exist in collisions index; qed");
+
+			return self.collided_value(&key, collision.get(key.key), stats);
+		}
+
+		// check if there's any data stored on the data file for the given prefix
+		if !self.metadata.prefixes.has(key.prefix).unwrap_or(false) {
+			return Ok(None);
+		}
+
+		let offset = key.prefix as usize * self.options.record_offset;
+		let data = unsafe { &self.mmap.as_slice()[offset..] };
+
+		stats.files_touched += 1;
+		let result = find::find_record_counted(data, field_body_size, value_size, key.key, &mut stats.fields_scanned);
+		stats.bytes_read += stats.fields_scanned * field_size(field_body_size);
+
+		match result? {
+			find::RecordResult::Found(record) => {
+				// fields following the first one are read when the value is accessed
+				stats.bytes_read += record.value_len().saturating_sub(field_body_size);
+				Ok(Some(Value::from(record)))
+			},
+			find::RecordResult::NotFound => Ok(None),
+			find::RecordResult::OutOfRange => unimplemented!(),
+		}
+	}
+
+	/// Returns the value of `key` read from its collision file. Values of corrupted entries
+	/// are fetched from the repair source, see `set_repair_source`.
+	fn collided_value<'a>(&'a self, key: &Key, value: Result<Option<&'a [u8]>>, stats: &mut ReadStats) -> Result<Option<Value<'a>>> {
+		let value = match value {
+			Ok(value) => value.map(Value::Raw),
+			Err(err) => Some(Value::Owned(self.repair.fetch(key.prefix, key.key, err)?)),
+		};
+		stats.files_touched += 1;
+		if let Some(len) = value.as_ref().and_then(|value| value.as_slice()).map(|value| value.len()) {
+			// entry consists of key and value, each prefixed with length
+			stats.bytes_read += 8 + key.key.len() + len;
+		}
+
+		Ok(value)
+	}
+
+	/// Returns true if a value is associated with given `key`.
+	///
+	/// Unlike `get` this never reads the value itself.
+	pub fn contains<K: AsRef<[u8]>>(&self, key: K) -> Result<bool> {
+		let key = key.as_ref();
+		if key.len() != self.options.external.key_len {
+			return Err(ErrorKind::InvalidKeyLen(self.options.external.key_len, key.len()).into());
+		}
+
+		match self.journal.get(key) {
+			Some(JournalOperation::Insert(_)) => return Ok(true),
+			Some(JournalOperation::Delete) => return Ok(false),
+			None => {},
+		}
+
+		let key = Key::new(key, self.options.external.key_index_bits);
+
+		if self.metadata.collided_prefixes.has(key.prefix).unwrap_or(false) {
+			let collision = self.collisions.get(&key.prefix).expect(
+				"prefix is declared as collided; \
+				 collision file should exist in collisions index; qed");
+
+			return collision.contains(key.key);
+		}
+
+		if !self.metadata.prefixes.has(key.prefix).unwrap_or(false) {
+			return Ok(false);
+		}
+
+		let offset = key.prefix as usize * self.options.record_offset;
+		let data = unsafe { &self.mmap.as_slice()[offset..] };
+
+		match find::find_record(data, self.options.field_body_size, self.options.value_size, key.key)? {
+			find::RecordResult::Found(_) => Ok(true),
+			// the search reached the end of the data file, where the record would have been
+			find::RecordResult::NotFound | find::RecordResult::OutOfRange => Ok(false),
+		}
+	}
+
+	/// Checks which of the `keys` have values associated with them.
+	///
+	/// Bit `i` of the result is set if `keys[i]` exists.
+	pub fn contains_many<K: AsRef<[u8]>>(&self, keys: &[K]) -> Result<BitVec> {
+		let mut result = BitVec::from_elem(keys.len(), false);
+		for (i, key) in keys.iter().enumerate() {
+			if self.contains(key)? {
+				result.set(i, true);
+			}
+		}
+
+		Ok(result)
+	}
+
+	/// Returns an iterator over all the database key-value pairs ordered by key.
+	pub fn iter(&self) -> Result<DatabaseIterator> {
+		self.iter_in(self.journal.len())
+	}
+
+	/// Returns a point-in-time view of the database.
+	///
+	/// While the snapshot is alive, later commits stay in the journal so that the data
+	/// and collision files only hold commits visible in the snapshot.
+	pub fn snapshot(&self) -> Snapshot {
+		Snapshot::new(self.next_sequence(), &self.snapshots)
+	}
+
+	/// Returns a view for repeatable reads within a short scope.
+	///
+	/// Unlike `snapshot`, it borrows the database, so it can't outlive the next commit.
+	pub fn read_tx(&self) -> ReadTransaction {
+		ReadTransaction::new(self)
+	}
+
+	fn check_snapshot(&self, snapshot: &Snapshot) -> Result<()> {
+		if !snapshot.is_pinned_by(&self.snapshots) {
+			bail!(ErrorKind::InvalidSnapshot(self.path.clone(), "Snapshot was taken of another database".into()));
+		}
+
+		Ok(())
+	}
+
+	pub(crate) fn get_before<'a>(&'a self, key: &[u8], snapshot: &Snapshot) -> Result<Option<Value<'a>>> {
+		self.check_snapshot(snapshot)?;
+		let journal_eras = self.journal.len_before(snapshot.sequence());
+		self.lookup_in(key, &mut ReadStats::default(), journal_eras)
+	}
+
+	pub(crate) fn iter_before<'a>(&'a self, snapshot: &Snapshot) -> Result<DatabaseIterator<'a>> {
+		self.check_snapshot(snapshot)?;
+		self.iter_in(self.journal.len_before(snapshot.sequence()))
+	}
+
+	fn iter_in(&self, journal_eras: usize) -> Result<DatabaseIterator> {
+		let record_collisions_iter = self.record_collisions_iter()?;
+		let journal_iter = self.journal.iter_in(journal_eras);
+		let pending = IteratorValue::None;
+		let latencies = &self.latencies;
+		let transforms = &self.transforms;
+
+		Ok(DatabaseIterator { record_collisions_iter, journal_iter, pending, latencies, transforms })
+	}
+
+	/// Returns an iterator over changes which turn this database into the `other` one.
+	///
+	/// Changes are ordered by key. Both databases should be created with the same key length.
+	pub fn diff<'a>(&'a self, other: &'a Database) -> Result<Box<Iterator<Item = Result<Change>> + 'a>> {
+		Ok(diff::diff(self.iter()?, other.iter()?))
+	}
+
+	/// Returns an iterator over only the database key-value pairs stored in the data file ordered
+	/// by key (i.e. it doesn't include data from the journal or collision files).
+	fn record_iter(&self) -> Result<RecordIterator> {
+		let data = unsafe { &self.mmap.as_slice() };
+		let occupied_prefixes_iter = self.metadata.prefixes.prefixes_iter();
+		let field_body_size = self.options.field_body_size;
+		let key_size = self.options.external.key_len;
+		let value_size = self.options.value_size;
+
+		let record_iter = find::iter(
+			data,
+			occupied_prefixes_iter,
+			field_body_size,
+			key_size,
+			value_size,
+		)?;
+
+		Ok(record_iter)
+	}
+
+	// TODO: refactor to avoid boxed iterator
+	/// Returns an iterator over the database key-value pairs stored in the data file and collision
+	/// files.
+	fn record_collisions_iter<'a>(&'a self) -> Result<Box<Iterator<Item=Result<(&'a [u8], Value<'a>)>> + 'a>> {
+		let collided_records = self.collisions.values()
+			.flat_map(|it| it.iter().ok()) // FIXME: swallowing errors here
+			.flat_map(|it| it);
+
+		let records = self.record_iter()?;
+
+		Ok(merge_records(records, collided_records))
+	}
+
+	/// Returns an iterator over all the database key-value pairs with keys starting with `prefix`
+	/// ordered by key.
+	///
+	/// Only the part of the data file and the collision files covering `prefix` are read,
+	/// so scans of prefixes without keys finish without touching any records.
+	pub fn iter_prefix<P: AsRef<[u8]>>(&self, prefix: P) -> Result<DatabaseIterator> {
+		let key_len = self.options.external.key_len;
+		let prefix = prefix.as_ref().to_vec();
+		if prefix.len() > key_len {
+			bail!(ErrorKind::InvalidKeyLen(key_len, prefix.len()));
+		}
+
+		// range of index prefixes which may contain keys starting with `prefix`
+		let mut lowest = prefix.to_vec();
+		lowest.resize(key_len, 0);
+		let mut highest = prefix.to_vec();
+		highest.resize(key_len, 0xff);
+		let prefix_bits = self.options.external.key_index_bits;
+		let first = Key::new(&lowest, prefix_bits).prefix;
+		let last = Key::new(&highest, prefix_bits).prefix;
+
+		self.iter_prefixes_filtered(first, last, move |key: &[u8]| key.starts_with(&prefix))
+	}
+
+	/// Returns an iterator over the key-value pairs with keys in `range` ordered by key.
+	///
+	/// Like `iter_prefix`, only the parts of the data file and the collision files which
+	/// may hold keys of the range are read.
+	pub fn iter_range<K: AsRef<[u8]>>(&self, range: Range<K>) -> Result<DatabaseIterator> {
+		let key_len = self.options.external.key_len;
+		let start = range.start.as_ref().to_vec();
+		let end = range.end.as_ref().to_vec();
+		for key in &[&start, &end] {
+			if key.len() != key_len {
+				bail!(ErrorKind::InvalidKeyLen(key_len, key.len()));
+			}
+		}
+
+		let prefix_bits = self.options.external.key_index_bits;
+		let first = Key::new(&start, prefix_bits).prefix;
+		let last = Key::new(&end, prefix_bits).prefix;
+
+		self.iter_prefixes_filtered(first, last, move |key: &[u8]| key >= &start[..] && key < &end[..])
+	}
+
+	/// Returns an iterator over the key-value pairs with keys not lower than `start` ordered by key.
+	pub fn iter_from<K: AsRef<[u8]>>(&self, start: K) -> Result<DatabaseIterator> {
+		let key_len = self.options.external.key_len;
+		let start = start.as_ref().to_vec();
+		if start.len() != key_len {
+			bail!(ErrorKind::InvalidKeyLen(key_len, start.len()));
+		}
+
+		let first = Key::new(&start, self.options.external.key_index_bits).prefix;
+		let last = (1u64 << self.options.external.key_index_bits) - 1;
+
+		self.iter_prefixes_filtered(first, last as u32, move |key: &[u8]| key >= &start[..])
+	}
+
+	/// Returns at most `limit` key-value pairs with keys greater than `after`, or the first ones
+	/// if it is `None`, e.g. for a page of a listing. Only keys from `after` on are read.
+	pub(crate) fn iter_page(&self, after: Option<&[u8]>, limit: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+		let iter = match after {
+			Some(after) => self.iter_from(after)?,
+			None => self.iter()?,
+		};
+
+		let mut page = Vec::new();
+		for item in iter {
+			if page.len() == limit {
+				break;
+			}
+
+			let (key, value) = item?;
+			if Some(key) != after {
+				page.push((key.to_vec(), value.to_vec()));
+			}
+		}
+
+		Ok(page)
+	}
+
+	/// Returns an iterator over the key-value pairs of index prefixes `first..=last` for which
+	/// `contains` returns true.
+	fn iter_prefixes_filtered<'a, F>(&'a self, first: u32, last: u32, contains: F) -> Result<DatabaseIterator<'a>> where
+		F: Fn(&[u8]) -> bool + 'a,
+	{
+		let journal_iter = self.journal.iter()
+			.filter(|op| contains(op.key()))
+			.collect::<BTreeSet<_>>()
+			.into_iter();
+
+		let record_collisions_iter = self.record_collisions_iter_filtered(first, last, contains)?;
+
+		Ok(DatabaseIterator {
+			record_collisions_iter,
+			journal_iter,
+			pending: IteratorValue::None,
+			latencies: &self.latencies,
+			transforms: &self.transforms,
+		})
+	}
+
+	/// Returns an iterator over the key-value pairs of index prefixes `first..=last` stored in the
+	/// data file and collision files for which `contains` returns true.
+	fn record_collisions_iter_filtered<'a, F>(&'a self, first: u32, last: u32, contains: F) -> Result<Box<Iterator<Item=Result<(&'a [u8], Value<'a>)>> + 'a>> where
+		F: Fn(&[u8]) -> bool + 'a,
+	{
+		let collided_records = self.collisions.range(first..)
+			.take_while(move |&(p, _)| *p <= last)
+			.flat_map(|(_, it)| it.iter().ok()) // FIXME: swallowing errors here
+			.flat_map(|it| it);
+
+		let occupied_prefixes = self.metadata.prefixes.prefixes_iter()
+			.skip_while(move |p| *p < first)
+			.take_while(move |p| *p <= last);
+
+		let records = find::iter_prefixes(
+			unsafe { self.mmap.as_slice() },
+			occupied_prefixes,
+			self.options.field_body_size,
+			self.options.external.key_len,
+			self.options.value_size,
+		)?;
+
+		// records of neighbouring prefixes may be stored in the same part of the data file
+		Ok(Box::new(merge_records(records, collided_records).filter(move |item| {
+			match *item {
+				Ok((key, _)) => contains(key),
+				Err(_) => true,
+			}
+		})))
+	}
+
+	/// Returns an iterator over all the database key-value pairs in descending key order.
+	///
+	/// Index prefixes are visited from the last one and only the records of the current
+	/// prefix are buffered, so taking the first few items reads just the end of the database.
+	pub fn iter_rev(&self) -> Result<DatabaseRevIterator> {
+		let last = (1u64 << self.options.external.key_index_bits) - 1;
+		Ok(self.iter_prefixes_rev(0, last as u32, |_: &[u8]| true))
+	}
+
+	/// Returns an iterator over the database key-value pairs with keys starting with `prefix`
+	/// in descending key order.
+	pub fn iter_prefix_rev<'a>(&'a self, prefix: &'a [u8]) -> Result<DatabaseRevIterator<'a>> {
+		let key_len = self.options.external.key_len;
+		if prefix.len() > key_len {
+			bail!(ErrorKind::InvalidKeyLen(key_len, prefix.len()));
+		}
+
+		let mut lowest = prefix.to_vec();
+		lowest.resize(key_len, 0);
+		let mut highest = prefix.to_vec();
+		highest.resize(key_len, 0xff);
+		let prefix_bits = self.options.external.key_index_bits;
+		let first = Key::new(&lowest, prefix_bits).prefix;
+		let last = Key::new(&highest, prefix_bits).prefix;
+
+		Ok(self.iter_prefixes_rev(first, last, move |key: &[u8]| key.starts_with(prefix)))
+	}
+
+	fn iter_prefixes_rev<'a, F>(&'a self, first: u32, last: u32, contains: F) -> DatabaseRevIterator<'a> where
+		F: Fn(&[u8]) -> bool + 'a,
+	{
+		let journal = self.journal.iter()
+			.filter(|op| contains(op.key()))
+			.collect();
+
+		DatabaseRevIterator {
+			db: self,
+			contains: Box::new(contains),
+			journal,
+			first,
+			next_prefix: Some(last),
+			buffer: Vec::new(),
+		}
+	}
+
+	/// Returns an iterator over all the database key-value pairs in the given `order`.
+	pub fn iter_ordered<'a>(&'a self, order: IterationOrder) -> Result<Box<Iterator<Item=Result<(&'a [u8], Value<'a>)>> + 'a>> {
+		match order {
+			IterationOrder::Lexicographic => Ok(Box::new(self.iter()?)),
+			IterationOrder::Physical => {
+				let last = (1u64 << self.options.external.key_index_bits) - 1;
+				self.iter_physical(0, last as u32, |_: &[u8]| true)
+			},
+		}
+	}
+
+	/// Returns an iterator over the database key-value pairs with keys starting with `prefix`
+	/// in the given `order`.
+	pub fn iter_prefix_ordered<'a>(&'a self, prefix: &'a [u8], order: IterationOrder) -> Result<Box<Iterator<Item=Result<(&'a [u8], Value<'a>)>> + 'a>> {
+		if order == IterationOrder::Lexicographic {
+			return Ok(Box::new(self.iter_prefix(prefix)?));
+		}
+
+		let key_len = self.options.external.key_len;
+		if prefix.len() > key_len {
+			bail!(ErrorKind::InvalidKeyLen(key_len, prefix.len()));
+		}
+
+		let mut lowest = prefix.to_vec();
+		lowest.resize(key_len, 0);
+		let mut highest = prefix.to_vec();
+		highest.resize(key_len, 0xff);
+		let prefix_bits = self.options.external.key_index_bits;
+		let first = Key::new(&lowest, prefix_bits).prefix;
+		let last = Key::new(&highest, prefix_bits).prefix;
+
+		self.iter_physical(first, last, move |key: &[u8]| key.starts_with(prefix))
+	}
+
+	/// Returns an iterator over the key-value pairs of index prefixes `first..=last` for which
+	/// `contains` returns true in `IterationOrder::Physical`.
+	///
+	/// Stored values are replaced with their journaled ones, while keys which are only journaled
+	/// are found by looking up every journaled insert in the data and collision files.
+	fn iter_physical<'a, F>(&'a self, first: u32, last: u32, contains: F) -> Result<Box<Iterator<Item=Result<(&'a [u8], Value<'a>)>> + 'a>> where
+		F: Fn(&[u8]) -> bool + 'a,
+	{
+		let contains: Rc<Fn(&[u8]) -> bool + 'a> = Rc::new(contains);
+
+		let occupied_prefixes = self.metadata.prefixes.prefixes_iter()
+			.skip_while(move |p| *p < first)
+			.take_while(move |p| *p <= last);
+
+		let records = find::iter_prefixes(
+			unsafe { self.mmap.as_slice() },
+			occupied_prefixes,
+			self.options.field_body_size,
+			self.options.external.key_len,
+			self.options.value_size,
+		)?.map(|record| match record {
+			Ok(record) => Ok((record.key(), Value::Record(record))),
+			Err(err) => Err(err.into()),
+		});
+
+		let collided_records = self.collisions.range(first..)
+			.take_while(move |&(p, _)| *p <= last)
+			.map(|(_, collision)| collision.iter())
+			.collect::<Result<Vec<_>>>()?
+			.into_iter()
+			.flat_map(|entries| entries)
+			.map(|entry| entry.map(|(key, value)| (key, Value::Raw(value))));
+
+		let stored_contains = contains.clone();
+		let stored = records.chain(collided_records).filter_map(move |item| {
+			let (key, value) = match item {
+				Ok(item) => item,
+				Err(err) => return Some(Err(err)),
+			};
+
+			if !stored_contains(key) {
+				return None;
+			}
+
+			match self.journal.get(key) {
+				Some(JournalOperation::Insert(value)) => Some(Ok((key, Value::Raw(value)))),
+				Some(JournalOperation::Delete) => None,
+				None => Some(Ok((key, value))),
+			}
+		});
+
+		let journaled = self.journal.iter().filter_map(move |op| {
+			let (key, value) = match op {
+				Operation::Insert(key, value) => (key, value),
+				_ => return None,
+			};
+
+			if !contains(key) {
+				return None;
+			}
+
+			match self.lookup_raw_in(key, &mut ReadStats::default(), 0) {
+				Ok(None) => Some(Ok((key, Value::Raw(value)))),
+				Ok(Some(_)) => None,
+				Err(err) => Some(Err(err)),
+			}
+		});
+
+		let transforms = &self.transforms;
+		Ok(Box::new(stored.chain(journaled).map(move |item| item.and_then(|(key, value)| {
+			Ok((key, transforms.decode_value(value)?))
+		}))))
+	}
+
+	fn collisions(&self) -> Result<BTreeMap<u32, Vec<&[u8]>>> {
+		let mut collisions: BTreeMap<u32, Vec<&[u8]>> = BTreeMap::new();
+
+		for record in self.record_iter()? {
+			let key = record?.key();
+
+			let prefix = Key::new(key, self.options.external.key_index_bits).prefix;
+			match collisions.entry(prefix) {
+				Entry::Vacant(entry) => {
+					entry.insert(vec![key]);
+				},
+				Entry::Occupied(mut entry) => {
+					entry.get_mut().push(key);
+				},
+			}
+		}
+
+		// drop prefixes that have a number of collisions lower than the allowed threshold
+		let collisions: BTreeMap<u32, Vec<&[u8]>> =
+			collisions.into_iter().filter(|p| {
+				p.1.len() >= self.options.external.max_prefix_collisions
+			}).collect();
+
+		Ok(collisions)
+	}
+
+	/// Calls `filter` with every record and applies its decisions before compacting the database.
+	///
+	/// Removals and changes are committed in transactions of about 4 MiB and the journal is
+	/// flushed after each of them, so filtering a large database never holds all decisions in
+	/// memory. A failure leaves the decisions of the records before it committed.
+	pub fn compact_with_filter<F>(&mut self, filter: F) -> Result<Vec<u32>> where F: FnMut(&[u8], &[u8]) -> FilterDecision {
+		self.compact_with_filter_batched(filter, Self::FILTER_BATCH_BYTES)
+	}
+
+	fn compact_with_filter_batched<F>(&mut self, mut filter: F, batch_bytes: usize) -> Result<Vec<u32>> where F: FnMut(&[u8], &[u8]) -> FilterDecision {
+		self.check_writable()?;
+		self.check_not_checkpointing()?;
+
+		let mut after: Option<Vec<u8>> = None;
+		loop {
+			let (tx, last) = {
+				let iter = match after {
+					Some(ref after) => self.iter_from(after)?,
+					None => self.iter()?,
+				};
+
+				let mut tx = self.create_transaction();
+				let mut last = None;
+				for item in iter {
+					let (key, value) = item?;
+					// the batch before ended with this key
+					if after.as_ref().map_or(false, |after| &after[..] == key) {
+						continue;
+					}
+
+					match filter(key, &value.to_vec()) {
+						FilterDecision::Keep => {},
+						FilterDecision::Remove => tx.delete(key)?,
+						FilterDecision::Change(value) => tx.insert(key, value)?,
+					}
+
+					if tx.byte_len() >= batch_bytes {
+						last = Some(key.to_vec());
+						break;
+					}
+				}
+
+				(tx, last)
+			};
+
+			if tx.byte_len() != 0 {
+				self.commit(&tx)?;
+				self.flush_journal(None)?;
+			}
+
+			match last {
+				Some(last) => after = Some(last),
+				None => break,
+			}
+		}
+
+		self.compact()
+	}
+
+	/// Moves records of collided prefixes with fewer than `max_prefix_collisions` keys back to
+	/// the data file and deletes their collision files.
+	///
+	/// Prefixes stop colliding as their keys are deleted, but keep their collision files.
+	/// Databases with sparse key spaces end up with many tiny files, each taking an inode,
+	/// a file descriptor and a mapping. Returns the merged prefixes.
+	pub fn merge_small_collisions(&mut self) -> Result<Vec<u32>> {
+		self.check_writable()?;
+		self.check_not_checkpointing()?;
+
+		let result = self.merge_small_collisions_internal();
+		if let Err(ref err) = result {
+			self.errors.record(err);
+			self.errors.degraded = true;
+		}
+		result
+	}
+
+	fn merge_small_collisions_internal(&mut self) -> Result<Vec<u32>> {
+		let max_collisions = self.options.external.max_prefix_collisions;
+		let mut merged = Vec::new();
+		for (prefix, collision) in self.collisions.iter() {
+			if collision.iter()?.take(max_collisions).count() < max_collisions {
+				merged.push(*prefix);
+			}
+		}
+
+		if merged.is_empty() {
+			return Ok(merged);
+		}
+
+		let flush = {
+			let mut metadata = self.metadata.clone();
+			let mut records = Vec::new();
+			// prefixes and keys in collision files are ordered, so the inserts are ordered too
+			for prefix in &merged {
+				metadata.remove_prefix_collision(*prefix);
+				for item in self.collisions[prefix].iter()? {
+					let (key, value) = item?;
+					records.push((key, value));
+				}
+			}
+
+			// values are moved to the data file with the compression of their column
+			let recoded = if self.transforms.has_overrides() {
+				records.iter().map(|&(key, value)| self.transforms.recode(key, value)).collect::<Result<Vec<_>>>()?
+			} else {
+				Vec::new()
+			};
+			let records: Vec<(&[u8], &[u8])> = if self.transforms.has_overrides() {
+				records.iter().zip(recoded.iter()).map(|(&(key, _), value)| (key, value.as_slice())).collect()
+			} else {
+				records
+			};
+
+			// the flush file holds the metadata without the merged prefixes, so after it is
+			// written a crash leaves only orphaned collision files, which are deleted on open
+			Flush::new(
+				&self.path,
+				&self.options,
+				unsafe { self.mmap.as_slice() },
+				&metadata,
+				records.into_iter().map(|(key, value)| Operation::Insert(key, value)),
+			)?
+		};
+
+		flush.flush(unsafe { self.mmap.as_mut_slice() }, unsafe { self.metadata_mmap.as_mut_slice() }, &mut self.metadata);
+		flush_blocks(&mut self.mmap, &flush, self.options.external.block_size)?;
+		self.metadata_mmap.flush()?;
+		flush.delete()?;
+
+		for prefix in &merged {
+			let collision = self.collisions.remove(prefix).expect("prefix was taken from the collisions index; qed");
+			collision.remove()?;
+		}
+
+		Ok(merged)
+	}
+
+	/// Seals `prefixes` against further writes, e.g. prefixes of historical chain data.
+	///
+	/// Commits writing keys of sealed prefixes fail with `PrefixSealed`. Collision files of the
+	/// prefixes are rewritten without overwritten and deleted entries. Eras committed before
+	/// sealing are still flushed to the prefixes, sealing them again afterwards compacts
+	/// their collision files once more. Sealed prefixes are persisted in the database directory.
+	pub fn seal_prefixes(&mut self, prefixes: Range<u32>) -> Result<()> {
+		self.check_writable()?;
+		self.check_not_checkpointing()?;
+
+		let mut sealed = self.sealed.clone();
+		seal::insert(&mut sealed, prefixes.clone());
+		seal::write_sealed(&self.path, &sealed)?;
+		self.sealed = sealed;
+
+		for (_, collision) in self.collisions.range_mut(prefixes) {
+			if !collision.is_compact() {
+				collision.compact()?;
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Returns sealed ranges of prefixes ordered by their first prefix, see `seal_prefixes`.
+	pub fn sealed_prefixes(&self) -> &[Range<u32>] {
+		&self.sealed
+	}
+
+	/// Drops indices of the least recently used collision files exceeding `max_resident_collisions`.
+	fn evict_collision_indices(&mut self) {
+		let max_resident = self.options.external.max_resident_collisions;
+		if max_resident == 0 {
+			return;
+		}
+
+		let prefix_bits = self.options.external.key_index_bits;
+		let pinned: HashSet<u32> = self.cache.lock().pinned_keys().iter()
+			.map(|key| Key::new(key, prefix_bits).prefix)
+			.collect();
+
+		let mut resident: Vec<_> = self.collisions.iter()
+			.filter(|&(prefix, collision)| collision.is_resident() && !pinned.contains(prefix))
+			.map(|(prefix, collision)| (collision.last_used(), *prefix))
+			.collect();
+		if resident.len() <= max_resident {
+			return;
+		}
+
+		resident.sort();
+		let evicted = resident.len() - max_resident;
+		for &(_, prefix) in &resident[..evicted] {
+			self.collisions.get_mut(&prefix).expect("prefix was taken from the collisions index; qed").evict();
+		}
+	}
+
+	/// Finds prefixes that have a number of collisions higher than the configured threshold and
+	/// moves all their data to a separate file (one file for each collided prefix). Returns a
+	/// vector of collided prefixes (empty if no collisions have been found).
+	pub fn compact(&mut self) -> Result<Vec<u32>> {
+		self.check_writable()?;
+		self.check_not_checkpointing()?;
+
+		let result = self.compact_internal();
+		match result {
+			Ok(_) => self.counters.compactions += 1,
+			Err(ref err) => {
+				self.errors.record(err);
+				self.errors.degraded = true;
+			},
+		}
+		result
+	}
+
+	fn compact_internal(&mut self) -> Result<Vec<u32>> {
+		let mut collision_files = Vec::new();
+		let mut collided_prefixes = Vec::new();
+
+		let (metadata, flush) = {
+			let collisions = self.collisions()?;
+
+			// create collision files and insert data from collided prefixes
+			for (prefix, keys) in collisions.iter() {
+				let mut collision_file = Collision::create(&self.path, *prefix, &self.options.external.value_len)?;
+
+				for key in keys {
+					// FIXME: store a reference to the value in the return Map from collisions
+					// journaled operations reach the collision file when they are flushed
+					let value = self.lookup_raw_in(&key, &mut ReadStats::default(), 0)?.expect("The key has been returned by the iterator; qed");
+					if self.transforms.has_overrides() {
+						// values are moved with the compression of their column
+						collision_file.insert(&key, &self.transforms.recode(&key, &value.to_vec())?)?;
+					} else {
+						collision_file.insert(&key, value.as_slice().unwrap_or(&value.to_vec()))?;
+					}
+				}
+				collision_file.flush()?;
+
+				collision_files.push(collision_file);
+				collided_prefixes.push(*prefix);
+			}
+
+			// clone metadata and update it with collided prefixes but don't persist it
+			let mut metadata = self.metadata.clone();
+			for prefix in collided_prefixes.iter() {
+				metadata.add_prefix_collision(*prefix);
+			}
+
+			// prepare flush to delete colliding keys but don't apply it
+			let deletions = collisions.values().flat_map(|ks| ks).map(|k| Operation::Delete(k));
+
+			let flush = Flush::new(
+				&self.path,
+				&self.options,
+				unsafe { self.mmap.as_slice() },
+				&metadata,
+				deletions)?;
+
+			(metadata, flush)
+		};
+
+		// persist metadata updated with collided prefixes
+		// if we crash after this the flush will be applied on restart and the metadata will
+		// already be properly updated
+		metadata.as_bytes().copy_to_slice(unsafe { self.metadata_mmap.as_mut_slice() });
+		self.metadata_mmap.flush()?;
+
+		// perform the flush and update metadata
+		flush.flush(unsafe { self.mmap.as_mut_slice() }, unsafe { self.metadata_mmap.as_mut_slice() }, &mut self.metadata);
+		flush_blocks(&mut self.mmap, &flush, self.options.external.block_size)?;
+		self.metadata_mmap.flush()?;
+		flush.delete()?;
+
+		// update collisions index
+		for collision_file in collision_files {
+			let prev = self.collisions.insert(collision_file.prefix(), collision_file);
+			assert!(prev.is_none());
+		}
+
+		self.evict_collision_indices();
+
+		Ok(collided_prefixes)
+	}
+}
+
+/// Writes blocks of the data file modified by `flush` to the disk.
+fn flush_blocks(mmap: &mut Mmap, flush: &Flush, block_size: usize) -> Result<()> {
+	for range in flush.dirty_ranges(block_size) {
+		let end = cmp::min(range.end, mmap.len());
+		mmap.flush_range(range.start, end - range.start)?;
+	}
+
+	Ok(())
+}
+
+/// Hard links `from` to `to`, copying the file if it can't be linked, e.g. across file systems.
+fn link_or_copy(from: &Path, to: &Path) -> Result<()> {
+	if fs::hard_link(from, to).is_err() {
+		fs::copy(from, to)?;
+	}
+
+	Ok(())
+}
+
+/// Returns the length of the value without copying it.
+fn value_len(value: &Value) -> usize {
+	match *value {
+		Value::Raw(slice) => slice.len(),
+		Value::Owned(ref vec) => vec.len(),
+		Value::Record(ref record) => record.value_len(),
+	}
+}
+
+/// Merges records from the data file and collision files ordered by key.
+fn merge_records<'a, R, C>(records: R, collided_records: C) -> Box<Iterator<Item=Result<(&'a [u8], Value<'a>)>> + 'a> where
+	R: Iterator<Item=::std::result::Result<Record<'a>, field::Error>> + 'a,
+	C: Iterator<Item=Result<(&'a [u8], &'a [u8])>> + 'a,
+{
+	Box::new(records.merge_join_by(collided_records, |r, c| {
+		match (r, c) {
+			(&Err(_), _) => Ordering::Less,
+			(_, &Err(_)) => Ordering::Greater,
+			(&Ok(ref r), &Ok(ref c)) => r.key().cmp(&c.0),
+		}
+	}).map(|either| {
+		match either {
+			EitherOrBoth::Left(Err(err)) => Err(err.into()),
+			EitherOrBoth::Right(Err(err)) => Err(err),
+			EitherOrBoth::Left(Ok(r)) => Ok((r.key(), Value::Record(r))),
+			EitherOrBoth::Right(Ok(c)) => Ok((c.0, Value::Raw(c.1))),
+			EitherOrBoth::Both(_, _) =>
+				unreachable!("value exists in collision file; \
+							  so cannot exist in data file; qed"),
+		}
+	}))
+}
+
+impl Drop for Database {
+	fn drop(&mut self) {
+		if let Some(ref lock_file) = self.lock_file {
+			let _ = lock_file.unlock();
+		}
+	}
+}
+
+#[derive(Debug)]
+enum IteratorValue<'a> {
+	None,
+	Journal(Operation<'a>),
+	DB((&'a [u8], Value<'a>)),
+}
+
+impl<'a> IteratorValue<'a> {
+	fn take(&mut self) -> Self {
+		::std::mem::replace(self, IteratorValue::None)
+	}
+}
+
+pub struct DatabaseIterator<'a> {
+	journal_iter: btree_set::IntoIter<Operation<'a>>,
+	record_collisions_iter: Box<Iterator<Item=Result<(&'a [u8], Value<'a>)>> + 'a>,
+	pending: IteratorValue<'a>,
+	latencies: &'a Latencies,
+	transforms: &'a ValueTransforms,
+}
+
+impl<'a> Iterator for DatabaseIterator<'a> {
+	type Item = Result<(&'a [u8], Value<'a>)>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		if !self.latencies.enabled() {
+			return self.next_decoded();
+		}
+
+		let start = Instant::now();
+		let item = self.next_decoded();
+		self.latencies.record_iter(start.elapsed());
+		item
+	}
+}
+
+/// Iterator over the database key-value pairs in descending key order.
+pub struct DatabaseRevIterator<'a> {
+	db: &'a Database,
+	contains: Box<Fn(&[u8]) -> bool + 'a>,
+	/// Journal operations of not yet visited prefixes ordered by key.
+	journal: Vec<Operation<'a>>,
+	first: u32,
+	next_prefix: Option<u32>,
+	/// Items of the current prefix ordered by key.
+	buffer: Vec<Result<(&'a [u8], Value<'a>)>>,
+}
+
+impl<'a> Iterator for DatabaseRevIterator<'a> {
+	type Item = Result<(&'a [u8], Value<'a>)>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		let latencies = &self.db.latencies;
+		if !latencies.enabled() {
+			return self.next_decoded();
+		}
+
+		let start = Instant::now();
+		let item = self.next_decoded();
+		latencies.record_iter(start.elapsed());
+		item
+	}
+}
+
+impl<'a> DatabaseRevIterator<'a> {
+	fn next_decoded(&mut self) -> Option<Result<(&'a [u8], Value<'a>)>> {
+		loop {
+			match self.buffer.pop() {
+				Some(Ok((key, value))) => {
+					if !(self.contains)(key) {
+						continue;
+					}
+
+					return Some(self.db.transforms.decode_value(value).map(|value| (key, value)));
+				},
+				Some(Err(err)) => return Some(Err(err)),
+				None => {},
+			}
+
+			let prefix = match self.next_prefix {
+				Some(prefix) => prefix,
+				None => return None,
+			};
+			self.next_prefix = if prefix > self.first { Some(prefix - 1) } else { None };
+
+			if let Err(err) = self.fill(prefix) {
+				return Some(Err(err));
+			}
+		}
+	}
+
+	/// Buffers the items of index prefix `prefix`.
+	fn fill(&mut self, prefix: u32) -> Result<()> {
+		let db = self.db;
+		let prefix_bits = db.options.external.key_index_bits;
+
+		// keys of a prefix are adjacent in the key order
+		let mut journal_ops = BTreeSet::new();
+		while self.journal.last().map_or(false, |op| Key::new(op.key(), prefix_bits).prefix == prefix) {
+			journal_ops.insert(self.journal.pop().expect("last operation exists; qed"));
+		}
+
+		let stored = db.metadata.prefixes.has(prefix).unwrap_or(false) || db.collisions.contains_key(&prefix);
+		if journal_ops.is_empty() && !stored {
+			return Ok(());
+		}
+
+		let record_collisions_iter = db.record_collisions_iter_filtered(prefix, prefix, move |key: &[u8]| {
+			Key::new(key, prefix_bits).prefix == prefix
+		})?;
+
+		let mut iter = DatabaseIterator {
+			record_collisions_iter,
+			journal_iter: journal_ops.into_iter(),
+			pending: IteratorValue::None,
+			latencies: &db.latencies,
+			transforms: &db.transforms,
+		};
+
+		while let Some(item) = iter.next_item() {
+			self.buffer.push(item);
+		}
+
+		Ok(())
+	}
+}
+
+impl<'a> DatabaseIterator<'a> {
+	fn next_decoded(&mut self) -> Option<Result<(&'a [u8], Value<'a>)>> {
+		let transforms = self.transforms;
+		self.next_item().map(|item| item.and_then(|(key, value)| {
+			Ok((key, transforms.decode_value(value)?))
+		}))
+	}
+
+	fn next_item(&mut self) -> Option<Result<(&'a [u8], Value<'a>)>> {
+		loop {
+			let (operation, record) = match self.pending.take() {
+				IteratorValue::None => {
+					let j = self.journal_iter.next().map_or(IteratorValue::None, IteratorValue::Journal);
+					let db = match self.record_collisions_iter.next() {
+						None => IteratorValue::None,
+						Some(Ok(r)) => IteratorValue::DB(r),
+						Some(Err(err)) => {
+							self.pending = j;
+							return Some(Err(err.into()));
+						},
+					};
+
+					(j, db)
+				},
+				j @ IteratorValue::Journal(_) => {
+					let db = match self.record_collisions_iter.next() {
+						None => IteratorValue::None,
+						Some(Ok(r)) => IteratorValue::DB(r),
+						Some(Err(err)) => {
+							self.pending = j;
+							return Some(Err(err.into()));
+						},
+					};
+
+					(j, db)
+				},
+				db @ IteratorValue::DB(_) => {
+					let j = self.journal_iter.next().map_or(IteratorValue::None, IteratorValue::Journal);
+
+					(j, db)
+				},
+			};
+
+			#[inline]
+			// returns `None` if the operation is a `Delete` and we should skip to the next value
+			fn handle_journal_operation<'a>(o: Operation<'a>) -> Option<Result<(&'a [u8], Value<'a>)>> {
+				match o {
+					Operation::Delete(_) => {
+						None
+					},
+					Operation::Insert(key, value) => {
+						Some(Ok((key, Value::Raw(value))))
+					},
+				}
+			}
+
+			match (operation, record) {
+				(IteratorValue::Journal(o), IteratorValue::None) => {
+					match handle_journal_operation(o) {
+						None => continue,
+						s => return s,
+					};
+				},
+				(IteratorValue::None, IteratorValue::DB(r)) => {
+					return Some(Ok(r))
+				},
+				(IteratorValue::Journal(o), IteratorValue::DB(r)) => {
+					let ord = (*r.0).partial_cmp(o.key()).expect(
+						"only returns None when compared keys don't have the same size; \
+						 all keys should have the same size; qed");
+
+					match ord {
+						Ordering::Equal => {
+							match handle_journal_operation(o) {
+								None => continue,
+								s => return s,
+							};
+						},
+						Ordering::Greater => {
+							self.pending = IteratorValue::DB(r);
+
+							match handle_journal_operation(o) {
+								None => continue,
+								s => return s,
+							};
+						},
+						Ordering::Less => {
+							self.pending = IteratorValue::Journal(o);
+							return Some(Ok(r))
+						},
+					};
+				},
+				(IteratorValue::None, IteratorValue::None) => return None,
+				(o, r) => unreachable!("operation: {:?}, record: {:?}", o, r)
+			};
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	extern crate tempdir;
+
+	use std::fs::File;
+	use std::io::{Read, Write};
+	use std::time::{Duration, Instant};
+
+	use super::{Database, FilterDecision, IterationOrder, OpenPhase, OpenProgress, Options, ShutdownReport};
+	use diff::Change;
+	use options::ValuesLen;
+	use error::{ErrorKind, Result};
+	use transaction::Operation;
+	use quickcheck::TestResult;
+
+	#[test]
+	fn create_insert_and_query() {
+		let temp = tempdir::TempDir::new("create_insert_and_query").unwrap();
+
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		}).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("abc", "xyz").unwrap();
+		tx.insert("cde", "123").unwrap();
+
+		db.commit(&tx).unwrap();
+
+		assert_eq!(db.get("abc").unwrap().unwrap(), b"xyz");
+		assert_eq!(db.get("cde").unwrap().unwrap(), b"123");
+
+		// Another transaction
+		let mut tx = db.create_transaction();
+		tx.insert("abc", "456").unwrap();
+		tx.delete("cde").unwrap();
+
+		db.commit(&tx).unwrap();
+
+		assert_eq!(db.get("abc").unwrap().unwrap(), b"456");
+		assert_eq!(db.get("cde").unwrap(), None); // from DB
+
+		// Flush journal and fetch everything from DB.
+		db.flush_journal(2).unwrap();
+
+		assert_eq!(db.get("abc").unwrap().unwrap(), b"456");
+		assert_eq!(db.get("cde").unwrap(), None);
+	}
+
+	#[test]
+	fn validate_key_length_at_insert() {
+		let temp = tempdir::TempDir::new("validate_key_length").unwrap();
+
+		let db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			..Default::default()
+		}).unwrap();
+
+		let mut tx = db.create_transaction();
+		assert_eq!(*tx.insert("abcdef", "456").unwrap_err().kind(), ErrorKind::InvalidKeyLen(3, 6));
+	}
+
+	#[test]
+	fn should_validate_key_length() {
+		let temp = tempdir::TempDir::new("create_insert_and_query").unwrap();
+
+		let db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			..Default::default()
+		}).unwrap();
+
+		assert_eq!(*db.get("a").unwrap_err().kind(), ErrorKind::InvalidKeyLen(3, 1));
+	}
+
+	#[test]
+	fn test_multi_get() {
+		let temp = tempdir::TempDir::new("test_multi_get").unwrap();
+
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 1,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		}).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("abc", "001").unwrap();
+		tx.insert("def", "002").unwrap();
+		db.commit(&tx).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("ghi", "003").unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+
+		// "abc" and "def" come from the data file, "ghi" from the journal
+		let values = db.multi_get(&["ghi", "xyz", "abc", "def", "abc"]).unwrap();
+		assert_eq!(values.len(), 5);
+		assert_eq!(values[0].as_ref().unwrap(), b"003");
+		assert_eq!(values[1], None);
+		assert_eq!(values[2].as_ref().unwrap(), b"001");
+		assert_eq!(values[3].as_ref().unwrap(), b"002");
+		assert_eq!(values[4].as_ref().unwrap(), b"001");
+
+		assert_eq!(*db.multi_get(&["abc", "a"]).unwrap_err().kind(), ErrorKind::InvalidKeyLen(3, 1));
+	}
+
+	#[test]
+	fn test_get_many() {
+		let temp = tempdir::TempDir::new("test_get_many").unwrap();
+
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 1,
+			key_len: 3,
+			key_index_bits: 8,
+			value_len: ValuesLen::Constant(3),
+			max_prefix_collisions: 2,
+			..Default::default()
+		}).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("aaa", "001").unwrap();
+		tx.insert("aab", "002").unwrap();
+		tx.insert("bbb", "003").unwrap();
+		db.commit(&tx).unwrap();
+		let mut tx = db.create_transaction();
+		tx.insert("ccc", "004").unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.compact().unwrap(), vec![b'a' as u32]);
+
+		// "aaa" and "aab" come from the collision file, "bbb" from the data file, "ccc" from the journal
+		let keys = vec![b"ccc".to_vec(), b"bbb".to_vec(), b"xyz".to_vec(), b"aab".to_vec(), b"aaa".to_vec(), b"ccc".to_vec()];
+		let values = db.get_many(keys.iter()).unwrap();
+		let values: Vec<_> = values.into_iter().map(|value| value.map(|value| value.to_vec())).collect();
+		assert_eq!(values, vec![
+			Some(b"004".to_vec()),
+			Some(b"003".to_vec()),
+			None,
+			Some(b"002".to_vec()),
+			Some(b"001".to_vec()),
+			Some(b"004".to_vec()),
+		]);
+		let multi: Vec<_> = db.multi_get(&keys).unwrap().into_iter().map(|value| value.map(|value| value.to_vec())).collect();
+		assert_eq!(multi, values);
+
+		assert!(db.get_many(Vec::<Vec<u8>>::new()).unwrap().is_empty());
+		assert_eq!(*db.get_many(vec!["abc", "a"]).unwrap_err().kind(), ErrorKind::InvalidKeyLen(3, 1));
+	}
+
+	#[test]
+	fn test_contains_many() {
+		let temp = tempdir::TempDir::new("test_contains_many").unwrap();
+
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 1,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			max_prefix_collisions: 2,
+			..Default::default()
+		}).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("aaa", "001").unwrap();
+		tx.insert("aab", "002").unwrap();
+		tx.insert("bbb", "003").unwrap();
+		tx.insert("ccc", "004").unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(0).unwrap();
+		let mut tx = db.create_transaction();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.compact().unwrap(), vec![b'a' as u32]);
+
+		tx = db.create_transaction();
+		tx.delete("ccc").unwrap();
+		tx.insert("ddd", "005").unwrap();
+		db.commit(&tx).unwrap();
+
+		// `ccc` is deleted in the journal, but still present in the data file
+		let keys = ["aaa", "aac", "bbb", "ccc", "ddd", "eee"];
+		let contained = db.contains_many(&keys).unwrap();
+		assert_eq!(contained.iter().collect::<Vec<_>>(), vec![true, false, true, false, true, false]);
+		assert!(db.contains_many(&["aaaa"]).is_err());
+	}
+
+	#[test]
+	fn test_journaled_delete_shadows_flushed_value() {
+		let temp = tempdir::TempDir::new("test_journaled_delete_shadows_flushed_value").unwrap();
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 1,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		}).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("ccc", "004").unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(0).unwrap();
+		let tx = db.create_transaction();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.delete("ccc").unwrap();
+		db.commit(&tx).unwrap();
+		assert_eq!(db.get("ccc").unwrap(), None);
+		assert!(!db.contains("ccc").unwrap());
+	}
+
+	#[test]
+	fn test_apply_journal_segment() {
+		let temp = tempdir::TempDir::new("test_apply_journal_segment").unwrap();
+		let options = || Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		};
+		let mut primary = Database::create(temp.path().join("primary"), options()).unwrap();
+		let mut replica = Database::create(temp.path().join("replica"), options()).unwrap();
+
+		let mut tx = primary.create_transaction();
+		tx.insert("aaa", "001").unwrap();
+		tx.insert("bbb", "002").unwrap();
+		primary.commit(&tx).unwrap();
+		let mut tx = primary.create_transaction();
+		tx.delete("aaa").unwrap();
+		primary.commit(&tx).unwrap();
+
+		for sequence in 0..primary.next_sequence() {
+			let segment = primary.journal_segment(sequence).unwrap();
+			replica.apply_journal_segment(&segment).unwrap();
+		}
+
+		assert_eq!(replica.get("aaa").unwrap(), None);
+		assert_eq!(replica.get("bbb").unwrap().unwrap(), "002");
+		assert_eq!(replica.next_sequence(), 2);
+
+		let mut segment = primary.journal_segment(0).unwrap();
+		let last = segment.len() - 1;
+		segment[last] ^= 1;
+		let err = replica.apply_journal_segment(&segment).unwrap_err();
+		assert!(matches!(*err.kind(), ErrorKind::InvalidJournalSegment(_)));
+	}
+
+	#[test]
+	fn test_validate() {
+		let temp = tempdir::TempDir::new("test_validate").unwrap();
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		}).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("aaa", "001").unwrap();
+		tx.delete("bbb").unwrap();
+		db.validate(&tx).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("aaa", "0001").unwrap();
+		assert_eq!(*db.validate(&tx).unwrap_err().kind(), ErrorKind::InvalidValueLen(3, 4));
+		assert_eq!(*db.commit(&tx).unwrap_err().kind(), ErrorKind::InvalidValueLen(3, 4));
+
+		let other_temp = tempdir::TempDir::new("test_validate_other").unwrap();
+		let mut other = Database::create(other_temp.path(), Options {
+			journal_eras: 0,
+			key_len: 4,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		}).unwrap();
+		let mut tx = other.create_transaction();
+		tx.insert("aaaa", "001").unwrap();
+		assert_eq!(*db.validate(&tx).unwrap_err().kind(), ErrorKind::InvalidKeyLen(3, 4));
+		other.commit(&tx).unwrap();
+
+		assert_eq!(db.next_sequence(), 0);
+	}
+
+	#[test]
+	fn test_audit_hook() {
+		use std::sync::{Arc, Mutex};
+
+		let temp = tempdir::TempDir::new("test_audit_hook").unwrap();
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		}).unwrap();
+
+		let audited = Arc::new(Mutex::new(Vec::new()));
+		let hook_audited = audited.clone();
+		db.set_audit_hook(move |record| {
+			if record.transaction.operations().any(|op| op.key() == b"bad") {
+				return Err("rejected by audit".into());
+			}
+
+			let keys: Vec<_> = record.transaction.operations().map(|op| op.key().to_vec()).collect();
+			hook_audited.lock().unwrap().push((record.sequence, keys));
+			Ok(())
+		});
+
+		let mut tx = db.create_transaction();
+		tx.insert("aaa", "001").unwrap();
+		tx.delete("bbb").unwrap();
+		db.commit(&tx).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("bad", "002").unwrap();
+		assert!(db.commit(&tx).is_err());
+		assert_eq!(db.get("bad").unwrap(), None);
+
+		assert_eq!(*audited.lock().unwrap(), vec![(0, vec![b"aaa".to_vec(), b"bbb".to_vec()])]);
+	}
+
+	#[test]
+	fn test_open_read_only() {
+		use std::collections::BTreeMap;
+
+		fn read_files(dir: &::std::path::Path) -> BTreeMap<::std::ffi::OsString, Vec<u8>> {
+			::std::fs::read_dir(dir).unwrap().map(|entry| {
+				let entry = entry.unwrap();
+				let mut data = Vec::new();
+				File::open(entry.path()).unwrap().read_to_end(&mut data).unwrap();
+				(entry.file_name(), data)
+			}).collect()
+		}
+
+		let temp = tempdir::TempDir::new("test_open_read_only").unwrap();
+		let options = Options {
+			journal_eras: 1,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			stats_retention: 4,
+			..Default::default()
+		};
+
+		{
+			let mut db = Database::create(temp.path(), options.clone()).unwrap();
+			let mut tx = db.create_transaction();
+			tx.insert("abc", "001").unwrap();
+			db.commit(&tx).unwrap();
+			db.flush_journal(None).unwrap();
+
+			let mut tx = db.create_transaction();
+			tx.insert("def", "002").unwrap();
+			tx.delete("abc").unwrap();
+			db.commit(&tx).unwrap();
+		}
+
+		let files = read_files(temp.path());
+		{
+			let mut db = Database::open_read_only(temp.path(), options.clone()).unwrap();
+			assert!(db.is_read_only());
+			assert_eq!(db.get("abc").unwrap(), None);
+			assert_eq!(db.get("def").unwrap().unwrap(), b"002");
+			assert_eq!(db.iter().unwrap().count(), 1);
+
+			let mut tx = db.create_transaction();
+			tx.insert("ghi", "003").unwrap();
+			assert_eq!(*db.commit(&tx).unwrap_err().kind(), ErrorKind::ReadOnly);
+			assert_eq!(*db.flush_journal(None).unwrap_err().kind(), ErrorKind::ReadOnly);
+			assert_eq!(*db.compact().unwrap_err().kind(), ErrorKind::ReadOnly);
+		}
+		assert_eq!(read_files(temp.path()), files);
+
+		// the lock is not taken
+		let _db = Database::open_read_only(temp.path(), options.clone()).unwrap();
+		let db = Database::open(temp.path(), options).unwrap();
+		assert!(!db.is_read_only());
+		assert_eq!(db.get("def").unwrap().unwrap(), b"002");
+	}
+
+	#[test]
+	fn test_triggers() {
+		let temp = tempdir::TempDir::new("test_triggers").unwrap();
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 1,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		}).unwrap();
+
+		// finalized headers are never overwritten or deleted
+		db.add_trigger("h", |operation, current| match (operation, current) {
+			(&Operation::Insert(_, value), Some(current)) if value != current => Err("header is finalized".into()),
+			(&Operation::Delete(_), Some(_)) => Err("header is finalized".into()),
+			_ => Ok(()),
+		});
+
+		let mut tx = db.create_transaction();
+		tx.insert("h01", "aaa").unwrap();
+		tx.insert("b01", "aaa").unwrap();
+		db.commit(&tx).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("b01", "bbb").unwrap();
+		tx.insert("h01", "bbb").unwrap();
+		assert_eq!(*db.commit(&tx).unwrap_err().kind(), ErrorKind::CommitRejected(b"h01".to_vec(), "header is finalized".into()));
+		assert_eq!(db.get("b01").unwrap().unwrap(), b"aaa");
+
+		// the value is read from the data file after the flush
+		db.flush_journal(None).unwrap();
+		let mut tx = db.create_transaction();
+		tx.delete("h01").unwrap();
+		assert!(db.validate(&tx).is_err());
+
+		let mut tx = db.create_transaction();
+		tx.insert("h01", "aaa").unwrap();
+		tx.insert("h02", "bbb").unwrap();
+		tx.delete("b01").unwrap();
+		db.commit(&tx).unwrap();
+
+		db.clear_triggers();
+		let mut tx = db.create_transaction();
+		tx.delete("h01").unwrap();
+		db.commit(&tx).unwrap();
+		assert_eq!(db.get("h01").unwrap(), None);
+	}
+
+	#[test]
+	fn test_compact_with_filter() {
+		let temp = tempdir::TempDir::new("test_compact_with_filter").unwrap();
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		}).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("aaa", "001").unwrap();
+		tx.insert("bbb", "002").unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("ccc", "003").unwrap();
+		db.commit(&tx).unwrap();
+
+		let mut seen = Vec::new();
+		db.compact_with_filter(|key, value| {
+			seen.push(key.to_vec());
+			match key {
+				b"aaa" => FilterDecision::Remove,
+				b"ccc" => FilterDecision::Change(value.iter().map(|byte| byte + 1).collect()),
+				_ => FilterDecision::Keep,
+			}
+		}).unwrap();
+
+		assert_eq!(seen, vec![b"aaa".to_vec(), b"bbb".to_vec(), b"ccc".to_vec()]);
+		assert_eq!(db.get("aaa").unwrap(), None);
+		assert_eq!(db.get("bbb").unwrap().unwrap(), "002");
+		assert_eq!(db.get("ccc").unwrap().unwrap(), "114");
+
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.get("aaa").unwrap(), None);
+		assert_eq!(db.get("ccc").unwrap().unwrap(), "114");
+	}
+
+	#[test]
+	fn test_compact_with_filter_batches() {
+		let temp = tempdir::TempDir::new("test_compact_with_filter_batches").unwrap();
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		}).unwrap();
+
+		let mut tx = db.create_transaction();
+		for key in &["aaa", "bbb", "ccc", "ddd"] {
+			tx.insert(key, "001").unwrap();
+		}
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+		let sequence = db.next_sequence();
+
+		// every decision is committed on its own and every record is seen once
+		let mut seen = Vec::new();
+		db.compact_with_filter_batched(|key, _| {
+			seen.push(key.to_vec());
+			match key {
+				b"bbb" => FilterDecision::Keep,
+				b"ccc" => FilterDecision::Change(b"002".to_vec()),
+				_ => FilterDecision::Remove,
+			}
+		}, 1).unwrap();
+
+		assert_eq!(seen, vec![b"aaa".to_vec(), b"bbb".to_vec(), b"ccc".to_vec(), b"ddd".to_vec()]);
+		assert_eq!(db.next_sequence(), sequence + 3);
+		assert_eq!(db.journal.len(), 0);
+		let keys: Vec<_> = db.iter().unwrap().map(|item| item.unwrap().0.to_vec()).collect();
+		assert_eq!(keys, vec![b"bbb".to_vec(), b"ccc".to_vec()]);
+		assert_eq!(db.get("ccc").unwrap().unwrap(), "002");
+	}
+
+	#[test]
+	fn test_occupancy() {
+		let temp = tempdir::TempDir::new("test_occupancy").unwrap();
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		}).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("abc", "001").unwrap();
+		tx.insert("abd", "002").unwrap();
+		db.commit(&tx).unwrap();
+		assert!(db.occupancy(0..512).none());
+
+		db.flush_journal(None).unwrap();
+		// both records have prefix 97, the second one is stored in the next field
+		let occupancy = db.occupancy(96..100);
+		assert_eq!(occupancy.iter().collect::<Vec<_>>(), vec![false, true, true, false]);
+		assert_eq!(db.occupancy(0..u32::max_value()).iter().filter(|occupied| *occupied).count(), 2);
+	}
+
+	#[test]
+	fn test_history() {
+		let temp = tempdir::TempDir::new("test_history").unwrap();
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 1,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			archive_journal: true,
+			..Default::default()
+		}).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("aaa", "001").unwrap();
+		tx.insert("bbb", "002").unwrap();
+		db.commit(&tx).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("bbb", "003").unwrap();
+		db.commit(&tx).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.delete("aaa").unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+
+		assert_eq!(db.history("aaa").unwrap(), vec![(0, Some(b"001".to_vec())), (2, None)]);
+		assert_eq!(db.history("bbb").unwrap(), vec![(0, Some(b"002".to_vec())), (1, Some(b"003".to_vec()))]);
+		assert_eq!(db.history("ccc").unwrap(), vec![]);
+	}
+
+	#[test]
+	fn test_commit_fenced() {
+		let temp = tempdir::TempDir::new("test_commit_fenced").unwrap();
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		}).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("aaa", "001").unwrap();
+
+		assert_eq!(db.fencing_epoch().unwrap(), 0);
+		db.commit_fenced(&tx, 0).unwrap();
+
+		db.advance_fencing_epoch(2).unwrap();
+		assert_eq!(*db.advance_fencing_epoch(2).unwrap_err().kind(), ErrorKind::FencedOff(2, 2));
+		assert_eq!(*db.commit_fenced(&tx, 1).unwrap_err().kind(), ErrorKind::FencedOff(1, 2));
+		db.commit_fenced(&tx, 2).unwrap();
+		db.commit_fenced(&tx, 3).unwrap();
+		assert_eq!(db.fencing_epoch().unwrap(), 2);
+	}
+
+	#[test]
+	fn test_export_snapshot() {
+		let temp = tempdir::TempDir::new("test_export_snapshot").unwrap();
+		let options = || Options {
+			journal_eras: 1,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		};
+
+		let mut db = Database::create(temp.path().join("db"), options()).unwrap();
+		for &(key, value) in &[("aaa", "001"), ("bbb", "002")] {
+			let mut tx = db.create_transaction();
+			tx.insert(key, value).unwrap();
+			db.commit(&tx).unwrap();
+		}
+		db.flush_journal(None).unwrap();
+		db.advance_fencing_epoch(3).unwrap();
+
+		let snapshot = temp.path().join("snapshot");
+		db.export_snapshot(&snapshot).unwrap();
+		Database::verify_snapshot(&snapshot).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("ccc", "003").unwrap();
+		db.commit(&tx).unwrap();
+
+		let exported = Database::open(&snapshot, options()).unwrap();
+		assert_eq!(exported.get("aaa").unwrap().unwrap(), "001");
+		assert_eq!(exported.get("bbb").unwrap().unwrap(), "002");
+		assert_eq!(exported.get("ccc").unwrap(), None);
+		assert_eq!(exported.fencing_epoch().unwrap(), 3);
+	}
+
+	#[test]
+	fn test_snapshot() {
+		let temp = tempdir::TempDir::new("test_snapshot").unwrap();
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		}).unwrap();
+
+		let commit = |db: &mut Database, key: &str, value: Option<&str>| {
+			let mut tx = db.create_transaction();
+			match value {
+				Some(value) => tx.insert(key, value).unwrap(),
+				None => tx.delete(key).unwrap(),
+			}
+			db.commit(&tx).unwrap();
+		};
+
+		commit(&mut db, "aaa", Some("001"));
+		commit(&mut db, "bbb", Some("002"));
+		db.flush_journal(1).unwrap();
+
+		let snapshot = db.snapshot();
+		assert_eq!(snapshot.sequence(), 2);
+		commit(&mut db, "aaa", Some("003"));
+		commit(&mut db, "bbb", None);
+		commit(&mut db, "ccc", Some("004"));
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.journal.len(), 3);
+
+		assert_eq!(snapshot.get(&db, "aaa").unwrap().unwrap(), "001");
+		assert_eq!(snapshot.get(&db, "bbb").unwrap().unwrap(), "002");
+		assert_eq!(snapshot.get(&db, "ccc").unwrap(), None);
+		assert_eq!(db.get("aaa").unwrap().unwrap(), "003");
+
+		let keys: Vec<_> = snapshot.iter(&db).unwrap().map(|item| item.unwrap().0.to_vec()).collect();
+		assert_eq!(keys, vec![b"aaa".to_vec(), b"bbb".to_vec()]);
+
+		drop(snapshot);
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.journal.len(), 0);
+		assert_eq!(db.get("bbb").unwrap(), None);
+
+		// snapshots can only be read from the database they were taken of
+		let other_temp = tempdir::TempDir::new("test_snapshot_other").unwrap();
+		let other = Database::create(other_temp.path(), Options {
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		}).unwrap();
+		let snapshot = other.snapshot();
+		let err = snapshot.get(&db, "aaa").unwrap_err();
+		assert_eq!(*err.kind(), ErrorKind::InvalidSnapshot(temp.path().into(), "Snapshot was taken of another database".into()));
+		assert!(snapshot.iter(&db).is_err());
+	}
+
+	#[test]
+	fn test_read_tx() {
+		let temp = tempdir::TempDir::new("test_read_tx").unwrap();
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 1,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		}).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("aaa", "001").unwrap();
+		tx.insert("abb", "002").unwrap();
+		db.commit(&tx).unwrap();
+
+		{
+			let read = db.read_tx();
+			assert_eq!(read.sequence(), 1);
+			assert_eq!(read.get("aaa").unwrap().unwrap(), "001");
+			assert!(read.contains("abb").unwrap());
+			assert_eq!(read.iter().unwrap().count(), 2);
+			assert_eq!(read.iter_prefix(b"ab").unwrap().count(), 1);
+		}
+
+		let mut tx = db.create_transaction();
+		tx.delete("aaa").unwrap();
+		db.commit(&tx).unwrap();
+		assert_eq!(db.read_tx().sequence(), 2);
+		assert_eq!(db.read_tx().get("aaa").unwrap(), None);
+	}
+
+	#[test]
+	fn test_test_clone() {
+		let temp = tempdir::TempDir::new("test_test_clone").unwrap();
+		let options = || Options {
+			journal_eras: 1,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		};
+
+		let mut db = Database::create(temp.path().join("db"), options()).unwrap();
+		for &(key, value) in &[("aaa", "001"), ("bbb", "002")] {
+			let mut tx = db.create_transaction();
+			tx.insert(key, value).unwrap();
+			db.commit(&tx).unwrap();
+		}
+		db.flush_journal(None).unwrap();
+
+		let mut clone = db.test_clone(temp.path().join("clone")).unwrap();
+		assert_eq!(clone.get("aaa").unwrap().unwrap(), "001");
+		assert_eq!(clone.get("bbb").unwrap().unwrap(), "002");
+		assert_eq!(clone.next_sequence(), db.next_sequence());
+
+		for &(key, value) in &[("aaa", "003"), ("ccc", "004")] {
+			let mut tx = clone.create_transaction();
+			tx.insert(key, value).unwrap();
+			clone.commit(&tx).unwrap();
+		}
+		clone.flush_journal(None).unwrap();
+		assert_eq!(clone.get("aaa").unwrap().unwrap(), "003");
+
+		assert_eq!(db.get("aaa").unwrap().unwrap(), "001");
+		assert_eq!(db.get("bbb").unwrap().unwrap(), "002");
+		assert_eq!(db.get("ccc").unwrap(), None);
+	}
+
+	#[test]
+	fn test_checkpoint() {
+		use std::thread;
+
+		let temp = tempdir::TempDir::new("test_checkpoint").unwrap();
+		let options = || Options {
+			journal_eras: 1,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		};
+
+		let mut db = Database::create(temp.path().join("db"), options()).unwrap();
+		for &(key, value) in &[("aaa", "001"), ("bbb", "002"), ("ccc", "003")] {
+			let mut tx = db.create_transaction();
+			tx.insert(key, value).unwrap();
+			db.commit(&tx).unwrap();
+		}
+		db.flush_journal(1).unwrap();
+
+		let checkpoint = db.begin_checkpoint(temp.path().join("checkpoint")).unwrap();
+		let finished = thread::spawn(move || checkpoint.finish());
+
+		// commits continue, while flushes wait for the checkpoint
+		let mut tx = db.create_transaction();
+		tx.insert("aaa", "004").unwrap();
+		db.commit(&tx).unwrap();
+		finished.join().unwrap().unwrap();
+
+		let checkpoint = db.begin_checkpoint(temp.path().join("pinned")).unwrap();
+		let journaled = db.journal.len();
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.journal.len(), journaled);
+		assert_eq!(*db.compact().unwrap_err().kind(), ErrorKind::CheckpointInProgress);
+		drop(checkpoint);
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.journal.len(), 1);
+
+		assert_eq!(
+			*db.checkpoint(temp.path().join("checkpoint")).unwrap_err().kind(),
+			ErrorKind::CheckpointExists(temp.path().join("checkpoint"))
+		);
+		// an unfinished checkpoint is not a database
+		assert!(Database::open(temp.path().join("pinned"), options()).is_err());
+
+		let restored = Database::open(temp.path().join("checkpoint"), options()).unwrap();
+		assert_eq!(restored.get("aaa").unwrap().unwrap(), "001");
+		assert_eq!(restored.get("bbb").unwrap().unwrap(), "002");
+		assert_eq!(restored.get("ccc").unwrap().unwrap(), "003");
+		assert_eq!(restored.next_sequence(), db.next_sequence() - 1);
+		assert_eq!(db.get("aaa").unwrap().unwrap(), "004");
+	}
+
+	#[test]
+	fn test_export_import() {
+		use options::Compression;
+
+		let temp = tempdir::TempDir::new("test_export_import").unwrap();
+		let options = |compression| Options {
+			journal_eras: 1,
+			key_len: 3,
+			value_len: ValuesLen::Variable { expected: 16 },
+			compression,
+			..Default::default()
+		};
+
+		let long = vec![b'a'; 300];
+		let mut db = Database::create(temp.path().join("db"), options(Compression::Lz4)).unwrap();
+		let mut tx = db.create_transaction();
+		tx.insert("aaa", &long).unwrap();
+		tx.insert("bbb", "002").unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+		let mut tx = db.create_transaction();
+		tx.insert("ccc", "003").unwrap();
+		tx.delete("bbb").unwrap();
+		db.commit(&tx).unwrap();
+
+		let mut dump = Vec::new();
+		assert_eq!(db.export(&mut dump).unwrap(), 2);
+
+		// values are dumped uncompressed, so they are imported into a database without compression
+		let mut imported = Database::create(temp.path().join("imported"), options(Compression::None)).unwrap();
+		assert_eq!(imported.import(&dump[..]).unwrap(), 2);
+		assert!(imported.health().journal_eras <= 1);
+		let items = imported.iter().unwrap().map(|item| {
+			let (key, value) = item.unwrap();
+			(key.to_vec(), value.to_vec())
+		}).collect::<Vec<_>>();
+		assert_eq!(items, vec![(b"aaa".to_vec(), long), (b"ccc".to_vec(), b"003".to_vec())]);
+
+		let mut other = Database::create(temp.path().join("other"), Options {
+			key_len: 4,
+			..options(Compression::None)
+		}).unwrap();
+		assert_eq!(*other.import(&dump[..]).unwrap_err().kind(), ErrorKind::InvalidKeyLen(4, 3));
+	}
+
+	#[test]
+	fn test_idempotency_keys() {
+		let temp = tempdir::TempDir::new("test_idempotency_keys").unwrap();
+		let options = Options {
+			journal_eras: 1,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		};
+
+		let mut db = Database::create(temp.path().join("db"), options.clone()).unwrap();
+		let mut tx = db.create_transaction();
+		tx.insert("abc", "001").unwrap();
+		tx.set_idempotency_key("offset-1");
+		db.commit(&tx).unwrap();
+
+		// a retried batch is not applied again
+		let mut retry = db.create_transaction();
+		retry.insert("abc", "002").unwrap();
+		retry.set_idempotency_key("offset-1");
+		db.commit(&retry).unwrap();
+		assert_eq!(db.get("abc").unwrap().unwrap(), b"001");
+
+		let mut tx = db.create_transaction();
+		tx.insert("cde", "003").unwrap();
+		tx.set_idempotency_key("offset-2");
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+		assert!(db.contains_idempotency_key("offset-1"));
+		assert!(!db.contains_idempotency_key("offset-3"));
+
+		db.checkpoint(temp.path().join("checkpoint")).unwrap();
+		drop(db);
+
+		// keys survive a restart, also after their eras were flushed
+		let mut db = Database::open(temp.path().join("db"), options.clone()).unwrap();
+		assert!(db.contains_idempotency_key("offset-1"));
+		assert!(db.contains_idempotency_key("offset-2"));
+		db.commit(&retry).unwrap();
+		assert_eq!(db.get("abc").unwrap().unwrap(), b"001");
+
+		let checkpoint = Database::open(temp.path().join("checkpoint"), options.clone()).unwrap();
+		assert!(checkpoint.contains_idempotency_key("offset-2"));
+
+		let mut disabled = Database::create(temp.path().join("disabled"), Options {
+			idempotency_window: 0,
+			..options
+		}).unwrap();
+		assert!(matches!(*disabled.commit(&retry).unwrap_err().kind(), ErrorKind::InvalidOptions("idempotency_window", _)));
+		assert_eq!(disabled.get("abc").unwrap(), None);
+	}
+
+	#[test]
+	fn test_try_recover() {
+		let temp = tempdir::TempDir::new("test_try_recover").unwrap();
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 1,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		}).unwrap();
+
+		for &(key, value) in &[("aaa", "001"), ("bbb", "002")] {
+			let mut tx = db.create_transaction();
+			tx.insert(key, value).unwrap();
+			db.commit(&tx).unwrap();
+		}
+		db.flush_journal(None).unwrap();
+
+		db.try_recover().unwrap();
+		assert!(db.health().is_healthy());
+		assert_eq!(db.health().journal_eras, 1);
+		assert_eq!(db.get("aaa").unwrap().unwrap(), "001");
+		assert_eq!(db.get("bbb").unwrap().unwrap(), "002");
+
+		let mut tx = db.create_transaction();
+		tx.insert("ccc", "003").unwrap();
+		db.commit(&tx).unwrap();
+		assert_eq!(db.get("ccc").unwrap().unwrap(), "003");
+	}
+
+	#[test]
+	fn test_shutdown() {
+		let temp = tempdir::TempDir::new("test_shutdown").unwrap();
+		let options = || Options {
+			journal_eras: 1,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		};
+
+		fn commit_values(db: &mut Database) {
+			for key in &["aaa", "bbb", "ccc"] {
+				let mut tx = db.create_transaction();
+				tx.insert(key, "001").unwrap();
+				db.commit(&tx).unwrap();
+			}
+		}
+
+		let mut db = Database::create(temp.path(), options()).unwrap();
+		commit_values(&mut db);
+		let report = db.shutdown(Instant::now() + Duration::from_secs(60)).unwrap();
+		assert_eq!(report, ShutdownReport { flushed_eras: 2, pending_eras: 1, timed_out: false });
+
+		let mut db = Database::open(temp.path(), options()).unwrap();
+		assert_eq!(db.get("aaa").unwrap().unwrap(), "001");
+		commit_values(&mut db);
+		let report = db.shutdown(Instant::now()).unwrap();
+		assert_eq!(report, ShutdownReport { flushed_eras: 0, pending_eras: 4, timed_out: true });
+
+		let db = Database::open(temp.path(), options()).unwrap();
+		assert_eq!(db.get("ccc").unwrap().unwrap(), "001");
+	}
+
+	#[test]
+	fn test_health() {
+		let temp = tempdir::TempDir::new("test_health").unwrap();
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 1,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			write_once: true,
+			..Default::default()
+		}).unwrap();
+
+		for key in &["aaa", "bbb"] {
+			let mut tx = db.create_transaction();
+			tx.insert(key, "001").unwrap();
+			db.commit(&tx).unwrap();
+		}
+
+		let health = db.health();
+		assert!(health.is_healthy());
+		assert_eq!(health.journal_eras, 2);
+		assert_eq!(health.journal_lag, 1);
+		assert_eq!(health.error_count, 0);
+
+		let mut tx = db.create_transaction();
+		tx.insert("aaa", "002").unwrap();
+		assert!(db.commit(&tx).is_err());
+
+		let health = db.health();
+		assert!(health.is_healthy());
+		assert_eq!(health.error_count, 1);
+		assert!(health.last_error.is_some());
+	}
+
+	#[test]
+	fn test_stats_history() {
+		let temp = tempdir::TempDir::new("test_stats_history").unwrap();
+		let options = || Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			stats_retention: 10,
+			..Default::default()
+		};
+
+		{
+			let mut db = Database::create(temp.path(), options()).unwrap();
+			for value in &["001", "002"] {
+				let mut tx = db.create_transaction();
+				tx.insert("aaa", value).unwrap();
+				db.commit(&tx).unwrap();
+				db.flush_journal(None).unwrap();
+			}
+
+			// nothing was flushed, so no snapshot is taken
+			db.flush_journal(None).unwrap();
+		}
+
+		let db = Database::open(temp.path(), options()).unwrap();
+		let history = db.stats_history(0..u64::max_value());
+		assert_eq!(history.len(), 2);
+		assert!(history.iter().all(|stats| stats.occupied_bytes == 7 && stats.journal_eras == 0));
+		assert_eq!(history[1].data_file_bytes, db.statistics().data_file_bytes);
+	}
+
+	#[test]
+	fn test_statistics() {
+		let temp = tempdir::TempDir::new("test_statistics").unwrap();
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			key_index_bits: 8,
+			value_len: ValuesLen::Constant(3),
+			max_prefix_collisions: 2,
+			cache_size: 1024,
+			..Default::default()
+		}).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("aaa", "001").unwrap();
+		tx.insert("aab", "002").unwrap();
+		tx.insert("bbb", "003").unwrap();
+		tx.delete("ccc").unwrap();
+		db.commit(&tx).unwrap();
+
+		let stats = db.statistics();
+		assert_eq!((stats.writes, stats.deletes, stats.journal_eras), (3, 1, 1));
+		assert!(stats.journal_bytes > 0);
+
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.compact().unwrap(), vec![b'a' as u32]);
+		let stats = db.statistics();
+		assert_eq!((stats.journal_eras, stats.journal_bytes, stats.compactions), (0, 0, 1));
+		assert_eq!((stats.collided_prefixes, stats.garbage_bytes), (1, 0));
+		assert!(stats.collision_log_bytes > 0);
+
+		let mut tx = db.create_transaction();
+		tx.insert("aaa", "004").unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+		assert!(db.statistics().garbage_bytes > 0);
+
+		for _ in 0..2 {
+			assert_eq!(db.get("bbb").unwrap().unwrap(), b"003");
+		}
+		let stats = db.statistics();
+		assert_eq!((stats.reads, stats.writes, stats.cache_hits, stats.cache_misses), (2, 4, 1, 1));
+		assert_eq!(stats.cache_hit_rate(), 0.5);
+	}
+
+	#[test]
+	fn test_write_once() {
+		let temp = tempdir::TempDir::new("test_write_once").unwrap();
+
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			write_once: true,
+			..Default::default()
+		}).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("aaa", "001").unwrap();
+		tx.insert("bbb", "002").unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("ccc", "003").unwrap();
+		db.commit(&tx).unwrap();
+
+		let violation = |key: &str| ErrorKind::WriteOnceViolation(key.as_bytes().to_vec());
+
+		// overwrite of a flushed key
+		let mut tx = db.create_transaction();
+		tx.insert("ddd", "004").unwrap();
+		tx.insert("aaa", "005").unwrap();
+		assert_eq!(*db.commit(&tx).unwrap_err().kind(), violation("aaa"));
+
+		// overwrite of a journaled key
+		let mut tx = db.create_transaction();
+		tx.insert("ccc", "005").unwrap();
+		assert_eq!(*db.commit(&tx).unwrap_err().kind(), violation("ccc"));
+
+		// key inserted twice in the same transaction
+		let mut tx = db.create_transaction();
+		tx.insert("eee", "005").unwrap();
+		tx.insert("eee", "006").unwrap();
+		assert_eq!(*db.commit(&tx).unwrap_err().kind(), violation("eee"));
+
+		let mut tx = db.create_transaction();
+		tx.delete("bbb").unwrap();
+		assert_eq!(*db.commit(&tx).unwrap_err().kind(), violation("bbb"));
+
+		// rejected transactions leave no trace
+		assert_eq!(db.get("aaa").unwrap().unwrap(), b"001");
+		assert_eq!(db.get("ddd").unwrap(), None);
+		assert_eq!(db.get("eee").unwrap(), None);
+		assert_eq!(db.get("bbb").unwrap().unwrap(), b"002");
+	}
+
+	#[test]
+	fn test_truncate_from() {
+		let temp = tempdir::TempDir::new("test_truncate_from").unwrap();
+
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		}).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("001", "aaa").unwrap();
+		tx.insert("002", "bbb").unwrap();
+		tx.insert("003", "ccc").unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("004", "ddd").unwrap();
+		db.commit(&tx).unwrap();
+
+		assert_eq!(db.truncate_from("002").unwrap(), 3);
+		assert_eq!(db.truncate_from("002").unwrap(), 0);
+		assert_eq!(db.iter().unwrap().map(|item| item.unwrap().0.to_vec()).collect::<Vec<_>>(), vec![b"001".to_vec()]);
+
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.get("001").unwrap().unwrap(), b"aaa");
+		assert_eq!(db.get("003").unwrap(), None);
+		assert_eq!(db.get("004").unwrap(), None);
+		assert_eq!(*db.truncate_from("00").unwrap_err().kind(), ErrorKind::InvalidKeyLen(3, 2));
+	}
+
+	#[test]
+	fn test_diff() {
+		let temp = tempdir::TempDir::new("test_diff").unwrap();
+
+		let options = Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		};
+
+		let mut old = Database::create(temp.path().join("old"), options.clone()).unwrap();
+		let mut new = Database::create(temp.path().join("new"), options).unwrap();
+
+		let mut tx = old.create_transaction();
+		tx.insert("aaa", "001").unwrap();
+		tx.insert("bbb", "002").unwrap();
+		tx.insert("ccc", "003").unwrap();
+		old.commit(&tx).unwrap();
+		old.flush_journal(None).unwrap();
+		new.commit(&tx).unwrap();
+		new.flush_journal(None).unwrap();
+
+		let mut tx = new.create_transaction();
+		tx.delete("aaa").unwrap();
+		tx.insert("ccc", "004").unwrap();
+		tx.insert("ddd", "005").unwrap();
+		new.commit(&tx).unwrap();
+
+		let changes = old.diff(&new).unwrap().collect::<Result<Vec<_>>>().unwrap();
+		assert_eq!(changes, vec![
+			Change { key: b"aaa".to_vec(), old: Some(b"001".to_vec()), new: None },
+			Change { key: b"ccc".to_vec(), old: Some(b"003".to_vec()), new: Some(b"004".to_vec()) },
+			Change { key: b"ddd".to_vec(), old: None, new: Some(b"005".to_vec()) },
+		]);
+		assert_eq!(new.diff(&new).unwrap().count(), 0);
+	}
+
+	#[test]
+	fn test_archive_retention() {
+		let temp = tempdir::TempDir::new("test_archive_retention").unwrap();
+
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 1,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			archive_journal: true,
+			archive_retention: 3,
+			..Default::default()
+		}).unwrap();
+		assert_eq!(db.oldest_replayable_seq().unwrap(), 0);
+
+		for i in 0..6 {
+			let mut tx = db.create_transaction();
+			if i % 2 == 0 {
+				tx.insert("aaa", "001").unwrap();
+			} else {
+				tx.delete("aaa").unwrap();
+			}
+			db.commit(&tx).unwrap();
+			db.flush_journal(None).unwrap();
+		}
+
+		// commits 3 and 4 are archived, 5 is journaled
+		assert_eq!(db.oldest_replayable_seq().unwrap(), 3);
+		assert_eq!(*db.journal_segment(2).unwrap_err().kind(), ErrorKind::JournalEraMissing(2));
+		let segment = db.journal_segment(3).unwrap();
+		assert_eq!(::journal::segment_operations(&segment).unwrap().collect::<Vec<_>>(), vec![Operation::Delete(b"aaa")]);
+		assert_eq!(db.history("aaa").unwrap(), vec![(3, None), (4, Some(b"001".to_vec())), (5, None)]);
+	}
+
+	#[test]
+	fn test_merge_small_collisions() {
+		let temp = tempdir::TempDir::new("test_merge_small_collisions").unwrap();
+		let options = Options {
+			journal_eras: 0,
+			key_len: 3,
+			key_index_bits: 8,
+			value_len: ValuesLen::Constant(3),
+			max_prefix_collisions: 2,
+			..Default::default()
+		};
+
+		{
+			let mut db = Database::create(temp.path(), options.clone()).unwrap();
+			let mut tx = db.create_transaction();
+			tx.insert("aaa", "001").unwrap();
+			tx.insert("aab", "002").unwrap();
+			tx.insert("bbb", "003").unwrap();
+			db.commit(&tx).unwrap();
+			db.flush_journal(None).unwrap();
+			assert_eq!(db.compact().unwrap(), vec![b'a' as u32]);
+			assert_eq!(db.merge_small_collisions().unwrap(), vec![]);
+
+			let mut tx = db.create_transaction();
+			tx.delete("aab").unwrap();
+			db.commit(&tx).unwrap();
+			db.flush_journal(None).unwrap();
+
+			assert_eq!(db.merge_small_collisions().unwrap(), vec![b'a' as u32]);
+			assert!(!temp.path().join("collision-97.log").exists());
+			assert_eq!(db.get("aaa").unwrap().unwrap(), "001");
+			assert!(db.get("aab").unwrap().is_none());
+		}
+
+		// orphaned collision files are deleted on open
+		File::create(temp.path().join("collision-5.log")).unwrap();
+
+		let mut db = Database::open(temp.path(), options).unwrap();
+		assert!(!temp.path().join("collision-5.log").exists());
+		let keys = db.iter().unwrap().map(|item| item.unwrap().0.to_vec()).collect::<Vec<_>>();
+		assert_eq!(keys, vec![b"aaa".to_vec(), b"bbb".to_vec()]);
+
+		// the prefix can collide again
+		let mut tx = db.create_transaction();
+		tx.insert("aac", "004").unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.compact().unwrap(), vec![b'a' as u32]);
+		assert_eq!(db.get("aac").unwrap().unwrap(), "004");
+	}
+
+	#[test]
+	fn test_seal_prefixes() {
+		let temp = tempdir::TempDir::new("test_seal_prefixes").unwrap();
+		let options = Options {
+			journal_eras: 0,
+			key_len: 3,
+			key_index_bits: 8,
+			value_len: ValuesLen::Constant(3),
+			max_prefix_collisions: 2,
+			..Default::default()
+		};
+
+		{
+			let mut db = Database::create(temp.path(), options.clone()).unwrap();
+			let mut tx = db.create_transaction();
+			tx.insert("aaa", "001").unwrap();
+			tx.insert("aab", "002").unwrap();
+			tx.insert("bbb", "003").unwrap();
+			db.commit(&tx).unwrap();
+			db.flush_journal(None).unwrap();
+			assert_eq!(db.compact().unwrap(), vec![b'a' as u32]);
+
+			let mut tx = db.create_transaction();
+			tx.insert("aaa", "004").unwrap();
+			db.commit(&tx).unwrap();
+			db.flush_journal(None).unwrap();
+			assert!(!db.collisions[&(b'a' as u32)].is_compact());
+
+			db.seal_prefixes(b'a' as u32..b'b' as u32).unwrap();
+			assert!(db.collisions[&(b'a' as u32)].is_compact());
+			assert_eq!(db.sealed_prefixes(), &[b'a' as u32..b'b' as u32][..]);
+
+			let mut tx = db.create_transaction();
+			tx.insert("bbb", "005").unwrap();
+			tx.delete("aab").unwrap();
+			assert_eq!(*db.commit(&tx).unwrap_err().kind(), ErrorKind::PrefixSealed(b"aab".to_vec(), b'a' as u32));
+
+			let mut tx = db.create_transaction();
+			tx.insert("bbb", "005").unwrap();
+			db.commit(&tx).unwrap();
+		}
+
+		let db = Database::open(temp.path(), options).unwrap();
+		assert_eq!(db.sealed_prefixes(), &[b'a' as u32..b'b' as u32][..]);
+		assert_eq!(db.get("aaa").unwrap().unwrap(), "004");
+		assert_eq!(db.get("aab").unwrap().unwrap(), "002");
+		assert_eq!(db.get("bbb").unwrap().unwrap(), "005");
+	}
+
+	#[test]
+	fn test_changeset() {
+		let temp = tempdir::TempDir::new("test_changeset").unwrap();
+
+		let options = Options {
+			journal_eras: 1,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			archive_journal: true,
+			..Default::default()
+		};
+
+		{
+			let mut db = Database::create(temp.path(), options.clone()).unwrap();
+			assert_eq!(db.next_sequence(), 0);
+
+			let mut tx = db.create_transaction();
+			tx.insert("aaa", "001").unwrap();
+			tx.insert("bbb", "002").unwrap();
+			db.commit(&tx).unwrap();
+
+			let mut tx = db.create_transaction();
+			tx.insert("aaa", "003").unwrap();
+			tx.delete("bbb").unwrap();
+			db.commit(&tx).unwrap();
+
+			let mut tx = db.create_transaction();
+			tx.insert("ccc", "004").unwrap();
+			db.commit(&tx).unwrap();
+
+			db.flush_journal(None).unwrap();
+			assert_eq!(db.next_sequence(), 3);
+		}
+
+		let db = Database::open(temp.path(), options).unwrap();
+		assert_eq!(db.next_sequence(), 3);
+
+		let tx = db.changeset(0..2).unwrap();
+		assert_eq!(tx.operations().collect::<Vec<_>>(), vec![
+			Operation::Insert(b"aaa", b"003"),
+			Operation::Delete(b"bbb"),
+		]);
+
+		let tx = db.changeset(1..3).unwrap();
+		assert_eq!(tx.operations().count(), 3);
+		assert_eq!(*db.changeset(2..4).unwrap_err().kind(), ErrorKind::JournalEraMissing(3));
+	}
+
+	#[test]
+	fn test_thread_spawner() {
+		use std::io;
+		use std::sync::{Arc, Mutex};
+		use options::Compression;
+		use spawn::{StdSpawner, Task, ThreadSpawner};
+
+		struct Recording(Arc<Mutex<Vec<String>>>);
+
+		impl ThreadSpawner for Recording {
+			fn spawn(&self, name: String, task: Task) -> io::Result<()> {
+				let mut names = self.0.lock().unwrap();
+				if names.len() >= 2 {
+					return Err(io::Error::new(io::ErrorKind::Other, "too many threads"));
+				}
+
+				names.push(name.clone());
+				StdSpawner.spawn(name, task)
+			}
+		}
+
+		let temp = tempdir::TempDir::new("test_thread_spawner").unwrap();
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 1,
+			key_len: 3,
+			value_len: ValuesLen::Variable { expected: 4 },
+			encode_threads: 2,
+			compression: Compression::Lz4,
+			..Default::default()
+		}).unwrap();
+
+		let names = Arc::new(Mutex::new(Vec::new()));
+		db.set_thread_spawner(Box::new(Recording(names.clone())));
+
+		let value = vec![7u8; 600 * 1024];
+		let mut tx = db.create_transaction();
+		tx.insert("aaa", &value).unwrap();
+		tx.insert("bbb", &value).unwrap();
+		db.commit(&tx).unwrap();
+		assert_eq!(*names.lock().unwrap(), vec!["paritydb-encode-0".to_owned(), "paritydb-encode-1".to_owned()]);
+		assert_eq!(db.get("bbb").unwrap().unwrap(), &value[..]);
+
+		// a commit fails if its workers can't be spawned
+		let mut tx = db.create_transaction();
+		tx.insert("ccc", &value).unwrap();
+		tx.insert("ddd", &value).unwrap();
+		assert!(db.commit(&tx).is_err());
+		assert_eq!(db.get("ccc").unwrap(), None);
+	}
+
+	#[test]
+	fn test_value_transforms() {
+		use transform::ValueTransform;
+
+		struct Reverse;
+
+		impl ValueTransform for Reverse {
+			fn encode(&self, value: &[u8]) -> Option<Vec<u8>> {
+				Some(value.iter().rev().cloned().collect())
+			}
+
+			fn decode(&self, value: &[u8]) -> Result<Vec<u8>> {
+				Ok(value.iter().rev().cloned().collect())
+			}
+		}
+
+		let temp = tempdir::TempDir::new("test_value_transforms").unwrap();
+
+		let options = Options {
+			journal_eras: 1,
+			key_len: 3,
+			value_len: ValuesLen::Variable { expected: 4 },
+			..Default::default()
+		};
+
+		let mut db = Database::create(temp.path(), options).unwrap();
+		db.set_value_transforms(vec![Box::new(Reverse) as Box<ValueTransform>]).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("aaa", "abc").unwrap();
+		tx.insert("bbb", "").unwrap();
+		db.commit(&tx).unwrap();
+		let mut tx = db.create_transaction();
+		tx.insert("ccc", "xyz").unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+
+		// `aaa` and `bbb` are read from the data file, `ccc` from the journal
+		assert_eq!(db.get("aaa").unwrap().unwrap(), b"abc");
+		assert_eq!(db.get("bbb").unwrap().unwrap(), b"");
+		assert_eq!(db.get("ccc").unwrap().unwrap(), b"xyz");
+		assert_eq!(db.lookup_raw(b"aaa", &mut Default::default()).unwrap().unwrap(), b"\x01cba");
+
+		let values = db.iter().unwrap().map(|item| item.unwrap().1.to_vec()).collect::<Vec<_>>();
+		assert_eq!(values, vec![b"abc".to_vec(), b"".to_vec(), b"xyz".to_vec()]);
+
+		let mut constant = Database::create(temp.path().join("constant"), Options::default()).unwrap();
+		assert!(constant.set_value_transforms(vec![Box::new(Reverse) as Box<ValueTransform>]).is_err());
+	}
+
+	#[test]
+	fn test_get_with_stats() {
+		use read::{ReadOptions, ReadStats};
+
+		let temp = tempdir::TempDir::new("test_get_with_stats").unwrap();
+
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 1,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			max_prefix_collisions: 2,
+			..Default::default()
+		}).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("aaa", "001").unwrap();
+		tx.insert("aab", "002").unwrap();
+		tx.insert("bbb", "003").unwrap();
+		tx.insert("bbc", "004").unwrap();
+		db.commit(&tx).unwrap();
+		let mut tx = db.create_transaction();
+		tx.insert("ccc", "005").unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.compact().unwrap(), vec![b'a' as u32, b'b' as u32]);
+
+		let mut tx = db.create_transaction();
+		tx.insert("ddd", "006").unwrap();
+		db.commit(&tx).unwrap();
+		let mut tx = db.create_transaction();
+		tx.insert("eee", "006").unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+
+		let options = ReadOptions { collect_stats: true };
+
+		let (value, stats) = db.get_with("aab", &options).unwrap();
+		assert_eq!(value.unwrap(), b"002");
+		assert_eq!(stats.unwrap(), ReadStats { journal_hit: false, cache_hit: false, files_touched: 1, fields_scanned: 0, bytes_read: 14 });
+
+		let (value, stats) = db.get_with("eee", &options).unwrap();
+		assert_eq!(value.unwrap(), b"006");
+		assert_eq!(stats.unwrap(), ReadStats { journal_hit: true, cache_hit: false, files_touched: 0, fields_scanned: 0, bytes_read: 0 });
+
+		let (value, stats) = db.get_with("ccc", &options).unwrap();
+		assert_eq!(value.unwrap(), b"005");
+		let stats = stats.unwrap();
+		assert!(!stats.journal_hit);
+		assert_eq!(stats.files_touched, 1);
+		assert!(stats.fields_scanned >= 1);
+
+		let (value, stats) = db.get_with("ccc", &ReadOptions::default()).unwrap();
+		assert_eq!(value.unwrap(), b"005");
+		assert_eq!(stats, None);
+	}
+
+	#[test]
+	fn test_read_repair() {
+		use std::fs::OpenOptions;
+		use std::io::{Seek, SeekFrom};
+		use std::sync::{Arc, Mutex};
+		use events::Event;
+
+		let temp = tempdir::TempDir::new("test_read_repair").unwrap();
+		let options = Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			max_prefix_collisions: 2,
+			..Default::default()
+		};
+
+		let mut db = Database::create(temp.path(), options.clone()).unwrap();
+		let events = Arc::new(Mutex::new(Vec::new()));
+		let listener_events = events.clone();
+		db.set_event_listener(move |event| listener_events.lock().unwrap().push(event.clone()));
+
+		let mut tx = db.create_transaction();
+		tx.insert("aaa", "001").unwrap();
+		tx.insert("aab", "002").unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.compact().unwrap(), vec![b'a' as u32]);
+
+		// flip a byte of the value of `aab`, the second entry of the compacted log
+		let path = temp.path().join("collision-97.log");
+		let offset = 8 + (8 + 3 + 3 + 4);
+		{
+			let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+			file.seek(SeekFrom::Start(offset + 4 + 3 + 4)).unwrap();
+			file.write_all(b"X").unwrap();
+		}
+		assert_eq!(*db.get("aab").unwrap_err().kind(), ErrorKind::Corruption(path.clone(), offset));
+
+		db.set_repair_source(|key: &[u8]| if key == b"aab" { Some(b"002".to_vec()) } else { None });
+		assert_eq!(db.get("aab").unwrap().unwrap(), b"002");
+		assert_eq!(db.get("aaa").unwrap().unwrap(), b"001");
+		assert_eq!(db.apply_repairs().unwrap(), 1);
+		assert_eq!(db.apply_repairs().unwrap(), 0);
+		assert_eq!(*events.lock().unwrap(), vec![Event::Repaired { path, offset, key: b"aab".to_vec() }]);
+
+		drop(db);
+		let db = Database::open(temp.path(), options).unwrap();
+		assert_eq!(db.get("aab").unwrap().unwrap(), b"002");
+	}
+
+	#[test]
+	fn test_compression() {
+		use options::Compression;
+
+		let temp = tempdir::TempDir::new("test_compression").unwrap();
+		let options = |compression| Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Variable { expected: 16 },
+			compression,
+			..Default::default()
+		};
+
+		let long = vec![b'a'; 300];
+		let mut db = Database::create(temp.path(), options(Compression::Lz4)).unwrap();
+		let mut tx = db.create_transaction();
+		tx.insert("aaa", &long).unwrap();
+		tx.insert("bbb", "short").unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.get("aaa").unwrap().unwrap(), &long);
+		assert!(db.key_statistics().value_bytes < 300);
+
+		// values compressed with lz4 are readable after switching to snappy
+		drop(db);
+		let mut db = Database::open(temp.path(), options(Compression::Snappy)).unwrap();
+		let mut tx = db.create_transaction();
+		tx.insert("ccc", &long).unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+
+		let items = db.iter().unwrap().map(|item| item.unwrap().1.to_vec()).collect::<Vec<_>>();
+		assert_eq!(items, vec![long.clone(), b"short".to_vec(), long.clone()]);
+
+		// the compressor is kept after compression is disabled
+		drop(db);
+		let db = Database::open(temp.path(), options(Compression::None)).unwrap();
+		assert_eq!(db.get("ccc").unwrap().unwrap(), &long);
+		drop(db);
+
+		// values stored without compression have no room for it
+		let uncompressed = temp.path().join("uncompressed");
+		let mut db = Database::create(&uncompressed, options(Compression::None)).unwrap();
+		let mut tx = db.create_transaction();
+		tx.insert("aaa", &long).unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+		drop(db);
+		let err = Database::open(&uncompressed, options(Compression::Lz4)).unwrap_err();
+		assert!(matches!(*err.kind(), ErrorKind::InvalidOptions("compression", _)));
+		let db = Database::open(&uncompressed, options(Compression::None)).unwrap();
+		assert_eq!(db.get("aaa").unwrap().unwrap(), &long);
+		drop(db);
+
+		// an empty database can still be compressed
+		let empty = temp.path().join("empty");
+		drop(Database::create(&empty, options(Compression::None)).unwrap());
+		let mut db = Database::open(&empty, options(Compression::Lz4)).unwrap();
+		let mut tx = db.create_transaction();
+		tx.insert("aaa", &long).unwrap();
+		db.commit(&tx).unwrap();
+		assert_eq!(db.get("aaa").unwrap().unwrap(), &long);
+	}
+
+	#[test]
+	fn test_column_compression() {
+		use options::Compression;
+		use read::ReadStats;
+
+		let temp = tempdir::TempDir::new("test_column_compression").unwrap();
+		let options = || Options {
+			journal_eras: 0,
+			key_len: 3,
+			key_index_bits: 8,
+			value_len: ValuesLen::Variable { expected: 16 },
+			max_prefix_collisions: 2,
+			..Default::default()
+		};
+		let raw = |db: &Database, key: &[u8]| -> Vec<u8> {
+			db.lookup_raw_in(key, &mut ReadStats::default(), 0).unwrap().unwrap().to_vec()
+		};
+
+		let long = vec![b'a'; 300];
+		let mut db = Database::create(temp.path().join("db"), options()).unwrap();
+		db.set_column_compression(b"b", Compression::Snappy).unwrap();
+		let mut tx = db.create_transaction();
+		for key in &["aaa", "aab", "bbb"] {
+			tx.insert(key, &long).unwrap();
+		}
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+		assert_eq!(raw(&db, b"aaa")[0], 0);
+		assert_eq!(raw(&db, b"bbb")[..2].to_vec(), vec![1, 2]);
+
+		// compaction moves the values of prefix `a` with the compression of their column
+		db.set_column_compression(b"a", Compression::Lz4).unwrap();
+		assert_eq!(db.compact().unwrap(), vec![b'a' as u32]);
+		assert_eq!(raw(&db, b"aaa")[..2].to_vec(), vec![1, 1]);
+		assert_eq!(db.get("aaa").unwrap().unwrap(), &long);
+
+		drop(db);
+		let mut db = Database::open(temp.path().join("db"), options()).unwrap();
+		assert_eq!(db.column_compression(), &[(b"b".to_vec(), Compression::Snappy), (b"a".to_vec(), Compression::Lz4)][..]);
+		db.set_column_compression(b"a", Compression::None).unwrap();
+		let mut tx = db.create_transaction();
+		tx.delete("aab").unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.merge_small_collisions().unwrap(), vec![b'a' as u32]);
+		assert_eq!(raw(&db, b"aaa")[0], 0);
+		let items = db.iter().unwrap().map(|item| item.unwrap().1.to_vec()).collect::<Vec<_>>();
+		assert_eq!(items, vec![long.clone(), long.clone()]);
+
+		// values of a database without compression are stored without an envelope
+		let mut db = Database::create(temp.path().join("uncompressed"), options()).unwrap();
+		let mut tx = db.create_transaction();
+		tx.insert("aaa", &long).unwrap();
+		db.commit(&tx).unwrap();
+		assert!(db.set_column_compression(b"a", Compression::Lz4).is_err());
+
+		let mut db = Database::create(temp.path().join("constant"), Options {
+			value_len: ValuesLen::Constant(3),
+			..options()
+		}).unwrap();
+		assert!(db.set_column_compression(b"a", Compression::Lz4).is_err());
+
+		// prefixes longer than keys or than the COMPRESSION file can store are rejected
+		let mut db = Database::create(temp.path().join("prefixes"), options()).unwrap();
+		let err = db.set_column_compression(b"aaaa", Compression::Lz4).unwrap_err();
+		assert!(matches!(*err.kind(), ErrorKind::InvalidOptions("compression", _)));
+
+		let mut db = Database::create(temp.path().join("long"), Options {
+			key_len: 300,
+			..options()
+		}).unwrap();
+		let err = db.set_column_compression(&[b'a'; 256], Compression::Lz4).unwrap_err();
+		assert!(matches!(*err.kind(), ErrorKind::InvalidOptions("compression", _)));
+		db.set_column_compression(&[b'a'; 255], Compression::Lz4).unwrap();
+		drop(db);
+		let db = Database::open(temp.path().join("long"), Options { key_len: 300, ..options() }).unwrap();
+		assert_eq!(db.column_compression(), &[(vec![b'a'; 255], Compression::Lz4)][..]);
+	}
+
+	#[test]
+	fn test_value_cache() {
+		use read::ReadOptions;
+
+		let temp = tempdir::TempDir::new("test_value_cache").unwrap();
+
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			cache_size: 1024,
+			..Default::default()
+		}).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("aaa", "001").unwrap();
+		tx.insert("bbb", "002").unwrap();
+		db.commit(&tx).unwrap();
+
+		// journaled values are not cached
+		let options = ReadOptions { collect_stats: true };
+		let (_, stats) = db.get_with("bbb", &options).unwrap();
+		assert!(stats.unwrap().journal_hit);
+		assert_eq!(db.cache_stats().misses, 0);
+
+		db.flush_journal(None).unwrap();
+		for key in &["aaa", "bbb", "aaa", "bbb"] {
+			db.get(*key).unwrap().unwrap();
+		}
+		let (value, stats) = db.get_with("aaa", &options).unwrap();
+		assert_eq!(value.unwrap(), b"001");
+		let stats = stats.unwrap();
+		assert!(stats.cache_hit);
+		assert_eq!(stats.files_touched, 0);
+
+		let cache = db.cache_stats();
+		assert_eq!((cache.hits, cache.misses, cache.entries), (3, 2, 2));
+
+		// flushing a new value drops the cached one
+		let mut tx = db.create_transaction();
+		tx.insert("aaa", "003").unwrap();
+		db.commit(&tx).unwrap();
+		assert_eq!(db.get("aaa").unwrap().unwrap(), b"003");
+		let mut tx = db.create_transaction();
+		tx.delete("bbb").unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.get("aaa").unwrap().unwrap(), b"003");
+		assert_eq!(db.get("bbb").unwrap(), None);
+		assert_eq!(db.cache_stats().misses, 4);
+	}
+
+	#[test]
+	fn test_open_with_progress() {
+		let temp = tempdir::TempDir::new("test_open_with_progress").unwrap();
+		let options = || Options {
+			journal_eras: 2,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			max_prefix_collisions: 2,
+			..Default::default()
+		};
+
+		let mut db = Database::create(temp.path(), Options { journal_eras: 0, ..options() }).unwrap();
+		let mut tx = db.create_transaction();
+		tx.insert("aaa", "001").unwrap();
+		tx.insert("aab", "002").unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.compact().unwrap(), vec![b'a' as u32]);
+		drop(db);
+
+		let mut db = Database::open(temp.path(), options()).unwrap();
+		for key in &["bbb", "ccc"] {
+			let mut tx = db.create_transaction();
+			tx.insert(key, "003").unwrap();
+			db.commit(&tx).unwrap();
+		}
+		let era_bytes = db.journal_segment(0).unwrap().len() as u64;
+		drop(db);
+
+		let mut reports = Vec::new();
+		Database::open_with_progress(temp.path(), options(), |progress| reports.push(progress.clone())).unwrap();
+
+		assert_eq!(reports.len(), 3);
+		assert_eq!(reports[0], OpenProgress { phase: OpenPhase::Journal, files_done: 1, files_total: 2, bytes: era_bytes });
+		assert_eq!(reports[1], OpenProgress { phase: OpenPhase::Journal, files_done: 2, files_total: 2, bytes: 2 * era_bytes });
+		assert_eq!(reports[2].phase, OpenPhase::Collisions);
+		assert_eq!((reports[2].files_done, reports[2].files_total), (1, 1));
+		assert_eq!(reports[2].bytes, 8 + 2 * (12 + 3 + 3));
+	}
+
+	#[test]
+	fn test_max_resident_collisions() {
+		let temp = tempdir::TempDir::new("test_max_resident_collisions").unwrap();
+		let options = || Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			max_prefix_collisions: 2,
+			max_resident_collisions: 1,
+			..Default::default()
+		};
+		let resident = |db: &Database| db.collisions.values().filter(|collision| collision.is_resident()).count();
+
+		let mut db = Database::create(temp.path(), options()).unwrap();
+		let mut tx = db.create_transaction();
+		tx.insert("aaa", "001").unwrap();
+		tx.insert("aab", "002").unwrap();
+		tx.insert("bbb", "003").unwrap();
+		tx.insert("bbc", "004").unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.compact().unwrap(), vec![b'a' as u32, b'b' as u32]);
+		assert_eq!(resident(&db), 1);
+
+		assert_eq!(db.get("aab").unwrap().unwrap(), "002");
+		assert_eq!(db.get("bbc").unwrap().unwrap(), "004");
+		assert!(db.contains("aaa").unwrap());
+		assert!(!db.contains("aac").unwrap());
+
+		let mut tx = db.create_transaction();
+		tx.insert("aac", "005").unwrap();
+		tx.delete("bbb").unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+		assert_eq!(resident(&db), 1);
+		drop(db);
+
+		let db = Database::open(temp.path(), options()).unwrap();
+		assert_eq!(resident(&db), 1);
+		let keys: Vec<_> = db.iter().unwrap().map(|item| item.unwrap().0.to_vec()).collect();
+		assert_eq!(keys, vec![b"aaa".to_vec(), b"aab".to_vec(), b"aac".to_vec(), b"bbc".to_vec()]);
+		assert_eq!(db.get("bbb").unwrap(), None);
+	}
+
+	#[test]
+	fn test_iter_prefix() {
+		let temp = tempdir::TempDir::new("test_iter_prefix").unwrap();
+
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 1,
+			key_len: 3,
+			key_index_bits: 12,
+			value_len: ValuesLen::Constant(3),
+			max_prefix_collisions: 2,
+			..Default::default()
+		}).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("aaa", "001").unwrap();
+		tx.insert("aab", "002").unwrap();
+		tx.insert("aqa", "003").unwrap();
+		tx.insert("bbb", "004").unwrap();
+		tx.insert("bqb", "005").unwrap();
+		db.commit(&tx).unwrap();
+		let mut tx = db.create_transaction();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+		// `aaa` and `aab` share the 12 bit prefix
+		assert_eq!(db.compact().unwrap(), vec![0x616]);
+
+		tx = db.create_transaction();
+		tx.insert("aqb", "006").unwrap();
+		tx.delete("aqa").unwrap();
+		tx.insert("cab", "007").unwrap();
+		db.commit(&tx).unwrap();
+
+		let keys = |prefix: &[u8]| db.iter_prefix(prefix).unwrap()
+			.map(|item| item.unwrap().0.to_vec())
+			.collect::<Vec<_>>();
+
+		assert_eq!(keys(b"a"), vec![b"aaa".to_vec(), b"aab".to_vec(), b"aqb".to_vec()]);
+		assert_eq!(keys(b"aa"), vec![b"aaa".to_vec(), b"aab".to_vec()]);
+		assert_eq!(keys(b"aq"), vec![b"aqb".to_vec()]);
+		assert_eq!(keys(b"b"), vec![b"bbb".to_vec(), b"bqb".to_vec()]);
+		assert_eq!(keys(b"bqb"), vec![b"bqb".to_vec()]);
+		assert_eq!(keys(b"c"), vec![b"cab".to_vec()]);
+		assert_eq!(keys(b"d"), Vec::<Vec<u8>>::new());
+		assert_eq!(keys(b""), db.iter().unwrap().map(|item| item.unwrap().0.to_vec()).collect::<Vec<_>>());
+		assert!(db.iter_prefix(b"aaaa").is_err());
+	}
+
+	#[test]
+	fn test_pinned_values() {
+		use read::ReadOptions;
+
+		let temp = tempdir::TempDir::new("test_pinned_values").unwrap();
+
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			key_index_bits: 8,
+			value_len: ValuesLen::Constant(3),
+			max_prefix_collisions: 2,
+			..Default::default()
+		}).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("aaa", "001").unwrap();
+		tx.insert("aab", "002").unwrap();
+		tx.insert("bbb", "003").unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.compact().unwrap(), vec![b'a' as u32]);
+
+		db.pin("aaa").unwrap();
+		db.pin("ccc").unwrap();
+		assert!(db.pin("cc").is_err());
+
+		// pinned keys are read from the cache, although it is disabled
+		let options = ReadOptions { collect_stats: true };
+		let (value, stats) = db.get_with("aaa", &options).unwrap();
+		assert_eq!(value.unwrap(), b"001");
+		assert_eq!(stats.unwrap().files_touched, 0);
+		let (value, stats) = db.get_with("ccc", &options).unwrap();
+		assert!(value.is_none());
+		assert!(stats.unwrap().cache_hit);
+
+		let mut tx = db.create_transaction();
+		tx.insert("aaa", "004").unwrap();
+		db.commit(&tx).unwrap();
+		assert_eq!(db.get("aaa").unwrap().unwrap(), b"004");
+
+		// the pinned value is read again when the key is flushed
+		db.flush_journal(None).unwrap();
+		let (value, stats) = db.get_with("aaa", &options).unwrap();
+		assert_eq!(value.unwrap(), b"004");
+		assert!(stats.unwrap().cache_hit);
+		assert_eq!(db.cache_stats().pinned, 2);
+
+		assert!(db.unpin("aaa"));
+		assert!(!db.unpin("aaa"));
+		let (value, stats) = db.get_with("aaa", &options).unwrap();
+		assert_eq!(value.unwrap(), b"004");
+		assert_eq!(stats.unwrap().files_touched, 1);
+	}
+
+	#[test]
+	fn test_concurrent_reads() {
+		use std::sync::Arc;
+		use std::thread;
+
+		fn assert_send_sync<T: Send + Sync>() {}
+		assert_send_sync::<Database>();
+
+		fn key(i: u8) -> [u8; 3] {
+			[b'k', b'0' + i / 10, b'0' + i % 10]
+		}
+
+		let temp = tempdir::TempDir::new("test_concurrent_reads").unwrap();
+
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			key_index_bits: 8,
+			value_len: ValuesLen::Constant(3),
+			max_prefix_collisions: 2,
+			cache_size: 1024,
+			..Default::default()
+		}).unwrap();
+
+		let mut tx = db.create_transaction();
+		for i in 0..100 {
+			tx.insert(key(i), [i; 3]).unwrap();
+		}
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+		// all the keys share the 8 bit prefix, so they are read from a collision file
+		assert_eq!(db.compact().unwrap(), vec![b'k' as u32]);
+
+		// the journaled values are read concurrently with the stored ones
+		let mut tx = db.create_transaction();
+		for i in 0..10 {
+			tx.insert(key(i), [0xff; 3]).unwrap();
+		}
+		db.commit(&tx).unwrap();
+
+		let db = Arc::new(db);
+		let readers = (0..4).map(|_| {
+			let db = db.clone();
+			thread::spawn(move || {
+				for i in 0..100 {
+					let expected = if i < 10 { [0xff; 3] } else { [i; 3] };
+					assert_eq!(db.get(&key(i)).unwrap().unwrap().to_vec(), expected.to_vec());
+				}
+				db.iter().unwrap().count()
+			})
+		}).collect::<Vec<_>>();
+
+		for reader in readers {
+			assert_eq!(reader.join().unwrap(), 100);
+		}
 	}
-}
 
-#[derive(Debug)]
-enum IteratorValue<'a> {
-	None,
-	Journal(Operation<'a>),
-	DB((&'a [u8], Value<'a>)),
-}
+	#[test]
+	fn test_key_statistics() {
+		let temp = tempdir::TempDir::new("test_key_statistics").unwrap();
+		let options = || Options {
+			journal_eras: 0,
+			key_len: 3,
+			key_index_bits: 8,
+			value_len: ValuesLen::Variable { expected: 3 },
+			max_prefix_collisions: 2,
+			..Default::default()
+		};
 
-impl<'a> IteratorValue<'a> {
-	fn take(&mut self) -> Self {
-		::std::mem::replace(self, IteratorValue::None)
-	}
-}
+		let mut db = Database::create(temp.path(), options()).unwrap();
+		let mut tx = db.create_transaction();
+		tx.insert("aaa", "1").unwrap();
+		tx.insert("bba", "22").unwrap();
+		tx.insert("bbb", "333").unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
 
-pub struct DatabaseIterator<'a> {
-	journal_iter: btree_set::IntoIter<Operation<'a>>,
-	record_collisions_iter: Box<Iterator<Item=Result<(&'a [u8], Value<'a>)>> + 'a>,
-	pending: IteratorValue<'a>,
-}
+		let key_stats = db.key_statistics();
+		assert_eq!((key_stats.keys, key_stats.value_bytes), (3, 6));
+		assert_eq!(&key_stats.value_sizes[..4], &[0, 1, 2, 0]);
 
-impl<'a> Iterator for DatabaseIterator<'a> {
-	type Item = Result<(&'a [u8], Value<'a>)>;
+		// moving keys to a collision file doesn't change the counts
+		assert_eq!(db.compact().unwrap(), vec![b'b' as u32]);
+		assert_eq!(db.key_statistics(), key_stats);
 
-	fn next(&mut self) -> Option<Self::Item> {
-		loop {
-			let (operation, record) = match self.pending.take() {
-				IteratorValue::None => {
-					let j = self.journal_iter.next().map_or(IteratorValue::None, IteratorValue::Journal);
-					let db = match self.record_collisions_iter.next() {
-						None => IteratorValue::None,
-						Some(Ok(r)) => IteratorValue::DB(r),
-						Some(Err(err)) => {
-							self.pending = j;
-							return Some(Err(err.into()));
-						},
-					};
+		let mut tx = db.create_transaction();
+		tx.delete("aaa").unwrap();
+		tx.insert("bbb", "4444").unwrap();
+		tx.insert("ccc", "55").unwrap();
+		db.commit(&tx).unwrap();
+		// journaled keys are not counted
+		assert_eq!(db.key_statistics(), key_stats);
+
+		db.flush_journal(None).unwrap();
+		let key_stats = db.key_statistics();
+		assert_eq!((key_stats.keys, key_stats.value_bytes), (3, 8));
+		assert_eq!(&key_stats.value_sizes[..4], &[0, 0, 2, 1]);
+
+		drop(db);
+		let db = Database::open(temp.path(), options()).unwrap();
+		assert_eq!(db.key_statistics(), key_stats);
+
+		// counted again from the stored records if the saved counts are missing
+		drop(db);
+		::std::fs::remove_file(temp.path().join("stats.keys")).unwrap();
+		let db = Database::open(temp.path(), options()).unwrap();
+		assert_eq!(db.key_statistics(), key_stats);
+	}
 
-					(j, db)
-				},
-				j @ IteratorValue::Journal(_) => {
-					let db = match self.record_collisions_iter.next() {
-						None => IteratorValue::None,
-						Some(Ok(r)) => IteratorValue::DB(r),
-						Some(Err(err)) => {
-							self.pending = j;
-							return Some(Err(err.into()));
-						},
-					};
+	#[test]
+	fn test_iter_ordered() {
+		let temp = tempdir::TempDir::new("test_iter_ordered").unwrap();
 
-					(j, db)
-				},
-				db @ IteratorValue::DB(_) => {
-					let j = self.journal_iter.next().map_or(IteratorValue::None, IteratorValue::Journal);
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 1,
+			key_len: 3,
+			key_index_bits: 12,
+			value_len: ValuesLen::Constant(3),
+			max_prefix_collisions: 2,
+			..Default::default()
+		}).unwrap();
 
-					(j, db)
-				},
-			};
+		let mut tx = db.create_transaction();
+		tx.insert("aaa", "001").unwrap();
+		tx.insert("aab", "002").unwrap();
+		tx.insert("aqa", "003").unwrap();
+		tx.insert("bbb", "004").unwrap();
+		tx.insert("bqb", "005").unwrap();
+		db.commit(&tx).unwrap();
+		let mut tx = db.create_transaction();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.compact().unwrap(), vec![0x616]);
+
+		tx = db.create_transaction();
+		tx.insert("aqb", "006").unwrap();
+		tx.delete("aqa").unwrap();
+		tx.insert("cab", "007").unwrap();
+		tx.insert("bbb", "008").unwrap();
+		db.commit(&tx).unwrap();
 
-			#[inline]
-			// returns `None` if the operation is a `Delete` and we should skip to the next value
-			fn handle_journal_operation<'a>(o: Operation<'a>) -> Option<Result<(&'a [u8], Value<'a>)>> {
-				match o {
-					Operation::Delete(_) => {
-						None
-					},
-					Operation::Insert(key, value) => {
-						Some(Ok((key, Value::Raw(value))))
-					},
-				}
-			}
+		let items = |order: IterationOrder| db.iter_ordered(order).unwrap()
+			.map(|item| item.map(|(key, value)| (key.to_vec(), value.to_vec())).unwrap())
+			.collect::<Vec<_>>();
+
+		// data file records first, then the collided prefix, then keys only in the journal
+		let physical = items(IterationOrder::Physical);
+		let keys = physical.iter().map(|&(ref key, _)| key.clone()).collect::<Vec<_>>();
+		assert_eq!(keys, vec![b"bbb".to_vec(), b"bqb".to_vec(), b"aaa".to_vec(), b"aab".to_vec(), b"aqb".to_vec(), b"cab".to_vec()]);
+		assert_eq!(physical[0].1, b"008".to_vec());
+
+		let mut sorted = physical.clone();
+		sorted.sort();
+		assert_eq!(items(IterationOrder::Lexicographic), sorted);
+
+		let prefix_keys = db.iter_prefix_ordered(b"a", IterationOrder::Physical).unwrap()
+			.map(|item| item.unwrap().0.to_vec())
+			.collect::<Vec<_>>();
+		assert_eq!(prefix_keys, vec![b"aaa".to_vec(), b"aab".to_vec(), b"aqb".to_vec()]);
+		assert!(db.iter_prefix_ordered(b"aaaa", IterationOrder::Physical).is_err());
+	}
 
-			match (operation, record) {
-				(IteratorValue::Journal(o), IteratorValue::None) => {
-					match handle_journal_operation(o) {
-						None => continue,
-						s => return s,
-					};
-				},
-				(IteratorValue::None, IteratorValue::DB(r)) => {
-					return Some(Ok(r))
-				},
-				(IteratorValue::Journal(o), IteratorValue::DB(r)) => {
-					let ord = (*r.0).partial_cmp(o.key()).expect(
-						"only returns None when compared keys don't have the same size; \
-						 all keys should have the same size; qed");
+	#[test]
+	fn test_flush_layout_is_deterministic() {
+		let temp = tempdir::TempDir::new("test_flush_layout_is_deterministic").unwrap();
+		let options = || Options {
+			journal_eras: 0,
+			key_len: 3,
+			key_index_bits: 8,
+			value_len: ValuesLen::Constant(1024),
+			max_prefix_collisions: 2,
+			..Default::default()
+		};
 
-					match ord {
-						Ordering::Equal => {
-							match handle_journal_operation(o) {
-								None => continue,
-								s => return s,
-							};
-						},
-						Ordering::Greater => {
-							self.pending = IteratorValue::DB(r);
+		let mut eager = Database::create(temp.path().join("eager"), options()).unwrap();
+		let mut batched = Database::create(temp.path().join("batched"), options()).unwrap();
+		for db in &mut [&mut eager, &mut batched] {
+			let mut tx = db.create_transaction();
+			tx.insert("aaa", &[0; 1024][..]).unwrap();
+			tx.insert("aab", &[0; 1024][..]).unwrap();
+			db.commit(&tx).unwrap();
+			db.flush_journal(None).unwrap();
+			assert_eq!(db.compact().unwrap(), vec![b'a' as u32]);
+		}
 
-							match handle_journal_operation(o) {
-								None => continue,
-								s => return s,
-							};
-						},
-						Ordering::Less => {
-							self.pending = IteratorValue::Journal(o);
-							return Some(Ok(r))
-						},
-					};
-				},
-				(IteratorValue::None, IteratorValue::None) => return None,
-				(o, r) => unreachable!("operation: {:?}, record: {:?}", o, r)
+		// overwrites make the collision log garbage heavy in the middle of the batch
+		for i in 0..100u8 {
+			for db in &mut [&mut eager, &mut batched] {
+				let mut tx = db.create_transaction();
+				tx.insert("aaa", &[i; 1024][..]).unwrap();
+				db.commit(&tx).unwrap();
+			}
+			eager.flush_journal(None).unwrap();
+		}
+		batched.flush_journal(None).unwrap();
+
+		for name in &["collision-97.log", Database::DB_FILE, Database::META_FILE] {
+			let read = |dir: &str| {
+				let mut data = Vec::new();
+				File::open(temp.path().join(dir).join(name)).unwrap().read_to_end(&mut data).unwrap();
+				data
 			};
+			assert!(read("eager") == read("batched"), "{} differs", name);
 		}
+		assert_eq!(batched.get("aaa").unwrap().unwrap(), &[99; 1024][..]);
 	}
-}
 
-#[cfg(test)]
-mod tests {
-	extern crate tempdir;
+	#[test]
+	fn test_flush_replays_era_after_crash() {
+		let temp = tempdir::TempDir::new("test_flush_replays_era_after_crash").unwrap();
+		let options = || Options {
+			journal_eras: 0,
+			key_len: 3,
+			key_index_bits: 8,
+			value_len: ValuesLen::Constant(3),
+			max_prefix_collisions: 2,
+			..Default::default()
+		};
 
-	use super::{Database, Options};
-	use options::ValuesLen;
-	use error::ErrorKind;
-	use quickcheck::TestResult;
+		let era = {
+			let mut db = Database::create(temp.path(), options()).unwrap();
+			let mut tx = db.create_transaction();
+			tx.insert("aaa", "001").unwrap();
+			tx.insert("aab", "002").unwrap();
+			tx.insert("bbb", "003").unwrap();
+			db.commit(&tx).unwrap();
+			db.flush_journal(None).unwrap();
+			assert_eq!(db.compact().unwrap(), vec![b'a' as u32]);
+
+			// a single commit touching the collision file and the data file
+			let mut tx = db.create_transaction();
+			tx.insert("aaa", "004").unwrap();
+			tx.delete("aab").unwrap();
+			tx.insert("ccc", "005").unwrap();
+			tx.delete("bbb").unwrap();
+			db.commit(&tx).unwrap();
+
+			let path = db.journal.era_paths()[0].to_path_buf();
+			let mut data = Vec::new();
+			File::open(&path).unwrap().read_to_end(&mut data).unwrap();
+			db.flush_journal(None).unwrap();
+			assert!(!path.exists());
+			(path, data)
+		};
+
+		// a crash right before the era was removed
+		File::create(&era.0).unwrap().write_all(&era.1).unwrap();
+
+		let mut db = Database::open(temp.path(), options()).unwrap();
+		assert_eq!(db.journal.len(), 1);
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.journal.len(), 0);
+
+		assert_eq!(db.get("aaa").unwrap().unwrap(), "004");
+		assert!(db.get("aab").unwrap().is_none());
+		assert!(db.get("bbb").unwrap().is_none());
+		assert_eq!(db.get("ccc").unwrap().unwrap(), "005");
+		assert_eq!(db.iter().unwrap().count(), 2);
+	}
 
 	#[test]
-	fn create_insert_and_query() {
-		let temp = tempdir::TempDir::new("create_insert_and_query").unwrap();
+	fn test_iter_range() {
+		let temp = tempdir::TempDir::new("test_iter_range").unwrap();
 
 		let mut db = Database::create(temp.path(), Options {
-			journal_eras: 0,
+			journal_eras: 1,
 			key_len: 3,
+			key_index_bits: 12,
 			value_len: ValuesLen::Constant(3),
+			max_prefix_collisions: 2,
 			..Default::default()
 		}).unwrap();
 
 		let mut tx = db.create_transaction();
-		tx.insert("abc", "xyz").unwrap();
-		tx.insert("cde", "123").unwrap();
-
+		tx.insert("aaa", "001").unwrap();
+		tx.insert("aab", "002").unwrap();
+		tx.insert("aqa", "003").unwrap();
+		tx.insert("bbb", "004").unwrap();
 		db.commit(&tx).unwrap();
+		let tx = db.create_transaction();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.compact().unwrap(), vec![0x616]);
 
-		assert_eq!(db.get("abc").unwrap().unwrap(), b"xyz");
-		assert_eq!(db.get("cde").unwrap().unwrap(), b"123");
-
-		// Another transaction
 		let mut tx = db.create_transaction();
-		tx.insert("abc", "456").unwrap();
-		tx.delete("cde").unwrap();
-
+		tx.insert("aac", "005").unwrap();
+		tx.delete("aqa").unwrap();
+		tx.insert("bab", "006").unwrap();
 		db.commit(&tx).unwrap();
 
-		assert_eq!(db.get("abc").unwrap().unwrap(), b"456");
-		assert_eq!(db.get("cde").unwrap(), None); // from DB
-
-		// Flush journal and fetch everything from DB.
-		db.flush_journal(2).unwrap();
+		let keys = |start: &str, end: &str| db.iter_range(start..end).unwrap()
+			.map(|item| item.unwrap().0.to_vec())
+			.collect::<Vec<_>>();
 
-		assert_eq!(db.get("abc").unwrap().unwrap(), b"456");
-		assert_eq!(db.get("cde").unwrap(), None);
+		assert_eq!(keys("aab", "bbb"), vec![b"aab".to_vec(), b"aac".to_vec(), b"bab".to_vec()]);
+		assert_eq!(keys("aaa", "aab"), vec![b"aaa".to_vec()]);
+		assert_eq!(keys("aba", "bab"), Vec::<Vec<u8>>::new());
+		assert_eq!(keys("bbb", "aaa"), Vec::<Vec<u8>>::new());
+		assert_eq!(keys("aaa", "zzz"), db.iter().unwrap().map(|item| item.unwrap().0.to_vec()).collect::<Vec<_>>());
+		assert!(db.iter_range("aa".."bbb").is_err());
 	}
 
 	#[test]
-	fn validate_key_length_at_insert() {
-		let temp = tempdir::TempDir::new("validate_key_length").unwrap();
+	fn test_iter_rev() {
+		let temp = tempdir::TempDir::new("test_iter_rev").unwrap();
 
-		let db = Database::create(temp.path(), Options {
-			journal_eras: 0,
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 1,
 			key_len: 3,
+			key_index_bits: 12,
+			value_len: ValuesLen::Constant(3),
+			max_prefix_collisions: 2,
 			..Default::default()
 		}).unwrap();
 
 		let mut tx = db.create_transaction();
-		assert_eq!(*tx.insert("abcdef", "456").unwrap_err().kind(), ErrorKind::InvalidKeyLen(3, 6));
+		tx.insert("aaa", "001").unwrap();
+		tx.insert("aab", "002").unwrap();
+		tx.insert("aqa", "003").unwrap();
+		tx.insert("bbb", "004").unwrap();
+		db.commit(&tx).unwrap();
+		let tx = db.create_transaction();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.compact().unwrap(), vec![0x616]);
+
+		let mut tx = db.create_transaction();
+		tx.insert("aac", "005").unwrap();
+		tx.delete("aqa").unwrap();
+		tx.insert("bab", "006").unwrap();
+		db.commit(&tx).unwrap();
+
+		let mut forward = db.iter().unwrap().map(|item| item.unwrap().0.to_vec()).collect::<Vec<_>>();
+		forward.reverse();
+		let reverse = db.iter_rev().unwrap().map(|item| item.unwrap().0.to_vec()).collect::<Vec<_>>();
+		assert_eq!(reverse, forward);
+
+		let latest = db.iter_prefix_rev(b"aa").unwrap().take(2).map(|item| item.unwrap()).collect::<Vec<_>>();
+		assert_eq!(latest.len(), 2);
+		assert_eq!(latest[0].0, b"aac");
+		assert_eq!(latest[0].1, "005");
+		assert_eq!(latest[1].0, b"aab");
+		assert_eq!(latest[1].1, "002");
+		assert_eq!(db.iter_prefix_rev(b"c").unwrap().count(), 0);
+		assert!(db.iter_prefix_rev(b"aaaa").is_err());
 	}
 
 	#[test]
-	fn should_validate_key_length() {
-		let temp = tempdir::TempDir::new("create_insert_and_query").unwrap();
+	fn test_get_into() {
+		let temp = tempdir::TempDir::new("test_get_into").unwrap();
 
-		let db = Database::create(temp.path(), Options {
-			journal_eras: 0,
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 1,
 			key_len: 3,
+			value_len: ValuesLen::Variable { expected: 1 },
 			..Default::default()
 		}).unwrap();
 
-		assert_eq!(*db.get("a").unwrap_err().kind(), ErrorKind::InvalidKeyLen(3, 1));
+		let mut tx = db.create_transaction();
+		tx.insert("aaa", "a value spanning multiple fields").unwrap();
+		db.commit(&tx).unwrap();
+		let mut tx = db.create_transaction();
+		tx.insert("bbb", "journaled").unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+
+		let mut buf = Vec::with_capacity(64);
+		let capacity = buf.capacity();
+
+		assert!(db.get_into("aaa", &mut buf).unwrap());
+		assert_eq!(buf, b"a value spanning multiple fields".to_vec());
+		assert!(db.get_into("bbb", &mut buf).unwrap());
+		assert_eq!(buf, b"journaled".to_vec());
+		assert!(!db.get_into("ccc", &mut buf).unwrap());
+		assert!(buf.is_empty());
+		assert_eq!(buf.capacity(), capacity);
 	}
 
 	#[test]
@@ -746,6 +5330,126 @@ mod tests {
 		);
 	}
 
+	#[test]
+	fn test_latency_report() {
+		let temp = tempdir::TempDir::new("test_latency_report").unwrap();
+
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			track_latencies: true,
+			..Default::default()
+		}).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("abc", "123").unwrap();
+		db.commit(&tx).unwrap();
+
+		assert_eq!(db.get("abc").unwrap().unwrap(), b"123");
+		assert_eq!(db.get("def").unwrap(), None);
+		assert_eq!(db.iter().unwrap().count(), 1);
+
+		let report = db.latency_report();
+		assert_eq!(report.get.count, 2);
+		assert_eq!(report.commit.count, 1);
+		// one item and the end of iteration
+		assert_eq!(report.iter.count, 2);
+		assert!(report.get.p50 <= report.get.max);
+	}
+
+	#[test]
+	fn test_fsync_policy() {
+		use options::FsyncPolicy;
+
+		let temp = tempdir::TempDir::new("test_fsync_policy").unwrap();
+
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 5,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			fsync: FsyncPolicy::EveryCommit,
+			..Default::default()
+		}).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("abc", "123").unwrap();
+		db.commit(&tx).unwrap();
+		assert!(db.journal.is_synced());
+
+		db.set_option("fsync", "never").unwrap();
+		db.commit(&tx).unwrap();
+		assert!(!db.journal.is_synced());
+		db.flush_wal().unwrap();
+		assert!(db.journal.is_synced());
+
+		db.set_option("fsync", "interval:3600000").unwrap();
+		db.commit(&tx).unwrap();
+		assert!(!db.journal.is_synced());
+		db.set_option("fsync", "interval:0").unwrap();
+		db.commit(&tx).unwrap();
+		assert!(db.journal.is_synced());
+	}
+
+	#[test]
+	fn test_set_option() {
+		use std::sync::{Arc, Mutex};
+		use events::Event;
+
+		let temp = tempdir::TempDir::new("test_set_option").unwrap();
+
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 5,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		}).unwrap();
+
+		let events = Arc::new(Mutex::new(Vec::new()));
+		let listener_events = events.clone();
+		db.set_event_listener(move |event| listener_events.lock().unwrap().push(event.clone()));
+
+		let mut tx = db.create_transaction();
+		tx.insert("abc", "123").unwrap();
+		db.commit(&tx).unwrap();
+
+		// the era is kept in the journal
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.journal.len(), 1);
+
+		db.set_option("journal_eras", "0").unwrap();
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.journal.len(), 0);
+		assert_eq!(db.get("abc").unwrap().unwrap(), b"123");
+
+		assert_eq!(*db.set_option("key_len", "4").unwrap_err().kind(), ErrorKind::OptionNotTunable("key_len".into()));
+		assert!(db.set_option("extend_threshold_percent", "101").is_err());
+		assert_eq!(db.options.external.extend_threshold_percent, 80);
+
+		// the cache is resized and keeps at most the new number of bytes
+		db.set_option("cache_size", "1024").unwrap();
+		db.get("abc").unwrap();
+		db.get("abc").unwrap();
+		assert_eq!((db.cache_stats().entries, db.cache_stats().hits), (1, 1));
+		db.set_option("cache_size", "0").unwrap();
+		db.get("abc").unwrap();
+		assert_eq!((db.cache_stats().entries, db.cache_stats().bytes), (0, 0));
+
+		assert_eq!(*events.lock().unwrap(), vec![Event::OptionChanged {
+			name: "journal_eras",
+			old: "5".into(),
+			new: "0".into(),
+		}, Event::OptionChanged {
+			name: "cache_size",
+			old: "0".into(),
+			new: "1024".into(),
+		}, Event::OptionChanged {
+			name: "cache_size",
+			old: "1024".into(),
+			new: "0".into(),
+		}]);
+	}
+
 	#[test]
 	fn should_validate_exclusive_access() {
 		let temp = tempdir::TempDir::new("exclusive_access").unwrap();
@@ -0,0 +1,256 @@
+//! Human readable dumps of database files for debugging and bug reports.
+//!
+//! Data files are printed record by record with their field offsets and the
+//! distance from the field of their prefix. Collision logs are printed entry
+//! by entry with their checksums, marking entries overwritten later in the log.
+//! Damage is reported inline instead of ending the dump.
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use collision;
+use error::Result;
+use field::{self, Header};
+use key::Key;
+use options::{InternalOptions, Options};
+use record::{self, Record, ValueSize};
+
+/// Longer values are shortened in dumps.
+const MAX_DUMPED_VALUE_BYTES: usize = 32;
+
+fn to_hex(data: &[u8]) -> String {
+	data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn format_value(value: &[u8]) -> String {
+	if value.len() > MAX_DUMPED_VALUE_BYTES {
+		format!("({} bytes) {}...", value.len(), to_hex(&value[..MAX_DUMPED_VALUE_BYTES]))
+	} else {
+		format!("({} bytes) {}", value.len(), to_hex(value))
+	}
+}
+
+/// Prints every record of the data file or collision log at `path` to `writer`.
+///
+/// Collision logs are recognized by their `collision-<prefix>.log` file name. `options`
+/// have to be the options the database was created with.
+pub fn dump_file<P: AsRef<Path>, W: Write>(path: P, options: &Options, mut writer: W) -> Result<()> {
+	let path = path.as_ref();
+	let mut data = Vec::new();
+	File::open(path)?.read_to_end(&mut data)?;
+
+	let is_collision = path.file_name()
+		.and_then(|name| name.to_str())
+		.map_or(false, |name| name.starts_with("collision-") && name.ends_with(".log"));
+
+	if is_collision {
+		dump_collision_log(path, &data, &mut writer)
+	} else {
+		let options = InternalOptions::from_external(options.clone())?;
+		dump_data_file(path, &data, &options, &mut writer)
+	}
+}
+
+fn dump_data_file<W: Write>(path: &Path, data: &[u8], options: &InternalOptions, writer: &mut W) -> Result<()> {
+	let field_body_size = options.field_body_size;
+	let field_size = field::field_size(field_body_size);
+	let key_len = options.external.key_len;
+	let prefix_bits = options.external.key_index_bits;
+	let fields = data.len() / field_size;
+
+	writeln!(writer, "data file {}: {} bytes, {} fields of {} bytes", path.display(), data.len(), fields, field_size)?;
+
+	let mut records = 0;
+	let mut errors = 0;
+	let mut field_index = 0;
+	while field_index < fields {
+		let offset = field_index * field_size;
+		let header = match Header::from_u8(data[offset]) {
+			Ok(header) => header,
+			Err(_) => {
+				writeln!(writer, "{:#010x} field {}: invalid header {:#04x}", offset, field_index, data[offset])?;
+				errors += 1;
+				field_index += 1;
+				continue;
+			},
+		};
+
+		match header {
+			Header::Uninitialized => {
+				field_index += 1;
+				continue;
+			},
+			Header::Continued => {
+				writeln!(writer, "{:#010x} field {}: continued field without a record", offset, field_index)?;
+				errors += 1;
+				field_index += 1;
+				continue;
+			},
+			Header::Inserted => {},
+		}
+
+		// the length of the record has to be known before it is read
+		let value_len = match options.value_size {
+			ValueSize::Constant(len) => len,
+			ValueSize::Variable => {
+				let mut len = [0u8; record::HEADER_SIZE];
+				let view = field::view::FieldsView::with_options(&data[offset..], field_body_size, key_len, record::HEADER_SIZE);
+				if (key_len + record::HEADER_SIZE + field_body_size - 1) / field_body_size * field_size > data.len() - offset {
+					writeln!(writer, "{:#010x} field {}: record truncated by the end of the file", offset, field_index)?;
+					errors += 1;
+					break;
+				}
+				view.copy_to_slice(&mut len);
+				LittleEndian::read_u32(&len) as usize
+			},
+		};
+
+		let header_len = if options.external.value_len.is_const() { 0 } else { record::HEADER_SIZE };
+		let record_fields = (key_len + header_len + value_len + field_body_size - 1) / field_body_size;
+		if (field_index + record_fields) * field_size > data.len() {
+			writeln!(writer, "{:#010x} field {}: record of {} fields truncated by the end of the file", offset, field_index, record_fields)?;
+			errors += 1;
+			break;
+		}
+
+		let record = Record::new(&data[offset..], field_body_size, options.value_size, key_len);
+		let mut value = vec![0u8; value_len];
+		record.read_value(&mut value);
+
+		let prefix = Key::new(record.key(), prefix_bits).prefix as usize;
+		let placement = if field_index >= prefix {
+			format!("+{}", field_index - prefix)
+		} else {
+			errors += 1;
+			"stored before its prefix".into()
+		};
+
+		writeln!(
+			writer,
+			"{:#010x} field {} prefix {} ({}): key {} value {}",
+			offset, field_index, prefix, placement, to_hex(record.key()), format_value(&value),
+		)?;
+		records += 1;
+
+		for continued in field_index + 1..field_index + record_fields {
+			let offset = continued * field_size;
+			if data[offset] != Header::Continued as u8 {
+				writeln!(writer, "{:#010x} field {}: expected continued field, got header {:#04x}", offset, continued, data[offset])?;
+				errors += 1;
+			}
+		}
+
+		field_index += record_fields;
+	}
+
+	writeln!(writer, "{} records, {} errors", records, errors)?;
+	Ok(())
+}
+
+fn dump_collision_log<W: Write>(path: &Path, data: &[u8], writer: &mut W) -> Result<()> {
+	let (checksums, entries, end) = collision::decode_log(data);
+	let format = if checksums { "checksummed entries" } else { "legacy entries without checksums" };
+	writeln!(writer, "collision log {}: {} bytes, {}", path.display(), data.len(), format)?;
+
+	let mut last = HashMap::new();
+	for (i, entry) in entries.iter().enumerate() {
+		last.insert(entry.key, i);
+	}
+
+	let mut errors = 0;
+	for (i, entry) in entries.iter().enumerate() {
+		let operation = match entry.value {
+			Some(value) => format!("insert key {} value {}", to_hex(entry.key), format_value(value)),
+			None => format!("delete key {}", to_hex(entry.key)),
+		};
+
+		let checksum = match entry.checksum {
+			Some((stored, computed)) if stored == computed => format!(" crc {:#010x}", stored),
+			Some((stored, computed)) => {
+				errors += 1;
+				format!(" crc {:#010x} MISMATCH, computed {:#010x}", stored, computed)
+			},
+			None => String::new(),
+		};
+
+		let stale = if last[&entry.key] != i { " (overwritten)" } else { "" };
+		writeln!(writer, "{:#010x} {}{}{}", entry.position, operation, checksum, stale)?;
+	}
+
+	if data[end..].iter().any(|byte| *byte != 0) {
+		writeln!(writer, "{:#010x} undecodable data before the end of the file", end)?;
+		errors += 1;
+	}
+
+	writeln!(writer, "end of log at {:#010x}, {} entries, {} errors", end, entries.len(), errors)?;
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	extern crate tempdir;
+
+	use std::fs::File;
+	use std::io::{Read, Write};
+
+	use database::Database;
+	use options::{Options, ValuesLen};
+	use super::dump_file;
+
+	fn dump(path: ::std::path::PathBuf, options: &Options) -> String {
+		let mut output = Vec::new();
+		dump_file(path, options, &mut output).unwrap();
+		String::from_utf8(output).unwrap()
+	}
+
+	#[test]
+	fn test_dump_file() {
+		let temp = tempdir::TempDir::new("test_dump_file").unwrap();
+		let options = Options {
+			journal_eras: 0,
+			key_len: 3,
+			key_index_bits: 8,
+			value_len: ValuesLen::Variable { expected: 3 },
+			max_prefix_collisions: 2,
+			..Default::default()
+		};
+
+		let mut db = Database::create(temp.path(), options.clone()).unwrap();
+		let mut tx = db.create_transaction();
+		tx.insert("aaa", "001").unwrap();
+		tx.insert("aab", "002").unwrap();
+		tx.insert("bbb", "a longer value spanning several fields").unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+		assert_eq!(db.compact().unwrap(), vec![b'a' as u32]);
+
+		let mut tx = db.create_transaction();
+		tx.insert("aaa", "003").unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+
+		let data = dump(temp.path().join(Database::DB_FILE), &options);
+		assert!(data.contains("prefix 98 (+0): key 626262 value (38 bytes) 61206c6f6e676572"));
+		assert!(data.ends_with("1 records, 0 errors\n"));
+
+		let log_path = temp.path().join("collision-97.log");
+		let log = dump(log_path.clone(), &options);
+		assert!(log.contains("checksummed entries"));
+		assert!(log.contains("insert key 616161 value (3 bytes) 303031 crc"));
+		assert!(log.contains("(overwritten)"));
+		assert!(log.ends_with("3 entries, 0 errors\n"));
+
+		// damage the value of the first entry
+		let mut data = Vec::new();
+		File::open(&log_path).unwrap().read_to_end(&mut data).unwrap();
+		data[8 + 4 + 3 + 4] ^= 1;
+		File::create(&log_path).unwrap().write_all(&data).unwrap();
+		let log = dump(log_path, &options);
+		assert!(log.contains("MISMATCH"));
+		assert!(log.ends_with("3 entries, 1 errors\n"));
+	}
+}
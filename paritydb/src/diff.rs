@@ -0,0 +1,98 @@
+//! Differences between two databases.
+
+use std::cmp::Ordering;
+
+use itertools::{EitherOrBoth, Itertools};
+
+use database::Value;
+use error::Result;
+
+/// A change of a single key.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Change {
+	/// Changed key.
+	pub key: Vec<u8>,
+	/// Value before the change, `None` if the key was inserted.
+	pub old: Option<Vec<u8>>,
+	/// Value after the change, `None` if the key was deleted.
+	pub new: Option<Vec<u8>>,
+}
+
+fn values_equal(old: &Value, new: &Value) -> bool {
+	match new.as_slice() {
+		Some(slice) => *old == slice,
+		None => *old == new.to_vec(),
+	}
+}
+
+/// Merges two iterators of key-value pairs ordered by key into an iterator of changes.
+pub fn diff<'a, A, B>(old: A, new: B) -> Box<Iterator<Item = Result<Change>> + 'a> where
+	A: Iterator<Item = Result<(&'a [u8], Value<'a>)>> + 'a,
+	B: Iterator<Item = Result<(&'a [u8], Value<'a>)>> + 'a,
+{
+	Box::new(old.merge_join_by(new, |o, n| {
+		match (o, n) {
+			(&Err(_), _) => Ordering::Less,
+			(_, &Err(_)) => Ordering::Greater,
+			(&Ok(ref o), &Ok(ref n)) => o.0.cmp(&n.0),
+		}
+	}).filter_map(|either| {
+		match either {
+			EitherOrBoth::Left(Err(err)) | EitherOrBoth::Right(Err(err)) => Some(Err(err)),
+			EitherOrBoth::Both(Err(err), _) | EitherOrBoth::Both(_, Err(err)) => Some(Err(err)),
+			EitherOrBoth::Left(Ok((key, old))) => Some(Ok(Change {
+				key: key.to_vec(),
+				old: Some(old.to_vec()),
+				new: None,
+			})),
+			EitherOrBoth::Right(Ok((key, new))) => Some(Ok(Change {
+				key: key.to_vec(),
+				old: None,
+				new: Some(new.to_vec()),
+			})),
+			EitherOrBoth::Both(Ok((key, old)), Ok((_, new))) => {
+				if values_equal(&old, &new) {
+					None
+				} else {
+					Some(Ok(Change {
+						key: key.to_vec(),
+						old: Some(old.to_vec()),
+						new: Some(new.to_vec()),
+					}))
+				}
+			},
+		}
+	}))
+}
+
+#[cfg(test)]
+mod tests {
+	use database::Value;
+	use error::Result;
+	use super::{diff, Change};
+
+	fn pairs<'a>(pairs: &[(&'a [u8], &'a [u8])]) -> Vec<Result<(&'a [u8], Value<'a>)>> {
+		pairs.iter().map(|&(key, value)| Ok((key, Value::Raw(value)))).collect()
+	}
+
+	#[test]
+	fn test_diff() {
+		let old = pairs(&[(b"a", b"1"), (b"b", b"2"), (b"c", b"3")]);
+		let new = pairs(&[(b"b", b"2"), (b"c", b"4"), (b"d", b"5")]);
+
+		let changes = diff(old.into_iter(), new.into_iter()).collect::<Result<Vec<_>>>().unwrap();
+		assert_eq!(changes, vec![
+			Change { key: b"a".to_vec(), old: Some(b"1".to_vec()), new: None },
+			Change { key: b"c".to_vec(), old: Some(b"3".to_vec()), new: Some(b"4".to_vec()) },
+			Change { key: b"d".to_vec(), old: None, new: Some(b"5".to_vec()) },
+		]);
+	}
+
+	#[test]
+	fn test_diff_equal() {
+		let old = pairs(&[(b"a", b"1"), (b"b", b"2")]);
+		let new = pairs(&[(b"a", b"1"), (b"b", b"2")]);
+
+		assert_eq!(diff(old.into_iter(), new.into_iter()).count(), 0);
+	}
+}
@@ -4,6 +4,8 @@
 use std::{io, num};
 use std::path::PathBuf;
 
+use hex_slice::AsHex;
+
 use field;
 
 error_chain! {
@@ -21,6 +23,10 @@ error_chain! {
 			description("Invalid key length")
 			display("Invalid key length. Expected: {}, got: {}", expected, got),
 		}
+		InvalidValueLen(expected: usize, got: usize) {
+			description("Invalid value length")
+			display("Invalid value length. Expected: {}, got: {}", expected, got),
+		}
 		CorruptedFlush(path: PathBuf, msg: String) {
 			description("Hash of flush data is invalid"),
 			display("Database flush corruption detected in file at {}. {}", path.display(), msg),
@@ -29,6 +35,42 @@ error_chain! {
 			description("Hash of journal data is invalid"),
 			display("Database journal corruption detected in file at {}. {}", path.display(), msg),
 		}
+		CorruptedEpoch(path: PathBuf, msg: String) {
+			description("Epoch file is invalid"),
+			display("Epoch corruption detected in file at {}. {}", path.display(), msg),
+		}
+		CorruptedSeal(path: PathBuf, msg: String) {
+			description("Sealed prefixes file is invalid"),
+			display("Sealed prefixes corruption detected in file at {}. {}", path.display(), msg),
+		}
+		CorruptedCompression(path: PathBuf, msg: String) {
+			description("Column compression file is invalid"),
+			display("Column compression corruption detected in file at {}. {}", path.display(), msg),
+		}
+		CorruptedSeries(path: PathBuf, msg: String) {
+			description("Series file is invalid"),
+			display("Series corruption detected in file at {}. {}", path.display(), msg),
+		}
+		CorruptedCommitGroup(path: PathBuf, msg: String) {
+			description("Commit group file is invalid"),
+			display("Commit group corruption detected in file at {}. {}", path.display(), msg),
+		}
+		Corruption(path: PathBuf, offset: u64) {
+			description("Collision log entry is invalid"),
+			display("Collision log corruption detected in file at {} at offset {}.", path.display(), offset),
+		}
+		InvalidSnapshot(path: PathBuf, msg: String) {
+			description("Snapshot is invalid"),
+			display("Invalid snapshot at {}. {}", path.display(), msg),
+		}
+		InvalidDump(msg: String) {
+			description("Dump is invalid"),
+			display("Invalid dump. {}", msg),
+		}
+		InvalidJournalSegment(msg: String) {
+			description("Journal segment is invalid"),
+			display("Invalid journal segment. {}", msg),
+		}
 		InvalidJournalLocation(path: PathBuf) {
 			description("Path to journal is a file"),
 			display("Expected a directory at {}, got file.", path.display()),
@@ -41,6 +83,62 @@ error_chain! {
 			description("Invalid options were provided"),
 			display("Invalid value of `{}`: {}", field, error),
 		}
+		UnknownOption(name: String) {
+			description("Unknown option"),
+			display("Unknown option `{}`", name),
+		}
+		OptionNotTunable(name: String) {
+			description("Option cannot be changed while the database is open"),
+			display("Option `{}` cannot be changed while the database is open", name),
+		}
+		InvalidOptionsFile(path: PathBuf, error: String) {
+			description("Options file could not be parsed"),
+			display("Invalid options file at {}: {}", path.display(), error),
+		}
+		PrefixSealed(key: Vec<u8>, prefix: u32) {
+			description("Prefix is sealed"),
+			display("Key {:02x} cannot be written, its prefix {} is sealed", key.as_hex(), prefix),
+		}
+		WriteOnceViolation(key: Vec<u8>) {
+			description("Write-once database key cannot be changed"),
+			display("Key {:02x} cannot be deleted or overwritten in a write-once database", key.as_hex()),
+		}
+		CommitRejected(key: Vec<u8>, reason: String) {
+			description("Commit was rejected by a trigger"),
+			display("Commit rejected by a trigger of key {:02x}: {}", key.as_hex(), reason),
+		}
+		InvalidValueEnvelope(msg: String) {
+			description("Transformed value is invalid"),
+			display("Invalid transformed value: {}", msg),
+		}
+		ReadOnly {
+			description("Database is opened read-only"),
+			display("Database is opened read-only and its files cannot be changed"),
+		}
+		InvalidColumn(col: u32) {
+			description("Column is not opened"),
+			display("Column {} is not opened, a database has at most 255 columns", col),
+		}
+		DatabaseClosed {
+			description("Database is closed"),
+			display("Database was closed after its files could not be opened by a restore"),
+		}
+		CheckpointExists(path: PathBuf) {
+			description("Checkpoint directory holds a database"),
+			display("Cannot write a checkpoint into {}, it already holds a database", path.display()),
+		}
+		CheckpointInProgress {
+			description("Checkpoint is in progress"),
+			display("Database files cannot be compacted until the checkpoint copying them is finished"),
+		}
+		UnsupportedFormatVersion(found: u16, supported: u16) {
+			description("Database was created with an unsupported format version"),
+			display("Unsupported database format version {}. This version supports format {}", found, supported),
+		}
+		FencedOff(epoch: u64, current: u64) {
+			description("Commit of a deposed writer was rejected"),
+			display("Commit with fencing epoch {} rejected, the database is at epoch {}", epoch, current),
+		}
 		DatabaseLocked(path: PathBuf) {
 			description("Database file lock is currently acquired"),
 			display("Could not acquire database file lock: {}. \
@@ -57,14 +155,59 @@ impl PartialEq for ErrorKind {
 		match (self, other) {
 			(&InvalidKeyLen(expected, got), &InvalidKeyLen(expected2, got2))
 				if expected == expected2 && got == got2 => true,
+			(&InvalidValueLen(expected, got), &InvalidValueLen(expected2, got2))
+				if expected == expected2 && got == got2 => true,
+			(&CorruptedEpoch(ref path, ref msg), &CorruptedEpoch(ref path2, ref msg2))
+				if path == path2 && msg == msg2 => true,
+			(&CorruptedSeal(ref path, ref msg), &CorruptedSeal(ref path2, ref msg2))
+				if path == path2 && msg == msg2 => true,
+			(&CorruptedCompression(ref path, ref msg), &CorruptedCompression(ref path2, ref msg2))
+				if path == path2 && msg == msg2 => true,
+			(&CorruptedSeries(ref path, ref msg), &CorruptedSeries(ref path2, ref msg2))
+				if path == path2 && msg == msg2 => true,
 			(&CorruptedJournal(ref path, ref msg), &CorruptedJournal(ref path2, ref msg2))
 				if path == path2 && msg == msg2 => true,
+			(&CorruptedCommitGroup(ref path, ref msg), &CorruptedCommitGroup(ref path2, ref msg2))
+				if path == path2 && msg == msg2 => true,
+			(&Corruption(ref path, offset), &Corruption(ref path2, offset2))
+				if path == path2 && offset == offset2 => true,
+			(&InvalidSnapshot(ref path, ref msg), &InvalidSnapshot(ref path2, ref msg2))
+				if path == path2 && msg == msg2 => true,
+			(&InvalidDump(ref msg), &InvalidDump(ref msg2))
+				if msg == msg2 => true,
+			(&InvalidJournalSegment(ref msg), &InvalidJournalSegment(ref msg2))
+				if msg == msg2 => true,
 			(&InvalidJournalLocation(ref path), &InvalidJournalLocation(ref path2))
 				if path == path2 => true,
 			(&JournalEraMissing(idx), &JournalEraMissing(idx2))
 				if idx == idx2 => true,
 			(&InvalidOptions(field, ref error), &InvalidOptions(field2, ref error2))
 				if field == field2 && error == error2 => true,
+			(&UnknownOption(ref name), &UnknownOption(ref name2))
+				if name == name2 => true,
+			(&OptionNotTunable(ref name), &OptionNotTunable(ref name2))
+				if name == name2 => true,
+			(&InvalidOptionsFile(ref path, ref error), &InvalidOptionsFile(ref path2, ref error2))
+				if path == path2 && error == error2 => true,
+			(&PrefixSealed(ref key, prefix), &PrefixSealed(ref key2, prefix2))
+				if key == key2 && prefix == prefix2 => true,
+			(&WriteOnceViolation(ref key), &WriteOnceViolation(ref key2))
+				if key == key2 => true,
+			(&CommitRejected(ref key, ref reason), &CommitRejected(ref key2, ref reason2))
+				if key == key2 && reason == reason2 => true,
+			(&InvalidValueEnvelope(ref msg), &InvalidValueEnvelope(ref msg2))
+				if msg == msg2 => true,
+			(&UnsupportedFormatVersion(found, supported), &UnsupportedFormatVersion(found2, supported2))
+				if found == found2 && supported == supported2 => true,
+			(&FencedOff(epoch, current), &FencedOff(epoch2, current2))
+				if epoch == epoch2 && current == current2 => true,
+			(&InvalidColumn(col), &InvalidColumn(col2))
+				if col == col2 => true,
+			(&CheckpointExists(ref path), &CheckpointExists(ref path2))
+				if path == path2 => true,
+			(&CheckpointInProgress, &CheckpointInProgress) => true,
+			(&DatabaseClosed, &DatabaseClosed) => true,
+			(&ReadOnly, &ReadOnly) => true,
 			_ => false,
 		}
 	}
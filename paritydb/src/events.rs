@@ -0,0 +1,54 @@
+//! Database events.
+
+use std::fmt;
+use std::path::PathBuf;
+
+/// A notable change of the database state.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+	/// An option was changed with `Database::set_option`.
+	OptionChanged {
+		/// Name of the option.
+		name: &'static str,
+		/// Previous value of the option.
+		old: String,
+		/// New value of the option.
+		new: String,
+	},
+	/// A corrupted entry of a collision file was rewritten with the value fetched from the
+	/// repair source, see `Database::set_repair_source`.
+	Repaired {
+		/// Path of the collision file.
+		path: PathBuf,
+		/// Position of the entry in the file.
+		offset: u64,
+		/// Key of the entry.
+		key: Vec<u8>,
+	},
+}
+
+/// Delivers events to the listener registered by the user.
+#[derive(Default)]
+pub struct Events {
+	listener: Option<Box<Fn(&Event) + Send + Sync>>,
+}
+
+impl fmt::Debug for Events {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "Events {{ listener: {} }}", if self.listener.is_some() { "Some(..)" } else { "None" })
+	}
+}
+
+impl Events {
+	/// Replaces the current listener.
+	pub fn set_listener(&mut self, listener: Box<Fn(&Event) + Send + Sync>) {
+		self.listener = Some(listener);
+	}
+
+	/// Notifies the listener about the event.
+	pub fn emit(&self, event: Event) {
+		if let Some(ref listener) = self.listener {
+			listener(&event);
+		}
+	}
+}
@@ -0,0 +1,279 @@
+//! Portable dumps of all key-value pairs, see `Database::export` and `Database::import`.
+//!
+//! A dump doesn't depend on the on-disk format or the options of the database which
+//! wrote it. Values are dumped as they were committed, without value transforms and
+//! compression. All integers are little-endian.
+//!
+//! ```text
+//!  magic     version  key len  chunks...  0    records
+//!   /         /        /        /         /    /
+//! |........|..      |....    |......   |....|........|
+//! ```
+//!
+//! Every chunk is prefixed with its u32 length and followed by its sha3, and holds
+//! records encoded as the key, the u32 length of the value and the value. A chunk
+//! of length 0 ends the dump and is followed by the u64 number of records.
+
+use std::io::{Read, Write};
+
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use tiny_keccak::sha3_256;
+
+use error::{ErrorKind, Result};
+
+const MAGIC: &'static [u8] = b"PDBDUMP\0";
+/// Version of the dump format written by this build.
+pub const VERSION: u16 = 1;
+const HEADER_SIZE: usize = 14;
+const CHECKSUM_SIZE: usize = 32;
+/// Chunks are written once they hold at least this many bytes.
+const CHUNK_SIZE: usize = 1 << 20;
+
+/// Writes a dump record by record.
+#[derive(Debug)]
+pub struct DumpWriter<W: Write> {
+	writer: W,
+	key_len: usize,
+	chunk: Vec<u8>,
+	records: u64,
+}
+
+impl<W: Write> DumpWriter<W> {
+	/// Writes the header of a dump of keys of `key_len` bytes.
+	pub fn new(mut writer: W, key_len: usize) -> Result<Self> {
+		writer.write_all(MAGIC)?;
+		writer.write_u16::<LittleEndian>(VERSION)?;
+		writer.write_u32::<LittleEndian>(key_len as u32)?;
+
+		Ok(DumpWriter {
+			writer,
+			key_len,
+			chunk: Vec::with_capacity(CHUNK_SIZE),
+			records: 0,
+		})
+	}
+
+	/// Adds a record to the dump.
+	pub fn write(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+		if key.len() != self.key_len {
+			bail!(ErrorKind::InvalidKeyLen(self.key_len, key.len()));
+		}
+		if value.len() > u32::max_value() as usize {
+			bail!(ErrorKind::InvalidDump(format!("value of {} bytes is too long", value.len())));
+		}
+
+		self.chunk.extend_from_slice(key);
+		self.chunk.write_u32::<LittleEndian>(value.len() as u32)?;
+		self.chunk.extend_from_slice(value);
+		self.records += 1;
+
+		if self.chunk.len() >= CHUNK_SIZE {
+			self.write_chunk()?;
+		}
+
+		Ok(())
+	}
+
+	fn write_chunk(&mut self) -> Result<()> {
+		if self.chunk.len() > u32::max_value() as usize {
+			bail!(ErrorKind::InvalidDump(format!("chunk of {} bytes is too long", self.chunk.len())));
+		}
+
+		self.writer.write_u32::<LittleEndian>(self.chunk.len() as u32)?;
+		self.writer.write_all(&self.chunk)?;
+		self.writer.write_all(&sha3_256(&self.chunk))?;
+		self.chunk.clear();
+		Ok(())
+	}
+
+	/// Writes the remaining records and the end of the dump. Returns the number of records.
+	pub fn finish(mut self) -> Result<u64> {
+		if !self.chunk.is_empty() {
+			self.write_chunk()?;
+		}
+
+		self.writer.write_u32::<LittleEndian>(0)?;
+		self.writer.write_u64::<LittleEndian>(self.records)?;
+		self.writer.flush()?;
+		Ok(self.records)
+	}
+}
+
+/// Reads a dump chunk by chunk.
+#[derive(Debug)]
+pub struct DumpReader<R: Read> {
+	reader: R,
+	key_len: usize,
+	records: u64,
+	finished: bool,
+}
+
+impl<R: Read> DumpReader<R> {
+	/// Reads the header of a dump, which has to hold keys of `key_len` bytes.
+	pub fn new(mut reader: R, key_len: usize) -> Result<Self> {
+		let mut header = [0u8; HEADER_SIZE];
+		read_exact(&mut reader, &mut header, "header")?;
+		if &header[..MAGIC.len()] != MAGIC {
+			bail!(ErrorKind::InvalidDump("not a paritydb dump".into()));
+		}
+
+		let version = LittleEndian::read_u16(&header[8..10]);
+		if version != VERSION {
+			bail!(ErrorKind::InvalidDump(format!("unsupported dump version {}, expected {}", version, VERSION)));
+		}
+
+		let dump_key_len = LittleEndian::read_u32(&header[10..]) as usize;
+		if dump_key_len != key_len {
+			bail!(ErrorKind::InvalidKeyLen(key_len, dump_key_len));
+		}
+
+		Ok(DumpReader {
+			reader,
+			key_len,
+			records: 0,
+			finished: false,
+		})
+	}
+
+	/// Returns the records of the next chunk, `None` after the end of the dump.
+	///
+	/// Fails if the chunk doesn't match its checksum or if the dump ends before its end.
+	pub fn next_chunk(&mut self) -> Result<Option<Vec<(Vec<u8>, Vec<u8>)>>> {
+		if self.finished {
+			return Ok(None);
+		}
+
+		let mut len = [0u8; 4];
+		read_exact(&mut self.reader, &mut len, "chunk length")?;
+		let len = LittleEndian::read_u32(&len) as usize;
+		if len == 0 {
+			let mut records = [0u8; 8];
+			read_exact(&mut self.reader, &mut records, "number of records")?;
+			let records = LittleEndian::read_u64(&records);
+			if records != self.records {
+				bail!(ErrorKind::InvalidDump(format!("expected {} records, got {}", records, self.records)));
+			}
+
+			self.finished = true;
+			return Ok(None);
+		}
+
+		// the chunk is not allocated upfront, so a damaged length doesn't allocate all memory
+		let mut chunk = Vec::new();
+		(&mut self.reader).take(len as u64).read_to_end(&mut chunk)?;
+		if chunk.len() != len {
+			bail!(ErrorKind::InvalidDump("chunk is truncated".into()));
+		}
+
+		let mut checksum = [0u8; CHECKSUM_SIZE];
+		read_exact(&mut self.reader, &mut checksum, "chunk checksum")?;
+		if sha3_256(&chunk) != checksum {
+			bail!(ErrorKind::InvalidDump(format!("checksum of chunk after record {} is invalid", self.records)));
+		}
+
+		let records = parse_chunk(&chunk, self.key_len)?;
+		self.records += records.len() as u64;
+		Ok(Some(records))
+	}
+
+	/// Returns the number of records read so far.
+	pub fn records(&self) -> u64 {
+		self.records
+	}
+}
+
+fn read_exact<R: Read>(reader: &mut R, buf: &mut [u8], what: &str) -> Result<()> {
+	let mut read = 0;
+	while read < buf.len() {
+		match reader.read(&mut buf[read..])? {
+			0 => bail!(ErrorKind::InvalidDump(format!("dump ended in the {}", what))),
+			n => read += n,
+		}
+	}
+
+	Ok(())
+}
+
+fn parse_chunk(mut chunk: &[u8], key_len: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+	let mut records = Vec::new();
+	while !chunk.is_empty() {
+		if chunk.len() < key_len + 4 {
+			bail!(ErrorKind::InvalidDump("record is truncated".into()));
+		}
+
+		let value_len = LittleEndian::read_u32(&chunk[key_len..key_len + 4]) as usize;
+		let end = key_len + 4 + value_len;
+		if chunk.len() < end {
+			bail!(ErrorKind::InvalidDump("value is truncated".into()));
+		}
+
+		records.push((chunk[..key_len].to_vec(), chunk[key_len + 4..end].to_vec()));
+		chunk = &chunk[end..];
+	}
+
+	Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+	use error::ErrorKind;
+	use super::{DumpReader, DumpWriter, CHUNK_SIZE};
+
+	fn read_all(dump: &[u8], key_len: usize) -> ::error::Result<Vec<(Vec<u8>, Vec<u8>)>> {
+		let mut reader = DumpReader::new(dump, key_len)?;
+		let mut records = Vec::new();
+		while let Some(chunk) = reader.next_chunk()? {
+			records.extend(chunk);
+		}
+		Ok(records)
+	}
+
+	#[test]
+	fn test_dump() {
+		let mut dump = Vec::new();
+		{
+			let mut writer = DumpWriter::new(&mut dump, 3).unwrap();
+			writer.write(b"aaa", b"001").unwrap();
+			writer.write(b"bbb", &vec![7; CHUNK_SIZE]).unwrap();
+			writer.write(b"ccc", b"").unwrap();
+			assert!(writer.write(b"dd", b"002").is_err());
+			assert_eq!(writer.finish().unwrap(), 3);
+		}
+
+		let records = read_all(&dump, 3).unwrap();
+		assert_eq!(records, vec![
+			(b"aaa".to_vec(), b"001".to_vec()),
+			(b"bbb".to_vec(), vec![7; CHUNK_SIZE]),
+			(b"ccc".to_vec(), Vec::new()),
+		]);
+
+		let mut reader = DumpReader::new(&dump[..], 3).unwrap();
+		assert_eq!(reader.next_chunk().unwrap().unwrap().len(), 2);
+		assert_eq!(reader.next_chunk().unwrap().unwrap().len(), 1);
+		assert_eq!(reader.next_chunk().unwrap(), None);
+		assert_eq!(reader.records(), 3);
+		assert_eq!(reader.next_chunk().unwrap(), None);
+	}
+
+	#[test]
+	fn test_invalid_dump() {
+		let mut dump = Vec::new();
+		{
+			let mut writer = DumpWriter::new(&mut dump, 3).unwrap();
+			writer.write(b"aaa", b"001").unwrap();
+			writer.finish().unwrap();
+		}
+
+		assert_eq!(*read_all(&dump, 4).unwrap_err().kind(), ErrorKind::InvalidKeyLen(4, 3));
+		assert!(matches!(*read_all(&dump[..dump.len() - 1], 3).unwrap_err().kind(), ErrorKind::InvalidDump(_)));
+		assert!(matches!(*read_all(b"PDBDUMP", 3).unwrap_err().kind(), ErrorKind::InvalidDump(_)));
+
+		let mut corrupted = dump.clone();
+		corrupted[20] ^= 1;
+		assert!(matches!(*read_all(&corrupted, 3).unwrap_err().kind(), ErrorKind::InvalidDump(_)));
+
+		let mut version = dump.clone();
+		version[8] = 2;
+		assert!(matches!(*read_all(&version, 3).unwrap_err().kind(), ErrorKind::InvalidDump(_)));
+	}
+}
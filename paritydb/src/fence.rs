@@ -0,0 +1,71 @@
+//! Fencing epoch of the database.
+//!
+//! The epoch is stored in its own file as a little-endian u64 and is read from
+//! the disk before every fenced commit, so a primary which was deposed by
+//! another process on shared storage notices the change immediately.
+//! A missing file means epoch 0.
+
+use std::fs;
+use std::io::{Read, Write};
+use std::path::Path;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use error::{ErrorKind, Result};
+
+pub const EPOCH_FILE: &'static str = "EPOCH";
+const EPOCH_SIZE: usize = 8;
+
+/// Reads the current epoch from database directory `dir`.
+pub fn read_epoch<P: AsRef<Path>>(dir: P) -> Result<u64> {
+	let path = dir.as_ref().join(EPOCH_FILE);
+	if !path.exists() {
+		return Ok(0);
+	}
+
+	let mut data = Vec::new();
+	fs::File::open(&path)?.read_to_end(&mut data)?;
+	if data.len() != EPOCH_SIZE {
+		bail!(ErrorKind::CorruptedEpoch(path, format!("Expected {} bytes, got {}", EPOCH_SIZE, data.len())));
+	}
+
+	Ok(LittleEndian::read_u64(&data))
+}
+
+/// Serializes `epoch` into the contents of the epoch file.
+pub fn encode(epoch: u64) -> Vec<u8> {
+	let mut data = vec![0u8; EPOCH_SIZE];
+	LittleEndian::write_u64(&mut data, epoch);
+	data
+}
+
+/// Atomically replaces the epoch in database directory `dir`.
+pub fn write_epoch<P: AsRef<Path>>(dir: P, epoch: u64) -> Result<()> {
+	let dir = dir.as_ref();
+	let tmp_path = dir.join(EPOCH_FILE).with_extension("tmp");
+
+	{
+		let mut file = fs::File::create(&tmp_path)?;
+		file.write_all(&encode(epoch))?;
+		file.sync_all()?;
+	}
+
+	fs::rename(&tmp_path, dir.join(EPOCH_FILE))?;
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	extern crate tempdir;
+
+	use super::{read_epoch, write_epoch};
+
+	#[test]
+	fn test_epoch() {
+		let temp = tempdir::TempDir::new("test_epoch").unwrap();
+
+		assert_eq!(read_epoch(temp.path()).unwrap(), 0);
+		write_epoch(temp.path(), 0x0102).unwrap();
+		assert_eq!(read_epoch(temp.path()).unwrap(), 0x0102);
+	}
+}
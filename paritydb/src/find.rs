@@ -21,6 +21,17 @@ pub fn find_record<'a>(
 	field_body_size: usize,
 	value_size: ValueSize,
 	key: &[u8],
+) -> Result<RecordResult<'a>, Error> {
+	find_record_counted(data, field_body_size, value_size, key, &mut 0)
+}
+
+/// Same as `find_record`, but also increments `fields_scanned` for every field header examined.
+pub fn find_record_counted<'a>(
+	data: &'a [u8],
+	field_body_size: usize,
+	value_size: ValueSize,
+	key: &[u8],
+	fields_scanned: &mut usize,
 ) -> Result<RecordResult<'a>, Error> {
 	let iter = FieldHeaderIterator::new(data, field_body_size)?;
 
@@ -28,6 +39,7 @@ pub fn find_record<'a>(
 	let mut offset = 0;
 	for header in iter {
 		let header = header?;
+		*fields_scanned += 1;
 		match header {
 			Header::Uninitialized => return Ok(RecordResult::NotFound),
 			Header::Inserted => {
@@ -55,6 +67,17 @@ pub fn iter<'a>(
 	key_size: usize,
 	value_size: ValueSize
 ) -> Result<RecordIterator<'a>, Error> {
+	iter_prefixes(data, occupied_prefixes_iter, field_body_size, key_size, value_size)
+}
+
+/// Same as `iter`, but only visits records stored from the given occupied prefixes onwards.
+pub fn iter_prefixes<'a, T: Iterator<Item=u32>>(
+	data: &'a [u8],
+	occupied_prefixes_iter: T,
+	field_body_size: usize,
+	key_size: usize,
+	value_size: ValueSize
+) -> Result<RecordIterator<'a, T>, Error> {
 	let offset = 0;
 	let peek_offset = None;
 	let field_size = field_size(field_body_size);
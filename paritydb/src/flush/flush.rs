@@ -1,4 +1,5 @@
 use std::io::{self, Write};
+use std::ops::Range;
 use std::path::{Path, PathBuf};
 use std::{mem, fs};
 
@@ -96,7 +97,7 @@ impl Flush {
 		}
 
 		let meta_offset = mmap.len() - metadata::bytes::len(prefix_bits);
-		let metadata = metadata::bytes::read(unsafe { &mmap.as_slice()[meta_offset..] }, prefix_bits);
+		let metadata = metadata::bytes::read(unsafe { &mmap.as_slice()[meta_offset..] }, prefix_bits)?;
 		Ok(Some(Flush {
 			path,
 			mmap,
@@ -120,6 +121,36 @@ impl Flush {
 		mem::swap(&mut self.metadata.clone(), metadata);
 	}
 
+	/// Returns ranges of the database modified by `flush`, aligned to `block_size`.
+	///
+	/// Overlapping and adjacent ranges are merged.
+	pub fn dirty_ranges(&self, block_size: usize) -> Vec<Range<usize>> {
+		let meta_offset = self.mmap.len() - metadata::bytes::len(self.prefix_bits);
+		let operations = unsafe { &self.mmap.as_slice()[Self::CHECKSUM_SIZE..meta_offset] };
+
+		let mut blocks: Vec<Range<usize>> = IdempotentOperationIterator::new(operations)
+			.map(|o| {
+				let start = o.offset / block_size * block_size;
+				let end = (o.offset + o.data.len() + block_size - 1) / block_size * block_size;
+				start..end
+			})
+			.collect();
+		blocks.sort_by_key(|range| range.start);
+
+		let mut ranges: Vec<Range<usize>> = Vec::with_capacity(blocks.len());
+		for block in blocks {
+			if let Some(last) = ranges.last_mut() {
+				if block.start <= last.end {
+					last.end = ::std::cmp::max(last.end, block.end);
+					continue;
+				}
+			}
+			ranges.push(block);
+		}
+
+		ranges
+	}
+
 	/// Delete flush file. Should be called only after database has been successfully flushed.
 	pub fn delete(self) -> Result<()> {
 		fs::remove_file(self.path)?;
@@ -0,0 +1,227 @@
+//! Atomic commits across several databases.
+//!
+//! Before anything is committed to the member databases, all transactions
+//! are written to a single group file together with the sequence number each
+//! database is expected to assign to its commit. Writing the group file is
+//! the commit point. After a crash `CommitGroup::recover` commits the
+//! transactions to the databases which have no journal era with the
+//! expected sequence number.
+//!
+//! ```text
+//!  checksum  members  sequence  len  transaction  sequence  len  transaction
+//!   /         /        /         /    /            /
+//! |........|....|........|....|.............|........|....|.............|
+//! ```
+
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use hex_slice::AsHex;
+use tiny_keccak::sha3_256;
+
+use database::Database;
+use error::{ErrorKind, Result};
+use transaction::{Operation, OperationsIterator, Transaction};
+
+const CHECKSUM_SIZE: usize = 32;
+
+/// Coordinates all-or-nothing commits to several databases, e.g. a state and a blocks database.
+///
+/// Databases have to be passed in the same order to `commit` and `recover`.
+#[derive(Debug)]
+pub struct CommitGroup {
+	dir: PathBuf,
+}
+
+impl CommitGroup {
+	const FILE: &'static str = "commit-group.log";
+
+	/// Opens a commit group which keeps its file in given directory.
+	pub fn open<P: AsRef<Path>>(dir: P) -> Result<Self> {
+		fs::create_dir_all(&dir)?;
+		Ok(CommitGroup {
+			dir: dir.as_ref().to_owned(),
+		})
+	}
+
+	fn path(&self) -> PathBuf {
+		self.dir.join(Self::FILE)
+	}
+
+	/// Commits each transaction to its database.
+	///
+	/// After a crash either all or none of the transactions are visible once `recover` is called.
+	pub fn commit(&self, members: &mut [(&mut Database, &Transaction)]) -> Result<()> {
+		{
+			let prepared: Vec<_> = members.iter().map(|&(ref db, tx)| (db.next_sequence(), tx)).collect();
+			self.prepare(&prepared)?;
+		}
+
+		for &mut (ref mut db, tx) in members.iter_mut() {
+			db.commit(tx)?;
+		}
+
+		fs::remove_file(self.path())?;
+		Ok(())
+	}
+
+	/// Durably writes the group file with transactions and their expected sequence numbers.
+	fn prepare(&self, members: &[(u64, &Transaction)]) -> Result<()> {
+		if self.path().exists() {
+			bail!(ErrorKind::CorruptedCommitGroup(self.path(), "An unrecovered group commit is pending".into()));
+		}
+
+		let mut data = Vec::new();
+		data.write_u32::<LittleEndian>(members.len() as u32)?;
+		for &(sequence, tx) in members {
+			data.write_u64::<LittleEndian>(sequence)?;
+			data.write_u32::<LittleEndian>(tx.raw().len() as u32)?;
+			data.extend_from_slice(tx.raw());
+		}
+
+		let tmp_path = self.path().with_extension("tmp");
+		{
+			let mut file = fs::File::create(&tmp_path)?;
+			file.write_all(&sha3_256(&data))?;
+			file.write_all(&data)?;
+			file.sync_all()?;
+		}
+		fs::rename(&tmp_path, self.path())?;
+		fs::File::open(&self.dir)?.sync_all()?;
+
+		Ok(())
+	}
+
+	/// Finishes an interrupted group commit. Returns true if there was one.
+	///
+	/// Has to be called after opening the databases and before committing anything else to them.
+	pub fn recover(&self, dbs: &mut [&mut Database]) -> Result<bool> {
+		let path = self.path();
+		if !path.exists() {
+			return Ok(false);
+		}
+
+		let mut data = Vec::new();
+		fs::File::open(&path)?.read_to_end(&mut data)?;
+
+		if data.len() < CHECKSUM_SIZE + 4 {
+			bail!(ErrorKind::CorruptedCommitGroup(path, "File is too short".into()));
+		}
+
+		let hash = sha3_256(&data[CHECKSUM_SIZE..]);
+		if &hash[..] != &data[..CHECKSUM_SIZE] {
+			bail!(ErrorKind::CorruptedCommitGroup(
+				path,
+				format!("Expected: {:02x}, Got: {:02x}", hash.as_hex(), data[..CHECKSUM_SIZE].as_hex())
+			));
+		}
+
+		let mut data = &data[CHECKSUM_SIZE..];
+		let members = LittleEndian::read_u32(data) as usize;
+		data = &data[4..];
+		if members != dbs.len() {
+			bail!(ErrorKind::CorruptedCommitGroup(
+				path,
+				format!("Group has {} members, got {} databases", members, dbs.len())
+			));
+		}
+
+		for db in dbs.iter_mut() {
+			if data.len() < 12 {
+				bail!(ErrorKind::CorruptedCommitGroup(path, "Truncated member".into()));
+			}
+
+			let sequence = LittleEndian::read_u64(data);
+			let len = LittleEndian::read_u32(&data[8..]) as usize;
+			if data.len() < 12 + len {
+				bail!(ErrorKind::CorruptedCommitGroup(path, "Truncated member".into()));
+			}
+			let raw = &data[12..12 + len];
+			data = &data[12 + len..];
+
+			// sequence numbers restart after flushed eras are deleted,
+			// so the presence of the era is checked instead of the next sequence
+			if db.has_sequence(sequence) {
+				// the transaction was committed before the crash
+				continue;
+			}
+
+			let mut tx = db.create_transaction();
+			// operations were serialized by `Transaction` and the checksum matches
+			for operation in unsafe { OperationsIterator::new(raw) } {
+				match operation {
+					Operation::Insert(key, value) => tx.insert(key, value)?,
+					Operation::Delete(key) => tx.delete(key)?,
+				}
+			}
+			db.commit(&tx)?;
+		}
+
+		fs::remove_file(path)?;
+		Ok(true)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	extern crate tempdir;
+
+	use database::Database;
+	use options::{Options, ValuesLen};
+	use super::CommitGroup;
+
+	fn options() -> Options {
+		Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		}
+	}
+
+	#[test]
+	fn test_commit_group() {
+		let temp = tempdir::TempDir::new("test_commit_group").unwrap();
+		let group = CommitGroup::open(temp.path().join("group")).unwrap();
+		let mut state = Database::create(temp.path().join("state"), options()).unwrap();
+		let mut blocks = Database::create(temp.path().join("blocks"), options()).unwrap();
+
+		let mut state_tx = state.create_transaction();
+		state_tx.insert("aaa", "001").unwrap();
+		let mut blocks_tx = blocks.create_transaction();
+		blocks_tx.insert("bbb", "002").unwrap();
+
+		group.commit(&mut [(&mut state, &state_tx), (&mut blocks, &blocks_tx)]).unwrap();
+		assert_eq!(state.get("aaa").unwrap().unwrap(), "001");
+		assert_eq!(blocks.get("bbb").unwrap().unwrap(), "002");
+		assert!(!group.recover(&mut [&mut state, &mut blocks]).unwrap());
+	}
+
+	#[test]
+	fn test_commit_group_recover() {
+		let temp = tempdir::TempDir::new("test_commit_group_recover").unwrap();
+		let group = CommitGroup::open(temp.path().join("group")).unwrap();
+		let mut state = Database::create(temp.path().join("state"), options()).unwrap();
+		let mut blocks = Database::create(temp.path().join("blocks"), options()).unwrap();
+
+		let mut state_tx = state.create_transaction();
+		state_tx.insert("aaa", "001").unwrap();
+		let mut blocks_tx = blocks.create_transaction();
+		blocks_tx.insert("bbb", "002").unwrap();
+
+		// simulate a crash after only the state database received its commit
+		group.prepare(&[(state.next_sequence(), &state_tx), (blocks.next_sequence(), &blocks_tx)]).unwrap();
+		state.commit(&state_tx).unwrap();
+		assert!(group.commit(&mut [(&mut state, &state_tx), (&mut blocks, &blocks_tx)]).is_err());
+		assert_eq!(blocks.get("bbb").unwrap(), None);
+
+		assert!(group.recover(&mut [&mut state, &mut blocks]).unwrap());
+		assert_eq!(state.get("aaa").unwrap().unwrap(), "001");
+		assert_eq!(blocks.get("bbb").unwrap().unwrap(), "002");
+		assert_eq!(state.next_sequence(), 1);
+		assert_eq!(blocks.next_sequence(), 1);
+		assert!(!temp.path().join("group").join("commit-group.log").exists());
+	}
+}
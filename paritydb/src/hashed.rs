@@ -0,0 +1,232 @@
+//! Database with keys of arbitrary length.
+//!
+//! Data file slots hold only a fixed size hash of the key, so the slots stay small
+//! no matter how long the keys are. The full key is stored in the overflow area
+//! at the beginning of the value and is verified on every read.
+//!
+//! ```text
+//!  hash(key)  key_len  key        value
+//!   /          /        /          /
+//! |.........|....|..............|.........|
+//! ```
+
+use std::path::Path;
+
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+use tiny_keccak::sha3_256;
+
+use database::Database;
+use error::{ErrorKind, Result};
+use options::Options;
+use transaction::Transaction;
+
+const KEY_LEN_SIZE: usize = 4;
+/// Minimum length of the stored key hash. Keys with the same hash replace each other, so
+/// shorter hashes would lose values of colliding keys.
+pub const MIN_HASH_LEN: usize = 16;
+/// Maximum length of the stored key hash.
+pub const MAX_HASH_LEN: usize = 32;
+
+fn validate_options(options: &Options) -> Result<()> {
+	if options.key_len < MIN_HASH_LEN || options.key_len > MAX_HASH_LEN {
+		bail!(ErrorKind::InvalidOptions(
+			"key_len",
+			format!("hashed keys are {} to {} bytes long, got {}", MIN_HASH_LEN, MAX_HASH_LEN, options.key_len)
+		));
+	}
+
+	if options.value_len.is_const() {
+		bail!(ErrorKind::InvalidOptions(
+			"value_len",
+			"hashed keys are stored with the value, so values have to be of variable length".into()
+		));
+	}
+
+	Ok(())
+}
+
+/// Database which stores values under hashes of the keys.
+#[derive(Debug)]
+pub struct HashedDatabase {
+	db: Database,
+	hash_len: usize,
+}
+
+impl HashedDatabase {
+	/// Creates new database at given location.
+	///
+	/// `options.key_len` is the length of the stored key hash, from `MIN_HASH_LEN` to `MAX_HASH_LEN`.
+	pub fn create<P: AsRef<Path>>(path: P, options: Options) -> Result<Self> {
+		validate_options(&options)?;
+		let hash_len = options.key_len;
+		Ok(HashedDatabase {
+			db: Database::create(path, options)?,
+			hash_len,
+		})
+	}
+
+	/// Opens an existing database at given location.
+	pub fn open<P: AsRef<Path>>(path: P, options: Options) -> Result<Self> {
+		validate_options(&options)?;
+		let hash_len = options.key_len;
+		Ok(HashedDatabase {
+			db: Database::open(path, options)?,
+			hash_len,
+		})
+	}
+
+	/// Returns the underlying database.
+	pub fn inner(&self) -> &Database {
+		&self.db
+	}
+
+	/// Create a new transaction.
+	pub fn create_transaction(&self) -> HashedTransaction {
+		HashedTransaction {
+			tx: self.db.create_transaction(),
+			hash_len: self.hash_len,
+		}
+	}
+
+	/// Commits changes in the transaction.
+	pub fn commit(&mut self, tx: &HashedTransaction) -> Result<()> {
+		self.db.commit(&tx.tx)
+	}
+
+	/// Flushes at most `max` eras of the journal to the database.
+	pub fn flush_journal<T: Into<Option<usize>>>(&mut self, max: T) -> Result<()> {
+		self.db.flush_journal(max)
+	}
+
+	/// Returns the value for the key.
+	///
+	/// Returns `None` if a different key with the same hash is stored.
+	pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>> {
+		let key = key.as_ref();
+		let stored = match self.db.get(hash_key(key, self.hash_len))? {
+			Some(stored) => stored.to_vec(),
+			None => return Ok(None),
+		};
+
+		if stored.len() < KEY_LEN_SIZE {
+			bail!(ErrorKind::InvalidValueEnvelope("missing key length".into()));
+		}
+
+		let key_len = LittleEndian::read_u32(&stored[..KEY_LEN_SIZE]) as usize;
+		if stored.len() < KEY_LEN_SIZE + key_len {
+			bail!(ErrorKind::InvalidValueEnvelope(
+				format!("key of length {} does not fit in a value of length {}", key_len, stored.len())
+			));
+		}
+
+		if &stored[KEY_LEN_SIZE..KEY_LEN_SIZE + key_len] != key {
+			return Ok(None);
+		}
+
+		Ok(Some(stored[KEY_LEN_SIZE + key_len..].to_vec()))
+	}
+
+	/// Returns true if the key is in the database.
+	pub fn contains<K: AsRef<[u8]>>(&self, key: K) -> Result<bool> {
+		self.get(key).map(|value| value.is_some())
+	}
+}
+
+/// Transaction of a `HashedDatabase`.
+pub struct HashedTransaction {
+	tx: Transaction,
+	hash_len: usize,
+}
+
+impl HashedTransaction {
+	/// Append new insert operation to the list of transactions.
+	pub fn insert<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, value: V) -> Result<()> {
+		let key = key.as_ref();
+		let value = value.as_ref();
+
+		let mut stored = Vec::with_capacity(KEY_LEN_SIZE + key.len() + value.len());
+		stored.write_u32::<LittleEndian>(key.len() as u32)?;
+		stored.extend_from_slice(key);
+		stored.extend_from_slice(value);
+
+		let hash = hash_key(key, self.hash_len);
+		self.tx.insert(hash, stored)
+	}
+
+	/// Append new delete operation to the list of transactions.
+	pub fn delete<K: AsRef<[u8]>>(&mut self, key: K) -> Result<()> {
+		let hash = hash_key(key.as_ref(), self.hash_len);
+		self.tx.delete(hash)
+	}
+}
+
+fn hash_key(key: &[u8], len: usize) -> Vec<u8> {
+	sha3_256(key)[..len].to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+	extern crate tempdir;
+
+	use error::ErrorKind;
+	use options::{Options, ValuesLen};
+	use super::HashedDatabase;
+
+	fn options() -> Options {
+		Options {
+			journal_eras: 0,
+			key_len: 16,
+			value_len: ValuesLen::Variable { expected: 16 },
+			..Default::default()
+		}
+	}
+
+	#[test]
+	fn test_hashed_keys() {
+		let temp = tempdir::TempDir::new("test_hashed_keys").unwrap();
+		let long_key = vec![7u8; 1000];
+
+		let mut db = HashedDatabase::create(temp.path(), options()).unwrap();
+		let mut tx = db.create_transaction();
+		tx.insert(&long_key, "long").unwrap();
+		tx.insert("a", "short").unwrap();
+		tx.insert("", "empty").unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+
+		assert_eq!(db.get(&long_key).unwrap(), Some(b"long".to_vec()));
+		assert_eq!(db.get("a").unwrap(), Some(b"short".to_vec()));
+		assert_eq!(db.get("").unwrap(), Some(b"empty".to_vec()));
+		assert_eq!(db.get("b").unwrap(), None);
+
+		let mut tx = db.create_transaction();
+		tx.delete("a").unwrap();
+		db.commit(&tx).unwrap();
+
+		assert!(!db.contains("a").unwrap());
+		assert!(db.contains(&long_key).unwrap());
+	}
+
+	#[test]
+	fn test_hashed_keys_options() {
+		let temp = tempdir::TempDir::new("test_hashed_keys_options").unwrap();
+
+		let err = HashedDatabase::create(temp.path(), Options {
+			key_len: 33,
+			..options()
+		}).unwrap_err();
+		assert!(matches!(*err.kind(), ErrorKind::InvalidOptions("key_len", _)));
+
+		let err = HashedDatabase::create(temp.path(), Options {
+			key_len: 8,
+			..options()
+		}).unwrap_err();
+		assert!(matches!(*err.kind(), ErrorKind::InvalidOptions("key_len", _)));
+
+		let err = HashedDatabase::create(temp.path(), Options {
+			value_len: ValuesLen::Constant(16),
+			..options()
+		}).unwrap_err();
+		assert!(matches!(*err.kind(), ErrorKind::InvalidOptions("value_len", _)));
+	}
+}
@@ -0,0 +1,120 @@
+//! Summary of the database health.
+
+use std::fmt::Write;
+
+use error::Error;
+
+/// Health of an open database, intended to back `/health` endpoints of nodes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Health {
+	/// True if the lock file still exists, so no other process can open the database.
+	pub lock_held: bool,
+	/// Number of eras in the journal.
+	pub journal_eras: usize,
+	/// Number of eras in the journal above configured `journal_eras` which are waiting to be flushed.
+	pub journal_lag: usize,
+	/// Percentage of the data file occupied by records.
+	pub occupied_percent: u8,
+	/// True if the occupancy exceeds `extend_threshold_percent` and the data file should be extended.
+	pub compaction_pending: bool,
+	/// Number of failed commits, flushes and compactions since the database was opened.
+	pub error_count: u64,
+	/// Description of the most recent error.
+	pub last_error: Option<String>,
+	/// True if a flush failed and the data file may be partially written until it is reopened.
+	pub degraded: bool,
+}
+
+impl Health {
+	/// Returns true if the database is not degraded and the lock is held.
+	pub fn is_healthy(&self) -> bool {
+		self.lock_held && !self.degraded
+	}
+
+	/// Serializes the health as a JSON object.
+	pub fn to_json(&self) -> String {
+		let mut json = String::new();
+		write!(
+			json,
+			"{{\"healthy\":{},\"lock_held\":{},\"journal_eras\":{},\"journal_lag\":{},\"occupied_percent\":{},\
+			\"compaction_pending\":{},\"error_count\":{},\"last_error\":",
+			self.is_healthy(),
+			self.lock_held,
+			self.journal_eras,
+			self.journal_lag,
+			self.occupied_percent,
+			self.compaction_pending,
+			self.error_count,
+		).expect("writing to a string cannot fail; qed");
+
+		match self.last_error {
+			Some(ref error) => write_json_string(&mut json, error),
+			None => json.push_str("null"),
+		}
+
+		write!(json, ",\"degraded\":{}}}", self.degraded).expect("writing to a string cannot fail; qed");
+		json
+	}
+}
+
+fn write_json_string(json: &mut String, value: &str) {
+	json.push('"');
+	for c in value.chars() {
+		match c {
+			'"' => json.push_str("\\\""),
+			'\\' => json.push_str("\\\\"),
+			c if (c as u32) < 0x20 => write!(json, "\\u{:04x}", c as u32).expect("writing to a string cannot fail; qed"),
+			c => json.push(c),
+		}
+	}
+	json.push('"');
+}
+
+/// Errors recorded since the database was opened.
+#[derive(Debug, Default)]
+pub struct ErrorLog {
+	pub count: u64,
+	pub last: Option<String>,
+	pub degraded: bool,
+}
+
+impl ErrorLog {
+	/// Records the error of a failed operation.
+	pub fn record(&mut self, error: &Error) {
+		self.count += 1;
+		self.last = Some(error.to_string());
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::Health;
+
+	#[test]
+	fn test_health_to_json() {
+		let mut health = Health {
+			lock_held: true,
+			journal_eras: 6,
+			journal_lag: 1,
+			occupied_percent: 42,
+			compaction_pending: false,
+			error_count: 0,
+			last_error: None,
+			degraded: false,
+		};
+
+		assert_eq!(
+			health.to_json(),
+			"{\"healthy\":true,\"lock_held\":true,\"journal_eras\":6,\"journal_lag\":1,\"occupied_percent\":42,\
+			\"compaction_pending\":false,\"error_count\":0,\"last_error\":null,\"degraded\":false}"
+		);
+
+		health.degraded = true;
+		health.error_count = 1;
+		health.last_error = Some("disk \"full\"\n".into());
+		assert!(health.to_json().ends_with(
+			"\"error_count\":1,\"last_error\":\"disk \\\"full\\\"\\u000a\",\"degraded\":true}"
+		));
+		assert!(health.to_json().starts_with("{\"healthy\":false,"));
+	}
+}
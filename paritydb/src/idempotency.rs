@@ -0,0 +1,281 @@
+//! Idempotency keys of recent commits, see `Transaction::set_idempotency_key`.
+//!
+//! A key is appended to the log before the journal era of its commit is created,
+//! together with the sequence number of the commit. Only the last key of the log
+//! may belong to a commit which was never journaled, because the log is rewritten
+//! without the key of a commit failing with an error. The last key is confirmed by
+//! a record without a key before its era is flushed, so on open an unconfirmed last
+//! key is kept only if its era is in the journal.
+//!
+//! ```text
+//!  sequence  len  key       sequence  u32::MAX
+//!   /         /    /         /         /
+//! |........|....|.......|........|....|
+//! ```
+//!
+//! A torn record at the end of the log is ignored. The log is rewritten with the
+//! newest `Options::idempotency_window` keys once it holds twice as many records.
+
+use std::collections::{HashSet, VecDeque};
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+
+use error::Result;
+use journal::Journal;
+
+pub const IDEMPOTENCY_FILE: &'static str = "idempotency.log";
+const RECORD_HEADER_SIZE: usize = 12;
+/// Length of the confirmation record of the last key.
+const CONFIRMED: u32 = u32::max_value();
+
+/// Idempotency keys of the most recent commits.
+#[derive(Debug)]
+pub struct Idempotency {
+	path: PathBuf,
+	window: usize,
+	/// Keys of the recent commits with their sequence numbers, oldest first.
+	keys: VecDeque<(Vec<u8>, u64)>,
+	applied: HashSet<Vec<u8>>,
+	/// Number of records in the log.
+	logged: usize,
+	/// True if the last key of the log is not confirmed.
+	unconfirmed: bool,
+	log: Option<fs::File>,
+}
+
+impl Idempotency {
+	/// Reads the log from database directory `dir`, dropping the last key if its commit was
+	/// not journaled. The log is left as it is if the database is `read_only`.
+	pub fn open<P: AsRef<Path>>(dir: P, window: usize, journal: &Journal, read_only: bool) -> Result<Self> {
+		let path = dir.as_ref().join(IDEMPOTENCY_FILE);
+		let mut idempotency = Idempotency {
+			path,
+			window,
+			keys: VecDeque::new(),
+			applied: HashSet::new(),
+			logged: 0,
+			unconfirmed: false,
+			log: None,
+		};
+
+		if !idempotency.path.exists() {
+			return Ok(idempotency);
+		}
+
+		let mut data = Vec::new();
+		fs::File::open(&idempotency.path)?.read_to_end(&mut data)?;
+
+		let mut keys = Vec::new();
+		let mut unconfirmed = false;
+		let mut rest = &data[..];
+		while rest.len() >= RECORD_HEADER_SIZE {
+			let sequence = LittleEndian::read_u64(&rest[..8]);
+			let len = LittleEndian::read_u32(&rest[8..RECORD_HEADER_SIZE]);
+			if len == CONFIRMED {
+				unconfirmed = false;
+				rest = &rest[RECORD_HEADER_SIZE..];
+				continue;
+			}
+
+			let end = RECORD_HEADER_SIZE + len as usize;
+			if rest.len() < end {
+				break;
+			}
+			keys.push((rest[RECORD_HEADER_SIZE..end].to_vec(), sequence));
+			unconfirmed = true;
+			rest = &rest[end..];
+		}
+
+		let torn = !rest.is_empty();
+		let lost = unconfirmed && !keys.last().map_or(false, |&(_, sequence)| journal.has_era(sequence));
+		if lost {
+			keys.pop();
+		}
+
+		idempotency.logged = keys.len();
+		idempotency.unconfirmed = unconfirmed && !lost;
+		for (key, sequence) in keys {
+			idempotency.remember(key, sequence);
+		}
+
+		if !read_only && (torn || lost) {
+			idempotency.rewrite()?;
+		}
+
+		Ok(idempotency)
+	}
+
+	/// Returns true if a commit with idempotency `key` is among the most recent commits.
+	pub fn is_applied(&self, key: &[u8]) -> bool {
+		self.applied.contains(key)
+	}
+
+	/// Returns the number of remembered keys, at most the window.
+	pub fn len(&self) -> usize {
+		self.keys.len()
+	}
+
+	/// Returns the path of the log, which may not exist.
+	pub fn path(&self) -> &Path {
+		&self.path
+	}
+
+	fn remember(&mut self, key: Vec<u8>, sequence: u64) {
+		if self.applied.contains(&key) {
+			return;
+		}
+
+		self.applied.insert(key.clone());
+		self.keys.push_back((key, sequence));
+		while self.keys.len() > self.window {
+			let (key, _) = self.keys.pop_front().expect("more keys than the window; qed");
+			self.applied.remove(&key);
+		}
+	}
+
+	fn append(&mut self, record: &[u8]) -> Result<()> {
+		if self.log.is_none() {
+			self.log = Some(fs::OpenOptions::new().append(true).create(true).open(&self.path)?);
+		}
+
+		self.log.as_mut().expect("log was just opened; qed").write_all(record)?;
+		Ok(())
+	}
+
+	/// Logs `key` of the commit which will be journaled with `sequence`, before it is journaled.
+	pub fn prepare(&mut self, key: &[u8], sequence: u64) -> Result<()> {
+		if self.logged >= 2 * self.window {
+			self.rewrite()?;
+		}
+
+		let mut record = Vec::with_capacity(RECORD_HEADER_SIZE + key.len());
+		record.write_u64::<LittleEndian>(sequence)?;
+		record.write_u32::<LittleEndian>(key.len() as u32)?;
+		record.extend_from_slice(key);
+		self.append(&record)?;
+		self.logged += 1;
+		self.unconfirmed = true;
+		Ok(())
+	}
+
+	/// Remembers `key` once its commit was journaled.
+	pub fn commit(&mut self, key: &[u8], sequence: u64) {
+		self.remember(key.to_vec(), sequence);
+	}
+
+	/// Drops the last prepared key from the log after its commit failed.
+	pub fn abort(&mut self) -> Result<()> {
+		self.rewrite()
+	}
+
+	/// Confirms the last key of the log, before its journal era is flushed.
+	pub fn confirm(&mut self) -> Result<()> {
+		if !self.unconfirmed {
+			return Ok(());
+		}
+
+		let mut record = Vec::with_capacity(RECORD_HEADER_SIZE);
+		record.write_u64::<LittleEndian>(0)?;
+		record.write_u32::<LittleEndian>(CONFIRMED)?;
+		self.append(&record)?;
+		self.sync()?;
+		self.unconfirmed = false;
+		Ok(())
+	}
+
+	/// Syncs the log to the disk.
+	pub fn sync(&mut self) -> Result<()> {
+		if let Some(ref log) = self.log {
+			log.sync_all()?;
+		}
+
+		Ok(())
+	}
+
+	/// Atomically replaces the log with the remembered keys, which are all journaled.
+	fn rewrite(&mut self) -> Result<()> {
+		let tmp_path = self.path.with_extension("tmp");
+		{
+			let mut file = fs::File::create(&tmp_path)?;
+			for &(ref key, sequence) in &self.keys {
+				file.write_u64::<LittleEndian>(sequence)?;
+				file.write_u32::<LittleEndian>(key.len() as u32)?;
+				file.write_all(key)?;
+			}
+			if !self.keys.is_empty() {
+				file.write_u64::<LittleEndian>(0)?;
+				file.write_u32::<LittleEndian>(CONFIRMED)?;
+			}
+			file.sync_all()?;
+		}
+
+		self.log = None;
+		fs::rename(&tmp_path, &self.path)?;
+		self.logged = self.keys.len();
+		self.unconfirmed = false;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	extern crate tempdir;
+
+	use std::fs;
+	use std::io::Write;
+
+	use journal::Journal;
+	use transaction::Transaction;
+	use super::{Idempotency, IDEMPOTENCY_FILE};
+
+	#[test]
+	fn test_idempotency_log() {
+		let temp = tempdir::TempDir::new("test_idempotency_log").unwrap();
+		let journal_dir = temp.path().join("journal");
+		fs::create_dir_all(&journal_dir).unwrap();
+		let mut journal = Journal::open(&journal_dir).unwrap();
+
+		let mut idempotency = Idempotency::open(temp.path(), 2, &journal, false).unwrap();
+		for (sequence, key) in [b"a", b"b", b"c"].iter().enumerate() {
+			idempotency.prepare(*key, sequence as u64).unwrap();
+			journal.push(&Transaction::new(1)).unwrap();
+			idempotency.commit(*key, sequence as u64);
+		}
+
+		// only the keys of the window are remembered
+		assert!(!idempotency.is_applied(b"a"));
+		assert!(idempotency.is_applied(b"b"));
+		assert!(idempotency.is_applied(b"c"));
+		assert_eq!(idempotency.len(), 2);
+
+		let reopened = Idempotency::open(temp.path(), 2, &journal, false).unwrap();
+		assert!(reopened.is_applied(b"b") && reopened.is_applied(b"c"));
+
+		// the process crashed before the era of `d` was created
+		idempotency.prepare(b"d", 3).unwrap();
+		let reopened = Idempotency::open(temp.path(), 2, &journal, true).unwrap();
+		assert!(!reopened.is_applied(b"d"));
+		assert!(reopened.is_applied(b"c"));
+
+		// a failed commit is dropped from the log
+		idempotency.abort().unwrap();
+		idempotency.prepare(b"e", 3).unwrap();
+		journal.push(&Transaction::new(1)).unwrap();
+		idempotency.commit(b"e", 3);
+		idempotency.confirm().unwrap();
+
+		// confirmed keys are kept after their eras are flushed
+		journal.drain_front(4).count();
+		for era in fs::read_dir(&journal_dir).unwrap() {
+			fs::remove_file(era.unwrap().path()).unwrap();
+		}
+		fs::OpenOptions::new().append(true).open(temp.path().join(IDEMPOTENCY_FILE)).unwrap().write_all(&[1, 2, 3]).unwrap();
+		let reopened = Idempotency::open(temp.path(), 2, &journal, false).unwrap();
+		assert!(reopened.is_applied(b"e"));
+		assert!(reopened.is_applied(b"c"));
+		assert!(!reopened.is_applied(b"d"));
+	}
+}
@@ -0,0 +1,376 @@
+//! Local read server over a unix domain socket.
+//!
+//! Lets tools query a live database without opening its files. The server never
+//! blocks, it is polled by the thread which owns the database, e.g. from the
+//! event loop of a node.
+//!
+//! Every request and response is a single frame.
+//!
+//! ```text
+//!  opcode/status  len   payload
+//!   /              /     /
+//! |.|            ....|.........|
+//! ```
+//!
+//! Requests:
+//!
+//! - `GET` with the key as the payload. The response payload is the value.
+//! - `ITER` with u32 limit and a key as the payload. The response payload holds at most
+//!   `limit` records with keys greater than the given key (all keys if it is empty),
+//!   each encoded as u32 key len, key, u32 value len, value.
+//!
+//! Responses have status `OK`, `NOT_FOUND` or `ERROR` with the error message as
+//! the payload. All integers are little-endian.
+
+use std::{fs, io};
+use std::io::{Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+
+use byteorder::{ByteOrder, LittleEndian, WriteBytesExt};
+
+use database::Database;
+use error::Result;
+
+const FRAME_HEADER_SIZE: usize = 5;
+/// Clients sending larger requests are disconnected.
+const MAX_REQUEST_SIZE: usize = 1 << 20;
+
+const GET: u8 = 1;
+const ITER: u8 = 2;
+
+const OK: u8 = 0;
+const NOT_FOUND: u8 = 1;
+const ERROR: u8 = 2;
+
+#[derive(Debug)]
+struct Client {
+	stream: UnixStream,
+	input: Vec<u8>,
+	output: Vec<u8>,
+	closed: bool,
+}
+
+impl Client {
+	/// Reads all available data without blocking.
+	fn read(&mut self) {
+		let mut buf = [0u8; 4096];
+		loop {
+			match self.stream.read(&mut buf) {
+				Ok(0) => {
+					self.closed = true;
+					return;
+				},
+				Ok(read) => self.input.extend_from_slice(&buf[..read]),
+				Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return,
+				Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {},
+				Err(_) => {
+					self.closed = true;
+					return;
+				},
+			}
+		}
+	}
+
+	/// Writes as much of the pending output as possible without blocking.
+	fn write(&mut self) {
+		while !self.output.is_empty() {
+			match self.stream.write(&self.output) {
+				Ok(0) => {
+					self.closed = true;
+					return;
+				},
+				Ok(written) => {
+					self.output.drain(..written);
+				},
+				Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return,
+				Err(ref err) if err.kind() == io::ErrorKind::Interrupted => {},
+				Err(_) => {
+					self.closed = true;
+					return;
+				},
+			}
+		}
+	}
+
+	/// Answers all complete requests. Returns number of answered requests.
+	fn serve(&mut self, db: &Database) -> usize {
+		let mut served = 0;
+		let mut consumed = 0;
+
+		while let Some((opcode, payload)) = next_frame(&self.input[consumed..]) {
+			consumed += FRAME_HEADER_SIZE + payload.len();
+			let (status, response) = match respond(db, opcode, payload) {
+				Ok(response) => response,
+				Err(err) => (ERROR, err.to_string().into_bytes()),
+			};
+			write_frame(&mut self.output, status, &response);
+			served += 1;
+		}
+
+		self.input.drain(..consumed);
+		if self.input.len() >= FRAME_HEADER_SIZE &&
+			LittleEndian::read_u32(&self.input[1..FRAME_HEADER_SIZE]) as usize > MAX_REQUEST_SIZE {
+			self.closed = true;
+		}
+
+		served
+	}
+}
+
+fn next_frame(data: &[u8]) -> Option<(u8, &[u8])> {
+	if data.len() < FRAME_HEADER_SIZE {
+		return None;
+	}
+
+	let len = LittleEndian::read_u32(&data[1..FRAME_HEADER_SIZE]) as usize;
+	if data.len() < FRAME_HEADER_SIZE + len {
+		return None;
+	}
+
+	Some((data[0], &data[FRAME_HEADER_SIZE..FRAME_HEADER_SIZE + len]))
+}
+
+fn write_frame(buf: &mut Vec<u8>, opcode: u8, payload: &[u8]) {
+	buf.push(opcode);
+	buf.write_u32::<LittleEndian>(payload.len() as u32).expect("writing to a vec cannot fail; qed");
+	buf.extend_from_slice(payload);
+}
+
+fn respond(db: &Database, opcode: u8, payload: &[u8]) -> Result<(u8, Vec<u8>)> {
+	match opcode {
+		GET => match db.get(payload)? {
+			Some(value) => Ok((OK, value.to_vec())),
+			None => Ok((NOT_FOUND, Vec::new())),
+		},
+		ITER => {
+			if payload.len() < 4 {
+				return Ok((ERROR, b"ITER request is missing the limit".to_vec()));
+			}
+
+			let limit = LittleEndian::read_u32(payload) as usize;
+			let after = &payload[4..];
+			let after = if after.is_empty() { None } else { Some(after) };
+			let mut response = Vec::new();
+
+			for (key, value) in db.iter_page(after, limit)? {
+				response.write_u32::<LittleEndian>(key.len() as u32)?;
+				response.extend_from_slice(&key);
+				response.write_u32::<LittleEndian>(value.len() as u32)?;
+				response.extend_from_slice(&value);
+			}
+
+			Ok((OK, response))
+		},
+		_ => Ok((ERROR, format!("Unknown opcode {}", opcode).into_bytes())),
+	}
+}
+
+/// Non-blocking server answering read requests of local processes.
+#[derive(Debug)]
+pub struct IpcServer {
+	path: PathBuf,
+	listener: UnixListener,
+	clients: Vec<Client>,
+}
+
+impl IpcServer {
+	/// Binds the server to a socket at given path, replacing a stale socket file.
+	pub fn bind<P: AsRef<Path>>(path: P) -> Result<Self> {
+		let path = path.as_ref();
+		if path.exists() {
+			fs::remove_file(path)?;
+		}
+
+		let listener = UnixListener::bind(path)?;
+		listener.set_nonblocking(true)?;
+
+		Ok(IpcServer {
+			path: path.to_owned(),
+			listener,
+			clients: Vec::new(),
+		})
+	}
+
+	/// Accepts new connections and answers all pending requests without blocking.
+	///
+	/// Returns the number of answered requests.
+	pub fn poll(&mut self, db: &Database) -> Result<usize> {
+		loop {
+			match self.listener.accept() {
+				Ok((stream, _)) => {
+					stream.set_nonblocking(true)?;
+					self.clients.push(Client {
+						stream,
+						input: Vec::new(),
+						output: Vec::new(),
+						closed: false,
+					});
+				},
+				Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => break,
+				Err(err) => return Err(err.into()),
+			}
+		}
+
+		let mut served = 0;
+		for client in &mut self.clients {
+			client.read();
+			served += client.serve(db);
+			client.write();
+		}
+
+		self.clients.retain(|client| !client.closed);
+		Ok(served)
+	}
+}
+
+impl Drop for IpcServer {
+	fn drop(&mut self) {
+		let _ = fs::remove_file(&self.path);
+	}
+}
+
+/// Blocking client of an `IpcServer`.
+#[derive(Debug)]
+pub struct IpcClient {
+	stream: UnixStream,
+}
+
+impl IpcClient {
+	/// Connects to the server listening at given path.
+	pub fn connect<P: AsRef<Path>>(path: P) -> Result<Self> {
+		Ok(IpcClient {
+			stream: UnixStream::connect(path)?,
+		})
+	}
+
+	fn request(&mut self, opcode: u8, payload: &[u8]) -> Result<(u8, Vec<u8>)> {
+		let mut frame = Vec::with_capacity(FRAME_HEADER_SIZE + payload.len());
+		write_frame(&mut frame, opcode, payload);
+		self.stream.write_all(&frame)?;
+
+		let mut header = [0u8; FRAME_HEADER_SIZE];
+		self.stream.read_exact(&mut header)?;
+		let len = LittleEndian::read_u32(&header[1..]) as usize;
+		// the response is not allocated upfront, so a damaged length doesn't allocate all memory
+		let mut response = Vec::new();
+		(&mut self.stream).take(len as u64).read_to_end(&mut response)?;
+		if response.len() != len {
+			bail!("response of the server is truncated");
+		}
+
+		if header[0] == ERROR {
+			return Err(String::from_utf8_lossy(&response).into_owned().into());
+		}
+
+		Ok((header[0], response))
+	}
+
+	/// Returns the value for the key.
+	pub fn get<K: AsRef<[u8]>>(&mut self, key: K) -> Result<Option<Vec<u8>>> {
+		match self.request(GET, key.as_ref())? {
+			(NOT_FOUND, _) => Ok(None),
+			(_, value) => Ok(Some(value)),
+		}
+	}
+
+	/// Returns at most `limit` records with keys greater than `after`, or the first records if it is `None`.
+	pub fn iter(&mut self, after: Option<&[u8]>, limit: u32) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+		let mut payload = Vec::new();
+		payload.write_u32::<LittleEndian>(limit)?;
+		payload.extend_from_slice(after.unwrap_or(&[]));
+
+		let (_, response) = self.request(ITER, &payload)?;
+		let mut data = &response[..];
+		let mut records = Vec::new();
+		while !data.is_empty() {
+			let key = read_field(&mut data)?;
+			let value = read_field(&mut data)?;
+			records.push((key, value));
+		}
+
+		Ok(records)
+	}
+}
+
+/// Reads a field of an ITER response prefixed with its u32 length.
+fn read_field(data: &mut &[u8]) -> Result<Vec<u8>> {
+	if data.len() < 4 {
+		bail!("ITER response is truncated");
+	}
+
+	let len = LittleEndian::read_u32(data) as usize;
+	if data.len() - 4 < len {
+		bail!("ITER response is truncated");
+	}
+
+	let field = data[4..4 + len].to_vec();
+	*data = &data[4 + len..];
+	Ok(field)
+}
+
+#[cfg(test)]
+mod tests {
+	extern crate tempdir;
+
+	use std::thread;
+
+	use database::Database;
+	use options::{Options, ValuesLen};
+	use super::{read_field, IpcClient, IpcServer};
+
+	#[test]
+	fn test_read_field() {
+		let mut data = &b"\x03\x00\x00\x00abc\x02\x00\x00\x00d"[..];
+		assert_eq!(read_field(&mut data).unwrap(), b"abc".to_vec());
+		assert_eq!(data, &b"\x02\x00\x00\x00d"[..]);
+		assert!(read_field(&mut data).is_err());
+		assert!(read_field(&mut &b"\x01\x00"[..]).is_err());
+		assert!(read_field(&mut &b"\xff\xff\xff\xff"[..]).is_err());
+	}
+
+	#[test]
+	fn test_ipc_server() {
+		let temp = tempdir::TempDir::new("test_ipc_server").unwrap();
+		let mut db = Database::create(temp.path().join("db"), Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		}).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("aaa", "001").unwrap();
+		tx.insert("bbb", "002").unwrap();
+		tx.insert("ccc", "003").unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+
+		let socket = temp.path().join("db.ipc");
+		let mut server = IpcServer::bind(&socket).unwrap();
+
+		let client = {
+			let socket = socket.clone();
+			thread::spawn(move || {
+				let mut client = IpcClient::connect(&socket).unwrap();
+				let found = client.get("bbb").unwrap();
+				let missing = client.get("ddd").unwrap();
+				let first = client.iter(None, 2).unwrap();
+				let next = client.iter(Some(&first[1].0[..]), 2).unwrap();
+				(found, missing, first, next)
+			})
+		};
+
+		let mut served = 0;
+		while served < 4 {
+			served += server.poll(&db).unwrap();
+			thread::yield_now();
+		}
+
+		let (found, missing, first, next) = client.join().unwrap();
+		assert_eq!(found, Some(b"002".to_vec()));
+		assert_eq!(missing, None);
+		assert_eq!(first, vec![(b"aaa".to_vec(), b"001".to_vec()), (b"bbb".to_vec(), b"002".to_vec())]);
+		assert_eq!(next, vec![(b"ccc".to_vec(), b"003".to_vec())]);
+	}
+}
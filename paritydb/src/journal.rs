@@ -6,6 +6,7 @@ use std::io::Write;
 use std::path::{PathBuf, Path};
 use std::slice;
 
+use byteorder::{ByteOrder, LittleEndian};
 use hex_slice::AsHex;
 use memmap::{Mmap, Protection};
 use tiny_keccak::sha3_256;
@@ -16,7 +17,7 @@ use transaction::{Operation, OperationsIterator, Transaction};
 const CHECKSUM_SIZE: usize = 32;
 
 #[derive(Debug, PartialEq)]
-enum JournalOperation<T> {
+pub enum JournalOperation<T> {
 	Insert(T),
 	Delete,
 }
@@ -59,6 +60,11 @@ impl PartialEq for JournalSlice {
 
 impl Eq for JournalSlice {}
 
+// The slice points into the memory map of the era holding it, which is never written
+// while the era is shared.
+unsafe impl Send for JournalSlice {}
+unsafe impl Sync for JournalSlice {}
+
 unsafe fn cache_memory(memory: &[u8]) -> HashMap<JournalSlice, JournalOperation<JournalSlice>> {
 	let iterator = OperationsIterator::new(memory);
 	iterator.map(|o| match o {
@@ -94,12 +100,15 @@ impl JournalEra {
 		file.write_all(transaction.raw())?;
 		file.flush()?;
 
-		Self::open(file_path)
+		// the checksum was just computed from the same data, hashing the file again
+		// would only double the cpu time the committing thread spends on it
+		let mmap = Mmap::open_path(&file_path, Protection::Read)?;
+		Ok(Self::from_mmap(file_path, mmap))
 	}
 
 	fn open<P: AsRef<Path>>(file: P) -> Result<JournalEra> {
 		let mmap = Mmap::open_path(&file, Protection::Read)?;
-		let cache = {
+		{
 			let checksum = unsafe { &mmap.as_slice()[..CHECKSUM_SIZE] };
 			let data = unsafe { &mmap.as_slice()[CHECKSUM_SIZE..] };
 			let hash = sha3_256(data);
@@ -113,20 +122,23 @@ impl JournalEra {
 					)
 				).into());
 			}
+		}
 
-			unsafe { cache_memory(data) }
-		};
+		Ok(Self::from_mmap(file, mmap))
+	}
+
+	fn from_mmap<P: AsRef<Path>>(file: P, mmap: Mmap) -> JournalEra {
+		let cache = unsafe { cache_memory(&mmap.as_slice()[CHECKSUM_SIZE..]) };
 
-		let era = JournalEra {
+		JournalEra {
 			file: file.as_ref().to_path_buf(),
 			mmap,
 			cache,
-		};
-
-		Ok(era)
+		}
 	}
 
-	fn get<'a>(&'a self, key: &[u8]) -> Option<JournalOperation<&'a [u8]>> {
+	/// Returns the operation of the era on the `key`.
+	pub fn get<'a>(&'a self, key: &[u8]) -> Option<JournalOperation<&'a [u8]>> {
 		let key = JournalSlice::new(key);
 
 		match self.cache.get(&key) {
@@ -151,11 +163,68 @@ impl JournalEra {
 		self.operations().into_iter()
 	}
 
+	/// Returns the contents of the era file, checksum included.
+	pub fn raw(&self) -> &[u8] {
+		unsafe { self.mmap.as_slice() }
+	}
+
 	/// Deletes underlying file
 	pub fn delete(self) -> Result<()> {
 		fs::remove_file(self.file)?;
 		Ok(())
 	}
+
+	/// Moves underlying file to the `archive` directory
+	pub fn archive<P: AsRef<Path>>(self, archive: P) -> Result<()> {
+		let name = self.file.file_name().expect("era file path is created from a file name; qed");
+		fs::rename(&self.file, archive.as_ref().join(name))?;
+		Ok(())
+	}
+}
+
+/// Serializes `transaction` into the contents of an era file.
+pub fn encode_segment(transaction: &Transaction) -> Vec<u8> {
+	let mut segment = sha3_256(transaction.raw()).to_vec();
+	segment.extend_from_slice(transaction.raw());
+	segment
+}
+
+/// Checks the checksum and the structure of an era file received from another database
+/// and returns an iterator over its operations.
+pub fn segment_operations(segment: &[u8]) -> Result<OperationsIterator> {
+	if segment.len() < CHECKSUM_SIZE {
+		bail!(ErrorKind::InvalidJournalSegment("Segment is shorter than the checksum".into()));
+	}
+
+	let (checksum, data) = segment.split_at(CHECKSUM_SIZE);
+	let hash = sha3_256(data);
+	if hash != checksum {
+		bail!(ErrorKind::InvalidJournalSegment(
+			format!("Expected: {:02x}, Got: {:02x}", hash.as_hex(), checksum.as_hex())
+		));
+	}
+
+	let mut offset = 0;
+	while offset < data.len() {
+		let (header_len, body_len) = match data[offset] {
+			0 if data.len() >= offset + 9 => {
+				let key_len = LittleEndian::read_u32(&data[offset + 1..]) as usize;
+				let value_len = LittleEndian::read_u32(&data[offset + 5..]) as usize;
+				(9, key_len + value_len)
+			},
+			1 if data.len() >= offset + 5 => (5, LittleEndian::read_u32(&data[offset + 1..]) as usize),
+			_ => bail!(ErrorKind::InvalidJournalSegment(format!("Invalid operation at offset {}", offset))),
+		};
+
+		offset += header_len + body_len;
+	}
+
+	if offset != data.len() {
+		bail!(ErrorKind::InvalidJournalSegment("Last operation is truncated".into()));
+	}
+
+	// every operation was checked to fit in the data
+	Ok(unsafe { OperationsIterator::new(data) })
 }
 
 mod dir {
@@ -206,6 +275,33 @@ mod dir {
 		Ok(1u64 + path.parse::<u64>()?)
 	}
 
+	/// Returns indices of the eras in `dir` in ascending order.
+	pub fn era_indices<P: AsRef<Path>>(dir: P) -> Result<Vec<u64>> {
+		let mut indices = Vec::new();
+		for entry in read_dir(dir)? {
+			let path = entry?.path();
+			if path.to_string_lossy().ends_with(ERA_EXTENSION) {
+				indices.push(era_index(path)? - 1);
+			}
+		}
+
+		indices.sort();
+		Ok(indices)
+	}
+
+	/// Returns index following the highest era index in `dir`, not requiring the eras to be consecutive.
+	pub fn last_era_index<P: AsRef<Path>>(dir: P) -> Result<u64> {
+		let mut next = 0;
+		for entry in read_dir(dir)? {
+			let path = entry?.path();
+			if path.to_string_lossy().ends_with(ERA_EXTENSION) {
+				next = ::std::cmp::max(next, era_index(path)?);
+			}
+		}
+
+		Ok(next)
+	}
+
 	pub fn next_era_index<P: AsRef<Path>>(files: &[P]) -> Result<u64> {
 		match files.last() {
 			Some(path) => era_index(path),
@@ -236,28 +332,119 @@ mod dir {
 #[derive(Debug)]
 pub struct Journal {
 	dir: PathBuf,
+	archive: Option<PathBuf>,
 	eras: VecDeque<JournalEra>,
 	next_era_index: u64,
+	/// Eras with lower indices were synced to the disk.
+	synced_era_index: u64,
 }
 
 impl Journal {
 	pub fn open<P: AsRef<Path>>(jdir: P) -> Result<Self> {
+		Self::open_with_progress(jdir, |_, _, _| {})
+	}
+
+	/// Opens the journal calling `progress` with the number of opened eras, the number of all eras
+	/// and the number of bytes read after every era.
+	pub fn open_with_progress<P, F>(jdir: P, mut progress: F) -> Result<Self> where
+		P: AsRef<Path>,
+		F: FnMut(usize, usize, u64),
+	{
 		let era_files = dir::era_files(&jdir)?;
 		let next_era_index = dir::next_era_index(&era_files)?;
 
-		let eras = era_files.into_iter()
-			.map(JournalEra::open)
-			.collect::<Result<VecDeque<_>>>()?;
+		let total = era_files.len();
+		let mut bytes = 0;
+		let mut eras = VecDeque::with_capacity(total);
+		for file in era_files {
+			let era = JournalEra::open(file)?;
+			bytes += era.raw().len() as u64;
+			eras.push_back(era);
+			progress(eras.len(), total, bytes);
+		}
 
 		let journal = Journal {
 			dir: jdir.as_ref().to_path_buf(),
+			archive: None,
 			eras,
 			next_era_index,
+			synced_era_index: 0,
 		};
 
 		Ok(journal)
 	}
 
+	/// Keeps flushed eras in the `archive` directory.
+	///
+	/// Era indices continue after the archived eras even if the journal itself is empty.
+	pub fn set_archive<P: AsRef<Path>>(&mut self, archive: P) -> Result<()> {
+		fs::create_dir_all(&archive)?;
+		self.next_era_index = ::std::cmp::max(self.next_era_index, dir::last_era_index(&archive)?);
+		self.archive = Some(archive.as_ref().to_path_buf());
+		Ok(())
+	}
+
+	/// Returns the archive directory if flushed eras are archived.
+	pub fn archive(&self) -> Option<&Path> {
+		self.archive.as_ref().map(|path| path.as_path())
+	}
+
+	/// Removes archived eras with indices lower than `oldest`. Returns the number of removed eras.
+	pub fn prune_archive(&self, oldest: u64) -> Result<usize> {
+		let archive = match self.archive {
+			Some(ref archive) => archive,
+			None => return Ok(0),
+		};
+
+		let mut removed = 0;
+		for index in dir::era_indices(archive)?.into_iter().take_while(|index| *index < oldest) {
+			fs::remove_file(dir::next_era_filename(archive, index))?;
+			removed += 1;
+		}
+
+		Ok(removed)
+	}
+
+	/// Returns index of the oldest era in the archive or in the journal, or the index of the next
+	/// era if there are none.
+	pub fn oldest_era_index(&self) -> Result<u64> {
+		if let Some(ref archive) = self.archive {
+			if let Some(index) = dir::era_indices(archive)?.into_iter().next() {
+				return Ok(index);
+			}
+		}
+
+		Ok(self.next_era_index - self.eras.len() as u64)
+	}
+
+	/// Returns index of the era which will be created by the next `push`.
+	pub fn next_era_index(&self) -> u64 {
+		self.next_era_index
+	}
+
+	/// Returns true if era with given `index` is in the journal or in the archive.
+	pub fn has_era(&self, index: u64) -> bool {
+		dir::next_era_filename(&self.dir, index).is_file() ||
+			self.archive.as_ref().map_or(false, |archive| dir::next_era_filename(archive, index).is_file())
+	}
+
+	/// Opens era with given `index` from the journal or from the archive.
+	pub fn open_era(&self, index: u64) -> Result<JournalEra> {
+		let journaled = dir::next_era_filename(&self.dir, index);
+		if journaled.is_file() {
+			return JournalEra::open(journaled);
+		}
+
+		if let Some(ref archive) = self.archive {
+			let archived = dir::next_era_filename(archive, index);
+			if archived.is_file() {
+				return JournalEra::open(archived);
+			}
+		}
+
+		Err(ErrorKind::JournalEraMissing(index).into())
+	}
+
 	pub fn push(&mut self, transaction: &Transaction) -> Result<()> {
 		let new_path = dir::next_era_filename(&self.dir, self.next_era_index);
 		self.next_era_index += 1;
@@ -268,6 +455,33 @@ impl Journal {
 		Ok(())
 	}
 
+	/// Syncs era files created since the last sync, and the journal directory holding them, to the disk.
+	pub fn sync(&mut self) -> Result<()> {
+		let unsynced = self.next_era_index.saturating_sub(self.synced_era_index) as usize;
+		if unsynced == 0 {
+			return Ok(());
+		}
+
+		let skip = self.eras.len().saturating_sub(unsynced);
+		for era in self.eras.iter().skip(skip) {
+			fs::File::open(&era.file)?.sync_all()?;
+		}
+
+		fs::File::open(&self.dir)?.sync_all()?;
+		self.synced_era_index = self.next_era_index;
+		Ok(())
+	}
+
+	/// Returns true if all era files were synced to the disk.
+	pub fn is_synced(&self) -> bool {
+		self.synced_era_index >= self.next_era_index
+	}
+
+	/// Returns paths of the journaled era files, oldest first.
+	pub fn era_paths(&self) -> Vec<&Path> {
+		self.eras.iter().map(|era| era.file.as_path()).collect()
+	}
+
 	pub fn drain_front(&mut self, elems: usize) -> Drain<JournalEra> {
 		self.eras.drain(..elems)
 	}
@@ -276,13 +490,32 @@ impl Journal {
 		self.eras.len()
 	}
 
-	pub fn get<'a>(&'a self, key: &[u8]) -> Option<&'a [u8]> {
-		for era in self.eras.iter().rev() {
-			if let Some(operation) = era.get(&key) {
-				return match operation {
-					JournalOperation::Insert(insert) => Some(insert),
-					JournalOperation::Delete => None,
-				}
+	/// Returns the number of bytes of the operations in the journaled eras.
+	pub fn bytes(&self) -> u64 {
+		self.eras.iter().map(|era| era.raw().len() as u64).sum()
+	}
+
+	/// Returns the journaled era at `position`, counting from the oldest one.
+	pub fn era(&self, position: usize) -> Option<&JournalEra> {
+		self.eras.get(position)
+	}
+
+	/// Returns the number of journaled eras with index lower than `end`.
+	pub fn len_before(&self, end: u64) -> usize {
+		let newer = self.next_era_index.saturating_sub(end) as usize;
+		self.eras.len().saturating_sub(newer)
+	}
+
+	/// Returns the latest journaled operation for the `key`.
+	pub fn get<'a>(&'a self, key: &[u8]) -> Option<JournalOperation<&'a [u8]>> {
+		self.get_in(key, self.eras.len())
+	}
+
+	/// Returns the latest operation for the `key` in the oldest `eras` journaled eras.
+	pub fn get_in<'a>(&'a self, key: &[u8], eras: usize) -> Option<JournalOperation<&'a [u8]>> {
+		for index in (0..::std::cmp::min(eras, self.eras.len())).rev() {
+			if let Some(operation) = self.eras[index].get(&key) {
+				return Some(operation);
 			}
 		}
 
@@ -291,8 +524,13 @@ impl Journal {
 
 	/// Returns an iterator over the journal entries across all eras
 	pub fn iter(&self) -> btree_set::IntoIter<Operation> {
+		self.iter_in(self.eras.len())
+	}
+
+	/// Returns an iterator over the journal entries of the oldest `eras` journaled eras.
+	pub fn iter_in(&self, eras: usize) -> btree_set::IntoIter<Operation> {
 		let mut ops = BTreeSet::new();
-		for era in self.eras.iter() {
+		for era in self.eras.iter().take(eras) {
 			// append should take the value from `era` for keys that are equal
 			ops.append(&mut era.operations())
 		}
@@ -310,7 +548,7 @@ mod tests {
 	use std::io::Write;
 	use error::ErrorKind;
 	use transaction::{Operation, Transaction};
-	use super::{Journal, JournalEra, JournalOperation};
+	use super::{segment_operations, Journal, JournalEra, JournalOperation};
 
 	#[test]
 	fn test_era_create() {
@@ -332,6 +570,25 @@ mod tests {
 		assert_eq!(None, era.get(b"key4"));
 	}
 
+	#[test]
+	fn test_segment_operations() {
+		let temp = TempDir::new("test_segment_operations").unwrap();
+		let mut tx = Transaction::new(3);
+		tx.insert(b"abc", b"001").unwrap();
+		tx.delete(b"bcd").unwrap();
+		let era = JournalEra::create(temp.path().join("0.era"), &tx).unwrap();
+
+		let operations: Vec<_> = segment_operations(era.raw()).unwrap().collect();
+		assert_eq!(operations, vec![Operation::Insert(b"abc", b"001"), Operation::Delete(b"bcd")]);
+
+		// a truncated delete with a valid checksum
+		let mut segment = era.raw()[..era.raw().len() - 1].to_vec();
+		let hash = ::tiny_keccak::sha3_256(&segment[32..]);
+		segment[..32].copy_from_slice(&hash);
+		assert!(matches!(*segment_operations(&segment).unwrap_err().kind(), ErrorKind::InvalidJournalSegment(_)));
+		assert!(segment_operations(&[0u8; 16]).is_err());
+	}
+
 	#[test]
 	fn test_journal_new() {
 		let temp = TempDir::new("test_journal_new").unwrap();
@@ -347,6 +604,76 @@ mod tests {
 		assert_eq!(journal.len(), 1);
 	}
 
+	#[test]
+	fn test_journal_sync() {
+		let temp = TempDir::new("test_journal_sync").unwrap();
+
+		let mut journal = Journal::open(temp.path()).unwrap();
+		assert!(journal.is_synced());
+		journal.push(&Transaction::new(1)).unwrap();
+		journal.push(&Transaction::new(1)).unwrap();
+		assert!(!journal.is_synced());
+		journal.sync().unwrap();
+		assert!(journal.is_synced());
+
+		// flushed eras are not synced again
+		journal.drain_front(2);
+		journal.push(&Transaction::new(1)).unwrap();
+		assert!(!journal.is_synced());
+		journal.sync().unwrap();
+		assert!(journal.is_synced());
+	}
+
+	#[test]
+	fn test_journal_get() {
+		let temp = TempDir::new("test_journal_get").unwrap();
+
+		let mut journal = Journal::open(temp.path()).unwrap();
+
+		let mut tx1 = Transaction::new(4);
+		tx1.insert(b"key1", b"value").unwrap();
+		tx1.insert(b"key2", b"value").unwrap();
+
+		let mut tx2 = Transaction::new(4);
+		tx2.delete(b"key1").unwrap();
+
+		journal.push(&tx1).unwrap();
+		journal.push(&tx2).unwrap();
+
+		assert_eq!(journal.get(b"key1"), Some(JournalOperation::Delete));
+		assert_eq!(journal.get(b"key2"), Some(JournalOperation::Insert(b"value" as &[u8])));
+		assert_eq!(journal.get(b"key3"), None);
+	}
+
+	#[test]
+	fn test_journal_archive() {
+		let temp = TempDir::new("test_journal_archive").unwrap();
+		let archive = temp.path().join("archive");
+
+		{
+			let mut journal = Journal::open(temp.path()).unwrap();
+			journal.set_archive(&archive).unwrap();
+
+			let mut tx = Transaction::new(4);
+			tx.insert(b"key1", b"value").unwrap();
+			journal.push(&tx).unwrap();
+			journal.push(&tx).unwrap();
+
+			for era in journal.drain_front(2) {
+				era.archive(&archive).unwrap();
+			}
+
+			assert_eq!(journal.len(), 0);
+			assert!(journal.open_era(1).is_ok());
+			assert_eq!(*journal.open_era(2).unwrap_err().kind(), ErrorKind::JournalEraMissing(2));
+		}
+
+		let mut journal = Journal::open(temp.path()).unwrap();
+		assert_eq!(journal.next_era_index(), 0);
+		journal.set_archive(&archive).unwrap();
+		assert_eq!(journal.next_era_index(), 2);
+	}
+
 	#[test]
 	fn test_journal_iter() {
 		let temp = TempDir::new("test_journal_iter").unwrap();
@@ -0,0 +1,572 @@
+//! Key-value database interface of Parity, so the database can replace rocksdb.
+//!
+//! `KeyValueDB` mirrors the trait of the `kvdb` crate: values are kept in numbered
+//! columns, reads take optional columns and writes are buffered until `flush`.
+//! `KeyValueAdapter` implements it on top of a `Database` whose keys are prefixed
+//! with a column byte, like the namespaces of a `NamespacedDatabase`, so keys of
+//! the adapter are `key_len - 1` bytes long. `None` column is stored as column
+//! byte 0, column `i` as `i + 1`.
+//!
+//! Keys of the database have a fixed length, so unlike rocksdb the adapter only stores
+//! keys of exactly `key_len - 1` bytes. Columns of Parity with keys of other lengths, or
+//! of variable length, have to be stored elsewhere or hashed, see `HashedDatabase`.
+//!
+//! Buffered writes are kept in memory, where they are visible to reads, until they
+//! are committed as a single transaction by `flush`.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use parking_lot::{Mutex, RwLock};
+
+use database::Database;
+use error::{ErrorKind, Result};
+use options::{Compression, Options};
+
+/// Value stored in a `KeyValueDB`.
+pub type DBValue = Vec<u8>;
+
+/// Highest number of columns of a `KeyValueAdapter`, one column byte is left for `None`.
+pub const MAX_COLUMNS: u32 = 255;
+/// Iterators read this many pairs of the database at a time.
+const ITER_BATCH_SIZE: usize = 1024;
+
+/// Write operation of a `DBTransaction`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DBOp {
+	/// Inserts `value` of `key` in `col`.
+	Insert {
+		col: Option<u32>,
+		key: Vec<u8>,
+		value: DBValue,
+	},
+	/// Deletes `key` from `col`.
+	Delete {
+		col: Option<u32>,
+		key: Vec<u8>,
+	},
+}
+
+impl DBOp {
+	/// Returns the key of the operation.
+	pub fn key(&self) -> &[u8] {
+		match *self {
+			DBOp::Insert { ref key, .. } | DBOp::Delete { ref key, .. } => key,
+		}
+	}
+
+	/// Returns the column of the operation.
+	pub fn col(&self) -> Option<u32> {
+		match *self {
+			DBOp::Insert { col, .. } | DBOp::Delete { col, .. } => col,
+		}
+	}
+}
+
+/// Write operations applied together by `KeyValueDB::write_buffered`.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct DBTransaction {
+	/// Operations in the order they were added.
+	pub ops: Vec<DBOp>,
+}
+
+impl DBTransaction {
+	/// Creates an empty transaction.
+	pub fn new() -> Self {
+		DBTransaction::default()
+	}
+
+	/// Creates an empty transaction with space reserved for `capacity` operations.
+	pub fn with_capacity(capacity: usize) -> Self {
+		DBTransaction {
+			ops: Vec::with_capacity(capacity),
+		}
+	}
+
+	/// Inserts `value` of `key` in `col`.
+	pub fn put(&mut self, col: Option<u32>, key: &[u8], value: &[u8]) {
+		self.put_vec(col, key, value.to_vec());
+	}
+
+	/// Inserts `value` of `key` in `col` without copying the value.
+	pub fn put_vec(&mut self, col: Option<u32>, key: &[u8], value: DBValue) {
+		self.ops.push(DBOp::Insert {
+			col,
+			key: key.to_vec(),
+			value,
+		});
+	}
+
+	/// Deletes `key` from `col`.
+	pub fn delete(&mut self, col: Option<u32>, key: &[u8]) {
+		self.ops.push(DBOp::Delete {
+			col,
+			key: key.to_vec(),
+		});
+	}
+}
+
+/// Generic key-value database, see the `kvdb` crate.
+pub trait KeyValueDB: Sync + Send {
+	/// Creates an empty transaction.
+	fn transaction(&self) -> DBTransaction {
+		DBTransaction::new()
+	}
+
+	/// Returns the value of `key` in `col`.
+	fn get(&self, col: Option<u32>, key: &[u8]) -> Result<Option<DBValue>>;
+
+	/// Returns the value of the first key of `col` starting with `prefix`.
+	fn get_by_prefix(&self, col: Option<u32>, prefix: &[u8]) -> Option<Box<[u8]>>;
+
+	/// Buffers the operations of `transaction`, they are written by the next `flush`.
+	fn write_buffered(&self, transaction: DBTransaction);
+
+	/// Writes the operations of `transaction` together with all buffered operations.
+	fn write(&self, transaction: DBTransaction) -> Result<()> {
+		self.write_buffered(transaction);
+		self.flush()
+	}
+
+	/// Writes the buffered operations.
+	fn flush(&self) -> Result<()>;
+
+	/// Returns an iterator over the key-value pairs of `col` ordered by key.
+	fn iter<'a>(&'a self, col: Option<u32>) -> Box<Iterator<Item = (Box<[u8]>, Box<[u8]>)> + 'a>;
+
+	/// Returns an iterator over the key-value pairs of `col` with keys starting with `prefix`
+	/// ordered by key.
+	fn iter_from_prefix<'a>(&'a self, col: Option<u32>, prefix: &'a [u8]) -> Box<Iterator<Item = (Box<[u8]>, Box<[u8]>)> + 'a>;
+
+	/// Replaces the database with the one at `new_db`, which is moved in its place.
+	fn restore(&self, new_db: &str) -> Result<()>;
+}
+
+/// `KeyValueDB` backed by a `Database`.
+#[derive(Debug)]
+pub struct KeyValueAdapter {
+	path: PathBuf,
+	options: Options,
+	columns: u32,
+	/// `None` only if reopening failed after the files were replaced by `restore`.
+	db: RwLock<Option<Database>>,
+	/// Buffered writes by namespaced key, `None` for deletes.
+	overlay: Mutex<BTreeMap<Vec<u8>, Option<DBValue>>>,
+	/// Length of a key of a transaction rejected by `write_buffered`, reported by the next `flush`.
+	rejected: Mutex<Option<usize>>,
+}
+
+impl KeyValueAdapter {
+	/// Opens the database at `path`, creating it if it doesn't exist, for use with `columns`
+	/// columns besides the `None` column.
+	///
+	/// `options.key_len` includes the column byte.
+	pub fn open<P: AsRef<Path>>(path: P, options: Options, columns: u32) -> Result<Self> {
+		if columns > MAX_COLUMNS {
+			bail!(ErrorKind::InvalidColumn(columns));
+		}
+
+		let path = path.as_ref().to_path_buf();
+		let db = Self::open_database(&path, &options)?;
+		Ok(KeyValueAdapter {
+			path,
+			options,
+			columns,
+			db: RwLock::new(Some(db)),
+			overlay: Mutex::new(BTreeMap::new()),
+			rejected: Mutex::new(None),
+		})
+	}
+
+	fn open_database(path: &Path, options: &Options) -> Result<Database> {
+		if path.join(Database::DB_FILE).exists() {
+			Database::open(path, options.clone())
+		} else {
+			Database::create(path, options.clone())
+		}
+	}
+
+	/// Moves the database at `new_db` in place of the closed database. The files of the closed
+	/// database are kept if it fails.
+	fn replace_files(&self, new_db: &str) -> Result<()> {
+		let backup = self.path.with_extension("restore-backup");
+		if backup.exists() {
+			fs::remove_dir_all(&backup)?;
+		}
+
+		fs::rename(&self.path, &backup)?;
+		if let Err(err) = fs::rename(new_db, &self.path) {
+			fs::rename(&backup, &self.path)?;
+			return Err(err.into());
+		}
+
+		fs::remove_dir_all(&backup)?;
+		Ok(())
+	}
+
+	/// Returns the column byte of `col`.
+	fn namespace(&self, col: Option<u32>) -> Result<[u8; 1]> {
+		match col {
+			None => Ok([0]),
+			Some(col) if col < self.columns => Ok([col as u8 + 1]),
+			Some(col) => bail!(ErrorKind::InvalidColumn(col)),
+		}
+	}
+
+	fn key(namespace: [u8; 1], key: &[u8]) -> Vec<u8> {
+		let mut namespaced = Vec::with_capacity(1 + key.len());
+		namespaced.extend_from_slice(&namespace);
+		namespaced.extend_from_slice(key);
+		namespaced
+	}
+
+	/// Compresses values of `col` with `compression`, see `Database::set_column_compression`.
+	pub fn set_column_compression(&self, col: Option<u32>, compression: Compression) -> Result<()> {
+		let namespace = self.namespace(col)?;
+		match *self.db.write() {
+			Some(ref mut db) => db.set_column_compression(&namespace, compression),
+			None => bail!(ErrorKind::DatabaseClosed),
+		}
+	}
+
+	/// Calls `f` with the database.
+	pub fn with_database<T, F>(&self, f: F) -> Result<T> where F: FnOnce(&Database) -> Result<T> {
+		match *self.db.read() {
+			Some(ref db) => f(db),
+			None => bail!(ErrorKind::DatabaseClosed),
+		}
+	}
+}
+
+/// Iterator over the pairs of a column, buffered writes included.
+///
+/// Pairs are read from the database in batches, so the database is locked only while a batch
+/// is read. Writes flushed or buffered in the meantime are seen by the following batches.
+struct ColumnIterator<'a> {
+	adapter: &'a KeyValueAdapter,
+	/// Namespaced prefix of the iterated keys.
+	prefix: Vec<u8>,
+	/// Namespaced key of the last pair of the previous batch.
+	after: Option<Vec<u8>>,
+	batch: VecDeque<(Vec<u8>, DBValue)>,
+	done: bool,
+}
+
+impl<'a> ColumnIterator<'a> {
+	fn new(adapter: &'a KeyValueAdapter, col: Option<u32>, prefix: &[u8]) -> Self {
+		let prefix = adapter.namespace(col).ok().map(|namespace| KeyValueAdapter::key(namespace, prefix));
+		let done = prefix.as_ref().map_or(true, |prefix| prefix.len() > adapter.options.key_len);
+
+		ColumnIterator {
+			adapter,
+			prefix: prefix.unwrap_or_default(),
+			after: None,
+			batch: VecDeque::new(),
+			done,
+		}
+	}
+
+	/// Reads the pairs of the database following the previous batch and merges them with the
+	/// buffered writes of their keys. Records which can't be read are left out.
+	fn read_batch(&mut self) {
+		let overlay = self.adapter.overlay.lock();
+		let stored = {
+			let prefix = &self.prefix;
+			let after = self.after.as_ref();
+			let start = after.cloned().unwrap_or_else(|| {
+				let mut start = prefix.clone();
+				start.resize(self.adapter.options.key_len, 0);
+				start
+			});
+
+			self.adapter.with_database(|db| {
+				let mut page = Vec::new();
+				for item in db.iter_from(&start)? {
+					let (key, value) = match item {
+						Ok(item) => item,
+						Err(_) => continue,
+					};
+					if !key.starts_with(prefix) || page.len() == ITER_BATCH_SIZE {
+						break;
+					}
+					if Some(key) != after.map(|after| &after[..]) {
+						page.push((key.to_vec(), value.to_vec()));
+					}
+				}
+				Ok(page)
+			}).unwrap_or_default()
+		};
+
+		// buffered writes after the last stored pair of a full batch belong to the next batch
+		let last = if stored.len() == ITER_BATCH_SIZE {
+			stored.last().map(|&(ref key, _)| key.clone())
+		} else {
+			None
+		};
+
+		let mut pairs: BTreeMap<Vec<u8>, DBValue> = stored.into_iter().collect();
+		{
+			let start = self.after.clone().unwrap_or_else(|| self.prefix.clone());
+			let buffered = overlay.range(start..)
+				.filter(|&(key, _)| Some(key) != self.after.as_ref())
+				.take_while(|&(key, _)| key.starts_with(&self.prefix) && last.as_ref().map_or(true, |last| key <= last));
+			for (key, value) in buffered {
+				match *value {
+					Some(ref value) => pairs.insert(key.clone(), value.clone()),
+					None => pairs.remove(key),
+				};
+			}
+		}
+
+		self.batch = pairs.into_iter().map(|(key, value)| (key[1..].to_vec(), value)).collect();
+		self.done = last.is_none();
+		self.after = last;
+	}
+}
+
+impl<'a> Iterator for ColumnIterator<'a> {
+	type Item = (Box<[u8]>, Box<[u8]>);
+
+	fn next(&mut self) -> Option<Self::Item> {
+		loop {
+			if let Some((key, value)) = self.batch.pop_front() {
+				return Some((key.into_boxed_slice(), value.into_boxed_slice()));
+			}
+
+			if self.done {
+				return None;
+			}
+
+			self.read_batch();
+		}
+	}
+}
+
+impl KeyValueDB for KeyValueAdapter {
+	fn get(&self, col: Option<u32>, key: &[u8]) -> Result<Option<DBValue>> {
+		let key = Self::key(self.namespace(col)?, key);
+		if let Some(value) = self.overlay.lock().get(&key) {
+			return Ok(value.clone());
+		}
+
+		self.with_database(|db| Ok(db.get(&key)?.map(|value| value.to_vec())))
+	}
+
+	fn get_by_prefix(&self, col: Option<u32>, prefix: &[u8]) -> Option<Box<[u8]>> {
+		self.iter_from_prefix(col, prefix).next().map(|(_, value)| value)
+	}
+
+	/// A transaction with a key of another length than `key_len - 1` is not buffered, and the
+	/// next `flush` fails with `InvalidKeyLen` instead.
+	///
+	/// # Panics
+	///
+	/// Panics if an operation writes a column which is not lower than the number of columns
+	/// the adapter was opened with, like the rocksdb implementation.
+	fn write_buffered(&self, transaction: DBTransaction) {
+		let key_len = self.options.key_len - 1;
+		if let Some(op) = transaction.ops.iter().find(|op| op.key().len() != key_len) {
+			*self.rejected.lock() = Some(op.key().len());
+			return;
+		}
+
+		let mut overlay = self.overlay.lock();
+		for op in transaction.ops {
+			let namespace = self.namespace(op.col()).expect("columns of written keys must be opened");
+			match op {
+				DBOp::Insert { key, value, .. } => overlay.insert(Self::key(namespace, &key), Some(value)),
+				DBOp::Delete { key, .. } => overlay.insert(Self::key(namespace, &key), None),
+			};
+		}
+	}
+
+	/// Commits the buffered writes, syncs the journal and flushes excessive journal eras.
+	/// The writes stay buffered if the commit fails.
+	fn flush(&self) -> Result<()> {
+		if let Some(got) = self.rejected.lock().take() {
+			bail!(ErrorKind::InvalidKeyLen(self.options.key_len - 1, got));
+		}
+
+		let mut overlay = self.overlay.lock();
+		let mut guard = self.db.write();
+		let db = match *guard {
+			Some(ref mut db) => db,
+			None => bail!(ErrorKind::DatabaseClosed),
+		};
+
+		if !overlay.is_empty() {
+			let mut tx = db.create_transaction_with_capacity(overlay.len());
+			for (key, value) in overlay.iter() {
+				match *value {
+					Some(ref value) => tx.insert(key, value)?,
+					None => tx.delete(key)?,
+				}
+			}
+
+			db.commit(&tx)?;
+			overlay.clear();
+		}
+
+		db.flush_wal()?;
+		db.flush_journal(None)
+	}
+
+	/// Reads the pairs from the database in batches, so the database is not locked while they
+	/// are iterated.
+	fn iter<'a>(&'a self, col: Option<u32>) -> Box<Iterator<Item = (Box<[u8]>, Box<[u8]>)> + 'a> {
+		self.iter_from_prefix(col, &[])
+	}
+
+	fn iter_from_prefix<'a>(&'a self, col: Option<u32>, prefix: &'a [u8]) -> Box<Iterator<Item = (Box<[u8]>, Box<[u8]>)> + 'a> {
+		Box::new(ColumnIterator::new(self, col, prefix))
+	}
+
+	/// Buffered writes are discarded. If the restored database can't be opened, the database
+	/// is closed and all further operations fail with `DatabaseClosed`.
+	fn restore(&self, new_db: &str) -> Result<()> {
+		let mut overlay = self.overlay.lock();
+		let mut guard = self.db.write();
+		overlay.clear();
+
+		// the files can only be replaced once the database released them
+		*guard = None;
+		let replaced = self.replace_files(new_db);
+		*guard = Some(Self::open_database(&self.path, &self.options)?);
+		replaced
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	extern crate tempdir;
+
+	use error::ErrorKind;
+	use options::{Options, ValuesLen};
+	use super::{DBTransaction, KeyValueAdapter, KeyValueDB, ITER_BATCH_SIZE};
+
+	fn options() -> Options {
+		Options {
+			journal_eras: 1,
+			key_len: 3,
+			value_len: ValuesLen::Variable { expected: 4 },
+			..Default::default()
+		}
+	}
+
+	fn pairs<'a>(iter: Box<Iterator<Item = (Box<[u8]>, Box<[u8]>)> + 'a>) -> Vec<(Vec<u8>, Vec<u8>)> {
+		iter.map(|(key, value)| (key.into_vec(), value.into_vec())).collect()
+	}
+
+	#[test]
+	fn test_key_value_adapter() {
+		let temp = tempdir::TempDir::new("test_key_value_adapter").unwrap();
+		let db = KeyValueAdapter::open(temp.path().join("db"), options(), 2).unwrap();
+
+		let mut tx = db.transaction();
+		tx.put(None, b"aa", b"1");
+		tx.put(Some(0), b"aa", b"2");
+		tx.put(Some(1), b"ab", b"3");
+		tx.put(Some(1), b"ba", b"4");
+		db.write(tx).unwrap();
+
+		// columns don't share keys
+		assert_eq!(db.get(None, b"aa").unwrap(), Some(b"1".to_vec()));
+		assert_eq!(db.get(Some(0), b"aa").unwrap(), Some(b"2".to_vec()));
+		assert_eq!(db.get(Some(1), b"aa").unwrap(), None);
+		assert_eq!(*db.get(Some(2), b"aa").unwrap_err().kind(), ErrorKind::InvalidColumn(2));
+
+		// buffered writes are visible before they are flushed
+		let mut tx = DBTransaction::new();
+		tx.delete(Some(1), b"ab");
+		tx.put(Some(1), b"bb", b"5");
+		db.write_buffered(tx);
+		assert_eq!(db.get(Some(1), b"ab").unwrap(), None);
+		assert_eq!(pairs(db.iter(Some(1))), vec![(b"ba".to_vec(), b"4".to_vec()), (b"bb".to_vec(), b"5".to_vec())]);
+		assert_eq!(db.get_by_prefix(Some(1), b"b").map(|value| value.into_vec()), Some(b"4".to_vec()));
+		assert!(pairs(db.iter_from_prefix(Some(1), b"a")).is_empty());
+		let stored = |db: &KeyValueAdapter| db.with_database(|db| Ok(db.get(b"\x02ab")?.is_some())).unwrap();
+		assert!(stored(&db));
+
+		db.flush().unwrap();
+		assert!(!stored(&db));
+		assert_eq!(db.get(Some(1), b"bb").unwrap(), Some(b"5".to_vec()));
+	}
+
+	#[test]
+	fn test_key_value_adapter_key_len() {
+		let temp = tempdir::TempDir::new("test_key_value_adapter_key_len").unwrap();
+		let db = KeyValueAdapter::open(temp.path().join("db"), options(), 1).unwrap();
+
+		let mut tx = db.transaction();
+		tx.put(Some(0), b"aa", b"1");
+		db.write_buffered(tx);
+		let mut tx = db.transaction();
+		tx.put(Some(0), b"bb", b"2");
+		tx.put(Some(0), b"long", b"3");
+		assert_eq!(*db.write(tx).unwrap_err().kind(), ErrorKind::InvalidKeyLen(2, 4));
+
+		// the rejected transaction is dropped and doesn't stop later flushes
+		assert_eq!(db.get(Some(0), b"bb").unwrap(), None);
+		db.flush().unwrap();
+		assert_eq!(db.with_database(|db| Ok(db.get(b"\x01aa")?.map(|value| value.to_vec()))).unwrap(), Some(b"1".to_vec()));
+	}
+
+	#[test]
+	fn test_key_value_adapter_iter_batches() {
+		let temp = tempdir::TempDir::new("test_key_value_adapter_iter_batches").unwrap();
+		let db = KeyValueAdapter::open(temp.path().join("db"), options(), 2).unwrap();
+
+		let keys: Vec<[u8; 2]> = (0..ITER_BATCH_SIZE as u16 * 2 + 10).map(|i| [(i >> 8) as u8, i as u8]).collect();
+		let mut tx = db.transaction();
+		for key in &keys {
+			tx.put(Some(0), key, key);
+			tx.put(Some(1), key, b"other");
+		}
+		db.write(tx).unwrap();
+
+		// buffered writes on both sides of a batch boundary
+		let boundary = keys[ITER_BATCH_SIZE - 1];
+		let mut tx = db.transaction();
+		tx.delete(Some(0), &boundary);
+		tx.delete(Some(0), &keys[ITER_BATCH_SIZE]);
+		tx.put(Some(0), &keys[0], b"changed");
+		db.write_buffered(tx);
+
+		let items = pairs(db.iter(Some(0)));
+		assert_eq!(items.len(), keys.len() - 2);
+		assert_eq!(items[0], (keys[0].to_vec(), b"changed".to_vec()));
+		assert!(items.iter().all(|&(ref key, _)| key[..] != boundary[..] && key[..] != keys[ITER_BATCH_SIZE][..]));
+		assert!(items.windows(2).all(|pair| pair[0].0 < pair[1].0));
+		assert!(items[1..].iter().all(|&(ref key, ref value)| key == value));
+		assert_eq!(pairs(db.iter_from_prefix(Some(0), &[5])).len(), 256);
+	}
+
+	#[test]
+	fn test_key_value_adapter_restore() {
+		let temp = tempdir::TempDir::new("test_key_value_adapter_restore").unwrap();
+		let restored = temp.path().join("restored");
+		{
+			let db = KeyValueAdapter::open(&restored, options(), 1).unwrap();
+			let mut tx = db.transaction();
+			tx.put(Some(0), b"aa", b"restored");
+			db.write(tx).unwrap();
+		}
+
+		let db = KeyValueAdapter::open(temp.path().join("db"), options(), 1).unwrap();
+		let mut tx = db.transaction();
+		tx.put(Some(0), b"bb", b"1");
+		db.write(tx).unwrap();
+		let mut tx = db.transaction();
+		tx.put(Some(0), b"cc", b"buffered");
+		db.write_buffered(tx);
+
+		db.restore(restored.to_str().unwrap()).unwrap();
+		assert!(!restored.exists());
+		assert_eq!(pairs(db.iter(Some(0))), vec![(b"aa".to_vec(), b"restored".to_vec())]);
+
+		// a missing database leaves the current one in place
+		assert!(db.restore(restored.to_str().unwrap()).is_err());
+		assert_eq!(db.get(Some(0), b"aa").unwrap(), Some(b"restored".to_vec()));
+	}
+}
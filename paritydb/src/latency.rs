@@ -0,0 +1,235 @@
+//! Latency histograms for database operations.
+//!
+//! Samples are kept in a histogram with logarithmic buckets (HDR-style).
+//! Each power of two is divided into `SUB_BUCKETS` linear sub-buckets.
+//! This bounds the relative error of reported percentiles to about 6%.
+//! The memory used is fixed and independent of the number of samples.
+
+use std::time::Duration;
+
+use parking_lot::Mutex;
+
+const SUB_BUCKET_BITS: u32 = 4;
+const SUB_BUCKETS: u64 = 1 << SUB_BUCKET_BITS;
+// one group of sub-buckets for exact small values and one per remaining power of two
+const BUCKETS: usize = ((64 - SUB_BUCKET_BITS + 1) as u64 * SUB_BUCKETS) as usize;
+
+#[inline]
+fn duration_to_nanos(duration: Duration) -> u64 {
+	duration.as_secs()
+		.saturating_mul(1_000_000_000)
+		.saturating_add(duration.subsec_nanos() as u64)
+}
+
+#[inline]
+fn nanos_to_duration(nanos: u64) -> Duration {
+	Duration::new(nanos / 1_000_000_000, (nanos % 1_000_000_000) as u32)
+}
+
+#[inline]
+fn bucket_index(value: u64) -> usize {
+	if value < SUB_BUCKETS {
+		return value as usize;
+	}
+
+	let exponent = 63 - value.leading_zeros();
+	let shift = exponent - SUB_BUCKET_BITS;
+	let mantissa = value >> shift;
+	((shift as u64 + 1) * SUB_BUCKETS + mantissa - SUB_BUCKETS) as usize
+}
+
+/// Returns the highest value which falls into the bucket.
+#[inline]
+fn bucket_upper_bound(index: usize) -> u64 {
+	let index = index as u64;
+	if index < SUB_BUCKETS {
+		return index;
+	}
+
+	let shift = index / SUB_BUCKETS - 1;
+	let mantissa = index % SUB_BUCKETS + SUB_BUCKETS;
+	// written as a sum to not overflow in the highest bucket
+	(mantissa << shift) + ((1u64 << shift) - 1)
+}
+
+/// Logarithmic histogram of durations.
+#[derive(Debug, Clone)]
+pub struct Histogram {
+	counts: Vec<u64>,
+	total: u64,
+	max: u64,
+}
+
+impl Default for Histogram {
+	fn default() -> Self {
+		Histogram {
+			counts: vec![0; BUCKETS],
+			total: 0,
+			max: 0,
+		}
+	}
+}
+
+impl Histogram {
+	/// Records a single sample.
+	pub fn record(&mut self, duration: Duration) {
+		let nanos = duration_to_nanos(duration);
+		self.counts[bucket_index(nanos)] += 1;
+		self.total += 1;
+		if nanos > self.max {
+			self.max = nanos;
+		}
+	}
+
+	/// Returns number of recorded samples.
+	pub fn count(&self) -> u64 {
+		self.total
+	}
+
+	/// Returns the value below which `quantile` (0.0 - 1.0) of samples fall.
+	pub fn percentile(&self, quantile: f64) -> Duration {
+		if self.total == 0 {
+			return Duration::new(0, 0);
+		}
+
+		let rank = ((quantile * self.total as f64).ceil() as u64).max(1).min(self.total);
+		let mut seen = 0;
+		for (index, count) in self.counts.iter().enumerate() {
+			seen += *count;
+			if seen >= rank {
+				return nanos_to_duration(bucket_upper_bound(index).min(self.max));
+			}
+		}
+
+		nanos_to_duration(self.max)
+	}
+
+	/// Summarizes the histogram.
+	pub fn summary(&self) -> LatencySummary {
+		LatencySummary {
+			count: self.total,
+			p50: self.percentile(0.5),
+			p90: self.percentile(0.9),
+			p99: self.percentile(0.99),
+			p999: self.percentile(0.999),
+			max: nanos_to_duration(self.max),
+		}
+	}
+}
+
+/// Percentiles of a single operation latency.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LatencySummary {
+	/// Number of samples.
+	pub count: u64,
+	/// Median latency.
+	pub p50: Duration,
+	/// 90th percentile.
+	pub p90: Duration,
+	/// 99th percentile.
+	pub p99: Duration,
+	/// 99.9th percentile.
+	pub p999: Duration,
+	/// Highest recorded latency.
+	pub max: Duration,
+}
+
+/// Latencies of database operations since the database was opened.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LatencyReport {
+	/// `Database::get` latencies.
+	pub get: LatencySummary,
+	/// `Database::commit` latencies.
+	pub commit: LatencySummary,
+	/// Latencies of yielding a single item from a database iterator.
+	pub iter: LatencySummary,
+}
+
+/// Histograms tracked by the database.
+#[derive(Debug)]
+pub struct Latencies {
+	enabled: bool,
+	get: Mutex<Histogram>,
+	commit: Mutex<Histogram>,
+	iter: Mutex<Histogram>,
+}
+
+impl Latencies {
+	pub fn new(enabled: bool) -> Self {
+		Latencies {
+			enabled,
+			get: Mutex::new(Histogram::default()),
+			commit: Mutex::new(Histogram::default()),
+			iter: Mutex::new(Histogram::default()),
+		}
+	}
+
+	#[inline]
+	pub fn enabled(&self) -> bool {
+		self.enabled
+	}
+
+	#[inline]
+	pub fn record_get(&self, duration: Duration) {
+		self.get.lock().record(duration);
+	}
+
+	#[inline]
+	pub fn record_commit(&self, duration: Duration) {
+		self.commit.lock().record(duration);
+	}
+
+	#[inline]
+	pub fn record_iter(&self, duration: Duration) {
+		self.iter.lock().record(duration);
+	}
+
+	pub fn report(&self) -> LatencyReport {
+		LatencyReport {
+			get: self.get.lock().summary(),
+			commit: self.commit.lock().summary(),
+			iter: self.iter.lock().summary(),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::time::Duration;
+	use super::{bucket_index, bucket_upper_bound, Histogram};
+
+	#[test]
+	fn test_bucket_bounds() {
+		for value in (0..10_000u64).chain(vec![1 << 40, (1 << 40) + 12345, u64::max_value()]) {
+			let index = bucket_index(value);
+			assert!(bucket_upper_bound(index) >= value);
+			if index > 0 {
+				assert!(bucket_upper_bound(index - 1) < value);
+			}
+		}
+	}
+
+	#[test]
+	fn test_percentiles() {
+		let mut histogram = Histogram::default();
+		for micros in 1..1001 {
+			histogram.record(Duration::new(0, micros * 1000));
+		}
+
+		let summary = histogram.summary();
+		assert_eq!(summary.count, 1000);
+		assert_eq!(summary.max, Duration::new(0, 1_000_000));
+
+		let p50 = summary.p50.subsec_nanos() as f64;
+		let p99 = summary.p99.subsec_nanos() as f64;
+		assert!((p50 - 500_000.0).abs() / 500_000.0 < 0.07, "p50: {}", p50);
+		assert!((p99 - 990_000.0).abs() / 990_000.0 < 0.07, "p99: {}", p99);
+	}
+
+	#[test]
+	fn test_empty_histogram() {
+		let histogram = Histogram::default();
+		assert_eq!(histogram.percentile(0.99), Duration::new(0, 0));
+		assert_eq!(histogram.summary().count, 0);
+	}
+}
@@ -57,11 +57,18 @@ extern crate byteorder;
 #[macro_use]
 extern crate error_chain;
 extern crate fs2;
+#[cfg(feature = "async")]
+extern crate futures;
+#[cfg(feature = "async")]
+extern crate futures_cpupool;
 extern crate hex_slice;
 extern crate itertools;
+extern crate lz4_compress;
 extern crate memmap;
 extern crate parking_lot;
+extern crate snap;
 extern crate tiny_keccak;
+extern crate toml;
 #[cfg(test)]
 #[macro_use]
 extern crate matches;
@@ -69,25 +76,87 @@ extern crate matches;
 #[macro_use]
 extern crate quickcheck;
 
+#[cfg(feature = "async")]
+mod async_db;
+mod audit;
+mod bloom;
+mod cache;
+mod checkpoint;
 mod collision;
+mod compression;
+mod corpus;
 mod database;
+pub mod debug;
+mod diff;
 mod error;
+mod events;
+mod export;
+mod fence;
 mod field;
 mod find;
 mod flush;
+mod group;
+mod hashed;
+mod health;
+mod idempotency;
+#[cfg(unix)]
+mod ipc;
 mod journal;
 mod key;
+mod kvdb;
+mod latency;
 mod metadata;
+mod namespaced;
 mod options;
+pub mod planner;
 mod prefix_tree;
+mod read;
 mod record;
+mod repair;
+mod seal;
+mod series;
+mod sharded;
+#[cfg(feature = "server")]
+mod server;
+mod snapshot;
 mod space;
+mod spawn;
+mod stats;
 mod transaction;
+mod transform;
+mod trigger;
+mod ttl;
 
-pub use database::{Database, Value};
+#[cfg(feature = "async")]
+pub use async_db::AsyncDatabase;
+pub use audit::AuditRecord;
+pub use cache::CacheStats;
+pub use checkpoint::Checkpoint;
+pub use corpus::{CorpusGenerator, FileCorruption};
+pub use database::{Database, FilterDecision, IterationOrder, OpenPhase, OpenProgress, ShutdownReport, Value};
+pub use diff::Change;
 pub use error::{Error, Result, ErrorKind};
-pub use options::{Options, ValuesLen};
+pub use events::Event;
+pub use group::CommitGroup;
+pub use hashed::{HashedDatabase, HashedTransaction};
+pub use health::Health;
+#[cfg(unix)]
+pub use ipc::{IpcClient, IpcServer};
+pub use kvdb::{DBOp, DBTransaction, DBValue, KeyValueAdapter, KeyValueDB, MAX_COLUMNS};
+pub use latency::{LatencyReport, LatencySummary};
+pub use namespaced::{NamespacedDatabase, NamespacedTransaction};
+pub use options::{Compression, FsyncPolicy, Options, ValuesLen};
+pub use read::{ReadOptions, ReadStats};
 pub use record::Record;
-pub use transaction::Transaction;
+pub use series::{Series, SeriesIterator};
+pub use sharded::{shard_for, ShardedDatabase, ShardedSnapshot, ShardedTransaction};
+#[cfg(feature = "server")]
+pub use server::HttpServer;
+pub use snapshot::{ReadTransaction, Snapshot};
+pub use spawn::{StdSpawner, Task, ThreadSpawner};
+pub use stats::{KeyStatistics, Statistics};
+pub use transaction::{Operation, Transaction};
+pub use transform::ValueTransform;
+pub use ttl::{TtlDatabase, TtlTransaction};
 #[doc(hidden)]
 pub use prefix_tree::PrefixTree;
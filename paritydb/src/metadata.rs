@@ -21,6 +21,12 @@ pub struct Metadata {
 }
 
 impl Metadata {
+	/// Version of the on-disk format.
+	///
+	/// Every integer in the database files is stored as fixed width little-endian,
+	/// so files with the same version are portable between architectures.
+	/// The version is bumped on any change of the layout, except of collision logs which
+	/// start with their own version marker, see `Collision`.
 	pub const DB_VERSION: u16 = 0;
 
 	/// Notify that record was inserted.
@@ -51,6 +57,13 @@ impl Metadata {
 		self.prefixes.remove(prefix);
 	}
 
+	/// Notify that a collided prefix was moved back to the data file.
+	///
+	/// The prefix is added back to `prefixes` when its records are inserted.
+	pub fn remove_prefix_collision(&mut self, prefix: u32) {
+		self.collided_prefixes.remove(prefix);
+	}
+
 	/// Returns bytes representation of `Metadata`.
 	pub fn as_bytes(&self) -> bytes::Metadata {
 		bytes::Metadata::new(self)
@@ -61,6 +74,7 @@ impl Metadata {
 pub mod bytes {
 	use byteorder::{LittleEndian, ByteOrder};
 
+	use error::{ErrorKind, Result};
 	use prefix_tree::PrefixTree;
 
 	/// Bytes representation of `Metadata`.
@@ -115,8 +129,14 @@ pub mod bytes {
 	}
 
 	/// Read `Metadata` from given slice.
-	pub fn read(data: &[u8], prefix_bits: u8) -> super::Metadata {
+	///
+	/// Fails if the data was written in a different format version.
+	pub fn read(data: &[u8], prefix_bits: u8) -> Result<super::Metadata> {
 		let db_version = LittleEndian::read_u16(&data[..Metadata::VERSION_SIZE]);
+		if db_version != super::Metadata::DB_VERSION {
+			bail!(ErrorKind::UnsupportedFormatVersion(db_version, super::Metadata::DB_VERSION));
+		}
+
 		let occupied_bytes = LittleEndian::read_u64(&data[Metadata::VERSION_SIZE..]);
 
 		let prefix_leaves_offset = prefix_leaves_offset();
@@ -125,21 +145,20 @@ pub mod bytes {
 		let prefixes = PrefixTree::from_leaves(&data[prefix_leaves_offset..collided_prefix_leaves_offset], prefix_bits);
 		let collided_prefixes = PrefixTree::from_leaves(&data[collided_prefix_leaves_offset..], prefix_bits);
 
-		assert_eq!(db_version, super::Metadata::DB_VERSION);
-
-		super::Metadata {
+		Ok(super::Metadata {
 			db_version,
 			occupied_bytes,
 			prefix_bits,
 			prefixes,
 			collided_prefixes,
-		}
+		})
 	}
 }
 
 #[cfg(test)]
 mod tests {
-	use super::bytes;
+	use super::{bytes, Metadata};
+	use error::ErrorKind;
 	use quickcheck::TestResult;
 
 	quickcheck! {
@@ -156,7 +175,7 @@ mod tests {
 			}
 
 			let initial_zeroed_buf: Vec<u8> = vec![0; bytes::len(key_index_bits)];
-			let metadata = bytes::read(&initial_zeroed_buf[..], key_index_bits);
+			let metadata = bytes::read(&initial_zeroed_buf[..], key_index_bits).unwrap();
 			assert_eq!(metadata.db_version, 0);
 			assert_eq!(metadata.occupied_bytes, 0);
 
@@ -171,4 +190,30 @@ mod tests {
 			TestResult::passed()
 		}
 	}
+
+	#[test]
+	fn test_metadata_format() {
+		let mut data = vec![0; bytes::len(8)];
+		data[0] = Metadata::DB_VERSION as u8;
+		data[2..10].copy_from_slice(&[0x07, 0x01, 0, 0, 0, 0, 0, 0x01]);
+		data[10 + 12] = 0b0000_0010;
+		data[10 + 32 + 31] = 0b1000_0000;
+
+		let metadata = bytes::read(&data, 8).unwrap();
+		assert_eq!(metadata.occupied_bytes, 0x0100_0000_0000_0107);
+		assert_eq!(metadata.prefixes.prefixes_iter().collect::<Vec<_>>(), vec![97]);
+		assert_eq!(metadata.collided_prefixes.prefixes_iter().collect::<Vec<_>>(), vec![255]);
+
+		let mut serialized = vec![0; bytes::len(8)];
+		metadata.as_bytes().copy_to_slice(&mut serialized);
+		assert_eq!(serialized, data);
+	}
+
+	#[test]
+	fn test_metadata_unsupported_version() {
+		let mut data = vec![0; bytes::len(8)];
+		data[..2].copy_from_slice(&[0x01, 0x01]);
+
+		assert_eq!(*bytes::read(&data, 8).unwrap_err().kind(), ErrorKind::UnsupportedFormatVersion(0x0101, Metadata::DB_VERSION));
+	}
 }
@@ -0,0 +1,185 @@
+//! Database keys prefixed with a namespace.
+//!
+//! Lets several subsystems share one database without colliding keys. Every key
+//! passed to a `NamespacedDatabase` or `NamespacedTransaction` is stored with the
+//! namespace in front of it and keys returned by iterators have it stripped.
+//! Keys of a namespace are `key_len - namespace.len()` bytes long.
+
+use std::ops::Range;
+
+use database::{Database, IterationOrder, Value};
+use error::Result;
+use transaction::Transaction;
+
+/// Read view of the keys in a single namespace.
+#[derive(Debug)]
+pub struct NamespacedDatabase<'a> {
+	db: &'a Database,
+	namespace: Vec<u8>,
+}
+
+impl<'a> NamespacedDatabase<'a> {
+	/// Creates a view of `db` with keys prefixed with `namespace`.
+	pub fn new<N: AsRef<[u8]>>(db: &'a Database, namespace: N) -> Self {
+		NamespacedDatabase {
+			db,
+			namespace: namespace.as_ref().to_vec(),
+		}
+	}
+
+	/// Returns the namespace.
+	pub fn namespace(&self) -> &[u8] {
+		&self.namespace
+	}
+
+	/// Returns the underlying database.
+	pub fn inner(&self) -> &'a Database {
+		self.db
+	}
+
+	fn key(&self, key: &[u8]) -> Vec<u8> {
+		let mut namespaced = Vec::with_capacity(self.namespace.len() + key.len());
+		namespaced.extend_from_slice(&self.namespace);
+		namespaced.extend_from_slice(key);
+		namespaced
+	}
+
+	/// Returns the value for the key.
+	pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Value<'a>>> {
+		self.db.get(self.key(key.as_ref()))
+	}
+
+	/// Returns true if the key is in the namespace.
+	pub fn contains<K: AsRef<[u8]>>(&self, key: K) -> Result<bool> {
+		self.db.contains(self.key(key.as_ref()))
+	}
+
+	/// Returns an iterator over all key-value pairs of the namespace ordered by key.
+	pub fn iter<'b>(&'b self) -> Result<Box<Iterator<Item = Result<(&'b [u8], Value<'b>)>> + 'b>> {
+		self.iter_prefix(&[])
+	}
+
+	/// Returns an iterator over the key-value pairs of the namespace with keys starting
+	/// with `prefix` ordered by key.
+	pub fn iter_prefix<'b>(&'b self, prefix: &'b [u8]) -> Result<Box<Iterator<Item = Result<(&'b [u8], Value<'b>)>> + 'b>> {
+		let namespace_len = self.namespace.len();
+		let iter = self.db.iter_prefix(self.key(prefix))?
+			.map(move |item| item.map(|(key, value)| (&key[namespace_len..], value)));
+
+		Ok(Box::new(iter))
+	}
+
+	/// Returns an iterator over the key-value pairs of the namespace with keys in `range`
+	/// ordered by key.
+	pub fn iter_range<'b, K: AsRef<[u8]>>(&'b self, range: Range<K>) -> Result<Box<Iterator<Item = Result<(&'b [u8], Value<'b>)>> + 'b>> {
+		let namespace_len = self.namespace.len();
+		let range = self.key(range.start.as_ref())..self.key(range.end.as_ref());
+		let iter = self.db.iter_range(range)?
+			.map(move |item| item.map(|(key, value)| (&key[namespace_len..], value)));
+
+		Ok(Box::new(iter))
+	}
+
+	/// Returns an iterator over all key-value pairs of the namespace in the given `order`.
+	pub fn iter_ordered<'b>(&'b self, order: IterationOrder) -> Result<Box<Iterator<Item = Result<(&'b [u8], Value<'b>)>> + 'b>> {
+		let namespace_len = self.namespace.len();
+		let iter = self.db.iter_prefix_ordered(&self.namespace, order)?
+			.map(move |item| item.map(|(key, value)| (&key[namespace_len..], value)));
+
+		Ok(Box::new(iter))
+	}
+
+	/// Returns a transaction which prefixes the keys of its operations and appends them to `tx`.
+	///
+	/// Operations of several namespaces may be committed atomically in a single transaction.
+	pub fn transaction<'t>(&self, tx: &'t mut Transaction) -> NamespacedTransaction<'t> {
+		NamespacedTransaction {
+			tx,
+			namespace: self.namespace.clone(),
+		}
+	}
+}
+
+/// Operations on the keys of a namespace appended to a shared transaction.
+pub struct NamespacedTransaction<'t> {
+	tx: &'t mut Transaction,
+	namespace: Vec<u8>,
+}
+
+impl<'t> NamespacedTransaction<'t> {
+	fn key(&self, key: &[u8]) -> Vec<u8> {
+		let mut namespaced = Vec::with_capacity(self.namespace.len() + key.len());
+		namespaced.extend_from_slice(&self.namespace);
+		namespaced.extend_from_slice(key);
+		namespaced
+	}
+
+	/// Append new insert operation to the list of transactions.
+	pub fn insert<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, value: V) -> Result<()> {
+		let key = self.key(key.as_ref());
+		self.tx.insert(key, value)
+	}
+
+	/// Append new delete operation to the list of transactions.
+	pub fn delete<K: AsRef<[u8]>>(&mut self, key: K) -> Result<()> {
+		let key = self.key(key.as_ref());
+		self.tx.delete(key)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	extern crate tempdir;
+
+	use database::Database;
+	use error::ErrorKind;
+	use options::{Options, ValuesLen};
+	use super::NamespacedDatabase;
+
+	fn keys<'a, I: Iterator<Item = ::error::Result<(&'a [u8], ::database::Value<'a>)>>>(iter: I) -> Vec<Vec<u8>> {
+		iter.map(|item| item.unwrap().0.to_vec()).collect()
+	}
+
+	#[test]
+	fn test_namespaced_database() {
+		let temp = tempdir::TempDir::new("test_namespaced_database").unwrap();
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		}).unwrap();
+
+		let mut tx = db.create_transaction();
+		{
+			let blocks = NamespacedDatabase::new(&db, "b");
+			let state = NamespacedDatabase::new(&db, "s");
+			let mut blocks_tx = blocks.transaction(&mut tx);
+			blocks_tx.insert("aa", "001").unwrap();
+			blocks_tx.insert("ab", "002").unwrap();
+			assert!(matches!(*blocks_tx.insert("a", "000").unwrap_err().kind(), ErrorKind::InvalidKeyLen(3, 2)));
+			drop(blocks_tx);
+			state.transaction(&mut tx).insert("aa", "003").unwrap();
+		}
+		db.commit(&tx).unwrap();
+		db.flush_journal(1).unwrap();
+
+		let mut tx = db.create_transaction();
+		NamespacedDatabase::new(&db, "s").transaction(&mut tx).insert("ba", "004").unwrap();
+		db.commit(&tx).unwrap();
+
+		let blocks = NamespacedDatabase::new(&db, "b");
+		let state = NamespacedDatabase::new(&db, "s");
+		assert_eq!(blocks.get("aa").unwrap().unwrap(), "001");
+		assert_eq!(state.get("aa").unwrap().unwrap(), "003");
+		assert!(!blocks.contains("ba").unwrap());
+		assert_eq!(db.get("saa").unwrap().unwrap(), "003");
+
+		assert_eq!(keys(blocks.iter().unwrap()), vec![b"aa".to_vec(), b"ab".to_vec()]);
+		assert_eq!(keys(state.iter().unwrap()), vec![b"aa".to_vec(), b"ba".to_vec()]);
+		assert_eq!(keys(state.iter_prefix(b"b").unwrap()), vec![b"ba".to_vec()]);
+		assert_eq!(keys(state.iter_range(b"ab"..b"bb").unwrap()), vec![b"ba".to_vec()]);
+		assert_eq!(keys(blocks.iter_range(b"aa"..b"ab").unwrap()), vec![b"aa".to_vec()]);
+		assert!(blocks.iter_range(b"a"..b"ab").is_err());
+	}
+}
@@ -1,9 +1,18 @@
+use std::{env, fmt};
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+use std::str::FromStr;
+use std::time::Duration;
+
+use toml;
+
 use error::{ErrorKind, Result};
 use field;
 use record;
 
 /// A length of values stored in the DB.
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub enum ValuesLen {
 	/// Values have constant length.
 	Constant(usize),
@@ -40,8 +49,34 @@ impl ValuesLen {
 	}
 }
 
+/// When journal eras are synced to the disk.
+///
+/// Committed eras which were not synced survive a crash of the process, but may be lost
+/// on a crash of the machine. Collision files and the data file are synced by every
+/// journal flush before the flushed eras are removed, regardless of the policy.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum FsyncPolicy {
+	/// Leave syncing to the operating system, unless `Database::flush_wal` is called.
+	Never,
+	/// Sync the era of every commit before the commit returns.
+	EveryCommit,
+	/// Sync committed eras in the first commit after the interval since the last sync elapsed.
+	Interval(Duration),
+}
+
+/// Algorithm compressing values on commit, see `Options::compression`.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Compression {
+	/// Store values uncompressed.
+	None,
+	/// Compress values with LZ4.
+	Lz4,
+	/// Compress values with Snappy.
+	Snappy,
+}
+
 /// Database options.
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct Options {
 	/// Number of eras to keep in the journal.
 	pub journal_eras: usize,
@@ -56,6 +91,258 @@ pub struct Options {
 	pub value_len: ValuesLen,
 	/// Maximum number of collisions per prefix before moving data to its own file.
 	pub max_prefix_collisions: usize,
+	/// Record latency histograms of reads, commits and iteration.
+	/// See `Database::latency_report`.
+	pub track_latencies: bool,
+	/// Reject commits which delete or overwrite existing keys.
+	/// Suitable for archives of immutable data, e.g. block headers.
+	pub write_once: bool,
+	/// Move flushed journal eras to the `archive` directory instead of deleting them.
+	/// Archived eras are used by `Database::changeset`.
+	pub archive_journal: bool,
+	/// Size of data file blocks in bytes. Must be a power of two and a multiple of the
+	/// filesystem block size. The data file size is a multiple of it and writes to the data
+	/// file are flushed in whole blocks. Use e.g. 4096 for NVMe drives and 65536 or more
+	/// for network storage.
+	pub block_size: usize,
+	/// Number of statistics snapshots kept in the database directory, 0 disables them.
+	/// A snapshot is taken after every journal flush. See `Database::stats_history`.
+	pub stats_retention: usize,
+	/// Maximum number of collision files with their index kept in memory, 0 means no limit.
+	/// Indices of the least recently used files are dropped after journal flushes and
+	/// rebuilt from the file when it is read or modified again, so the limit may be exceeded
+	/// until the next flush.
+	pub max_resident_collisions: usize,
+	/// When journal eras are synced to the disk. See `FsyncPolicy`.
+	pub fsync: FsyncPolicy,
+	/// Number of most recent commits kept in the journal archive, 0 keeps all of them.
+	/// Older archived eras are removed by every flush, which never runs while a checkpoint is
+	/// unfinished. Checkpoints and clones keep the eras they linked or copied at their start.
+	/// Followers which fell further behind can't catch up with journal segments, see
+	/// `Database::oldest_replayable_seq`. Only used with `archive_journal`.
+	pub archive_retention: u64,
+	/// Number of threads encoding values of large commits with value transforms, e.g. compressing
+	/// them. Commits smaller than 1 MiB, and all commits with 0 or 1 thread, are encoded by the
+	/// committing thread. See `Database::set_value_transforms`. The workers are named
+	/// `paritydb-encode-<index>` and spawned with `Database::set_thread_spawner`.
+	pub encode_threads: usize,
+	/// Maximum number of bytes of values read from the data file and collision files kept in
+	/// memory, 0 disables the cache. See `Database::cache_stats`.
+	pub cache_size: usize,
+	/// Algorithm compressing values before they are journaled and written to the data file
+	/// and collision files. Values which don't get smaller are stored uncompressed.
+	/// Compressed values record their algorithm, so switching between the algorithms, and to
+	/// `Compression::None`, keeps all values readable. Values of a database which stored them
+	/// with `Compression::None` have no room for the algorithm, so opening it with compression
+	/// fails, see `Database::export`. Like value transforms, it requires variable length values and is
+	/// applied before the transforms set with `Database::set_value_transforms`. Columns may
+	/// be compressed with other algorithms, see `Database::set_column_compression`.
+	pub compression: Compression,
+	/// Number of most recent idempotency keys the database remembers, see
+	/// `Transaction::set_idempotency_key`. Commits with a key fail if it's 0.
+	pub idempotency_window: usize,
+}
+
+impl Options {
+	/// Names of all options which can be set with `Options::set`.
+	pub const NAMES: &'static [&'static str] = &[
+		"journal_eras",
+		"extend_threshold_percent",
+		"key_index_bits",
+		"key_len",
+		"value_len",
+		"max_prefix_collisions",
+		"track_latencies",
+		"write_once",
+		"archive_journal",
+		"block_size",
+		"stats_retention",
+		"max_resident_collisions",
+		"fsync",
+		"archive_retention",
+		"encode_threads",
+		"cache_size",
+		"compression",
+		"idempotency_window",
+	];
+
+	/// Sets the option called `name` from its string representation.
+	///
+	/// `value_len` is written as `constant:<len>` (or just `<len>`) and `variable:<expected len>`,
+	/// `fsync` as `never`, `every_commit` or `interval:<milliseconds>` and `compression` as `none`,
+	/// `lz4` or `snappy`.
+	pub fn set(&mut self, name: &str, value: &str) -> Result<()> {
+		match name {
+			"journal_eras" => self.journal_eras = parse_value("journal_eras", value)?,
+			"extend_threshold_percent" => self.extend_threshold_percent = parse_value("extend_threshold_percent", value)?,
+			"key_index_bits" => self.key_index_bits = parse_value("key_index_bits", value)?,
+			"key_len" => self.key_len = parse_value("key_len", value)?,
+			"value_len" => self.value_len = parse_values_len(value)?,
+			"max_prefix_collisions" => self.max_prefix_collisions = parse_value("max_prefix_collisions", value)?,
+			"track_latencies" => self.track_latencies = parse_value("track_latencies", value)?,
+			"write_once" => self.write_once = parse_value("write_once", value)?,
+			"archive_journal" => self.archive_journal = parse_value("archive_journal", value)?,
+			"block_size" => self.block_size = parse_value("block_size", value)?,
+			"stats_retention" => self.stats_retention = parse_value("stats_retention", value)?,
+			"max_resident_collisions" => self.max_resident_collisions = parse_value("max_resident_collisions", value)?,
+			"fsync" => self.fsync = parse_fsync(value)?,
+			"archive_retention" => self.archive_retention = parse_value("archive_retention", value)?,
+			"encode_threads" => self.encode_threads = parse_value("encode_threads", value)?,
+			"cache_size" => self.cache_size = parse_value("cache_size", value)?,
+			"compression" => self.compression = parse_compression(value)?,
+			"idempotency_window" => self.idempotency_window = parse_value("idempotency_window", value)?,
+			_ => bail!(ErrorKind::UnknownOption(name.into())),
+		}
+
+		Ok(())
+	}
+
+	/// Returns string representation of the option called `name`.
+	///
+	/// The representation is accepted by `Options::set`.
+	pub fn get(&self, name: &str) -> Result<String> {
+		let value = match name {
+			"journal_eras" => self.journal_eras.to_string(),
+			"extend_threshold_percent" => self.extend_threshold_percent.to_string(),
+			"key_index_bits" => self.key_index_bits.to_string(),
+			"key_len" => self.key_len.to_string(),
+			"value_len" => match self.value_len {
+				ValuesLen::Constant(len) => format!("constant:{}", len),
+				ValuesLen::Variable { expected } => format!("variable:{}", expected),
+			},
+			"max_prefix_collisions" => self.max_prefix_collisions.to_string(),
+			"track_latencies" => self.track_latencies.to_string(),
+			"write_once" => self.write_once.to_string(),
+			"archive_journal" => self.archive_journal.to_string(),
+			"block_size" => self.block_size.to_string(),
+			"stats_retention" => self.stats_retention.to_string(),
+			"max_resident_collisions" => self.max_resident_collisions.to_string(),
+			"fsync" => match self.fsync {
+				FsyncPolicy::Never => "never".into(),
+				FsyncPolicy::EveryCommit => "every_commit".into(),
+				FsyncPolicy::Interval(interval) => {
+					let millis = interval.as_secs() * 1000 + (interval.subsec_nanos() / 1_000_000) as u64;
+					format!("interval:{}", millis)
+				},
+			},
+			"archive_retention" => self.archive_retention.to_string(),
+			"encode_threads" => self.encode_threads.to_string(),
+			"cache_size" => self.cache_size.to_string(),
+			"compression" => match self.compression {
+				Compression::None => "none".into(),
+				Compression::Lz4 => "lz4".into(),
+				Compression::Snappy => "snappy".into(),
+			},
+			"idempotency_window" => self.idempotency_window.to_string(),
+			_ => bail!(ErrorKind::UnknownOption(name.into())),
+		};
+
+		Ok(value)
+	}
+
+	/// Creates options from environment variables.
+	///
+	/// Each option is read from variable `<prefix>_<NAME>`, e.g. `PARITYDB_JOURNAL_ERAS`
+	/// for `prefix` equal to `PARITYDB`. Missing variables are left at their default values.
+	pub fn from_env(prefix: &str) -> Result<Self> {
+		let mut options = Options::default();
+
+		for &name in Self::NAMES {
+			let variable = format!("{}_{}", prefix, name.to_uppercase());
+			match env::var(&variable) {
+				Ok(value) => options.set(name, &value)?,
+				Err(env::VarError::NotPresent) => {},
+				Err(env::VarError::NotUnicode(_)) => bail!(ErrorKind::InvalidOptions(
+					name,
+					format!("environment variable {} is not valid unicode", variable)
+				)),
+			}
+		}
+
+		Ok(options)
+	}
+
+	/// Creates options from a TOML file.
+	///
+	/// The file is a flat table with keys named as the fields of `Options`.
+	/// Missing keys are left at their default values.
+	///
+	/// ```toml
+	/// journal_eras = 10
+	/// key_index_bits = 16
+	/// value_len = "variable:128"
+	/// ```
+	pub fn from_toml<P: AsRef<Path>>(path: P) -> Result<Self> {
+		let path = path.as_ref();
+		let mut contents = String::new();
+		File::open(path)?.read_to_string(&mut contents)?;
+
+		let config = contents.parse::<toml::Value>()
+			.map_err(|err| ErrorKind::InvalidOptionsFile(path.into(), err.to_string()))?;
+		let table = config.as_table()
+			.ok_or_else(|| ErrorKind::InvalidOptionsFile(path.into(), "expected a table".into()))?;
+
+		let mut options = Options::default();
+		for (name, value) in table {
+			let value = match *value {
+				toml::Value::String(ref value) => value.clone(),
+				toml::Value::Integer(value) => value.to_string(),
+				toml::Value::Boolean(value) => value.to_string(),
+				ref other => bail!(ErrorKind::InvalidOptionsFile(
+					path.into(),
+					format!("unsupported value of `{}`: {}", name, other)
+				)),
+			};
+
+			options.set(name, &value)?;
+		}
+
+		Ok(options)
+	}
+}
+
+fn parse_value<T>(field: &'static str, value: &str) -> Result<T> where T: FromStr, T::Err: fmt::Display {
+	value.trim().parse().map_err(|err: T::Err| ErrorKind::InvalidOptions(field, err.to_string()).into())
+}
+
+fn parse_values_len(value: &str) -> Result<ValuesLen> {
+	let value = value.trim();
+	let mut parts = value.splitn(2, ':');
+	match (parts.next(), parts.next()) {
+		(Some("constant"), Some(len)) => Ok(ValuesLen::Constant(parse_value("value_len", len)?)),
+		(Some("variable"), Some(expected)) => Ok(ValuesLen::Variable { expected: parse_value("value_len", expected)? }),
+		(Some(len), None) => Ok(ValuesLen::Constant(parse_value("value_len", len)?)),
+		_ => bail!(ErrorKind::InvalidOptions(
+			"value_len",
+			format!("{} is neither `constant:<len>` nor `variable:<expected len>`", value)
+		)),
+	}
+}
+
+fn parse_fsync(value: &str) -> Result<FsyncPolicy> {
+	let value = value.trim();
+	let mut parts = value.splitn(2, ':');
+	match (parts.next(), parts.next()) {
+		(Some("never"), None) => Ok(FsyncPolicy::Never),
+		(Some("every_commit"), None) => Ok(FsyncPolicy::EveryCommit),
+		(Some("interval"), Some(millis)) => Ok(FsyncPolicy::Interval(Duration::from_millis(parse_value("fsync", millis)?))),
+		_ => bail!(ErrorKind::InvalidOptions(
+			"fsync",
+			format!("{} is neither `never`, `every_commit` nor `interval:<milliseconds>`", value)
+		)),
+	}
+}
+
+fn parse_compression(value: &str) -> Result<Compression> {
+	match value.trim() {
+		"none" => Ok(Compression::None),
+		"lz4" => Ok(Compression::Lz4),
+		"snappy" => Ok(Compression::Snappy),
+		other => bail!(ErrorKind::InvalidOptions(
+			"compression",
+			format!("{} is neither `none`, `lz4` nor `snappy`", other)
+		)),
+	}
 }
 
 impl Default for Options {
@@ -67,6 +354,18 @@ impl Default for Options {
 			key_len: 32,
 			value_len: ValuesLen::Constant(64),
 			max_prefix_collisions: 6,
+			track_latencies: false,
+			write_once: false,
+			archive_journal: false,
+			block_size: 4096,
+			stats_retention: 0,
+			max_resident_collisions: 0,
+			fsync: FsyncPolicy::Never,
+			archive_retention: 0,
+			encode_threads: 1,
+			cache_size: 0,
+			compression: Compression::None,
+			idempotency_window: 1024,
 		}
 	}
 }
@@ -81,6 +380,9 @@ pub struct InternalOptions {
 }
 
 impl InternalOptions {
+	/// Smallest supported block size, the sector size of most disks.
+	const MIN_BLOCK_SIZE: usize = 512;
+
 	pub fn from_external(external: Options) -> Result<Self> {
 		if external.extend_threshold_percent > 100 || external.extend_threshold_percent == 0 {
 			bail!(ErrorKind::InvalidOptions(
@@ -116,11 +418,28 @@ impl InternalOptions {
 			));
 		}
 
+		if external.block_size < Self::MIN_BLOCK_SIZE || !external.block_size.is_power_of_two() {
+			bail!(ErrorKind::InvalidOptions(
+				"block_size",
+				format!("{} is not a power of two greater or equal to {}", external.block_size, Self::MIN_BLOCK_SIZE)
+			));
+		}
+
+		if external.compression != Compression::None && external.value_len.is_const() {
+			bail!(ErrorKind::InvalidOptions(
+				"compression",
+				"values of constant length cannot be compressed".into()
+			));
+		}
+
 		let value_size = external.value_len.to_value_size();
 		let field_body_size = external.key_len + external.value_len.size();
 		let record_offset = field::field_size(field_body_size as usize);
 		// +1 for last record with prefix 0xffff....
 		let initial_db_size = (2u64 << external.key_index_bits + 1) * record_offset as u64;
+		// round up to whole blocks
+		let block_size = external.block_size as u64;
+		let initial_db_size = (initial_db_size + block_size - 1) / block_size * block_size;
 
 		Ok(InternalOptions {
 			external,
@@ -134,11 +453,119 @@ impl InternalOptions {
 
 #[cfg(test)]
 mod tests {
-	use super::ValuesLen;
+	extern crate tempdir;
+
+	use std::env;
+	use std::fs::File;
+	use std::io::Write;
+	use error::ErrorKind;
+	use std::time::Duration;
+	use super::{Compression, FsyncPolicy, InternalOptions, Options, ValuesLen};
 
 	#[test]
 	fn test_values_len_const() {
 		assert_eq!(true, ValuesLen::Constant(1).is_const());
 		assert_eq!(false, ValuesLen::Variable { expected: 5 }.is_const());
 	}
+
+	#[test]
+	fn test_set_option() {
+		let mut options = Options::default();
+		options.set("journal_eras", "10").unwrap();
+		options.set("value_len", "variable:128").unwrap();
+		options.set("track_latencies", "true").unwrap();
+
+		assert_eq!(options.journal_eras, 10);
+		assert_eq!(options.value_len, ValuesLen::Variable { expected: 128 });
+		assert_eq!(options.track_latencies, true);
+
+		options.set("value_len", "32").unwrap();
+		assert_eq!(options.value_len, ValuesLen::Constant(32));
+
+		for &name in Options::NAMES {
+			let value = options.get(name).unwrap();
+			let mut copy = Options::default();
+			copy.set(name, &value).unwrap();
+			assert_eq!(copy.get(name).unwrap(), value);
+		}
+
+		assert_eq!(*options.set("no_such_option", "1").unwrap_err().kind(), ErrorKind::UnknownOption("no_such_option".into()));
+		assert!(options.set("key_len", "abc").is_err());
+		assert!(options.set("value_len", "sometimes:5").is_err());
+	}
+
+	#[test]
+	fn test_set_fsync() {
+		let mut options = Options::default();
+		options.set("fsync", "interval:1500").unwrap();
+		assert_eq!(options.fsync, FsyncPolicy::Interval(Duration::from_millis(1500)));
+		assert_eq!(options.get("fsync").unwrap(), "interval:1500");
+
+		options.set("fsync", "every_commit").unwrap();
+		assert_eq!(options.fsync, FsyncPolicy::EveryCommit);
+		options.set("fsync", "never").unwrap();
+		assert_eq!(options.fsync, FsyncPolicy::Never);
+
+		assert!(options.set("fsync", "interval").is_err());
+		assert!(options.set("fsync", "always").is_err());
+	}
+
+	#[test]
+	fn test_compression_option() {
+		let mut options = Options::default();
+		options.set("compression", "snappy").unwrap();
+		assert_eq!(options.compression, Compression::Snappy);
+		assert_eq!(options.get("compression").unwrap(), "snappy");
+		assert!(options.set("compression", "zstd").is_err());
+
+		// default values are of constant length
+		assert!(InternalOptions::from_external(options.clone()).is_err());
+		options.value_len = ValuesLen::Variable { expected: 32 };
+		InternalOptions::from_external(options).unwrap();
+	}
+
+	#[test]
+	fn test_options_from_env() {
+		env::set_var("TEST_OPTIONS_FROM_ENV_KEY_LEN", "20");
+		env::set_var("TEST_OPTIONS_FROM_ENV_MAX_PREFIX_COLLISIONS", "3");
+
+		let options = Options::from_env("TEST_OPTIONS_FROM_ENV").unwrap();
+		assert_eq!(options, Options {
+			key_len: 20,
+			max_prefix_collisions: 3,
+			..Default::default()
+		});
+	}
+
+	#[test]
+	fn test_options_from_toml() {
+		let temp = tempdir::TempDir::new("test_options_from_toml").unwrap();
+		let path = temp.path().join("options.toml");
+		let mut file = File::create(&path).unwrap();
+		file.write_all(b"journal_eras = 2\nkey_index_bits = 12\nvalue_len = \"variable:64\"\n").unwrap();
+		file.flush().unwrap();
+
+		let options = Options::from_toml(&path).unwrap();
+		assert_eq!(options, Options {
+			journal_eras: 2,
+			key_index_bits: 12,
+			value_len: ValuesLen::Variable { expected: 64 },
+			..Default::default()
+		});
+	}
+
+	#[test]
+	fn test_block_size() {
+		let options = |block_size| Options {
+			block_size,
+			..Default::default()
+		};
+
+		assert!(InternalOptions::from_external(options(256)).is_err());
+		assert!(InternalOptions::from_external(options(5000)).is_err());
+
+		let internal = InternalOptions::from_external(options(65536)).unwrap();
+		assert_eq!(internal.initial_db_size % 65536, 0);
+		assert!(internal.initial_db_size >= InternalOptions::from_external(options(512)).unwrap().initial_db_size);
+	}
 }
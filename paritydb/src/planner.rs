@@ -0,0 +1,198 @@
+//! Workload sizing estimates.
+//!
+//! Helps to choose `Options` before creating a large database. All numbers
+//! are expected values. They assume uniformly distributed keys (e.g. hashes).
+
+use std::time::Duration;
+
+use error::Result;
+use field;
+use metadata;
+use options::{InternalOptions, Options, ValuesLen};
+use record;
+
+/// Sequential read throughput assumed when estimating open time.
+const SCAN_BYTES_PER_SECOND: u64 = 500 * 1024 * 1024;
+/// Approximate memory used by a single entry of the in-memory collision index.
+const INDEX_ENTRY_BYTES: u64 = 64;
+/// Approximate memory used by a single journaled operation in the journal cache.
+const JOURNAL_CACHE_ENTRY_BYTES: u64 = 64;
+/// Size of a journaled operation excluding key and value.
+const JOURNAL_OPERATION_OVERHEAD: u64 = 9;
+/// Size of journal era checksum.
+const JOURNAL_CHECKSUM_SIZE: u64 = 32;
+/// Size of collision log entry excluding key and value.
+const COLLISION_ENTRY_OVERHEAD: u64 = 8;
+
+/// Expected database workload.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Workload {
+	/// Expected number of keys stored in the database.
+	pub keys: u64,
+	/// Average length of values. Ignored if values have constant length.
+	pub average_value_len: usize,
+	/// Number of commits per second.
+	pub commits_per_second: f64,
+	/// Average number of operations in a single commit.
+	pub operations_per_commit: u64,
+}
+
+/// Predicted resource usage of a database.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Estimate {
+	/// Size of the data file.
+	pub data_file_bytes: u64,
+	/// Percentage of the data file occupied by records.
+	pub data_file_occupancy_percent: f64,
+	/// Size of the metadata file.
+	pub metadata_bytes: u64,
+	/// Expected number of collided prefixes, i.e. collision files after compaction.
+	pub collision_files: u64,
+	/// Expected number of keys stored in collision files.
+	pub collision_keys: u64,
+	/// Total size of collision files.
+	pub collision_bytes: u64,
+	/// Size of journal eras kept on disk.
+	pub journal_bytes: u64,
+	/// Number of bytes written to the journal per second.
+	pub journal_write_bytes_per_second: u64,
+	/// Memory used by collision indices and journal caches.
+	pub index_memory_bytes: u64,
+	/// Time it takes to open the database (collision logs and journal are replayed).
+	pub open_time: Duration,
+}
+
+/// Probability that a Poisson distributed variable with mean `lambda` is at least `k`.
+fn poisson_tail(lambda: f64, k: usize) -> f64 {
+	let mut term = (-lambda).exp();
+	let mut cdf = 0.0;
+	for i in 0..k {
+		cdf += term;
+		term *= lambda / (i + 1) as f64;
+	}
+
+	(1.0 - cdf).max(0.0).min(1.0)
+}
+
+/// Estimates resource usage of a database created with `options` under given `workload`.
+pub fn estimate(options: Options, workload: Workload) -> Result<Estimate> {
+	let options = InternalOptions::from_external(options)?;
+
+	let key_len = options.external.key_len as u64;
+	let value_len = match options.external.value_len {
+		ValuesLen::Constant(len) => len as u64,
+		ValuesLen::Variable { .. } => workload.average_value_len as u64,
+	};
+	let record_body_len = match options.external.value_len {
+		ValuesLen::Constant(_) => key_len + value_len,
+		ValuesLen::Variable { .. } => key_len + record::HEADER_SIZE as u64 + value_len,
+	};
+
+	let field_body_size = options.field_body_size as u64;
+	let fields_per_record = (record_body_len + field_body_size - 1) / field_body_size;
+	let record_bytes = fields_per_record * field::field_size(options.field_body_size) as u64;
+
+	// keys of prefixes with more than `max_prefix_collisions` records are moved to collision files
+	let prefixes = 1u64 << options.external.key_index_bits;
+	let lambda = workload.keys as f64 / prefixes as f64;
+	let max_collisions = options.external.max_prefix_collisions;
+	let collision_files = (prefixes as f64 * poisson_tail(lambda, max_collisions)).round() as u64;
+	// E[X; X >= k] = lambda * P(X >= k - 1) for Poisson distributed X
+	let collision_keys = (workload.keys as f64 * poisson_tail(lambda, max_collisions - 1)).round() as u64;
+	let collision_bytes = collision_keys * (COLLISION_ENTRY_OVERHEAD + key_len + value_len);
+
+	let data_keys = workload.keys - collision_keys.min(workload.keys);
+	let data_bytes = data_keys * record_bytes;
+	let data_file_bytes = options.initial_db_size.max(data_bytes);
+	let data_file_occupancy_percent = 100.0 * data_bytes as f64 / data_file_bytes as f64;
+
+	let commit_bytes = JOURNAL_CHECKSUM_SIZE +
+		workload.operations_per_commit * (JOURNAL_OPERATION_OVERHEAD + key_len + value_len);
+	// the journal holds `journal_eras` eras and the one which is about to be flushed
+	let journaled_commits = options.external.journal_eras as u64 + 1;
+	let journal_bytes = journaled_commits * commit_bytes;
+	let journal_write_bytes_per_second = (commit_bytes as f64 * workload.commits_per_second) as u64;
+
+	let index_memory_bytes = collision_keys * INDEX_ENTRY_BYTES +
+		journaled_commits * workload.operations_per_commit * JOURNAL_CACHE_ENTRY_BYTES;
+
+	let replayed_bytes = collision_bytes + journal_bytes;
+	let open_time_nanos = replayed_bytes as f64 / SCAN_BYTES_PER_SECOND as f64 * 1_000_000_000.0;
+	let open_time = Duration::new(
+		(open_time_nanos / 1_000_000_000.0) as u64,
+		(open_time_nanos % 1_000_000_000.0) as u32,
+	);
+
+	Ok(Estimate {
+		data_file_bytes,
+		data_file_occupancy_percent,
+		metadata_bytes: metadata::bytes::len(options.external.key_index_bits) as u64,
+		collision_files,
+		collision_keys,
+		collision_bytes,
+		journal_bytes,
+		journal_write_bytes_per_second,
+		index_memory_bytes,
+		open_time,
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use options::{Options, ValuesLen};
+	use super::{estimate, poisson_tail, Workload};
+
+	#[test]
+	fn test_poisson_tail() {
+		assert_eq!(poisson_tail(1.0, 0), 1.0);
+		assert!((poisson_tail(1.0, 1) - (1.0 - (-1.0f64).exp())).abs() < 1e-12);
+		assert!(poisson_tail(0.001, 6) < 1e-15);
+		assert!(poisson_tail(1000.0, 6) > 0.999);
+	}
+
+	#[test]
+	fn test_estimate() {
+		let options = Options {
+			journal_eras: 5,
+			key_len: 32,
+			key_index_bits: 16,
+			value_len: ValuesLen::Constant(64),
+			max_prefix_collisions: 6,
+			..Default::default()
+		};
+
+		let workload = Workload {
+			keys: 1 << 16,
+			average_value_len: 0,
+			commits_per_second: 1.0,
+			operations_per_commit: 1000,
+		};
+
+		let estimate = estimate(options, workload).unwrap();
+		// one key per prefix on average, so only a fraction of prefixes collide
+		assert!(estimate.collision_files > 0);
+		assert!(estimate.collision_files < 100);
+		assert!(estimate.collision_keys >= 6 * estimate.collision_files);
+		assert_eq!(estimate.journal_bytes, 6 * (32 + 1000 * (9 + 32 + 64)));
+		assert_eq!(estimate.journal_write_bytes_per_second, 32 + 1000 * (9 + 32 + 64));
+		assert!(estimate.data_file_occupancy_percent > 0.0);
+		assert!(estimate.data_file_occupancy_percent <= 100.0);
+	}
+
+	#[test]
+	fn should_validate_options() {
+		let options = Options {
+			key_index_bits: 0,
+			..Default::default()
+		};
+
+		let workload = Workload {
+			keys: 0,
+			average_value_len: 0,
+			commits_per_second: 0.0,
+			operations_per_commit: 0,
+		};
+
+		assert!(estimate(options, workload).is_err());
+	}
+}
@@ -0,0 +1,26 @@
+//! Per-read options and statistics.
+
+/// Options of a single read.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ReadOptions {
+	/// Return `ReadStats` describing the work done by the read.
+	pub collect_stats: bool,
+}
+
+/// Work done to answer a single read.
+///
+/// Reads of keys in heavily collided prefixes or fragmented
+/// parts of the data file scan many fields.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ReadStats {
+	/// The read was answered from the journal, which is cached in memory.
+	pub journal_hit: bool,
+	/// The read was answered from the value cache, see `Options::cache_size`.
+	pub cache_hit: bool,
+	/// Number of files read: the data file or a collision file.
+	pub files_touched: usize,
+	/// Number of data file fields examined.
+	pub fields_scanned: usize,
+	/// Number of bytes read from the files.
+	pub bytes_read: usize,
+}
@@ -0,0 +1,115 @@
+//! Read repair of corrupted collision log entries using a replica.
+//!
+//! When a read finds an entry of a collision log whose checksum does not match,
+//! the value is fetched with the repair source registered by the user, e.g. from
+//! a peer which holds the same data. The read returns the fetched value and the
+//! corrupted entry is queued to be rewritten in place by the next mutable
+//! operation, see `Database::apply_repairs`.
+
+use std::fmt;
+
+use parking_lot::Mutex;
+
+use error::{Error, ErrorKind, Result};
+
+/// Corrupted entry of a collision log and the value fetched for it.
+#[derive(Debug)]
+pub struct PendingRepair {
+	/// Prefix of the collision file.
+	pub prefix: u32,
+	/// Position of the corrupted entry in the log.
+	pub offset: u64,
+	/// Key of the entry.
+	pub key: Vec<u8>,
+	/// Value of the key fetched from the repair source.
+	pub value: Vec<u8>,
+}
+
+/// Repair source registered by the user and entries waiting to be rewritten.
+pub struct Repair {
+	source: Option<Box<Fn(&[u8]) -> Option<Vec<u8>> + Send + Sync>>,
+	pending: Mutex<Vec<PendingRepair>>,
+}
+
+impl Default for Repair {
+	fn default() -> Self {
+		Repair {
+			source: None,
+			pending: Mutex::new(Vec::new()),
+		}
+	}
+}
+
+impl fmt::Debug for Repair {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "Repair {{ source: {}, pending: {} }}",
+			if self.source.is_some() { "Some(..)" } else { "None" },
+			self.pending.lock().len())
+	}
+}
+
+impl Repair {
+	/// Replaces the current repair source.
+	pub fn set_source(&mut self, source: Box<Fn(&[u8]) -> Option<Vec<u8>> + Send + Sync>) {
+		self.source = Some(source);
+	}
+
+	/// Fetches the value of `key` after its lookup in the collision file of `prefix` failed
+	/// with `err`, and queues the corrupted entry to be rewritten.
+	///
+	/// Returns `err` if it is not a corrupted entry, or if there is no source or the source
+	/// doesn't know the key.
+	pub fn fetch(&self, prefix: u32, key: &[u8], err: Error) -> Result<Vec<u8>> {
+		let offset = match *err.kind() {
+			ErrorKind::Corruption(_, offset) => offset,
+			_ => return Err(err),
+		};
+
+		let value = match self.source.as_ref().and_then(|source| source(key)) {
+			Some(value) => value,
+			None => return Err(err),
+		};
+
+		self.pending.lock().push(PendingRepair {
+			prefix,
+			offset,
+			key: key.to_vec(),
+			value: value.clone(),
+		});
+
+		Ok(value)
+	}
+
+	/// Returns the queued repairs, leaving the queue empty.
+	pub fn take_pending(&self) -> Vec<PendingRepair> {
+		self.pending.lock().drain(..).collect()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::path::PathBuf;
+
+	use error::{Error, ErrorKind};
+	use super::Repair;
+
+	fn corruption(offset: u64) -> Error {
+		ErrorKind::Corruption(PathBuf::from("collision-0.log"), offset).into()
+	}
+
+	#[test]
+	fn test_repair() {
+		let mut repair = Repair::default();
+		assert!(repair.fetch(0, b"key", corruption(8)).is_err());
+
+		repair.set_source(Box::new(|key: &[u8]| if key == b"key" { Some(b"value".to_vec()) } else { None }));
+		assert_eq!(repair.fetch(0, b"key", corruption(8)).unwrap(), b"value");
+		assert!(repair.fetch(0, b"other", corruption(24)).is_err());
+		assert!(repair.fetch(0, b"key", ErrorKind::ReadOnly.into()).is_err());
+
+		let pending = repair.take_pending();
+		assert_eq!(pending.len(), 1);
+		assert_eq!((pending[0].prefix, pending[0].offset), (0, 8));
+		assert!(repair.take_pending().is_empty());
+	}
+}
@@ -0,0 +1,127 @@
+//! Ranges of prefixes sealed against further writes.
+//!
+//! Sealed ranges are stored in their own file as little-endian u32 pairs of
+//! the first and the last prefix of every range. A missing file means no
+//! prefix is sealed.
+
+use std::fs;
+use std::io::{Read, Write};
+use std::ops::Range;
+use std::path::Path;
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use error::{ErrorKind, Result};
+
+pub const SEALED_FILE: &'static str = "SEALED";
+const RANGE_SIZE: usize = 8;
+
+/// Returns true if `prefix` is in one of the `sealed` ranges.
+pub fn is_sealed(sealed: &[Range<u32>], prefix: u32) -> bool {
+	sealed.iter().any(|range| range.start <= prefix && prefix < range.end)
+}
+
+/// Adds `range` to `sealed`, merging overlapping and adjacent ranges.
+pub fn insert(sealed: &mut Vec<Range<u32>>, range: Range<u32>) {
+	if range.start >= range.end {
+		return;
+	}
+
+	sealed.push(range);
+	sealed.sort_by_key(|range| range.start);
+
+	let mut merged: Vec<Range<u32>> = Vec::with_capacity(sealed.len());
+	for range in sealed.drain(..) {
+		if let Some(last) = merged.last_mut() {
+			if range.start <= last.end {
+				if range.end > last.end {
+					last.end = range.end;
+				}
+				continue;
+			}
+		}
+		merged.push(range);
+	}
+
+	*sealed = merged;
+}
+
+/// Serializes sealed ranges.
+pub fn encode(sealed: &[Range<u32>]) -> Vec<u8> {
+	let mut data = vec![0u8; sealed.len() * RANGE_SIZE];
+	for (range, chunk) in sealed.iter().zip(data.chunks_mut(RANGE_SIZE)) {
+		LittleEndian::write_u32(&mut chunk[..4], range.start);
+		LittleEndian::write_u32(&mut chunk[4..], range.end - 1);
+	}
+	data
+}
+
+/// Reads sealed ranges from database directory `dir`.
+pub fn read_sealed<P: AsRef<Path>>(dir: P) -> Result<Vec<Range<u32>>> {
+	let path = dir.as_ref().join(SEALED_FILE);
+	if !path.exists() {
+		return Ok(Vec::new());
+	}
+
+	let mut data = Vec::new();
+	fs::File::open(&path)?.read_to_end(&mut data)?;
+	if data.len() % RANGE_SIZE != 0 {
+		bail!(ErrorKind::CorruptedSeal(path, format!("Expected a multiple of {} bytes, got {}", RANGE_SIZE, data.len())));
+	}
+
+	let mut sealed = Vec::with_capacity(data.len() / RANGE_SIZE);
+	for chunk in data.chunks(RANGE_SIZE) {
+		let first = LittleEndian::read_u32(&chunk[..4]);
+		let last = LittleEndian::read_u32(&chunk[4..]);
+		// ranges have an exclusive end in memory, so no valid range ends with the maximum prefix
+		if last < first || last == u32::max_value() {
+			bail!(ErrorKind::CorruptedSeal(path, format!("Invalid range of prefixes from {} to {}", first, last)));
+		}
+		sealed.push(first..last + 1);
+	}
+
+	Ok(sealed)
+}
+
+/// Atomically replaces sealed ranges in database directory `dir`.
+pub fn write_sealed<P: AsRef<Path>>(dir: P, sealed: &[Range<u32>]) -> Result<()> {
+	let dir = dir.as_ref();
+	let tmp_path = dir.join(SEALED_FILE).with_extension("tmp");
+
+	{
+		let mut file = fs::File::create(&tmp_path)?;
+		file.write_all(&encode(sealed))?;
+		file.sync_all()?;
+	}
+
+	fs::rename(&tmp_path, dir.join(SEALED_FILE))?;
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	extern crate tempdir;
+
+	use super::{insert, is_sealed, read_sealed, write_sealed};
+
+	#[test]
+	fn test_sealed_ranges() {
+		let temp = tempdir::TempDir::new("test_sealed_ranges").unwrap();
+		assert_eq!(read_sealed(temp.path()).unwrap(), vec![]);
+
+		let mut sealed = Vec::new();
+		insert(&mut sealed, 10..20);
+		insert(&mut sealed, 0..5);
+		insert(&mut sealed, 15..25);
+		insert(&mut sealed, 5..5);
+		assert_eq!(sealed, vec![0..5, 10..25]);
+		insert(&mut sealed, 5..10);
+		assert_eq!(sealed, vec![0..25]);
+		assert!(is_sealed(&sealed, 24));
+		assert!(!is_sealed(&sealed, 25));
+
+		insert(&mut sealed, 100..0x1_0000);
+		write_sealed(temp.path(), &sealed).unwrap();
+		assert_eq!(read_sealed(temp.path()).unwrap(), sealed);
+	}
+}
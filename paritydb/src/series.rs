@@ -0,0 +1,332 @@
+//! Append-only series of constant size values indexed by consecutive numbers.
+//!
+//! Suitable for data keyed by block number, e.g. canonical block hashes.
+//! Values are stored densely one after another, so a lookup is a single
+//! offset calculation and a range scan is a sequential read.
+//!
+//! ```text
+//!  value_len  first   len    value(first)  value(first + 1)
+//!   /         /       /       /             /
+//! |........|........|........|.............|.............|......
+//! ```
+//!
+//! The header is updated only after appended values are written to the disk,
+//! so values appended before a crash are either all visible or not at all.
+
+use std::{cmp, fs};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::slice;
+
+use byteorder::{ByteOrder, LittleEndian};
+use memmap::{Mmap, Protection};
+
+use error::{ErrorKind, Result};
+
+const HEADER_SIZE: usize = 24;
+/// Number of values for which space is allocated when the series is created.
+const INITIAL_CAPACITY: u64 = 1024;
+
+/// Append-only series of values.
+#[derive(Debug)]
+pub struct Series {
+	path: PathBuf,
+	mmap: Mmap,
+	value_len: usize,
+	first: u64,
+	len: u64,
+}
+
+impl Series {
+	/// Creates new series file at given location.
+	///
+	/// The first value pushed to the series will have number `first`.
+	pub fn create<P: AsRef<Path>>(path: P, first: u64, value_len: usize) -> Result<Self> {
+		if value_len == 0 {
+			bail!(ErrorKind::InvalidOptions("value_len", "must be greater than 0.".into()));
+		}
+
+		let path = path.as_ref();
+		{
+			let file = fs::OpenOptions::new()
+				.write(true)
+				.create_new(true)
+				.open(path)?;
+			file.set_len(HEADER_SIZE as u64 + INITIAL_CAPACITY * value_len as u64)?;
+		}
+
+		let mut mmap = Mmap::open_path(path, Protection::ReadWrite)?;
+		{
+			let header = unsafe { &mut mmap.as_mut_slice()[..HEADER_SIZE] };
+			LittleEndian::write_u64(&mut header[0..8], value_len as u64);
+			LittleEndian::write_u64(&mut header[8..16], first);
+			LittleEndian::write_u64(&mut header[16..24], 0);
+		}
+		mmap.flush()?;
+
+		Ok(Series {
+			path: path.to_owned(),
+			mmap,
+			value_len,
+			first,
+			len: 0,
+		})
+	}
+
+	/// Opens an existing series file.
+	pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+		let path = path.as_ref();
+		let mmap = Mmap::open_path(path, Protection::ReadWrite)?;
+
+		if mmap.len() < HEADER_SIZE {
+			bail!(ErrorKind::CorruptedSeries(path.into(), "File is smaller than the header".into()));
+		}
+
+		let (value_len, first, len) = {
+			let header = unsafe { &mmap.as_slice()[..HEADER_SIZE] };
+			(
+				LittleEndian::read_u64(&header[0..8]) as usize,
+				LittleEndian::read_u64(&header[8..16]),
+				LittleEndian::read_u64(&header[16..24]),
+			)
+		};
+
+		if value_len == 0 || ((mmap.len() - HEADER_SIZE) / value_len) < len as usize {
+			bail!(ErrorKind::CorruptedSeries(
+				path.into(),
+				format!("Header declares {} values of length {} in a file of {} bytes", len, value_len, mmap.len())
+			));
+		}
+
+		Ok(Series {
+			path: path.to_owned(),
+			mmap,
+			value_len,
+			first,
+			len,
+		})
+	}
+
+	/// Returns length of the values.
+	pub fn value_len(&self) -> usize {
+		self.value_len
+	}
+
+	/// Returns number of the first value.
+	pub fn first(&self) -> u64 {
+		self.first
+	}
+
+	/// Returns number which will be assigned to the next pushed value.
+	pub fn end(&self) -> u64 {
+		self.first + self.len
+	}
+
+	/// Returns number of values in the series.
+	pub fn len(&self) -> u64 {
+		self.len
+	}
+
+	/// Returns true if the series has no values.
+	pub fn is_empty(&self) -> bool {
+		self.len == 0
+	}
+
+	/// Returns the value with given `number`.
+	pub fn get(&self, number: u64) -> Option<&[u8]> {
+		if number < self.first || number >= self.end() {
+			return None;
+		}
+
+		let offset = self.offset(number);
+		Some(unsafe { &self.mmap.as_slice()[offset..offset + self.value_len] })
+	}
+
+	/// Returns an iterator over values with numbers in given `range`.
+	///
+	/// The range is clamped to the numbers present in the series.
+	pub fn iter_range(&self, range: Range<u64>) -> SeriesIterator {
+		let start = cmp_clamp(range.start, self.first, self.end());
+		let end = cmp_clamp(range.end, start, self.end());
+		let data = unsafe { &self.mmap.as_slice()[self.offset(start)..self.offset(end)] };
+
+		SeriesIterator {
+			number: start,
+			chunks: data.chunks(self.value_len),
+		}
+	}
+
+	/// Returns an iterator over all values.
+	pub fn iter(&self) -> SeriesIterator {
+		self.iter_range(self.first..self.end())
+	}
+
+	/// Appends a single value and returns its number.
+	pub fn push<V: AsRef<[u8]>>(&mut self, value: V) -> Result<u64> {
+		self.extend(&[value])
+	}
+
+	/// Appends values atomically and returns number of the first of them.
+	pub fn extend<V: AsRef<[u8]>>(&mut self, values: &[V]) -> Result<u64> {
+		let first_number = self.end();
+		if let Some(value) = values.iter().map(|v| v.as_ref()).find(|v| v.len() != self.value_len) {
+			bail!(ErrorKind::InvalidValueLen(self.value_len, value.len()));
+		}
+
+		self.reserve(values.len() as u64)?;
+
+		let start = self.offset(first_number);
+		{
+			let data = unsafe { &mut self.mmap.as_mut_slice()[start..] };
+			for (chunk, value) in data.chunks_mut(self.value_len).zip(values) {
+				chunk.copy_from_slice(value.as_ref());
+			}
+		}
+		self.mmap.flush_range(start, values.len() * self.value_len)?;
+
+		self.len += values.len() as u64;
+		self.write_len()?;
+
+		Ok(first_number)
+	}
+
+	/// Removes all values with numbers greater or equal to `number`.
+	///
+	/// Used to roll back the tip of the series, e.g. on a chain reorganization.
+	pub fn truncate_from(&mut self, number: u64) -> Result<()> {
+		if number >= self.end() {
+			return Ok(());
+		}
+
+		self.len = number.saturating_sub(self.first);
+		self.write_len()
+	}
+
+	#[inline]
+	fn offset(&self, number: u64) -> usize {
+		HEADER_SIZE + (number - self.first) as usize * self.value_len
+	}
+
+	fn capacity(&self) -> u64 {
+		((self.mmap.len() - HEADER_SIZE) / self.value_len) as u64
+	}
+
+	/// Makes sure there is space for `additional` values, growing the file twice if necessary.
+	fn reserve(&mut self, additional: u64) -> Result<()> {
+		let required = self.len + additional;
+		if required <= self.capacity() {
+			return Ok(());
+		}
+
+		let capacity = cmp::max(required, self.capacity() * 2);
+		self.mmap.flush()?;
+		{
+			let file = fs::OpenOptions::new().write(true).open(&self.path)?;
+			file.set_len(HEADER_SIZE as u64 + capacity * self.value_len as u64)?;
+		}
+		self.mmap = Mmap::open_path(&self.path, Protection::ReadWrite)?;
+
+		Ok(())
+	}
+
+	fn write_len(&mut self) -> Result<()> {
+		LittleEndian::write_u64(unsafe { &mut self.mmap.as_mut_slice()[16..24] }, self.len);
+		self.mmap.flush_range(16, 8)?;
+		Ok(())
+	}
+}
+
+#[inline]
+fn cmp_clamp(value: u64, min: u64, max: u64) -> u64 {
+	cmp::min(cmp::max(value, min), max)
+}
+
+/// Iterator over numbered values of a `Series`.
+pub struct SeriesIterator<'a> {
+	number: u64,
+	chunks: slice::Chunks<'a, u8>,
+}
+
+impl<'a> Iterator for SeriesIterator<'a> {
+	type Item = (u64, &'a [u8]);
+
+	fn next(&mut self) -> Option<Self::Item> {
+		match self.chunks.next() {
+			Some(value) => {
+				let number = self.number;
+				self.number += 1;
+				Some((number, value))
+			},
+			None => None,
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	extern crate tempdir;
+
+	use error::ErrorKind;
+	use super::Series;
+
+	#[test]
+	fn test_series() {
+		let temp = tempdir::TempDir::new("test_series").unwrap();
+		let path = temp.path().join("hashes.series");
+
+		{
+			let mut series = Series::create(&path, 10, 2).unwrap();
+			assert!(series.is_empty());
+			assert_eq!(series.push(b"aa").unwrap(), 10);
+			assert_eq!(series.extend(&[b"bb", b"cc"]).unwrap(), 11);
+			assert_eq!(*series.push(b"ddd").unwrap_err().kind(), ErrorKind::InvalidValueLen(2, 3));
+		}
+
+		let series = Series::open(&path).unwrap();
+		assert_eq!(series.len(), 3);
+		assert_eq!(series.end(), 13);
+		assert_eq!(series.get(9), None);
+		assert_eq!(series.get(11), Some(b"bb" as &[u8]));
+		assert_eq!(series.get(13), None);
+		assert_eq!(
+			series.iter_range(11..100).collect::<Vec<_>>(),
+			vec![(11, b"bb" as &[u8]), (12, b"cc" as &[u8])]
+		);
+		assert_eq!(series.iter_range(0..10).count(), 0);
+	}
+
+	#[test]
+	fn test_series_grows() {
+		let temp = tempdir::TempDir::new("test_series_grows").unwrap();
+		let path = temp.path().join("numbers.series");
+
+		let mut series = Series::create(&path, 0, 8).unwrap();
+		for i in 0..5000u64 {
+			series.push(&[i as u8; 8]).unwrap();
+		}
+
+		assert_eq!(series.len(), 5000);
+		assert_eq!(series.get(4999), Some(&[4999u64 as u8; 8] as &[u8]));
+		assert!(series.iter().all(|(number, value)| value == &[number as u8; 8]));
+	}
+
+	#[test]
+	fn test_series_truncate_from() {
+		let temp = tempdir::TempDir::new("test_series_truncate_from").unwrap();
+		let path = temp.path().join("hashes.series");
+
+		{
+			let mut series = Series::create(&path, 0, 1).unwrap();
+			series.extend(&[b"a", b"b", b"c", b"d"]).unwrap();
+			series.truncate_from(2).unwrap();
+			assert_eq!(series.get(2), None);
+			assert_eq!(series.push(b"x").unwrap(), 2);
+		}
+
+		let series = Series::open(&path).unwrap();
+		assert_eq!(
+			series.iter().collect::<Vec<_>>(),
+			vec![(0, b"a" as &[u8]), (1, b"b" as &[u8]), (2, b"x" as &[u8])]
+		);
+	}
+}
@@ -0,0 +1,417 @@
+//! Minimal HTTP access to the database for internal tooling.
+//!
+//! Available with the `server` feature. Every request has to carry
+//! `Authorization: Bearer <token>` with the token the server was bound with.
+//! Keys and values are hex encoded, lists are separated by newlines.
+//!
+//! ```text
+//! GET  /v1/get/<key>                      -> <value> or 404
+//! POST /v1/multi_get   <key>\n<key>...    -> <value or empty line>\n...
+//! GET  /v1/iter?after=<key>&limit=<n>     -> <key> <value>\n...
+//! POST /v1/commit      insert <key> <value>\ndelete <key>\n...
+//! ```
+//!
+//! The server is polled by the thread which owns the database and answers a single
+//! request per connection. A request has to be read within a few seconds, with bounded
+//! request and header lines, so a slow client stalls the database only briefly.
+
+use std::io;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::time::{Duration, Instant};
+
+use database::Database;
+use error::{ErrorKind, Result};
+
+/// Requests with larger bodies are rejected.
+const MAX_BODY_SIZE: usize = 16 << 20;
+/// Connections sending longer request or header lines are closed.
+const MAX_LINE_LEN: usize = 8 << 10;
+/// Connections sending more headers are closed.
+const MAX_HEADERS: usize = 64;
+const DEFAULT_ITER_LIMIT: usize = 100;
+/// Time to read a whole request, after which the connection is closed.
+const REQUEST_TIMEOUT_SECS: u64 = 5;
+
+struct Response {
+	status: &'static str,
+	body: Vec<u8>,
+}
+
+impl Response {
+	fn ok(body: Vec<u8>) -> Self {
+		Response { status: "200 OK", body }
+	}
+
+	fn error(status: &'static str, message: &str) -> Self {
+		Response { status, body: message.as_bytes().to_vec() }
+	}
+}
+
+fn to_hex(data: &[u8]) -> String {
+	data.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+	if hex.len() % 2 != 0 {
+		return None;
+	}
+
+	hex.as_bytes().chunks(2)
+		.map(|byte| ::std::str::from_utf8(byte).ok().and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+		.collect()
+}
+
+/// Reads from a stream until a deadline, which unlike a read timeout isn't extended by every read.
+struct DeadlineReader {
+	stream: TcpStream,
+	deadline: Instant,
+}
+
+impl Read for DeadlineReader {
+	fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+		let now = Instant::now();
+		if now >= self.deadline {
+			return Err(io::Error::new(io::ErrorKind::TimedOut, "request was not read in time"));
+		}
+
+		self.stream.set_read_timeout(Some(self.deadline - now))?;
+		self.stream.read(buf)
+	}
+}
+
+/// Reads a line of at most `MAX_LINE_LEN` bytes.
+fn read_line<R: BufRead>(reader: &mut R) -> io::Result<String> {
+	let mut line = Vec::new();
+	reader.by_ref().take(MAX_LINE_LEN as u64 + 1).read_until(b'\n', &mut line)?;
+	if line.len() > MAX_LINE_LEN {
+		return Err(io::Error::new(io::ErrorKind::InvalidData, "request line is too long"));
+	}
+
+	Ok(String::from_utf8_lossy(&line).into_owned())
+}
+
+/// Compares tokens in time independent of the position of the first difference.
+fn tokens_equal(a: &[u8], b: &[u8]) -> bool {
+	a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+/// Non-blocking HTTP server exposing reads and commits.
+#[derive(Debug)]
+pub struct HttpServer {
+	listener: TcpListener,
+	token: String,
+}
+
+impl HttpServer {
+	/// Binds the server to given address. Clients have to authenticate with `token`, which
+	/// must not be empty.
+	pub fn bind<A: ToSocketAddrs>(addr: A, token: &str) -> Result<Self> {
+		if token.is_empty() {
+			bail!(ErrorKind::InvalidOptions("token", "must not be empty".into()));
+		}
+
+		let listener = TcpListener::bind(addr)?;
+		listener.set_nonblocking(true)?;
+
+		Ok(HttpServer {
+			listener,
+			token: token.into(),
+		})
+	}
+
+	/// Returns the address the server is bound to.
+	pub fn local_addr(&self) -> Result<::std::net::SocketAddr> {
+		Ok(self.listener.local_addr()?)
+	}
+
+	/// Answers requests of all pending connections. Returns the number of answered requests.
+	///
+	/// Never waits for new connections, but reading a request of an accepted connection
+	/// may block for up to 5 seconds.
+	pub fn poll(&mut self, db: &mut Database) -> Result<usize> {
+		let mut served = 0;
+
+		loop {
+			let stream = match self.listener.accept() {
+				Ok((stream, _)) => stream,
+				Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(served),
+				Err(err) => return Err(err.into()),
+			};
+
+			stream.set_nonblocking(false)?;
+			stream.set_write_timeout(Some(Duration::from_secs(REQUEST_TIMEOUT_SECS)))?;
+
+			// a misbehaving client must not stop the server
+			if self.serve(stream, db).is_ok() {
+				served += 1;
+			}
+		}
+	}
+
+	fn serve(&self, stream: TcpStream, db: &mut Database) -> io::Result<()> {
+		let mut reader = BufReader::new(DeadlineReader {
+			stream: stream.try_clone()?,
+			deadline: Instant::now() + Duration::from_secs(REQUEST_TIMEOUT_SECS),
+		});
+
+		let request_line = read_line(&mut reader)?;
+
+		let mut authorized = false;
+		let mut content_len = 0;
+		let mut headers = 0;
+		loop {
+			let header = read_line(&mut reader)?;
+			let header = header.trim_right();
+			if header.is_empty() {
+				break;
+			}
+
+			headers += 1;
+			if headers > MAX_HEADERS {
+				return Err(io::Error::new(io::ErrorKind::InvalidData, "request has too many headers"));
+			}
+
+			let mut parts = header.splitn(2, ':');
+			let name = parts.next().unwrap_or("").trim().to_lowercase();
+			let value = parts.next().unwrap_or("").trim();
+			match name.as_str() {
+				"authorization" => {
+					authorized = value.starts_with("Bearer ") &&
+						tokens_equal(value["Bearer ".len()..].as_bytes(), self.token.as_bytes());
+				},
+				"content-length" => content_len = value.parse().unwrap_or(usize::max_value()),
+				_ => {},
+			}
+		}
+
+		let response = if !authorized {
+			Response::error("401 Unauthorized", "missing or invalid token")
+		} else if content_len > MAX_BODY_SIZE {
+			Response::error("413 Payload Too Large", "request body is too large")
+		} else {
+			let mut body = vec![0u8; content_len];
+			reader.read_exact(&mut body)?;
+
+			let mut parts = request_line.split_whitespace();
+			let method = parts.next().unwrap_or("");
+			let target = parts.next().unwrap_or("");
+			match handle(db, method, target, &body) {
+				Ok(response) => response,
+				Err(err) => Response::error("500 Internal Server Error", &err.to_string()),
+			}
+		};
+
+		let mut stream = stream;
+		write!(
+			stream,
+			"HTTP/1.1 {}\r\nContent-Length: {}\r\nContent-Type: text/plain\r\nConnection: close\r\n\r\n",
+			response.status,
+			response.body.len(),
+		)?;
+		stream.write_all(&response.body)?;
+		stream.flush()
+	}
+}
+
+fn handle(db: &mut Database, method: &str, target: &str, body: &[u8]) -> Result<Response> {
+	let mut target = target.splitn(2, '?');
+	let path = target.next().unwrap_or("");
+	let query = target.next().unwrap_or("");
+	let body = String::from_utf8_lossy(body);
+
+	if method == "GET" && path.starts_with("/v1/get/") {
+		let key = match from_hex(&path["/v1/get/".len()..]) {
+			Some(key) => key,
+			None => return Ok(Response::error("400 Bad Request", "key is not valid hex")),
+		};
+
+		return match db.get(&key)? {
+			Some(value) => Ok(Response::ok(to_hex(&value.to_vec()).into_bytes())),
+			None => Ok(Response::error("404 Not Found", "")),
+		};
+	}
+
+	match (method, path) {
+		("POST", "/v1/multi_get") => {
+			let keys = match body.lines().map(from_hex).collect::<Option<Vec<_>>>() {
+				Some(keys) => keys,
+				None => return Ok(Response::error("400 Bad Request", "key is not valid hex")),
+			};
+
+			let mut response = String::new();
+			for value in db.multi_get(&keys)? {
+				if let Some(value) = value {
+					response.push_str(&to_hex(&value.to_vec()));
+				}
+				response.push('\n');
+			}
+
+			Ok(Response::ok(response.into_bytes()))
+		},
+		("GET", "/v1/iter") => {
+			let mut after = None;
+			let mut limit = DEFAULT_ITER_LIMIT;
+			for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+				let mut pair = pair.splitn(2, '=');
+				let value = match (pair.next(), pair.next()) {
+					(Some("after"), Some(value)) => from_hex(value).map(|key| after = Some(key)),
+					(Some("limit"), Some(value)) => value.parse().ok().map(|value| limit = value),
+					_ => None,
+				};
+
+				if value.is_none() {
+					return Ok(Response::error("400 Bad Request", "invalid query"));
+				}
+			}
+
+			let mut response = String::new();
+			for (key, value) in db.iter_page(after.as_ref().map(|key| &key[..]), limit)? {
+				response.push_str(&format!("{} {}\n", to_hex(&key), to_hex(&value)));
+			}
+
+			Ok(Response::ok(response.into_bytes()))
+		},
+		("POST", "/v1/commit") => {
+			let mut tx = db.create_transaction();
+			for line in body.lines().filter(|line| !line.is_empty()) {
+				let parts: Vec<_> = line.split(' ').collect();
+				let parsed = match (parts[0], parts.len()) {
+					("insert", 3) => match (from_hex(parts[1]), from_hex(parts[2])) {
+						(Some(key), Some(value)) => Some(tx.insert(key, value)),
+						_ => None,
+					},
+					("delete", 2) => from_hex(parts[1]).map(|key| tx.delete(key)),
+					_ => None,
+				};
+
+				match parsed {
+					Some(result) => result?,
+					None => return Ok(Response::error("400 Bad Request", &format!("invalid operation: {}", line))),
+				}
+			}
+
+			db.commit(&tx)?;
+			Ok(Response::ok(Vec::new()))
+		},
+		_ => Ok(Response::error("404 Not Found", "unknown endpoint")),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	extern crate tempdir;
+
+	use std::io::{Read, Write};
+	use std::net::TcpStream;
+	use std::thread;
+	use std::time::{Duration, Instant};
+
+	use database::Database;
+	use options::{Options, ValuesLen};
+	use super::{from_hex, to_hex, HttpServer, MAX_HEADERS, MAX_LINE_LEN, REQUEST_TIMEOUT_SECS};
+
+	fn request(addr: ::std::net::SocketAddr, token: &str, method: &str, target: &str, body: &str) -> String {
+		let mut stream = TcpStream::connect(addr).unwrap();
+		write!(
+			stream,
+			"{} {} HTTP/1.1\r\nAuthorization: Bearer {}\r\nContent-Length: {}\r\n\r\n{}",
+			method, target, token, body.len(), body
+		).unwrap();
+
+		let mut response = String::new();
+		stream.read_to_string(&mut response).unwrap();
+		response
+	}
+
+	#[test]
+	fn test_hex() {
+		assert_eq!(to_hex(b"\x00\xab"), "00ab");
+		assert_eq!(from_hex("00ab"), Some(b"\x00\xab".to_vec()));
+		assert_eq!(from_hex("0"), None);
+		assert_eq!(from_hex("zz"), None);
+	}
+
+	#[test]
+	fn test_http_server() {
+		let temp = tempdir::TempDir::new("test_http_server").unwrap();
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		}).unwrap();
+
+		let mut server = HttpServer::bind("127.0.0.1:0", "secret").unwrap();
+		let addr = server.local_addr().unwrap();
+
+		let client = thread::spawn(move || {
+			vec![
+				request(addr, "wrong", "GET", "/v1/get/616161", ""),
+				request(addr, "secret", "POST", "/v1/commit", "insert 616161 303031\ninsert 626262 303032\n"),
+				request(addr, "secret", "GET", "/v1/get/616161", ""),
+				request(addr, "secret", "POST", "/v1/multi_get", "626262\n636363"),
+				request(addr, "secret", "GET", "/v1/iter?after=616161&limit=10", ""),
+			]
+		});
+
+		let mut served = 0;
+		while served < 5 {
+			served += server.poll(&mut db).unwrap();
+			thread::yield_now();
+		}
+
+		let responses = client.join().unwrap();
+		assert!(responses[0].starts_with("HTTP/1.1 401"));
+		assert!(responses[1].starts_with("HTTP/1.1 200"));
+		assert!(responses[2].ends_with("\r\n\r\n303031"));
+		assert!(responses[3].ends_with("\r\n\r\n303032\n\n"));
+		assert!(responses[4].ends_with("\r\n\r\n626262 303032\n"));
+	}
+
+	#[test]
+	fn test_http_server_limits() {
+		let temp = tempdir::TempDir::new("test_http_server_limits").unwrap();
+		let mut db = Database::create(temp.path(), Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		}).unwrap();
+
+		assert!(HttpServer::bind("127.0.0.1:0", "").is_err());
+		let mut server = HttpServer::bind("127.0.0.1:0", "secret").unwrap();
+		let addr = server.local_addr().unwrap();
+
+		// an endless request line and endless headers close the connection
+		let mut long_line = TcpStream::connect(addr).unwrap();
+		long_line.write_all(&vec![b'a'; MAX_LINE_LEN + 2]).unwrap();
+		let mut many_headers = TcpStream::connect(addr).unwrap();
+		write!(many_headers, "GET /v1/get/616161 HTTP/1.1\r\n").unwrap();
+		for _ in 0..MAX_HEADERS + 1 {
+			write!(many_headers, "X-Header: 1\r\n").unwrap();
+		}
+
+		// a client trickling its request is cut off at the deadline
+		let mut slow = TcpStream::connect(addr).unwrap();
+		let trickle = thread::spawn(move || {
+			for _ in 0..REQUEST_TIMEOUT_SECS * 4 {
+				if slow.write_all(b"a").is_err() {
+					break;
+				}
+				thread::sleep(Duration::from_millis(500));
+			}
+		});
+
+		let start = Instant::now();
+		assert_eq!(server.poll(&mut db).unwrap(), 0);
+		assert!(start.elapsed() < Duration::from_secs(REQUEST_TIMEOUT_SECS + 2));
+
+		for stream in &mut [long_line, many_headers] {
+			let mut response = String::new();
+			let _ = stream.read_to_string(&mut response);
+			assert_eq!(response, "");
+		}
+		trickle.join().unwrap();
+	}
+}
@@ -0,0 +1,428 @@
+//! Key space split between several databases.
+//!
+//! Every key is owned by one shard, so the shards may live on different disks.
+//! The shard is determined by the 32 bits of the key following the
+//! `key_index_bits` the shards index their keys by. Sharding on the leading bits
+//! would leave every shard with a single range of prefixes and the rest of its
+//! index empty, so the keys would collide as if they were stored in one database.
+//!
+//! The price is that shards don't own contiguous ranges of keys, so ordered
+//! iteration merges the iterators of all shards, and every shard is searched for
+//! a prefix.
+//!
+//! A snapshot exported from a sharded database is a directory per shard. Next to
+//! the regular snapshot files, every directory holds a `SHARDS` file naming the
+//! shard and the sequence numbers of all shards at the time of the export.
+//!
+//! ```text
+//! shard <index> of <count>
+//! <sequence of shard 0> <sequence of shard 1> ...
+//! ```
+
+use std::fs;
+use std::io::Read;
+use std::iter::Peekable;
+use std::path::Path;
+
+use database::{Database, Value};
+use error::{ErrorKind, Result};
+use options::Options;
+use snapshot::{Snapshot, SnapshotWriter};
+use transaction::Transaction;
+
+const SHARDS_FILE: &'static str = "SHARDS";
+
+/// Returns the index of the shard owning `key` out of `shards` shards indexing their keys by
+/// `index_bits` bits.
+///
+/// Shards own about the same number of the values of the 32 bits following the index bits,
+/// keys too short to have all of them are read as if they were padded with zeros.
+pub fn shard_for(key: &[u8], index_bits: u8, shards: usize) -> usize {
+	let start = index_bits as usize / 8;
+	let mut head = 0u64;
+	for i in start..start + 8 {
+		head = head << 8 | key.get(i).cloned().unwrap_or(0) as u64;
+	}
+
+	// bits of the index shifted out of the top of `head` are dropped
+	let bits = (head << (index_bits % 8)) >> 32;
+	((bits * shards as u64) >> 32) as usize
+}
+
+fn validate_shards(shards: usize) -> Result<()> {
+	if shards == 0 {
+		bail!(ErrorKind::InvalidOptions("shards", "at least one shard is required".into()));
+	}
+
+	Ok(())
+}
+
+/// Database composed of several databases, each owning a part of the keys.
+#[derive(Debug)]
+pub struct ShardedDatabase {
+	shards: Vec<Database>,
+	index_bits: u8,
+}
+
+impl ShardedDatabase {
+	/// Creates a new database with one shard at every location in `paths`.
+	///
+	/// All shards are created with the same `options`.
+	pub fn create<P: AsRef<Path>>(paths: &[P], options: Options) -> Result<Self> {
+		validate_shards(paths.len())?;
+		let shards = paths.iter()
+			.map(|path| Database::create(path, options.clone()))
+			.collect::<Result<_>>()?;
+
+		Ok(ShardedDatabase {
+			shards,
+			index_bits: options.key_index_bits,
+		})
+	}
+
+	/// Opens an existing database with shards at `paths`, in the order they were created with.
+	pub fn open<P: AsRef<Path>>(paths: &[P], options: Options) -> Result<Self> {
+		validate_shards(paths.len())?;
+		let shards = paths.iter()
+			.map(|path| Database::open(path, options.clone()))
+			.collect::<Result<_>>()?;
+
+		Ok(ShardedDatabase {
+			shards,
+			index_bits: options.key_index_bits,
+		})
+	}
+
+	/// Returns the underlying databases in the order they were created with.
+	pub fn shards(&self) -> &[Database] {
+		&self.shards
+	}
+
+	/// Returns the index of the shard owning `key`.
+	pub fn shard_for<K: AsRef<[u8]>>(&self, key: K) -> usize {
+		shard_for(key.as_ref(), self.index_bits, self.shards.len())
+	}
+
+	/// Create a new transaction.
+	pub fn create_transaction(&self) -> ShardedTransaction {
+		ShardedTransaction {
+			txs: self.shards.iter().map(Database::create_transaction).collect(),
+			index_bits: self.index_bits,
+		}
+	}
+
+	/// Commits changes in the transaction to the shards owning its keys.
+	///
+	/// The transaction is validated by every shard before any of them commits, but a crash
+	/// may still leave it committed to some shards only.
+	pub fn commit(&mut self, tx: &ShardedTransaction) -> Result<()> {
+		for (shard, tx) in self.shards.iter().zip(&tx.txs) {
+			shard.validate(tx)?;
+		}
+
+		for (shard, tx) in self.shards.iter_mut().zip(&tx.txs) {
+			if tx.byte_len() != 0 {
+				shard.commit(tx)?;
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Flushes at most `max` eras of the journal of every shard.
+	pub fn flush_journal<T: Into<Option<usize>>>(&mut self, max: T) -> Result<()> {
+		let max = max.into();
+		for shard in &mut self.shards {
+			shard.flush_journal(max)?;
+		}
+
+		Ok(())
+	}
+
+	/// Returns the value for the key.
+	pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Value>> {
+		let key = key.as_ref();
+		self.shards[self.shard_for(key)].get(key)
+	}
+
+	/// Returns true if the key is in the database.
+	pub fn contains<K: AsRef<[u8]>>(&self, key: K) -> Result<bool> {
+		let key = key.as_ref();
+		self.shards[self.shard_for(key)].contains(key)
+	}
+
+	/// Returns an iterator over all key-value pairs of all shards ordered by key.
+	pub fn iter<'a>(&'a self) -> Result<Box<Iterator<Item = Result<(&'a [u8], Value<'a>)>> + 'a>> {
+		self.iter_prefix(&[])
+	}
+
+	/// Returns an iterator over the key-value pairs with keys starting with `prefix` ordered by key.
+	pub fn iter_prefix<'a>(&'a self, prefix: &'a [u8]) -> Result<Box<Iterator<Item = Result<(&'a [u8], Value<'a>)>> + 'a>> {
+		let iters = self.shards.iter()
+			.map(|shard| shard.iter_prefix(prefix))
+			.collect::<Result<Vec<_>>>()?;
+
+		Ok(Box::new(Merged::new(iters)))
+	}
+
+	/// Returns a snapshot of all shards taken at the same `commit` boundary.
+	pub fn snapshot(&self) -> ShardedSnapshot {
+		ShardedSnapshot {
+			snapshots: self.shards.iter().map(Database::snapshot).collect(),
+		}
+	}
+
+	/// Exports a snapshot of every shard into the directory at the same position in `dirs`.
+	///
+	/// Nothing can be committed while the database is borrowed, so the snapshots of all shards
+	/// are taken at the same `commit` boundary and a backup restored from them is consistent.
+	/// Check them with `verify_snapshot`.
+	pub fn export_snapshot<P: AsRef<Path>>(&self, dirs: &[P]) -> Result<()> {
+		if dirs.len() != self.shards.len() {
+			bail!(ErrorKind::InvalidOptions(
+				"shards",
+				format!("expected a directory for each of {} shards, got {}", self.shards.len(), dirs.len())
+			));
+		}
+
+		let sequences: Vec<_> = self.shards.iter().map(|shard| shard.next_sequence().to_string()).collect();
+		let sequences = sequences.join(" ");
+		for (index, (shard, dir)) in self.shards.iter().zip(dirs).enumerate() {
+			let mut snapshot = SnapshotWriter::create(dir)?;
+			shard.write_snapshot(&mut snapshot)?;
+			snapshot.write(SHARDS_FILE, format!("shard {} of {}\n{}\n", index, dirs.len(), sequences).as_bytes())?;
+			snapshot.seal()?;
+		}
+
+		Ok(())
+	}
+
+	/// Checks that `dirs` hold complete snapshots of all shards exported together by `export_snapshot`.
+	pub fn verify_snapshot<P: AsRef<Path>>(dirs: &[P]) -> Result<()> {
+		let mut exported: Option<String> = None;
+		for (index, dir) in dirs.iter().enumerate() {
+			let dir = dir.as_ref();
+			Database::verify_snapshot(dir)?;
+
+			let mut shards = String::new();
+			fs::File::open(dir.join(SHARDS_FILE))
+				.map_err(|_| ErrorKind::InvalidSnapshot(dir.into(), "Missing shards file".into()))?
+				.read_to_string(&mut shards)?;
+
+			let mut lines = shards.lines();
+			if lines.next() != Some(format!("shard {} of {}", index, dirs.len()).as_str()) {
+				bail!(ErrorKind::InvalidSnapshot(dir.into(), format!("Not shard {} of {}", index, dirs.len())));
+			}
+
+			let sequences = lines.next().unwrap_or("").to_owned();
+			if exported.as_ref().map_or(false, |exported| *exported != sequences) {
+				bail!(ErrorKind::InvalidSnapshot(dir.into(), "Shard was exported at a different time".into()));
+			}
+			exported = Some(sequences);
+		}
+
+		Ok(())
+	}
+}
+
+/// Snapshots of all shards of a `ShardedDatabase` taken at the same time.
+#[derive(Debug)]
+pub struct ShardedSnapshot {
+	snapshots: Vec<Snapshot>,
+}
+
+impl ShardedSnapshot {
+	/// Returns the sequence number of the first commit not visible in the snapshot of every shard.
+	pub fn sequences(&self) -> Vec<u64> {
+		self.snapshots.iter().map(Snapshot::sequence).collect()
+	}
+
+	/// Lookup a value associated with given `key` in database `db` the snapshot was taken of.
+	pub fn get<'a, K: AsRef<[u8]>>(&self, db: &'a ShardedDatabase, key: K) -> Result<Option<Value<'a>>> {
+		let key = key.as_ref();
+		let shard = db.shard_for(key);
+		self.snapshots[shard].get(&db.shards[shard], key)
+	}
+
+	/// Returns an iterator over the key-value pairs of database `db` the snapshot was taken of.
+	pub fn iter<'a>(&self, db: &'a ShardedDatabase) -> Result<Box<Iterator<Item = Result<(&'a [u8], Value<'a>)>> + 'a>> {
+		let iters = self.snapshots.iter().zip(&db.shards)
+			.map(|(snapshot, shard)| snapshot.iter(shard))
+			.collect::<Result<Vec<_>>>()?;
+
+		Ok(Box::new(Merged::new(iters)))
+	}
+}
+
+/// Iterator merging the ordered iterators of all shards into one ordered by key.
+struct Merged<'a> {
+	iters: Vec<Peekable<Box<Iterator<Item = Result<(&'a [u8], Value<'a>)>> + 'a>>>,
+}
+
+impl<'a> Merged<'a> {
+	fn new(iters: Vec<Box<Iterator<Item = Result<(&'a [u8], Value<'a>)>> + 'a>>) -> Self {
+		Merged {
+			iters: iters.into_iter().map(Iterator::peekable).collect(),
+		}
+	}
+}
+
+impl<'a> Iterator for Merged<'a> {
+	type Item = Result<(&'a [u8], Value<'a>)>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		// errors are returned first, shards never share keys
+		let mut next: Option<(usize, Option<&'a [u8]>)> = None;
+		for (index, iter) in self.iters.iter_mut().enumerate() {
+			let key = match iter.peek() {
+				Some(&Ok((key, _))) => Some(key),
+				Some(&Err(_)) => None,
+				None => continue,
+			};
+
+			let smaller = match next {
+				None => true,
+				Some((_, None)) => false,
+				Some((_, Some(next))) => key.map_or(true, |key| key < next),
+			};
+			if smaller {
+				next = Some((index, key));
+			}
+		}
+
+		next.and_then(|(index, _)| self.iters[index].next())
+	}
+}
+
+/// Transaction of a `ShardedDatabase`.
+pub struct ShardedTransaction {
+	txs: Vec<Transaction>,
+	index_bits: u8,
+}
+
+impl ShardedTransaction {
+	/// Append new insert operation to the list of transactions.
+	pub fn insert<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, value: V) -> Result<()> {
+		let key = key.as_ref();
+		let shard = shard_for(key, self.index_bits, self.txs.len());
+		self.txs[shard].insert(key, value)
+	}
+
+	/// Append new delete operation to the list of transactions.
+	pub fn delete<K: AsRef<[u8]>>(&mut self, key: K) -> Result<()> {
+		let key = key.as_ref();
+		let shard = shard_for(key, self.index_bits, self.txs.len());
+		self.txs[shard].delete(key)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	extern crate tempdir;
+
+	use options::{Options, ValuesLen};
+	use super::{shard_for, ShardedDatabase};
+
+	#[test]
+	fn test_shard_for() {
+		assert_eq!(shard_for(&[0, 0, 0, 0], 0, 4), 0);
+		assert_eq!(shard_for(&[0x3f, 0xff, 0xff, 0xff], 0, 4), 0);
+		assert_eq!(shard_for(&[0x40, 0, 0, 0], 0, 4), 1);
+		assert_eq!(shard_for(&[0xff, 0xff, 0xff, 0xff, 0xff], 0, 4), 3);
+		assert_eq!(shard_for(&[0xc0], 0, 4), 3);
+		assert_eq!(shard_for(&[], 0, 4), 0);
+		assert_eq!(shard_for(&[0xff, 0xff], 0, 1), 0);
+
+		// the index bits are skipped
+		assert_eq!(shard_for(&[0xff, 0x40, 0, 0, 0], 8, 4), 1);
+		assert_eq!(shard_for(&[0xff, 0xff, 0x3f, 0xff, 0xff, 0xff], 16, 4), 0);
+		assert_eq!(shard_for(&[0xff, 0x0c, 0, 0, 0], 12, 4), 3);
+		assert_eq!(shard_for(&[0xff, 0xf4, 0, 0, 0], 12, 4), 1);
+	}
+
+	#[test]
+	fn test_sharded_database() {
+		let temp = tempdir::TempDir::new("test_sharded_database").unwrap();
+		let paths = vec![temp.path().join("a"), temp.path().join("b"), temp.path().join("c")];
+		let options = Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		};
+
+		{
+			let mut db = ShardedDatabase::create(&paths, options.clone()).unwrap();
+			let mut tx = db.create_transaction();
+			tx.insert(b"\x10\x00\x00", "001").unwrap();
+			tx.insert(b"\x10\x60\x00", "002").unwrap();
+			tx.insert(b"\x60\xb0\x00", "003").unwrap();
+			tx.insert(b"\xf0\x00\x01", "004").unwrap();
+			db.commit(&tx).unwrap();
+			db.flush_journal(None).unwrap();
+
+			// an invalid transaction is committed to none of the shards
+			let mut tx = db.create_transaction();
+			tx.insert(b"\x10\x00\x01", "005").unwrap();
+			tx.insert(b"\x60\xb0\x02", "00").unwrap();
+			assert!(db.commit(&tx).is_err());
+		}
+
+		let db = ShardedDatabase::open(&paths, options).unwrap();
+		assert_eq!(db.shard_for(b"\x10\x60\x00"), 1);
+		assert_eq!(db.get(b"\x10\x60\x00").unwrap().unwrap(), "002");
+		assert!(db.shards()[1].contains(b"\x10\x60\x00").unwrap());
+		assert!(db.shards()[2].contains(b"\x60\xb0\x00").unwrap());
+		assert!(!db.contains(b"\x10\x00\x01").unwrap());
+
+		// keys of the shards are merged in order
+		let keys: Vec<_> = db.iter().unwrap().map(|item| item.unwrap().0.to_vec()).collect();
+		assert_eq!(keys, vec![b"\x10\x00\x00".to_vec(), b"\x10\x60\x00".to_vec(), b"\x60\xb0\x00".to_vec(), b"\xf0\x00\x01".to_vec()]);
+		assert_eq!(db.iter_prefix(b"\x10").unwrap().count(), 2);
+
+		assert!(ShardedDatabase::create::<&str>(&[], Default::default()).is_err());
+	}
+
+	#[test]
+	fn test_sharded_snapshot() {
+		let temp = tempdir::TempDir::new("test_sharded_snapshot").unwrap();
+		let paths = vec![temp.path().join("a"), temp.path().join("b")];
+		let backups = vec![temp.path().join("backup-a"), temp.path().join("backup-b")];
+		let options = Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		};
+
+		let mut db = ShardedDatabase::create(&paths, options.clone()).unwrap();
+		let mut tx = db.create_transaction();
+		tx.insert(b"\x10\x00\x00", "001").unwrap();
+		tx.insert(b"\xf0\x80\x00", "002").unwrap();
+		db.commit(&tx).unwrap();
+
+		let snapshot = db.snapshot();
+		db.export_snapshot(&backups).unwrap();
+		ShardedDatabase::verify_snapshot(&backups).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert(b"\x10\x00\x00", "003").unwrap();
+		tx.delete(b"\xf0\x80\x00").unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+
+		assert_eq!(snapshot.sequences(), vec![1, 1]);
+		assert_eq!(snapshot.get(&db, b"\x10\x00\x00").unwrap().unwrap(), "001");
+		assert_eq!(snapshot.iter(&db).unwrap().count(), 2);
+		assert_eq!(db.iter().unwrap().count(), 1);
+
+		let restored = ShardedDatabase::open(&backups, options).unwrap();
+		assert_eq!(restored.get(b"\xf0\x80\x00").unwrap().unwrap(), "002");
+
+		// backups of different times don't make a consistent snapshot
+		let later = vec![backups[0].clone(), temp.path().join("later-b")];
+		db.export_snapshot(&[temp.path().join("later-a"), later[1].clone()]).unwrap();
+		assert!(ShardedDatabase::verify_snapshot(&later).is_err());
+		assert!(ShardedDatabase::verify_snapshot(&[&backups[1], &backups[0]]).is_err());
+	}
+}
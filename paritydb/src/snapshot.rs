@@ -0,0 +1,260 @@
+//! Snapshots of the database.
+//!
+//! `Snapshot` is a point-in-time view of an open database, which keeps the
+//! journal eras committed after it from being flushed while it is alive.
+//! `ReadTransaction` is a view for a short scope, which borrows the database
+//! instead, so nothing can be committed while it is alive.
+//!
+//! A sealed snapshot is a directory with copies of the data, metadata, collision
+//! and journal files, sealed by a `MANIFEST` written after all of them.
+//! Another process may open the snapshot directory as a regular database
+//! and serve queries from it without talking to the writer process.
+//!
+//! The manifest is a text file. The first line holds the format version,
+//! every other line describes a single file.
+//!
+//! ```text
+//! paritydb <format version>
+//! <file name> <length> <sha3 of the file contents>
+//! ```
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use tiny_keccak::sha3_256;
+
+use database::{Database, DatabaseIterator, Value};
+use error::{ErrorKind, Result};
+use metadata::Metadata;
+
+const MANIFEST_FILE: &'static str = "MANIFEST";
+
+fn hash_hex(data: &[u8]) -> String {
+	sha3_256(data).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Number of live snapshots by the sequence number of the first commit they don't see.
+pub(crate) type SnapshotPins = Arc<Mutex<BTreeMap<u64, usize>>>;
+
+/// Frozen view of the database at the time it was taken with `Database::snapshot`.
+///
+/// Reads through the snapshot don't see commits made after it was taken, which stay
+/// in the journal until the snapshot is dropped.
+#[derive(Debug)]
+pub struct Snapshot {
+	sequence: u64,
+	pins: SnapshotPins,
+}
+
+impl Snapshot {
+	pub(crate) fn new(sequence: u64, pins: &SnapshotPins) -> Self {
+		*pins.lock().entry(sequence).or_insert(0) += 1;
+
+		Snapshot {
+			sequence,
+			pins: pins.clone(),
+		}
+	}
+
+	/// Returns sequence number of the first commit which is not visible in the snapshot.
+	pub fn sequence(&self) -> u64 {
+		self.sequence
+	}
+
+	pub(crate) fn is_pinned_by(&self, pins: &SnapshotPins) -> bool {
+		Arc::ptr_eq(&self.pins, pins)
+	}
+
+	/// Lookup a value associated with given `key` in database `db` the snapshot was taken of.
+	pub fn get<'a, K: AsRef<[u8]>>(&self, db: &'a Database, key: K) -> Result<Option<Value<'a>>> {
+		db.get_before(key.as_ref(), self)
+	}
+
+	/// Returns an iterator over the key-value pairs of database `db` the snapshot was taken of.
+	pub fn iter<'a>(&self, db: &'a Database) -> Result<DatabaseIterator<'a>> {
+		db.iter_before(self)
+	}
+}
+
+impl Drop for Snapshot {
+	fn drop(&mut self) {
+		let mut pins = self.pins.lock();
+		let released = {
+			let count = pins.get_mut(&self.sequence).expect("snapshot is pinned until dropped; qed");
+			*count -= 1;
+			*count == 0
+		};
+
+		if released {
+			pins.remove(&self.sequence);
+		}
+	}
+}
+
+/// Repeatable reads of the database, created with `Database::read_tx`.
+///
+/// The transaction borrows the database, so commits and flushes wait until it is dropped
+/// and all reads observe the same committed state without pinning any journal eras.
+#[derive(Debug)]
+pub struct ReadTransaction<'a> {
+	db: &'a Database,
+	sequence: u64,
+}
+
+impl<'a> ReadTransaction<'a> {
+	pub(crate) fn new(db: &'a Database) -> Self {
+		ReadTransaction {
+			db,
+			sequence: db.next_sequence(),
+		}
+	}
+
+	/// Returns sequence number of the first commit which is not visible in the transaction.
+	pub fn sequence(&self) -> u64 {
+		self.sequence
+	}
+
+	/// Lookup a value associated with given `key`.
+	pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Value<'a>>> {
+		self.db.get(key)
+	}
+
+	/// Returns true if the key has a value.
+	pub fn contains<K: AsRef<[u8]>>(&self, key: K) -> Result<bool> {
+		self.db.contains(key)
+	}
+
+	/// Returns an iterator over all the key-value pairs ordered by key.
+	pub fn iter(&self) -> Result<DatabaseIterator<'a>> {
+		self.db.iter()
+	}
+
+	/// Returns an iterator over the key-value pairs with keys starting with `prefix` ordered by key.
+	pub fn iter_prefix(&self, prefix: &'a [u8]) -> Result<DatabaseIterator<'a>> {
+		self.db.iter_prefix(prefix)
+	}
+}
+
+/// Copies the database files into a snapshot directory and seals them with a manifest.
+#[derive(Debug)]
+pub struct SnapshotWriter {
+	dir: PathBuf,
+	manifest: String,
+}
+
+impl SnapshotWriter {
+	/// Creates an empty snapshot directory.
+	pub fn create<P: AsRef<Path>>(dir: P) -> Result<Self> {
+		let dir = dir.as_ref();
+		if dir.join(MANIFEST_FILE).exists() {
+			bail!(ErrorKind::InvalidSnapshot(dir.into(), "Snapshot already exists".into()));
+		}
+
+		fs::create_dir_all(dir)?;
+		Ok(SnapshotWriter {
+			dir: dir.to_owned(),
+			manifest: format!("paritydb {}\n", Metadata::DB_VERSION),
+		})
+	}
+
+	/// Writes a file of the snapshot.
+	pub fn write(&mut self, name: &str, data: &[u8]) -> Result<()> {
+		let mut file = fs::File::create(self.dir.join(name))?;
+		file.write_all(data)?;
+		file.sync_all()?;
+
+		self.manifest.push_str(&format!("{} {} {}\n", name, data.len(), hash_hex(data)));
+		Ok(())
+	}
+
+	/// Copies an existing file into the snapshot.
+	pub fn copy<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+		let path = path.as_ref();
+		let name = path.file_name()
+			.and_then(|name| name.to_str())
+			.ok_or_else(|| ErrorKind::InvalidSnapshot(path.into(), "Invalid file name".into()))?
+			.to_owned();
+
+		let mut data = Vec::new();
+		fs::File::open(path)?.read_to_end(&mut data)?;
+		self.write(&name, &data)
+	}
+
+	/// Writes the manifest, after which the snapshot is complete.
+	pub fn seal(self) -> Result<()> {
+		let tmp_path = self.dir.join(MANIFEST_FILE).with_extension("tmp");
+		{
+			let mut file = fs::File::create(&tmp_path)?;
+			file.write_all(self.manifest.as_bytes())?;
+			file.sync_all()?;
+		}
+
+		fs::rename(&tmp_path, self.dir.join(MANIFEST_FILE))?;
+		Ok(())
+	}
+}
+
+/// Checks that the snapshot in `dir` is sealed, of a supported format version and that
+/// none of the files were modified.
+pub fn verify_snapshot<P: AsRef<Path>>(dir: P) -> Result<()> {
+	let dir = dir.as_ref();
+	let mut manifest = String::new();
+	fs::File::open(dir.join(MANIFEST_FILE))
+		.map_err(|_| ErrorKind::InvalidSnapshot(dir.into(), "Missing manifest".into()))?
+		.read_to_string(&mut manifest)?;
+
+	let mut lines = manifest.lines();
+	let version = lines.next()
+		.and_then(|line| line.trim_left_matches("paritydb ").parse::<u16>().ok())
+		.ok_or_else(|| ErrorKind::InvalidSnapshot(dir.into(), "Invalid manifest header".into()))?;
+	if version != Metadata::DB_VERSION {
+		bail!(ErrorKind::UnsupportedFormatVersion(version, Metadata::DB_VERSION));
+	}
+
+	for line in lines {
+		let parts: Vec<_> = line.split(' ').collect();
+		if parts.len() != 3 {
+			bail!(ErrorKind::InvalidSnapshot(dir.into(), format!("Invalid manifest line: {}", line)));
+		}
+
+		let mut data = Vec::new();
+		fs::File::open(dir.join(parts[0]))?.read_to_end(&mut data)?;
+		if data.len().to_string() != parts[1] || hash_hex(&data) != parts[2] {
+			bail!(ErrorKind::InvalidSnapshot(dir.into(), format!("File {} was modified", parts[0])));
+		}
+	}
+
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	extern crate tempdir;
+
+	use std::fs;
+	use std::io::Write;
+
+	use error::ErrorKind;
+	use super::{verify_snapshot, SnapshotWriter};
+
+	#[test]
+	fn test_snapshot_manifest() {
+		let temp = tempdir::TempDir::new("test_snapshot_manifest").unwrap();
+		let dir = temp.path().join("snapshot");
+
+		let mut writer = SnapshotWriter::create(&dir).unwrap();
+		writer.write("data.db", b"abc").unwrap();
+		assert!(matches!(*verify_snapshot(&dir).unwrap_err().kind(), ErrorKind::InvalidSnapshot(..)));
+
+		writer.seal().unwrap();
+		verify_snapshot(&dir).unwrap();
+		assert!(SnapshotWriter::create(&dir).is_err());
+
+		fs::OpenOptions::new().append(true).open(dir.join("data.db")).unwrap().write_all(b"d").unwrap();
+		assert!(matches!(*verify_snapshot(&dir).unwrap_err().kind(), ErrorKind::InvalidSnapshot(..)));
+	}
+}
@@ -0,0 +1,167 @@
+//! Threads spawned by the database besides the calling thread.
+//!
+//! Threads are named `paritydb-<role>-<index>`, e.g. `paritydb-encode-0` for the workers
+//! encoding values of large commits, see `Options::encode_threads`. Embedders with their
+//! own threading policy, e.g. pinning threads to cores or running them on a pool of their
+//! runtime, register a spawner with `Database::set_thread_spawner`.
+
+use std::{fmt, io, panic, thread};
+use std::sync::mpsc;
+
+use error::Result;
+
+/// Work passed to a `ThreadSpawner`.
+pub struct Task {
+	work: Box<FnMut() + Send>,
+}
+
+impl fmt::Debug for Task {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "Task")
+	}
+}
+
+impl Task {
+	fn new<F>(work: F) -> Self where F: FnOnce() + Send + 'static {
+		let mut work = Some(work);
+		Task {
+			work: Box::new(move || if let Some(work) = work.take() { work() }),
+		}
+	}
+
+	/// Runs the task on the current thread.
+	pub fn run(mut self) {
+		(self.work)()
+	}
+}
+
+/// Runs tasks of the database on threads, see `Database::set_thread_spawner`.
+pub trait ThreadSpawner: Send + Sync {
+	/// Runs `task` once on a thread called `name`, or on a pooled thread which may be named
+	/// differently. Returning an error fails the operation which needed the thread.
+	fn spawn(&self, name: String, task: Task) -> io::Result<()>;
+}
+
+/// Spawns a new named thread for every task, used unless another spawner is set.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct StdSpawner;
+
+impl ThreadSpawner for StdSpawner {
+	fn spawn(&self, name: String, task: Task) -> io::Result<()> {
+		thread::Builder::new().name(name).spawn(move || task.run()).map(|_| ())
+	}
+}
+
+/// Result of a task run by `Spawner::spawn`.
+#[derive(Debug)]
+pub struct Handle<T> {
+	result: mpsc::Receiver<thread::Result<T>>,
+}
+
+impl<T> Handle<T> {
+	/// Waits for the task. Returns the payload of its panic if it panicked.
+	pub fn join(self) -> thread::Result<T> {
+		match self.result.recv() {
+			Ok(result) => result,
+			Err(_) => Err(Box::new("task was dropped by the thread spawner without running")),
+		}
+	}
+}
+
+/// Thread spawner configured for a database.
+pub struct Spawner {
+	spawner: Box<ThreadSpawner>,
+}
+
+impl Default for Spawner {
+	fn default() -> Self {
+		Spawner::new(Box::new(StdSpawner))
+	}
+}
+
+impl fmt::Debug for Spawner {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "Spawner")
+	}
+}
+
+impl Spawner {
+	pub fn new(spawner: Box<ThreadSpawner>) -> Self {
+		Spawner {
+			spawner,
+		}
+	}
+
+	/// Runs `work` on the thread `index` of `role`, named `paritydb-<role>-<index>`.
+	pub fn spawn<T, F>(&self, role: &str, index: usize, work: F) -> Result<Handle<T>> where
+		T: Send + 'static,
+		F: FnOnce() -> T + Send + 'static,
+	{
+		let (sender, result) = mpsc::channel();
+		let task = Task::new(move || {
+			// the panic reaches `join` also if the spawner catches panics of its threads
+			let _ = sender.send(panic::catch_unwind(panic::AssertUnwindSafe(work)));
+		});
+
+		self.spawner.spawn(format!("paritydb-{}-{}", role, index), task)?;
+		Ok(Handle {
+			result,
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{io, thread};
+	use std::sync::{Arc, Mutex};
+
+	use super::{Spawner, Task, ThreadSpawner};
+
+	/// Runs tasks on the calling thread and records their names.
+	struct Inline(Arc<Mutex<Vec<String>>>);
+
+	impl ThreadSpawner for Inline {
+		fn spawn(&self, name: String, task: Task) -> io::Result<()> {
+			self.0.lock().unwrap().push(name);
+			task.run();
+			Ok(())
+		}
+	}
+
+	struct Failing;
+
+	impl ThreadSpawner for Failing {
+		fn spawn(&self, _name: String, _task: Task) -> io::Result<()> {
+			Err(io::Error::new(io::ErrorKind::Other, "no threads"))
+		}
+	}
+
+	#[test]
+	fn test_std_spawner() {
+		let spawner = Spawner::default();
+		let handle = spawner.spawn("encode", 2, || thread::current().name().map(str::to_owned)).unwrap();
+		assert_eq!(handle.join().unwrap(), Some("paritydb-encode-2".to_owned()));
+
+		let handle = spawner.spawn("encode", 0, || -> () { panic!("encoding failed") }).unwrap();
+		let err = handle.join().unwrap_err();
+		assert_eq!(err.downcast_ref::<&str>(), Some(&"encoding failed"));
+	}
+
+	#[test]
+	fn test_custom_spawner() {
+		let names = Arc::new(Mutex::new(Vec::new()));
+		let spawner = Spawner::new(Box::new(Inline(names.clone())));
+		let handles: Vec<_> = (0..2).map(|i| spawner.spawn("encode", i, move || i * 10).unwrap()).collect();
+		let results: Vec<_> = handles.into_iter().map(|handle| handle.join().unwrap()).collect();
+		assert_eq!(results, vec![0, 10]);
+		assert_eq!(*names.lock().unwrap(), vec!["paritydb-encode-0".to_owned(), "paritydb-encode-1".to_owned()]);
+
+		let failing = Spawner::new(Box::new(Failing));
+		assert!(failing.spawn("encode", 0, || ()).is_err());
+
+		// panics are caught before they reach the spawner
+		let inline = Spawner::new(Box::new(Inline(names)));
+		let handle = inline.spawn("encode", 0, || -> () { panic!("encoding failed") }).unwrap();
+		assert!(handle.join().is_err());
+	}
+}
@@ -0,0 +1,417 @@
+//! Database statistics and their persisted history.
+//!
+//! Snapshots are appended to a series file in the database directory,
+//! each stored as consecutive little-endian u64 fields in declaration order.
+//! Only the last `retention` snapshots are guaranteed to be kept. Histories
+//! written with fewer fields are rewritten when they are opened, the missing
+//! fields of their snapshots are 0.
+//!
+//! Counts of the stored keys are kept up to date by journal flushes and saved
+//! after every flush, also as little-endian u64 fields, preceded by the index
+//! of the first journal era they don't include. Counts saved before other eras
+//! were flushed are stale and are counted again from the stored records.
+
+use std::{cmp, fs};
+use std::io::{Read, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use error::Result;
+use series::Series;
+
+const SNAPSHOT_FIELDS: usize = 14;
+const SNAPSHOT_SIZE: usize = 8 * SNAPSHOT_FIELDS;
+
+/// Number of buckets of `KeyStatistics::value_sizes`.
+pub const VALUE_SIZE_BUCKETS: usize = 16;
+const KEY_STATISTICS_SIZE: usize = 8 * (3 + VALUE_SIZE_BUCKETS);
+
+/// Snapshot of database statistics.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct Statistics {
+	/// Seconds since the unix epoch when the snapshot was taken.
+	pub timestamp: u64,
+	/// Size of the data file.
+	pub data_file_bytes: u64,
+	/// Number of bytes occupied by records in the data file.
+	pub occupied_bytes: u64,
+	/// Number of unflushed journal eras.
+	pub journal_eras: u64,
+	/// Number of prefixes stored in collision files, each of them has its own collision file.
+	pub collided_prefixes: u64,
+	/// Number of bytes of the operations in unflushed journal eras.
+	pub journal_bytes: u64,
+	/// Number of bytes of the logs of all collision files.
+	pub collision_log_bytes: u64,
+	/// Number of bytes of collision logs taken by overwritten and deleted entries.
+	pub garbage_bytes: u64,
+	/// Number of key lookups since the database was opened.
+	pub reads: u64,
+	/// Number of inserted keys committed since the database was opened.
+	pub writes: u64,
+	/// Number of deleted keys committed since the database was opened.
+	pub deletes: u64,
+	/// Number of compactions since the database was opened.
+	pub compactions: u64,
+	/// Number of reads answered from the value cache, see `CacheStats`.
+	pub cache_hits: u64,
+	/// Number of reads of the data file or a collision file, see `CacheStats`.
+	pub cache_misses: u64,
+}
+
+impl Statistics {
+	/// Returns current time in seconds since the unix epoch.
+	pub fn now() -> u64 {
+		SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+	}
+
+	/// Returns the ratio of the data file size to the size of stored records.
+	pub fn space_amplification(&self) -> f64 {
+		if self.occupied_bytes == 0 {
+			return 0.0;
+		}
+
+		self.data_file_bytes as f64 / self.occupied_bytes as f64
+	}
+
+	/// Returns the share of reads answered from the value cache.
+	pub fn cache_hit_rate(&self) -> f64 {
+		let reads = self.cache_hits + self.cache_misses;
+		if reads == 0 {
+			return 0.0;
+		}
+
+		self.cache_hits as f64 / reads as f64
+	}
+
+	fn to_bytes(&self) -> [u8; SNAPSHOT_SIZE] {
+		let fields = [
+			self.timestamp,
+			self.data_file_bytes,
+			self.occupied_bytes,
+			self.journal_eras,
+			self.collided_prefixes,
+			self.journal_bytes,
+			self.collision_log_bytes,
+			self.garbage_bytes,
+			self.reads,
+			self.writes,
+			self.deletes,
+			self.compactions,
+			self.cache_hits,
+			self.cache_misses,
+		];
+
+		let mut data = [0u8; SNAPSHOT_SIZE];
+		for (i, field) in fields.iter().enumerate() {
+			LittleEndian::write_u64(&mut data[8 * i..], *field);
+		}
+		data
+	}
+
+	/// Reads a snapshot, fields missing in shorter snapshots of older versions are 0.
+	fn from_bytes(data: &[u8]) -> Self {
+		let field = |i: usize| if data.len() >= 8 * (i + 1) { LittleEndian::read_u64(&data[8 * i..]) } else { 0 };
+
+		Statistics {
+			timestamp: field(0),
+			data_file_bytes: field(1),
+			occupied_bytes: field(2),
+			journal_eras: field(3),
+			collided_prefixes: field(4),
+			journal_bytes: field(5),
+			collision_log_bytes: field(6),
+			garbage_bytes: field(7),
+			reads: field(8),
+			writes: field(9),
+			deletes: field(10),
+			compactions: field(11),
+			cache_hits: field(12),
+			cache_misses: field(13),
+		}
+	}
+}
+
+/// Counters of the operations since the database was opened, see `Statistics`.
+#[derive(Debug, Default)]
+pub struct Counters {
+	reads: AtomicUsize,
+	pub writes: u64,
+	pub deletes: u64,
+	pub compactions: u64,
+}
+
+impl Counters {
+	/// Counts a key lookup.
+	pub fn record_read(&self) {
+		self.reads.fetch_add(1, Ordering::Relaxed);
+	}
+
+	/// Returns the number of key lookups.
+	pub fn reads(&self) -> u64 {
+		self.reads.load(Ordering::Relaxed) as u64
+	}
+}
+
+/// Counts of the keys stored in the data file and collision files, see `Database::key_statistics`.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub struct KeyStatistics {
+	/// Number of stored keys.
+	pub keys: u64,
+	/// Number of bytes of the stored values, as they are written after value transforms.
+	pub value_bytes: u64,
+	/// Number of stored values by their length. Bucket `i` counts values shorter than `2^i`
+	/// bytes but not shorter than `2^(i - 1)`, the last bucket counts all the longer ones.
+	pub value_sizes: [u64; VALUE_SIZE_BUCKETS],
+}
+
+impl KeyStatistics {
+	const FILE: &'static str = "stats.keys";
+
+	fn bucket(len: usize) -> usize {
+		let bits = 64 - (len as u64).leading_zeros() as usize;
+		cmp::min(bits, VALUE_SIZE_BUCKETS - 1)
+	}
+
+	/// Counts a stored value of length `len`.
+	pub fn insert(&mut self, len: usize) {
+		self.keys += 1;
+		self.value_bytes += len as u64;
+		self.value_sizes[Self::bucket(len)] += 1;
+	}
+
+	/// Stops counting a stored value of length `len`.
+	pub fn remove(&mut self, len: usize) {
+		self.keys -= 1;
+		self.value_bytes -= len as u64;
+		self.value_sizes[Self::bucket(len)] -= 1;
+	}
+
+	/// Reads the counts saved in given database directory.
+	///
+	/// Returns `None` if they were not saved or don't include exactly the eras before `era`.
+	pub fn read<P: AsRef<Path>>(dir: P, era: u64) -> Result<Option<Self>> {
+		let path = dir.as_ref().join(Self::FILE);
+		if !path.exists() {
+			return Ok(None);
+		}
+
+		let mut data = Vec::new();
+		fs::File::open(&path)?.read_to_end(&mut data)?;
+		if data.len() != KEY_STATISTICS_SIZE || LittleEndian::read_u64(&data[0..8]) != era {
+			return Ok(None);
+		}
+
+		let mut stats = KeyStatistics {
+			keys: LittleEndian::read_u64(&data[8..16]),
+			value_bytes: LittleEndian::read_u64(&data[16..24]),
+			value_sizes: [0; VALUE_SIZE_BUCKETS],
+		};
+		for (i, count) in stats.value_sizes.iter_mut().enumerate() {
+			*count = LittleEndian::read_u64(&data[24 + 8 * i..]);
+		}
+
+		Ok(Some(stats))
+	}
+
+	/// Saves the counts, which include the journal eras before `era`, in given database directory.
+	pub fn write<P: AsRef<Path>>(&self, dir: P, era: u64) -> Result<()> {
+		let mut data = [0u8; KEY_STATISTICS_SIZE];
+		LittleEndian::write_u64(&mut data[0..8], era);
+		LittleEndian::write_u64(&mut data[8..16], self.keys);
+		LittleEndian::write_u64(&mut data[16..24], self.value_bytes);
+		for (i, count) in self.value_sizes.iter().enumerate() {
+			LittleEndian::write_u64(&mut data[24 + 8 * i..], *count);
+		}
+
+		let path = dir.as_ref().join(Self::FILE);
+		let tmp_path = path.with_extension("keys.tmp");
+		fs::File::create(&tmp_path)?.write_all(&data)?;
+		fs::rename(&tmp_path, &path)?;
+		Ok(())
+	}
+}
+
+/// Persisted history of statistics snapshots.
+#[derive(Debug)]
+pub struct StatsHistory {
+	path: PathBuf,
+	series: Series,
+	retention: usize,
+}
+
+impl StatsHistory {
+	const FILE: &'static str = "stats.series";
+
+	/// Opens the history in given database directory, creating it if necessary.
+	pub fn open<P: AsRef<Path>>(dir: P, retention: usize) -> Result<Self> {
+		let path = dir.as_ref().join(Self::FILE);
+		let series = if path.exists() {
+			Series::open(&path)?
+		} else {
+			Series::create(&path, 0, SNAPSHOT_SIZE)?
+		};
+
+		let mut history = StatsHistory {
+			path,
+			series,
+			retention,
+		};
+
+		if history.series.value_len() != SNAPSHOT_SIZE {
+			let first = history.series.first();
+			history.rewrite(first)?;
+		}
+
+		Ok(history)
+	}
+
+	/// Appends a snapshot.
+	///
+	/// Once the history holds twice as many snapshots as it should retain,
+	/// it is rewritten with only the most recent ones.
+	pub fn record(&mut self, stats: &Statistics) -> Result<()> {
+		self.series.push(&stats.to_bytes()[..])?;
+
+		if self.series.len() >= 2 * self.retention as u64 {
+			self.truncate()?;
+		}
+
+		Ok(())
+	}
+
+	/// Returns snapshots taken within given `range` of timestamps.
+	pub fn range(&self, range: Range<u64>) -> Vec<Statistics> {
+		self.series.iter()
+			.map(|(_, data)| Statistics::from_bytes(data))
+			.filter(|stats| stats.timestamp >= range.start && stats.timestamp < range.end)
+			.collect()
+	}
+
+	fn truncate(&mut self) -> Result<()> {
+		let first = self.series.end() - self.retention as u64;
+		self.rewrite(first)
+	}
+
+	/// Rewrites the history with the current snapshot size, keeping the snapshots from `first`.
+	fn rewrite(&mut self, first: u64) -> Result<()> {
+		let tmp_path = self.path.with_extension("tmp");
+		if tmp_path.exists() {
+			fs::remove_file(&tmp_path)?;
+		}
+
+		{
+			let values: Vec<_> = self.series.iter_range(first..self.series.end())
+				.map(|(_, data)| Statistics::from_bytes(data).to_bytes().to_vec())
+				.collect();
+			let mut rewritten = Series::create(&tmp_path, first, SNAPSHOT_SIZE)?;
+			rewritten.extend(&values)?;
+		}
+
+		fs::rename(&tmp_path, &self.path)?;
+		self.series = Series::open(&self.path)?;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	extern crate tempdir;
+
+	use byteorder::{ByteOrder, LittleEndian};
+	use series::Series;
+	use super::{KeyStatistics, Statistics, StatsHistory};
+
+	fn stats(timestamp: u64) -> Statistics {
+		Statistics {
+			timestamp,
+			data_file_bytes: 1000,
+			occupied_bytes: 250,
+			journal_eras: 2,
+			collided_prefixes: 1,
+			reads: 30,
+			cache_hits: 10,
+			cache_misses: 20,
+			..Default::default()
+		}
+	}
+
+	#[test]
+	fn test_stats_history() {
+		let temp = tempdir::TempDir::new("test_stats_history").unwrap();
+
+		{
+			let mut history = StatsHistory::open(temp.path(), 10).unwrap();
+			history.record(&stats(100)).unwrap();
+			history.record(&stats(200)).unwrap();
+			history.record(&stats(300)).unwrap();
+		}
+
+		let history = StatsHistory::open(temp.path(), 10).unwrap();
+		assert_eq!(history.range(150..1000), vec![stats(200), stats(300)]);
+		assert_eq!(stats(100).space_amplification(), 4.0);
+		assert!((stats(100).cache_hit_rate() - 1.0 / 3.0).abs() < 1e-9);
+	}
+
+	#[test]
+	fn test_stats_history_with_fewer_fields() {
+		let temp = tempdir::TempDir::new("test_stats_history_with_fewer_fields").unwrap();
+
+		// snapshots of the first five fields
+		{
+			let mut series = Series::create(temp.path().join(StatsHistory::FILE), 7, 40).unwrap();
+			let mut data = [0u8; 40];
+			for (i, field) in [100u64, 1000, 250, 2, 1].iter().enumerate() {
+				LittleEndian::write_u64(&mut data[8 * i..], *field);
+			}
+			series.push(&data[..]).unwrap();
+		}
+
+		let mut history = StatsHistory::open(temp.path(), 10).unwrap();
+		assert_eq!(history.series.first(), 7);
+		let old = Statistics { reads: 0, cache_hits: 0, cache_misses: 0, ..stats(100) };
+		assert_eq!(history.range(0..1000), vec![old.clone()]);
+
+		history.record(&stats(200)).unwrap();
+		let history = StatsHistory::open(temp.path(), 10).unwrap();
+		assert_eq!(history.range(0..1000), vec![old, stats(200)]);
+	}
+
+	#[test]
+	fn test_stats_history_retention() {
+		let temp = tempdir::TempDir::new("test_stats_history_retention").unwrap();
+
+		let mut history = StatsHistory::open(temp.path(), 3).unwrap();
+		for timestamp in 0..10 {
+			history.record(&stats(timestamp)).unwrap();
+		}
+
+		let timestamps: Vec<_> = history.range(0..10).into_iter().map(|stats| stats.timestamp).collect();
+		assert_eq!(timestamps, vec![6, 7, 8, 9]);
+	}
+
+	#[test]
+	fn test_key_statistics() {
+		let temp = tempdir::TempDir::new("test_key_statistics").unwrap();
+
+		let mut stats = KeyStatistics::default();
+		stats.insert(0);
+		stats.insert(3);
+		stats.insert(4);
+		stats.insert(1 << 20);
+		stats.remove(4);
+		assert_eq!((stats.keys, stats.value_bytes), (3, 3 + (1 << 20)));
+		assert_eq!(&stats.value_sizes[..4], &[1, 0, 1, 0]);
+		assert_eq!(stats.value_sizes[15], 1);
+
+		assert_eq!(KeyStatistics::read(temp.path(), 5).unwrap(), None);
+		stats.write(temp.path(), 5).unwrap();
+		assert_eq!(KeyStatistics::read(temp.path(), 5).unwrap(), Some(stats));
+		// counts saved before more eras were flushed are stale
+		assert_eq!(KeyStatistics::read(temp.path(), 6).unwrap(), None);
+	}
+}
@@ -1,11 +1,14 @@
 use std::cmp::Ordering;
 use byteorder::{LittleEndian, ByteOrder, WriteBytesExt};
+use database::{Database, Value};
 use error::{ErrorKind, Result};
 
 /// Database operations
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub enum Operation<'a> {
+	/// Key and value.
 	Insert(&'a [u8], &'a [u8]),
+	/// Key.
 	Delete(&'a [u8]),
 }
 
@@ -24,7 +27,10 @@ impl<'a> Ord for Operation<'a> {
 impl<'a> Operation<'a> {
 	const INSERT: u8 = 0;
 	const DELETE: u8 = 1;
+	/// Size of serialized insert excluding key and value.
+	const INSERT_OVERHEAD: usize = 9;
 
+	/// Returns the key of the operation.
 	pub fn key(&self) -> &'a [u8] {
 		match *self {
 			Operation::Insert(key, _) | Operation::Delete(key) => key,
@@ -88,6 +94,8 @@ pub struct Transaction {
 	/// we only care about key size, so it's enough info.
 	key_len: usize,
 	operations: Vec<u8>,
+	/// Key of the commit, see `set_idempotency_key`.
+	idempotency_key: Option<Vec<u8>>,
 }
 
 impl Transaction {
@@ -97,9 +105,24 @@ impl Transaction {
 		Transaction {
 			key_len: key_len,
 			operations: Vec::new(),
+			idempotency_key: None,
 		}
 	}
 
+	/// Reserves space for at least `operations` more inserts of values of `value_len` bytes.
+	///
+	/// Operations are serialized into a single buffer, so building a large transaction
+	/// with enough reserved space performs no allocations.
+	pub fn reserve(&mut self, operations: usize, value_len: usize) {
+		let operation_len = Operation::INSERT_OVERHEAD + self.key_len + value_len;
+		self.operations.reserve(operations * operation_len);
+	}
+
+	/// Returns the number of bytes the serialized operations take.
+	pub fn byte_len(&self) -> usize {
+		self.operations.len()
+	}
+
 	/// Append new insert operation to the list of transactions.
 	#[inline]
 	pub fn insert<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, value: V) -> Result<()> {
@@ -124,6 +147,38 @@ impl Transaction {
 		}
 	}
 
+	/// Sets the idempotency key of the transaction, e.g. the offset of the consumed message.
+	///
+	/// A commit with the key of one of the last `Options::idempotency_window` commits does
+	/// nothing, so a pipeline can retry a write batch after a crash without applying it twice.
+	/// The keys survive restarts, see `Database::contains_idempotency_key`.
+	pub fn set_idempotency_key<K: AsRef<[u8]>>(&mut self, key: K) {
+		self.idempotency_key = Some(key.as_ref().to_vec());
+	}
+
+	/// Returns the idempotency key of the transaction.
+	pub fn idempotency_key(&self) -> Option<&[u8]> {
+		self.idempotency_key.as_ref().map(|key| &key[..])
+	}
+
+	/// Returns the value of the key as it will be after the transaction is committed to `db`.
+	///
+	/// The last operation of the transaction on the key wins, keys which the transaction
+	/// doesn't touch are read from `db`.
+	pub fn get<'a, K: AsRef<[u8]>>(&'a self, db: &'a Database, key: K) -> Result<Option<Value<'a>>> {
+		let key = key.as_ref();
+		if key.len() != self.key_len {
+			bail!(ErrorKind::InvalidKeyLen(self.key_len, key.len()));
+		}
+
+		let pending = self.operations().filter(|operation| operation.key() == key).last();
+		match pending {
+			Some(Operation::Insert(_, value)) => Ok(Some(Value::Raw(value))),
+			Some(Operation::Delete(_)) => Ok(None),
+			None => db.get(key),
+		}
+	}
+
 	/// Returns double-ended iterator over all operations in a transaction.
 	pub fn operations(&self) -> OperationsIterator {
 		OperationsIterator {
@@ -173,7 +228,12 @@ impl<'a> Iterator for OperationsIterator<'a> {
 
 #[cfg(test)]
 mod tests {
+	extern crate tempdir;
+
 	use super::{Transaction, Operation};
+	use database::Database;
+	use error::ErrorKind;
+	use options::{Options, ValuesLen};
 	use quickcheck::TestResult;
 
 	#[test]
@@ -189,6 +249,49 @@ mod tests {
 		assert_eq!(operations.next(), None);
 	}
 
+	#[test]
+	fn test_transaction_get() {
+		let temp = tempdir::TempDir::new("test_transaction_get").unwrap();
+		let mut db = Database::create(temp.path(), Options {
+			key_len: 3,
+			value_len: ValuesLen::Constant(3),
+			..Default::default()
+		}).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("aaa", "001").unwrap();
+		tx.insert("bbb", "002").unwrap();
+		db.commit(&tx).unwrap();
+
+		let mut tx = db.create_transaction();
+		tx.insert("ccc", "003").unwrap();
+		tx.insert("ccc", "004").unwrap();
+		tx.delete("aaa").unwrap();
+		assert_eq!(tx.get(&db, "ccc").unwrap().unwrap(), "004");
+		assert!(tx.get(&db, "aaa").unwrap().is_none());
+		assert_eq!(tx.get(&db, "bbb").unwrap().unwrap(), "002");
+		assert!(tx.get(&db, "ddd").unwrap().is_none());
+		assert!(matches!(*tx.get(&db, "dd").unwrap_err().kind(), ErrorKind::InvalidKeyLen(3, 2)));
+
+		tx.insert("aaa", "005").unwrap();
+		assert_eq!(tx.get(&db, "aaa").unwrap().unwrap(), "005");
+	}
+
+	#[test]
+	fn test_transaction_reserve() {
+		let mut t = Transaction::new(3);
+		t.reserve(100, 5);
+
+		let capacity = t.operations.capacity();
+		assert!(capacity >= 100 * (9 + 3 + 5));
+		for _ in 0..100 {
+			t.insert(b"key", b"value").unwrap();
+		}
+
+		assert_eq!(t.operations.capacity(), capacity);
+		assert_eq!(t.byte_len(), 100 * (9 + 3 + 5));
+	}
+
 	#[test]
 	fn test_transaction_invalid_key_len_for_insert() {
 		let mut t = Transaction::new(4);
@@ -0,0 +1,302 @@
+//! Value transforms applied on write and reversed on read.
+//!
+//! Every value written while transforms are configured is prefixed with
+//! a single flags byte. Bit `i` of the flags is set if the `i`-th transform
+//! was applied to the value.
+//!
+//! ```text
+//!  flags  encoded value
+//!   /      /
+//! |.|..............|
+//! ```
+//!
+//! Transforms are applied in order on write and in reverse order on read.
+//! Values of large commits may be encoded by several threads, see `encode_all`.
+//!
+//! The first transform may be replaced for keys with a given prefix, e.g. to compress
+//! the values of a column with another algorithm, see `set_overrides`. Values are always
+//! decoded by the first transform, so its replacements have to be decodable by it.
+
+use std::sync::Arc;
+use std::{fmt, panic};
+
+use database::Value;
+use error::{ErrorKind, Result};
+use spawn::Spawner;
+
+/// Maximum number of transforms, limited by the number of bits in the flags byte.
+pub const MAX_TRANSFORMS: usize = 8;
+/// Values of commits with fewer bytes are encoded by a single thread.
+pub const PARALLEL_ENCODE_MIN_BYTES: usize = 1 << 20;
+
+/// A reversible encoding of values, e.g. compression or encryption.
+pub trait ValueTransform: Send + Sync {
+	/// Encodes the value.
+	///
+	/// Returns `None` if the value should be stored unchanged,
+	/// e.g. when compression does not make it any smaller.
+	fn encode(&self, value: &[u8]) -> Option<Vec<u8>>;
+
+	/// Reverses `encode`.
+	fn decode(&self, value: &[u8]) -> Result<Vec<u8>>;
+}
+
+/// Chain of transforms configured for a database.
+#[derive(Default, Clone)]
+pub struct ValueTransforms {
+	transforms: Arc<Vec<Box<ValueTransform>>>,
+	/// Replacements of the first transform by key prefix, longest prefix first.
+	overrides: Arc<Vec<(Vec<u8>, Box<ValueTransform>)>>,
+}
+
+impl fmt::Debug for ValueTransforms {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "ValueTransforms {{ len: {}, overrides: {} }}", self.transforms.len(), self.overrides.len())
+	}
+}
+
+impl ValueTransforms {
+	pub fn new(transforms: Vec<Box<ValueTransform>>) -> Result<Self> {
+		if transforms.len() > MAX_TRANSFORMS {
+			bail!(ErrorKind::InvalidOptions(
+				"value_transforms",
+				format!("at most {} transforms are supported, got {}", MAX_TRANSFORMS, transforms.len())
+			));
+		}
+
+		Ok(ValueTransforms {
+			transforms: Arc::new(transforms),
+			overrides: Arc::new(Vec::new()),
+		})
+	}
+
+	/// Replaces the first transform with the transform of the longest matching prefix when
+	/// encoding values of keys with one of the `overrides` prefixes.
+	pub fn set_overrides(&mut self, mut overrides: Vec<(Vec<u8>, Box<ValueTransform>)>) -> Result<()> {
+		if self.transforms.is_empty() && !overrides.is_empty() {
+			bail!(ErrorKind::InvalidOptions(
+				"value_transforms",
+				"the first transform can't be replaced if there are no transforms".into()
+			));
+		}
+
+		overrides.sort_by(|a, b| b.0.len().cmp(&a.0.len()));
+		self.overrides = Arc::new(overrides);
+		Ok(())
+	}
+
+	/// Returns true if the first transform is replaced for some keys.
+	pub fn has_overrides(&self) -> bool {
+		!self.overrides.is_empty()
+	}
+
+	/// Returns true if no transforms are configured and values are stored as they are.
+	pub fn is_empty(&self) -> bool {
+		self.transforms.is_empty()
+	}
+
+	/// Encodes the value of `key` and prefixes it with flags of applied transforms.
+	pub fn encode(&self, key: &[u8], value: &[u8]) -> Vec<u8> {
+		let mut flags = 0u8;
+		let mut encoded = None;
+
+		let first = self.overrides.iter().find(|&&(ref prefix, _)| key.starts_with(prefix));
+		for (i, transform) in self.transforms.iter().enumerate() {
+			let transform = match first {
+				Some(&(_, ref first)) if i == 0 => first,
+				_ => transform,
+			};
+			let result = match encoded {
+				Some(ref encoded) => transform.encode(encoded),
+				None => transform.encode(value),
+			};
+
+			if let Some(result) = result {
+				flags |= 1 << i;
+				encoded = Some(result);
+			}
+		}
+
+		let encoded = encoded.as_ref().map(Vec::as_slice).unwrap_or(value);
+		let mut envelope = Vec::with_capacity(1 + encoded.len());
+		envelope.push(flags);
+		envelope.extend_from_slice(encoded);
+		envelope
+	}
+
+	/// Encodes the values of the keys, splitting them between up to `threads` threads of `spawner`
+	/// if they take at least `PARALLEL_ENCODE_MIN_BYTES` bytes. Encoded values are returned in order.
+	///
+	/// Fails only if a thread could not be spawned.
+	pub fn encode_all(&self, values: &[(&[u8], &[u8])], threads: usize, spawner: &Spawner) -> Result<Vec<Vec<u8>>> {
+		let bytes: usize = values.iter().map(|&(_, value)| value.len()).sum();
+		if threads <= 1 || values.len() < 2 || bytes < PARALLEL_ENCODE_MIN_BYTES {
+			return Ok(values.iter().map(|&(key, value)| self.encode(key, value)).collect());
+		}
+
+		// workers can't borrow the values, so each of them gets a copy of its chunk
+		let chunk_len = (values.len() + threads - 1) / threads;
+		let mut workers = Vec::with_capacity(threads);
+		for (index, chunk) in values.chunks(chunk_len).enumerate() {
+			let chunk: Vec<(Vec<u8>, Vec<u8>)> = chunk.iter().map(|&(key, value)| (key.to_vec(), value.to_vec())).collect();
+			let transforms = self.clone();
+			workers.push(spawner.spawn("encode", index, move || {
+				chunk.iter().map(|&(ref key, ref value)| transforms.encode(key, value)).collect::<Vec<_>>()
+			})?);
+		}
+
+		let mut encoded = Vec::with_capacity(values.len());
+		for worker in workers {
+			match worker.join() {
+				Ok(chunk) => encoded.extend(chunk),
+				// a panicking transform panics on the committing thread like it would without workers
+				Err(err) => panic::resume_unwind(err),
+			}
+		}
+
+		Ok(encoded)
+	}
+
+	/// Reverses `encode`.
+	pub fn decode(&self, envelope: &[u8]) -> Result<Vec<u8>> {
+		if envelope.is_empty() {
+			bail!(ErrorKind::InvalidValueEnvelope("missing flags byte".into()));
+		}
+
+		let flags = envelope[0];
+		if self.transforms.len() < MAX_TRANSFORMS && flags >> self.transforms.len() != 0 {
+			bail!(ErrorKind::InvalidValueEnvelope(
+				format!("flags {:08b} refer to transforms which are not configured", flags)
+			));
+		}
+
+		let mut decoded = envelope[1..].to_vec();
+		for (i, transform) in self.transforms.iter().enumerate().rev() {
+			if flags & (1 << i) != 0 {
+				decoded = transform.decode(&decoded)?;
+			}
+		}
+
+		Ok(decoded)
+	}
+
+	/// Decodes the stored value of `key` and encodes it again, e.g. after the transforms of its
+	/// prefix were replaced.
+	pub fn recode(&self, key: &[u8], envelope: &[u8]) -> Result<Vec<u8>> {
+		Ok(self.encode(key, &self.decode(envelope)?))
+	}
+
+	/// Decodes a value read from the database. Returns the value unchanged if no transforms are configured.
+	pub fn decode_value<'a>(&self, value: Value<'a>) -> Result<Value<'a>> {
+		if self.is_empty() {
+			return Ok(value);
+		}
+
+		let decoded = match value.as_slice() {
+			Some(slice) => self.decode(slice)?,
+			None => self.decode(&value.to_vec())?,
+		};
+
+		Ok(Value::Owned(decoded))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use error::{ErrorKind, Result};
+	use spawn::Spawner;
+	use super::{ValueTransform, ValueTransforms, PARALLEL_ENCODE_MIN_BYTES};
+
+	struct Xor(u8);
+
+	impl ValueTransform for Xor {
+		fn encode(&self, value: &[u8]) -> Option<Vec<u8>> {
+			Some(value.iter().map(|b| b ^ self.0).collect())
+		}
+
+		fn decode(&self, value: &[u8]) -> Result<Vec<u8>> {
+			Ok(value.iter().map(|b| b ^ self.0).collect())
+		}
+	}
+
+	/// Reverses values longer than 2 bytes.
+	struct Reverse;
+
+	impl ValueTransform for Reverse {
+		fn encode(&self, value: &[u8]) -> Option<Vec<u8>> {
+			if value.len() > 2 {
+				Some(value.iter().rev().cloned().collect())
+			} else {
+				None
+			}
+		}
+
+		fn decode(&self, value: &[u8]) -> Result<Vec<u8>> {
+			Ok(value.iter().rev().cloned().collect())
+		}
+	}
+
+	#[test]
+	fn test_transforms() {
+		let transforms = ValueTransforms::new(vec![Box::new(Reverse) as Box<ValueTransform>, Box::new(Xor(1))]).unwrap();
+
+		let encoded = transforms.encode(b"key", b"abc");
+		assert_eq!(encoded, vec![0b11, b'c' ^ 1, b'b' ^ 1, b'a' ^ 1]);
+		assert_eq!(transforms.decode(&encoded).unwrap(), b"abc".to_vec());
+
+		let encoded = transforms.encode(b"key", b"ab");
+		assert_eq!(encoded, vec![0b10, b'a' ^ 1, b'b' ^ 1]);
+		assert_eq!(transforms.decode(&encoded).unwrap(), b"ab".to_vec());
+	}
+
+	#[test]
+	fn test_encode_all() {
+		let transforms = ValueTransforms::new(vec![Box::new(Reverse) as Box<ValueTransform>, Box::new(Xor(1))]).unwrap();
+
+		let values: Vec<Vec<u8>> = (0..100u32).map(|i| vec![i as u8; PARALLEL_ENCODE_MIN_BYTES / 50 + i as usize % 3]).collect();
+		let slices: Vec<(&[u8], &[u8])> = values.iter().map(|value| (&value[..1], &value[..])).collect();
+		let expected: Vec<_> = slices.iter().map(|&(key, value)| transforms.encode(key, value)).collect();
+
+		let spawner = Spawner::default();
+		assert_eq!(transforms.encode_all(&slices, 1, &spawner).unwrap(), expected);
+		assert_eq!(transforms.encode_all(&slices, 3, &spawner).unwrap(), expected);
+		assert_eq!(transforms.encode_all(&slices, 200, &spawner).unwrap(), expected);
+		assert_eq!(transforms.encode_all(&[], 4, &spawner).unwrap(), Vec::<Vec<u8>>::new());
+	}
+
+	#[test]
+	fn test_overrides() {
+		let mut transforms = ValueTransforms::new(vec![Box::new(Xor(1)) as Box<ValueTransform>, Box::new(Xor(2))]).unwrap();
+		transforms.set_overrides(vec![
+			(b"a".to_vec(), Box::new(Reverse) as Box<ValueTransform>),
+			(b"ab".to_vec(), Box::new(Xor(1))),
+		]).unwrap();
+		assert!(transforms.has_overrides());
+
+		assert_eq!(transforms.encode(b"bcd", b"abc"), vec![0b11, b'a' ^ 3, b'b' ^ 3, b'c' ^ 3]);
+		assert_eq!(transforms.encode(b"acd", b"abc"), vec![0b11, b'c' ^ 2, b'b' ^ 2, b'a' ^ 2]);
+		// the longest prefix wins
+		assert_eq!(transforms.encode(b"abd", b"abc"), transforms.encode(b"bcd", b"abc"));
+
+		let encoded = transforms.encode(b"bcd", b"abc");
+		assert_eq!(transforms.recode(b"acd", &encoded).unwrap(), transforms.encode(b"acd", b"abc"));
+
+		let mut empty = ValueTransforms::default();
+		assert!(empty.set_overrides(vec![(b"a".to_vec(), Box::new(Reverse) as Box<ValueTransform>)]).is_err());
+		empty.set_overrides(Vec::new()).unwrap();
+	}
+
+	#[test]
+	fn test_invalid_envelope() {
+		let transforms = ValueTransforms::new(vec![Box::new(Xor(1)) as Box<ValueTransform>]).unwrap();
+
+		assert!(matches!(*transforms.decode(&[]).unwrap_err().kind(), ErrorKind::InvalidValueEnvelope(_)));
+		assert!(matches!(*transforms.decode(&[0b10, 0]).unwrap_err().kind(), ErrorKind::InvalidValueEnvelope(_)));
+	}
+
+	#[test]
+	fn test_too_many_transforms() {
+		let transforms = (0..9).map(|i| Box::new(Xor(i)) as Box<ValueTransform>).collect();
+		assert!(ValueTransforms::new(transforms).is_err());
+	}
+}
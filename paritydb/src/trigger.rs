@@ -0,0 +1,97 @@
+//! Pre-commit triggers enforcing invariants of keys with a given prefix.
+//!
+//! Triggers are run by `Database::validate`, so every commit is checked after its merges
+//! are resolved and before it reaches the audit hook and the journal. A trigger is called
+//! with each operation on a key with its prefix and the value the key had before the
+//! commit, and rejects the whole commit by returning the reason.
+
+use std::fmt;
+
+use error::{ErrorKind, Result};
+use transaction::Operation;
+
+/// Callback of a trigger, see `Database::add_trigger`.
+pub type TriggerFn = Box<Fn(&Operation, Option<&[u8]>) -> ::std::result::Result<(), String> + Send + Sync>;
+
+/// Triggers registered by the user, with the key prefixes they watch.
+#[derive(Default)]
+pub struct Triggers {
+	triggers: Vec<(Vec<u8>, TriggerFn)>,
+}
+
+impl fmt::Debug for Triggers {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "Triggers {{ triggers: {} }}", self.triggers.len())
+	}
+}
+
+impl Triggers {
+	/// Adds a trigger called for the keys starting with `prefix`.
+	pub fn add(&mut self, prefix: Vec<u8>, trigger: TriggerFn) {
+		self.triggers.push((prefix, trigger));
+	}
+
+	/// Drops all triggers.
+	pub fn clear(&mut self) {
+		self.triggers.clear();
+	}
+
+	/// Returns true if there are no triggers.
+	pub fn is_empty(&self) -> bool {
+		self.triggers.is_empty()
+	}
+
+	/// Passes `operation` to the triggers watching its key.
+	///
+	/// `current` reads the value of the key before the commit, it is called only if
+	/// a trigger watches the key.
+	pub fn check<F>(&self, operation: &Operation, current: F) -> Result<()> where F: FnOnce() -> Result<Option<Vec<u8>>> {
+		let key = operation.key();
+		let mut matching = self.triggers.iter()
+			.filter(|&&(ref prefix, _)| key.starts_with(prefix))
+			.peekable();
+		if matching.peek().is_none() {
+			return Ok(());
+		}
+
+		let current = current()?;
+		for &(_, ref trigger) in matching {
+			if let Err(reason) = trigger(operation, current.as_ref().map(|value| &**value)) {
+				bail!(ErrorKind::CommitRejected(key.to_vec(), reason));
+			}
+		}
+
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use error::ErrorKind;
+	use transaction::Operation;
+	use super::Triggers;
+
+	#[test]
+	fn test_triggers() {
+		let mut triggers = Triggers::default();
+		assert!(triggers.is_empty());
+		triggers.add(b"h".to_vec(), Box::new(|operation: &Operation, current: Option<&[u8]>| match (operation, current) {
+			(&Operation::Delete(_), Some(_)) => Err("finalized".to_string()),
+			_ => Ok(()),
+		}));
+
+		// the current value is only read for watched keys
+		let unread = || -> ::error::Result<Option<Vec<u8>>> { panic!("value of an unwatched key was read") };
+		triggers.check(&Operation::Delete(b"abc"), unread).unwrap();
+
+		triggers.check(&Operation::Insert(b"hab", b"001"), || Ok(Some(b"000".to_vec()))).unwrap();
+		triggers.check(&Operation::Delete(b"hab"), || Ok(None)).unwrap();
+		assert_eq!(
+			*triggers.check(&Operation::Delete(b"hab"), || Ok(Some(b"000".to_vec()))).unwrap_err().kind(),
+			ErrorKind::CommitRejected(b"hab".to_vec(), "finalized".into())
+		);
+
+		triggers.clear();
+		triggers.check(&Operation::Delete(b"hab"), || Ok(Some(b"000".to_vec()))).unwrap();
+	}
+}
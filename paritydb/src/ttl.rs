@@ -0,0 +1,222 @@
+//! Database with entries expiring after a time to live.
+//!
+//! Every value is stored with the time it expires at, in milliseconds since
+//! the unix epoch, in front of it. 0 means the value never expires. Expired
+//! values are treated as missing on reads and are deleted by `purge_expired`.
+//!
+//! ```text
+//!  expiry   value
+//!   /        /
+//! |........|..............|
+//! ```
+
+use std::cmp;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use byteorder::{ByteOrder, LittleEndian};
+
+use database::{Database, FilterDecision};
+use error::{ErrorKind, Result};
+use options::Options;
+use transaction::Transaction;
+
+const EXPIRY_SIZE: usize = 8;
+const NEVER: u64 = 0;
+
+fn validate_options(options: &Options) -> Result<()> {
+	if options.value_len.is_const() {
+		bail!(ErrorKind::InvalidOptions(
+			"value_len",
+			"expiry is stored with the value, so values have to be of variable length".into()
+		));
+	}
+
+	Ok(())
+}
+
+/// Returns current time in milliseconds since the unix epoch.
+fn now_millis() -> u64 {
+	SystemTime::now().duration_since(UNIX_EPOCH).map(to_millis).unwrap_or(0)
+}
+
+fn to_millis(duration: Duration) -> u64 {
+	duration.as_secs().saturating_mul(1000).saturating_add((duration.subsec_nanos() / 1_000_000) as u64)
+}
+
+/// Splits a stored value into its expiry and the value.
+fn decode(stored: &[u8]) -> Result<(u64, &[u8])> {
+	if stored.len() < EXPIRY_SIZE {
+		bail!(ErrorKind::InvalidValueEnvelope("missing expiry".into()));
+	}
+
+	Ok((LittleEndian::read_u64(&stored[..EXPIRY_SIZE]), &stored[EXPIRY_SIZE..]))
+}
+
+fn is_expired(expiry: u64, now: u64) -> bool {
+	expiry != NEVER && expiry <= now
+}
+
+/// Database which stores values with an expiry time.
+#[derive(Debug)]
+pub struct TtlDatabase {
+	db: Database,
+}
+
+impl TtlDatabase {
+	/// Creates new database at given location.
+	pub fn create<P: AsRef<Path>>(path: P, options: Options) -> Result<Self> {
+		validate_options(&options)?;
+		Ok(TtlDatabase {
+			db: Database::create(path, options)?,
+		})
+	}
+
+	/// Opens an existing database at given location.
+	pub fn open<P: AsRef<Path>>(path: P, options: Options) -> Result<Self> {
+		validate_options(&options)?;
+		Ok(TtlDatabase {
+			db: Database::open(path, options)?,
+		})
+	}
+
+	/// Returns the underlying database.
+	pub fn inner(&self) -> &Database {
+		&self.db
+	}
+
+	/// Create a new transaction.
+	pub fn create_transaction(&self) -> TtlTransaction {
+		TtlTransaction {
+			tx: self.db.create_transaction(),
+		}
+	}
+
+	/// Commits changes in the transaction.
+	pub fn commit(&mut self, tx: &TtlTransaction) -> Result<()> {
+		self.db.commit(&tx.tx)
+	}
+
+	/// Flushes at most `max` eras of the journal to the database.
+	pub fn flush_journal<T: Into<Option<usize>>>(&mut self, max: T) -> Result<()> {
+		self.db.flush_journal(max)
+	}
+
+	/// Returns the value for the key, or `None` if it has expired.
+	pub fn get<K: AsRef<[u8]>>(&self, key: K) -> Result<Option<Vec<u8>>> {
+		let stored = match self.db.get(key)? {
+			Some(stored) => stored.to_vec(),
+			None => return Ok(None),
+		};
+
+		let (expiry, value) = decode(&stored)?;
+		if is_expired(expiry, now_millis()) {
+			return Ok(None);
+		}
+
+		Ok(Some(value.to_vec()))
+	}
+
+	/// Returns true if the key is in the database and has not expired.
+	pub fn contains<K: AsRef<[u8]>>(&self, key: K) -> Result<bool> {
+		self.get(key).map(|value| value.is_some())
+	}
+
+	/// Deletes expired entries in bounded commits and compacts the database, see
+	/// `Database::compact_with_filter`.
+	///
+	/// Returns the number of deleted entries.
+	pub fn purge_expired(&mut self) -> Result<usize> {
+		let now = now_millis();
+		let mut purged = 0;
+		self.db.compact_with_filter(|_, stored| match decode(stored) {
+			Ok((expiry, _)) if is_expired(expiry, now) => {
+				purged += 1;
+				FilterDecision::Remove
+			},
+			// values which can't be decoded are reported by `get`
+			_ => FilterDecision::Keep,
+		})?;
+
+		Ok(purged)
+	}
+}
+
+/// Transaction of a `TtlDatabase`.
+pub struct TtlTransaction {
+	tx: Transaction,
+}
+
+impl TtlTransaction {
+	/// Append new insert operation of a value which never expires.
+	pub fn insert<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, value: V) -> Result<()> {
+		self.insert_with_expiry(key.as_ref(), value.as_ref(), NEVER)
+	}
+
+	/// Append new insert operation of a value which expires after `ttl`.
+	///
+	/// The expiry is counted from the time this function is called.
+	pub fn insert_with_ttl<K: AsRef<[u8]>, V: AsRef<[u8]>>(&mut self, key: K, value: V, ttl: Duration) -> Result<()> {
+		// a ttl of 0 must expire the value, not make it live forever
+		let expiry = cmp::max(now_millis().saturating_add(to_millis(ttl)), NEVER + 1);
+		self.insert_with_expiry(key.as_ref(), value.as_ref(), expiry)
+	}
+
+	fn insert_with_expiry(&mut self, key: &[u8], value: &[u8], expiry: u64) -> Result<()> {
+		let mut stored = vec![0u8; EXPIRY_SIZE + value.len()];
+		LittleEndian::write_u64(&mut stored[..EXPIRY_SIZE], expiry);
+		stored[EXPIRY_SIZE..].copy_from_slice(value);
+		self.tx.insert(key, stored)
+	}
+
+	/// Append new delete operation to the list of transactions.
+	pub fn delete<K: AsRef<[u8]>>(&mut self, key: K) -> Result<()> {
+		self.tx.delete(key)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	extern crate tempdir;
+
+	use std::time::Duration;
+
+	use options::{Options, ValuesLen};
+	use super::TtlDatabase;
+
+	#[test]
+	fn test_ttl_entries() {
+		let temp = tempdir::TempDir::new("test_ttl_entries").unwrap();
+		let options = Options {
+			journal_eras: 0,
+			key_len: 3,
+			value_len: ValuesLen::Variable { expected: 16 },
+			..Default::default()
+		};
+
+		let mut db = TtlDatabase::create(temp.path(), options).unwrap();
+		let mut tx = db.create_transaction();
+		tx.insert("aaa", "forever").unwrap();
+		tx.insert_with_ttl("bbb", "an hour", Duration::from_secs(3600)).unwrap();
+		tx.insert_with_ttl("ccc", "expired", Duration::from_secs(0)).unwrap();
+		db.commit(&tx).unwrap();
+		db.flush_journal(None).unwrap();
+
+		assert_eq!(db.get("aaa").unwrap(), Some(b"forever".to_vec()));
+		assert_eq!(db.get("bbb").unwrap(), Some(b"an hour".to_vec()));
+		assert_eq!(db.get("ccc").unwrap(), None);
+		assert!(!db.contains("ccc").unwrap());
+		assert!(db.inner().contains("ccc").unwrap());
+
+		assert_eq!(db.purge_expired().unwrap(), 1);
+		assert!(!db.inner().contains("ccc").unwrap());
+		assert_eq!(db.purge_expired().unwrap(), 0);
+		assert_eq!(db.get("bbb").unwrap(), Some(b"an hour".to_vec()));
+
+		let constant = Options {
+			value_len: ValuesLen::Constant(8),
+			..Default::default()
+		};
+		assert!(TtlDatabase::create(temp.path().join("constant"), constant).is_err());
+	}
+}
@@ -0,0 +1,150 @@
+//! Golden tests of the on-disk format.
+//!
+//! Files in `tests/golden` were written by hand from the format description
+//! and must never be regenerated. If any of these tests fails, the format
+//! changed and `Metadata::DB_VERSION` has to be bumped. Collision logs are
+//! versioned by the magic bytes at their beginning instead, so only the fixture
+//! of the current log version is kept here.
+
+extern crate tempdir;
+extern crate paritydb;
+
+use std::fs;
+use std::io::{Read, Write};
+use std::path::Path;
+use tempdir::TempDir;
+use paritydb::{Database, Options, ValuesLen};
+
+fn read_file<P: AsRef<Path>>(path: P) -> Vec<u8> {
+	let mut file = fs::File::open(path).unwrap();
+	let mut data = Vec::new();
+	file.read_to_end(&mut data).unwrap();
+	data
+}
+
+fn golden(name: &str) -> Vec<u8> {
+	read_file(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("golden").join(name))
+}
+
+fn options() -> Options {
+	Options {
+		journal_eras: 0,
+		key_len: 3,
+		value_len: ValuesLen::Constant(3),
+		..Default::default()
+	}
+}
+
+/// Options of a database moving prefixes with two keys to collision files.
+fn collision_options() -> Options {
+	Options {
+		max_prefix_collisions: 2,
+		..options()
+	}
+}
+
+/// Collides prefix 'a' and deletes one of its keys, leaving a tombstone in the log.
+fn write_golden_collision_log(db: &mut Database) {
+	let mut tx = db.create_transaction();
+	tx.insert("abc", "001").unwrap();
+	tx.insert("abd", "002").unwrap();
+	db.commit(&tx).unwrap();
+	db.flush_journal(None).unwrap();
+	assert_eq!(db.compact().unwrap(), vec![b'a' as u32]);
+
+	let mut tx = db.create_transaction();
+	tx.delete("abd").unwrap();
+	db.commit(&tx).unwrap();
+	db.flush_journal(None).unwrap();
+}
+
+fn commit_golden_transaction(db: &mut Database) {
+	let mut tx = db.create_transaction();
+	tx.insert("abc", "001").unwrap();
+	tx.insert("bcd", "002").unwrap();
+	tx.delete("cde").unwrap();
+	db.commit(&tx).unwrap();
+}
+
+#[test]
+fn test_format_version() {
+	assert_eq!(Database::format_version(), 0);
+}
+
+#[test]
+fn test_journal_format() {
+	let temp = TempDir::new("test_journal_format").unwrap();
+	let mut db = Database::create(temp.path(), options()).unwrap();
+	commit_golden_transaction(&mut db);
+
+	assert_eq!(read_file(temp.path().join("0.era")), golden("0.era"));
+}
+
+#[test]
+fn test_data_format() {
+	let temp = TempDir::new("test_data_format").unwrap();
+	let mut db = Database::create(temp.path(), options()).unwrap();
+	commit_golden_transaction(&mut db);
+	db.flush_journal(None).unwrap();
+
+	assert_eq!(read_file(temp.path().join("meta.db")), golden("meta.db"));
+
+	// Records are placed at `prefix * field_size`, keys 'a' and 'b' are prefixes 97 and 98.
+	let data = read_file(temp.path().join("data.db"));
+	assert_eq!(&data[97 * 7..99 * 7], b"\x01abc001\x01bcd002" as &[u8]);
+	assert!(data[..97 * 7].iter().chain(&data[99 * 7..]).all(|b| *b == 0));
+}
+
+#[test]
+fn test_open_golden_journal() {
+	let temp = TempDir::new("test_open_golden_journal").unwrap();
+	drop(Database::create(temp.path(), options()).unwrap());
+	fs::File::create(temp.path().join("0.era")).unwrap().write_all(&golden("0.era")).unwrap();
+
+	let db = Database::open(temp.path(), options()).unwrap();
+	assert_eq!(db.get("abc").unwrap().unwrap(), "001");
+	assert_eq!(db.get("bcd").unwrap().unwrap(), "002");
+	assert_eq!(db.get("cde").unwrap(), None);
+}
+
+#[test]
+fn test_reject_unsupported_format_version() {
+	let temp = TempDir::new("test_reject_unsupported_format_version").unwrap();
+	drop(Database::create(temp.path(), options()).unwrap());
+
+	let mut meta = golden("meta.db");
+	meta[0] = 0xff;
+	fs::File::create(temp.path().join("meta.db")).unwrap().write_all(&meta).unwrap();
+
+	assert!(Database::open(temp.path(), options()).is_err());
+}
+
+#[test]
+fn test_collision_log_format() {
+	let temp = TempDir::new("test_collision_log_format").unwrap();
+	let mut db = Database::create(temp.path(), collision_options()).unwrap();
+	write_golden_collision_log(&mut db);
+
+	// The log is followed by the zeroed rest of its 64 KiB chunk.
+	let log = read_file(temp.path().join("collision-97.log"));
+	let golden = golden("collision-97.log");
+	assert_eq!(log.len(), 64 * 1024);
+	assert_eq!(&log[..golden.len()], &golden[..]);
+	assert!(log[golden.len()..].iter().all(|b| *b == 0));
+}
+
+#[test]
+fn test_open_golden_collision_log() {
+	let temp = TempDir::new("test_open_golden_collision_log").unwrap();
+	let mut db = Database::create(temp.path(), collision_options()).unwrap();
+	write_golden_collision_log(&mut db);
+	drop(db);
+
+	let mut log = golden("collision-97.log");
+	log.resize(64 * 1024, 0);
+	fs::File::create(temp.path().join("collision-97.log")).unwrap().write_all(&log).unwrap();
+
+	let db = Database::open(temp.path(), collision_options()).unwrap();
+	assert_eq!(db.get("abc").unwrap().unwrap(), "001");
+	assert_eq!(db.get("abd").unwrap(), None);
+}